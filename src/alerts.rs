@@ -0,0 +1,278 @@
+//! Alerting rules evaluated against a running node's status.
+//!
+//! Rules live in the config file:
+//!
+//! ```toml
+//! [[alerts]]
+//! name = "small routing table"
+//! when = { routing_table_below = 50 }
+//! for = "5m"
+//! action = { webhook = "http://localhost:9000/hook" }
+//!
+//! [[alerts]]
+//! name = "deaf"
+//! when = "no_inbound_requests"
+//! for = "1h"
+//! action = { exit = 2 }
+//! ```
+//!
+//! A rule fires once its condition has held for the `for` duration and can fire again
+//! after the condition clears.
+
+use crate::{runtime, timespec::parse_duration};
+use futures::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    time::{Duration, Instant},
+};
+
+/// how recent a stall has to be for `event_loop_stalled`
+const STALL_WINDOW: Duration = Duration::from_secs(60);
+
+/// What a rule watches for
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    /// fewer than this many peers in the routing table
+    RoutingTableBelow(usize),
+    /// the last dial to every bootstrap peer failed
+    BootstrapFailing,
+    /// the routing table hasn't reached the `[bootstrap]` goal yet
+    NotBootstrapped,
+    /// no inbound DHT requests within the rule's `for` duration
+    NoInboundRequests,
+    /// we had a confirmed external address and lost it
+    ReachabilityLost,
+    /// the event loop stalled in the last minute
+    EventLoopStalled,
+}
+
+/// What happens when a rule fires
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// write a warning to the log
+    Log,
+    /// post the alert as json to this http url
+    Webhook(String),
+    /// shut down with this exit code
+    Exit(i32),
+}
+
+/// A single alerting rule
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub name: String,
+    pub when: Condition,
+    /// how long the condition has to hold, e.g. "5m", immediately if not given
+    #[serde(rename = "for", default)]
+    pub hold: Option<String>,
+    #[serde(default = "default_action")]
+    pub action: Action,
+}
+
+fn default_action() -> Action {
+    Action::Log
+}
+
+/// What the rules are checked against
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Status {
+    /// peers in the routing table
+    pub routing_table: usize,
+    /// true while every bootstrap peer's last dial failed
+    pub bootstrap_failing: bool,
+    /// true once the routing table reached the `[bootstrap]` goal
+    pub bootstrapped: bool,
+    /// when the last inbound DHT request arrived
+    pub last_inbound: Option<Instant>,
+    /// whether we have a confirmed external address, none until we learn either way
+    pub reachable: Option<bool>,
+    /// how many times the event loop stalled
+    pub stalls: u64,
+    pub last_stall: Option<Instant>,
+}
+
+/// A rule that fired
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Alert {
+    pub name: String,
+    pub message: String,
+    #[serde(skip)]
+    pub action: Action,
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.name, self.message)
+    }
+}
+
+// a rule and its progress towards firing
+#[derive(Debug)]
+struct Watch {
+    rule: Rule,
+    hold: Duration,
+    since: Option<Instant>,
+    fired: bool,
+}
+
+/// Evaluates the rules over time
+#[derive(Debug)]
+pub struct Alerts {
+    watches: Vec<Watch>,
+    started: Instant,
+}
+
+impl Alerts {
+    /// check the rules are valid and start watching
+    pub fn new(rules: Vec<Rule>) -> Result<Self, String> {
+        let mut watches = Vec::new();
+        for rule in rules {
+            let hold = match &rule.hold {
+                Some(hold) => {
+                    parse_duration(hold).map_err(|e| format!("alert {}: {e}", rule.name))?
+                }
+                None => Duration::ZERO,
+            };
+            if let Action::Webhook(url) = &rule.action {
+                split_url(url).map_err(|e| format!("alert {}: {e}", rule.name))?;
+            }
+            watches.push(Watch {
+                rule,
+                hold,
+                since: None,
+                fired: false,
+            });
+        }
+        Ok(Self {
+            watches,
+            started: Instant::now(),
+        })
+    }
+
+    /// true if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    /// evaluate the rules, returning the ones that fired since the last check
+    pub fn check(&mut self, status: &Status, now: Instant) -> Vec<Alert> {
+        let mut alerts = Vec::new();
+        for watch in &mut self.watches {
+            let message = match &watch.rule.when {
+                Condition::RoutingTableBelow(n) => (status.routing_table < *n)
+                    .then(|| format!("{} peers in the routing table", status.routing_table)),
+                Condition::BootstrapFailing => status
+                    .bootstrap_failing
+                    .then(|| "the bootstrap peers can't be reached".to_string()),
+                Condition::NotBootstrapped => (!status.bootstrapped)
+                    .then(|| "the routing table hasn't reached the bootstrap goal".to_string()),
+                Condition::NoInboundRequests => {
+                    // the hold is the quiet period so the condition is immediate
+                    let last = status.last_inbound.unwrap_or(self.started);
+                    let quiet = now.saturating_duration_since(last);
+                    (quiet >= watch.hold)
+                        .then(|| format!("no inbound requests for {}s", quiet.as_secs()))
+                }
+                Condition::ReachabilityLost => (status.reachable == Some(false))
+                    .then(|| "no confirmed external address".to_string()),
+                Condition::EventLoopStalled => status
+                    .last_stall
+                    .filter(|at| now.saturating_duration_since(*at) < STALL_WINDOW)
+                    .map(|_| format!("the event loop stalled, {} times so far", status.stalls)),
+            };
+            let Some(message) = message else {
+                watch.since = None;
+                watch.fired = false;
+                continue;
+            };
+            let since = *watch.since.get_or_insert(now);
+            let held = matches!(watch.rule.when, Condition::NoInboundRequests)
+                || now.saturating_duration_since(since) >= watch.hold;
+            if held && !watch.fired {
+                watch.fired = true;
+                alerts.push(Alert {
+                    name: watch.rule.name.clone(),
+                    message,
+                    action: watch.rule.action.clone(),
+                });
+            }
+        }
+        alerts
+    }
+}
+
+/// post an alert as json to a plain http url, giving up after a few seconds
+pub async fn post_webhook(url: &str, alert: &Alert) -> io::Result<()> {
+    let limit = Duration::from_secs(5);
+    runtime::timeout(limit, post(url, alert)).await?
+}
+
+async fn post(url: &str, alert: &Alert) -> io::Result<()> {
+    let (host, path) =
+        split_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let body = serde_json::to_string(alert)?;
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let addr = if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{host}:80")
+    };
+    let mut stream = runtime::connect(&addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    stream.flush().await
+}
+
+// split an http url into its host and path
+fn split_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("only http:// webhooks are supported: {url}"))?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_after_hold() {
+        let rules: Vec<Rule> = vec![Rule {
+            name: "small".into(),
+            when: Condition::RoutingTableBelow(10),
+            hold: Some("5m".into()),
+            action: Action::Log,
+        }];
+        let mut alerts = Alerts::new(rules).unwrap();
+        let now = Instant::now();
+        let small = Status {
+            routing_table: 3,
+            ..Default::default()
+        };
+        assert!(alerts.check(&small, now).is_empty());
+        let later = now + Duration::from_secs(301);
+        assert_eq!(alerts.check(&small, later).len(), 1);
+        // fires once until the condition clears
+        assert!(alerts.check(&small, later).is_empty());
+        let big = Status {
+            routing_table: 30,
+            ..Default::default()
+        };
+        assert!(alerts.check(&big, later).is_empty());
+
+        assert!(split_url("https://example.com").is_err());
+        assert_eq!(
+            split_url("http://localhost:9000"),
+            Ok(("localhost:9000", "/"))
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Signed query results.
+//!
+//! A node answering queries for others signs each result with its key, so a consumer
+//! that got the answer relayed through something else can still check which node it came
+//! from and that nothing was changed on the way. The signature covers the result and when
+//! it was signed, `fleyg verify --answer` checks a saved one.
+
+use crate::peerstore::now_secs;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path};
+
+/// the prefix signed along with the answer so the signature can't be reused
+const DOMAIN: &[u8] = b"fleyg-answer:";
+
+/// A peer in a result, with the addresses we know for it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnswerPeer {
+    pub peer: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// The result of a query
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "query", rename_all = "snake_case")]
+pub enum Answer {
+    /// a record's value, none if it wasn't found
+    Record {
+        #[serde(with = "hex::serde")]
+        key: Vec<u8>,
+        #[serde(with = "hex_opt")]
+        value: Option<Vec<u8>>,
+        publisher: Option<PeerId>,
+    },
+    /// the providers of a key
+    Providers {
+        #[serde(with = "hex::serde")]
+        key: Vec<u8>,
+        providers: Vec<AnswerPeer>,
+    },
+    /// the peers closest to a key
+    ClosestPeers {
+        #[serde(with = "hex::serde")]
+        key: Vec<u8>,
+        peers: Vec<AnswerPeer>,
+    },
+}
+
+impl Answer {
+    /// the key the query was for
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Answer::Record { key, .. }
+            | Answer::Providers { key, .. }
+            | Answer::ClosestPeers { key, .. } => key,
+        }
+    }
+}
+
+// the signed part of an answer
+#[derive(Serialize)]
+struct Signed<'a> {
+    answer: &'a Answer,
+    signer: &'a PeerId,
+    issued: u64,
+}
+
+/// A query result signed by the node that ran the query
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedAnswer {
+    pub answer: Answer,
+    pub signer: PeerId,
+    /// unix seconds when it was signed
+    pub issued: u64,
+    /// the signer's public key in protobuf format
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub signature: Vec<u8>,
+}
+
+impl SignedAnswer {
+    /// sign the answer with our key
+    pub fn sign(key: &Keypair, answer: Answer) -> Self {
+        let mut signed = Self {
+            answer,
+            signer: key.public().to_peer_id(),
+            issued: now_secs(),
+            public_key: key.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        signed.signature = key
+            .sign(&signed.message())
+            .expect("signing with our own key works");
+        signed
+    }
+
+    /// check the answer was signed by its signer and hasn't changed since
+    pub fn verify(&self) -> Result<(), String> {
+        let public = PublicKey::try_decode_protobuf(&self.public_key).map_err(|e| e.to_string())?;
+        if public.to_peer_id() != self.signer {
+            return Err(format!("answer key doesn't belong to {}", self.signer));
+        }
+        if !public.verify(&self.message(), &self.signature) {
+            return Err("answer signature is invalid".to_string());
+        }
+        Ok(())
+    }
+
+    /// read a signed answer file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// write the signed answer to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let signed = Signed {
+            answer: &self.answer,
+            signer: &self.signer,
+            issued: self.issued,
+        };
+        let json = serde_json::to_vec(&signed).expect("answers always serialize");
+        [DOMAIN, &json].concat()
+    }
+}
+
+// an optional byte string as hex, or null
+mod hex_opt {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, s: S) -> Result<S::Ok, S::Error> {
+        match bytes {
+            Some(bytes) => s.serialize_some(&hex::encode(bytes)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<u8>>, D::Error> {
+        Option::<String>::deserialize(d)?
+            .map(|s| hex::decode(s).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = Keypair::generate_ed25519();
+        let answer = Answer::Providers {
+            key: b"/key".to_vec(),
+            providers: vec![AnswerPeer {
+                peer: PeerId::random(),
+                addrs: vec!["/ip4/1.2.3.4/tcp/4001".parse().unwrap()],
+            }],
+        };
+        let signed = SignedAnswer::sign(&key, answer);
+        assert!(signed.verify().is_ok());
+        let json = serde_json::to_vec(&signed).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<SignedAnswer>(&json).unwrap(),
+            signed
+        );
+
+        let mut forged = signed.clone();
+        if let Answer::Providers { providers, .. } = &mut forged.answer {
+            providers.clear();
+        }
+        assert!(forged.verify().is_err());
+    }
+}
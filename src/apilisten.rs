@@ -0,0 +1,213 @@
+//! Where fleyg's control and metrics APIs listen, and refusing to expose them.
+//!
+//! APIs listen on a unix socket in the state directory by default, readable only by the
+//! user running fleyg, or on windows a named pipe named after the state directory.
+//! Listening on TCP has to be asked for with `--api-listen`, and as the APIs have no
+//! authentication one that would be reachable from other hosts, on anything but a
+//! loopback address, isn't started:
+//!
+//! ```text
+//! --api-listen unix:/run/fleyg/api.sock
+//! --api-listen pipe:\\.\pipe\fleyg
+//! --api-listen 127.0.0.1:9920
+//! ```
+
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+/// the namespace windows keeps local named pipes in
+const PIPE_PREFIX: &str = r"\\.\pipe\";
+
+/// how long to try reaching a TCP API before taking nothing to be listening
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Where an API listens
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ApiListen {
+    Unix(PathBuf),
+    /// a windows named pipe, which has to be created rejecting remote clients to stay local
+    Pipe(String),
+    Tcp(SocketAddr),
+}
+
+impl ApiListen {
+    /// the named pipe of a state directory, pipes live in their own namespace so it's named
+    /// after a hash of the directory's path, which windows compares without case
+    pub fn pipe_for(root: &Path) -> Self {
+        let path = root.to_string_lossy().to_lowercase();
+        let hash = hex::encode(&Sha256::digest(path.as_bytes())[..8]);
+        ApiListen::Pipe(format!("{PIPE_PREFIX}fleyg-{hash}"))
+    }
+
+    /// true if other hosts can reach the API
+    pub fn is_exposed(&self) -> bool {
+        match self {
+            ApiListen::Unix(_) | ApiListen::Pipe(_) => false,
+            ApiListen::Tcp(addr) => !addr.ip().is_loopback(),
+        }
+    }
+
+    /// true if something answers on the API, as a running daemon does
+    pub fn answers(&self) -> bool {
+        match self {
+            #[cfg(unix)]
+            ApiListen::Unix(path) => std::os::unix::net::UnixStream::connect(path).is_ok(),
+            #[cfg(not(unix))]
+            ApiListen::Unix(_) => false,
+            // opening a pipe connects to it
+            ApiListen::Pipe(name) => std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(name)
+                .is_ok(),
+            ApiListen::Tcp(addr) => {
+                std::net::TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).is_ok()
+            }
+        }
+    }
+
+    /// refuse to listen where other hosts can reach an API, none of them authenticate
+    pub fn check(&self, api: &str) -> Result<(), String> {
+        if self.is_exposed() {
+            return Err(format!(
+                "refusing to expose the {api} API on {self}, it has no authentication, \
+                 listen on a loopback address or unix socket"
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for ApiListen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(ApiListen::Unix(path.into()));
+        }
+        if let Some(name) = s.strip_prefix("pipe:") {
+            return match name.starts_with(PIPE_PREFIX) {
+                true => Ok(ApiListen::Pipe(name.to_string())),
+                false => Err(format!("named pipes are under {PIPE_PREFIX}: {s}")),
+            };
+        }
+        let addr = s.strip_prefix("tcp:").unwrap_or(s);
+        addr.parse()
+            .map(ApiListen::Tcp)
+            .map_err(|_| format!("bad API address: {s} (unix:<path>, pipe:<name> or <ip>:<port>)"))
+    }
+}
+
+impl fmt::Display for ApiListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiListen::Unix(path) => write!(f, "unix:{}", path.display()),
+            ApiListen::Pipe(name) => write!(f, "pipe:{name}"),
+            ApiListen::Tcp(addr) => write!(f, "{addr}"),
+        }
+    }
+}
+
+/// Listening on a unix socket only the owner can use
+#[cfg(unix)]
+pub mod unix {
+    use std::{
+        fs, io,
+        os::unix::{
+            fs::{DirBuilderExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+        path::Path,
+    };
+
+    /// bind the socket, replacing a stale one nothing answers on. It's bound in a
+    /// directory only we can enter and moved into place once it's private, so nobody else
+    /// can connect to it in between
+    pub fn bind(path: &Path) -> io::Result<UnixListener> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            fs::remove_file(path)?;
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        fs::create_dir_all(dir)?;
+        let private = dir.join(format!(".fleyg-{}", std::process::id()));
+        fs::DirBuilder::new().mode(0o700).create(&private)?;
+        let bound = bind_in(&private, path);
+        let _ = fs::remove_dir(&private);
+        bound
+    }
+
+    // bind in the private directory, then move the socket to its path
+    fn bind_in(private: &Path, path: &Path) -> io::Result<UnixListener> {
+        let staged = private.join("s");
+        let listener = UnixListener::bind(&staged)?;
+        fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+        if let Err(e) = fs::rename(&staged, path) {
+            let _ = fs::remove_file(&staged);
+            return Err(e);
+        }
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exposure() {
+        let unix: ApiListen = "unix:/tmp/fleyg.sock".parse().unwrap();
+        assert!(unix.check("control").is_ok());
+        let local: ApiListen = "127.0.0.1:9920".parse().unwrap();
+        assert!(local.check("metrics").is_ok());
+        let public: ApiListen = "tcp:0.0.0.0:9920".parse().unwrap();
+        assert!(public.check("metrics").is_err());
+        assert!("nonsense".parse::<ApiListen>().is_err());
+
+        let pipe = ApiListen::pipe_for(Path::new(r"C:\Users\Me\AppData\Roaming\fleyg"));
+        assert_eq!(
+            pipe,
+            ApiListen::pipe_for(Path::new(r"c:\users\me\appdata\roaming\fleyg"))
+        );
+        assert_eq!(pipe.to_string().parse::<ApiListen>().unwrap(), pipe);
+        assert!(pipe.check("control").is_ok());
+        assert!("pipe:fleyg".parse::<ApiListen>().is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let api = ApiListen::Tcp(listener.local_addr().unwrap());
+        assert!(api.answers());
+        assert!(!ApiListen::Unix("/nonexistent/fleyg.sock".into()).answers());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_private_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("fleyg-apilisten-{}", std::process::id()));
+        let path = dir.join("api.sock");
+        let listener = unix::bind(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(ApiListen::Unix(path.clone()).answers());
+        // the directory it was bound in is gone
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+        assert!(unix::bind(&path).is_err());
+        drop(listener);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
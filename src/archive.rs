@@ -0,0 +1,388 @@
+//! An append only archive of every event a serving node handles.
+//!
+//! Events are kept whatever the log level, so what a node saw can be looked into after
+//! the fact with `fleyg events query`. The archive is a directory of gzipped json lines,
+//! one file per hour named by the unix time the hour starts, so a query only reads the
+//! hours it covers and `fleyg state prune` drops old hours:
+//!
+//! ```text
+//! events/1700000000.jsonl.gz
+//! {"at":1700000012345,"kind":"kad","peer":"12D3KooW...","detail":"RoutingUpdated { .. }"}
+//! ```
+//!
+//! Events are buffered and written out as one gzip member every few seconds, a member
+//! torn by a crash ends the reading of its file.
+
+use crate::{node::FleygBehaviorEvent, sessions::now_millis};
+use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+#[cfg(feature = "pubsub")]
+use libp2p::gossipsub;
+use libp2p::{
+    autonat, identify, kad::KademliaEvent, relay, request_response, swarm::SwarmEvent, PeerId,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// the seconds of events in each file
+const SEGMENT_SECS: u64 = 60 * 60;
+
+/// how often buffered events are written out
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+const SUFFIX: &str = ".jsonl.gz";
+
+/// One archived event
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedEvent {
+    /// unix time in milliseconds
+    pub at: u64,
+    /// what the event came from: identify, kad, ping, relay, relay_server, pex,
+    /// gossipsub, mdns, autonat, connection, dial, listen or swarm
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    /// the whole event as libp2p prints it
+    pub detail: String,
+}
+
+impl ArchivedEvent {
+    /// a swarm event handled now
+    pub fn from_swarm<E: fmt::Debug>(event: &SwarmEvent<FleygBehaviorEvent, E>) -> Self {
+        Self {
+            at: now_millis(),
+            kind: kind(event).to_string(),
+            peer: peer(event),
+            detail: format!("{event:?}"),
+        }
+    }
+}
+
+// the kind of a swarm event
+fn kind<E>(event: &SwarmEvent<FleygBehaviorEvent, E>) -> &'static str {
+    match event {
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(_)) => "identify",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(_)) => "kad",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(_)) => "ping",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayClient(_)) => "relay",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayServer(_)) => "relay_server",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Pex(_)) => "pex",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Blocks(_)) => "blocks",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(_)) => "gossipsub",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Mdns(_)) => "mdns",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Autonat(_)) => "autonat",
+        SwarmEvent::ConnectionEstablished { .. }
+        | SwarmEvent::ConnectionClosed { .. }
+        | SwarmEvent::IncomingConnection { .. }
+        | SwarmEvent::IncomingConnectionError { .. } => "connection",
+        SwarmEvent::OutgoingConnectionError { .. } | SwarmEvent::Dialing { .. } => "dial",
+        SwarmEvent::NewListenAddr { .. }
+        | SwarmEvent::ExpiredListenAddr { .. }
+        | SwarmEvent::ListenerClosed { .. }
+        | SwarmEvent::ListenerError { .. } => "listen",
+        _ => "swarm",
+    }
+}
+
+// the remote peer of a swarm event, if it names one
+fn peer<E>(event: &SwarmEvent<FleygBehaviorEvent, E>) -> Option<PeerId> {
+    match event {
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(e)) => match e {
+            identify::Event::Received { peer_id, .. }
+            | identify::Event::Sent { peer_id, .. }
+            | identify::Event::Pushed { peer_id, .. }
+            | identify::Event::Error { peer_id, .. } => Some(*peer_id),
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(e)) => match e {
+            KademliaEvent::RoutingUpdated { peer, .. }
+            | KademliaEvent::UnroutablePeer { peer, .. }
+            | KademliaEvent::RoutablePeer { peer, .. }
+            | KademliaEvent::PendingRoutablePeer { peer, .. } => Some(*peer),
+            _ => None,
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(e)) => Some(e.peer),
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayClient(e)) => match e {
+            relay::client::Event::ReservationReqAccepted { relay_peer_id, .. }
+            | relay::client::Event::OutboundCircuitEstablished { relay_peer_id, .. } => {
+                Some(*relay_peer_id)
+            }
+            relay::client::Event::InboundCircuitEstablished { src_peer_id, .. } => {
+                Some(*src_peer_id)
+            }
+            #[allow(unreachable_patterns)]
+            _ => None,
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayServer(e)) => match e {
+            relay::Event::ReservationReqAccepted { src_peer_id, .. }
+            | relay::Event::ReservationReqDenied { src_peer_id }
+            | relay::Event::ReservationTimedOut { src_peer_id }
+            | relay::Event::CircuitReqDenied { src_peer_id, .. }
+            | relay::Event::CircuitReqAccepted { src_peer_id, .. }
+            | relay::Event::CircuitClosed { src_peer_id, .. } => Some(*src_peer_id),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Pex(e)) => match e {
+            request_response::Event::Message { peer, .. }
+            | request_response::Event::OutboundFailure { peer, .. }
+            | request_response::Event::InboundFailure { peer, .. }
+            | request_response::Event::ResponseSent { peer, .. } => Some(*peer),
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Blocks(e)) => match e {
+            request_response::Event::Message { peer, .. }
+            | request_response::Event::OutboundFailure { peer, .. }
+            | request_response::Event::InboundFailure { peer, .. }
+            | request_response::Event::ResponseSent { peer, .. } => Some(*peer),
+        },
+        #[cfg(feature = "pubsub")]
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(e)) => match e {
+            gossipsub::Event::Message {
+                propagation_source, ..
+            } => Some(*propagation_source),
+            gossipsub::Event::Subscribed { peer_id, .. }
+            | gossipsub::Event::Unsubscribed { peer_id, .. }
+            | gossipsub::Event::GossipsubNotSupported { peer_id } => Some(*peer_id),
+        },
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Autonat(e)) => match e {
+            autonat::Event::InboundProbe(
+                autonat::InboundProbeEvent::Request { peer, .. }
+                | autonat::InboundProbeEvent::Response { peer, .. }
+                | autonat::InboundProbeEvent::Error { peer, .. },
+            )
+            | autonat::Event::OutboundProbe(
+                autonat::OutboundProbeEvent::Request { peer, .. }
+                | autonat::OutboundProbeEvent::Response { peer, .. },
+            ) => Some(*peer),
+            autonat::Event::OutboundProbe(autonat::OutboundProbeEvent::Error { peer, .. }) => *peer,
+            autonat::Event::StatusChanged { .. } => None,
+        },
+        SwarmEvent::ConnectionEstablished { peer_id, .. }
+        | SwarmEvent::ConnectionClosed { peer_id, .. } => Some(*peer_id),
+        SwarmEvent::OutgoingConnectionError { peer_id, .. }
+        | SwarmEvent::Dialing { peer_id, .. } => *peer_id,
+        _ => None,
+    }
+}
+
+/// The archive being written to
+#[derive(Debug)]
+pub struct EventArchive {
+    dir: PathBuf,
+    /// the start of the hour the buffered events are in
+    segment: u64,
+    pending: Vec<ArchivedEvent>,
+    last_flush: Instant,
+}
+
+impl EventArchive {
+    /// archive into the given directory
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            segment: 0,
+            pending: Vec::new(),
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// the archive directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// add an event, writing out the buffered ones if they're due
+    pub fn append(&mut self, event: ArchivedEvent) -> io::Result<()> {
+        let segment = segment_of(event.at);
+        if segment != self.segment {
+            self.flush()?;
+            self.segment = segment;
+        }
+        self.pending.push(event);
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// write the buffered events to their hour's file
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let path = self.dir.join(format!("{}{SUFFIX}", self.segment));
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut gz = GzEncoder::new(BufWriter::new(file), Compression::default());
+        for event in self.pending.drain(..) {
+            serde_json::to_writer(&mut gz, &event)?;
+            gz.write_all(b"\n")?;
+        }
+        gz.finish()?.flush()
+    }
+}
+
+impl Drop for EventArchive {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!(
+                "Lost archived events writing to {}: {e}",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+/// Which archived events to return, the times are unix seconds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventQuery {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub peer: Option<PeerId>,
+    pub kind: Option<String>,
+}
+
+impl EventQuery {
+    /// true if the event is one asked for
+    pub fn matches(&self, event: &ArchivedEvent) -> bool {
+        let secs = event.at / 1000;
+        !(self.since.is_some_and(|since| secs < since)
+            || self.until.is_some_and(|until| secs > until)
+            || self.peer.is_some_and(|peer| event.peer != Some(peer))
+            || self.kind.as_ref().is_some_and(|kind| event.kind != *kind))
+    }
+
+    // true if the hour starting at the given time can hold events asked for
+    fn covers(&self, segment: u64) -> bool {
+        !(self
+            .since
+            .is_some_and(|since| segment + SEGMENT_SECS <= since)
+            || self.until.is_some_and(|until| segment > until))
+    }
+}
+
+/// the archived events in a directory matching the query, oldest first, none if there's
+/// no archive
+pub fn query<P: AsRef<Path>>(dir: P, query: &EventQuery) -> io::Result<Vec<ArchivedEvent>> {
+    let mut events = Vec::new();
+    for (segment, path) in segments(dir.as_ref())? {
+        if !query.covers(segment) {
+            continue;
+        }
+        let lines = BufReader::new(MultiGzDecoder::new(BufReader::new(File::open(&path)?)));
+        for line in lines.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("Ignoring the rest of {}: {e}", path.display());
+                    break;
+                }
+            };
+            match serde_json::from_str::<ArchivedEvent>(&line) {
+                Ok(event) if query.matches(&event) => events.push(event),
+                Ok(_) => {}
+                Err(e) => warn!("Skipping a bad event in {}: {e}", path.display()),
+            }
+        }
+    }
+    Ok(events)
+}
+
+// the start of the hour a time in milliseconds is in
+fn segment_of(millis: u64) -> u64 {
+    let secs = millis / 1000;
+    secs - secs % SEGMENT_SECS
+}
+
+// the archive files in a directory by the hour they start, oldest first
+fn segments(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut segments = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let start = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_suffix(SUFFIX))
+            .and_then(|n| n.parse().ok());
+        if let Some(start) = start {
+            segments.push((start, path));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_and_query() {
+        let dir = std::env::temp_dir().join(format!("fleyg-archive-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let peer = PeerId::random();
+        let event = |at: u64, kind: &str, peer: Option<PeerId>| ArchivedEvent {
+            at,
+            kind: kind.to_string(),
+            peer,
+            detail: format!("{kind} at {at}"),
+        };
+
+        let mut archive = EventArchive::open(&dir).unwrap();
+        archive
+            .append(event(1_700_000_000_000, "kad", None))
+            .unwrap();
+        archive
+            .append(event(1_700_000_001_000, "ping", Some(peer)))
+            .unwrap();
+        // the next hour goes in its own file
+        archive
+            .append(event(1_700_003_600_000, "kad", Some(peer)))
+            .unwrap();
+        archive.flush().unwrap();
+        archive
+            .append(event(1_700_003_601_000, "identify", Some(peer)))
+            .unwrap();
+        drop(archive);
+        assert_eq!(segments(&dir).unwrap().len(), 2);
+
+        // a torn member ends its file
+        let (_, last) = segments(&dir).unwrap().pop().unwrap();
+        let mut file = OpenOptions::new().append(true).open(last).unwrap();
+        file.write_all(&[0x1f, 0x8b, 0x08]).unwrap();
+        drop(file);
+
+        let all = query(&dir, &EventQuery::default()).unwrap();
+        assert_eq!(all.len(), 4);
+        let kad = EventQuery {
+            kind: Some("kad".into()),
+            ..Default::default()
+        };
+        assert_eq!(query(&dir, &kad).unwrap().len(), 2);
+        let recent = EventQuery {
+            since: Some(1_700_003_600),
+            peer: Some(peer),
+            ..Default::default()
+        };
+        let found: Vec<_> = query(&dir, &recent)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.kind)
+            .collect();
+        assert_eq!(found, vec!["kad", "identify"]);
+        assert!(query(dir.join("missing"), &kad).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
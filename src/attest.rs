@@ -0,0 +1,150 @@
+//! Signed operator attestations published in the DHT.
+//!
+//! An attestation binds a peer id to a small blob of operator metadata and is signed
+//! with the peer's own key, so anyone fetching it can check it came from that peer. It is
+//! stored under `/fleyg-attest/<peer id bytes>`. Networks whose nodes only accept known
+//! record namespaces, like the IPFS DHT, won't store these.
+
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// the record key namespace
+const NAMESPACE: &[u8] = b"/fleyg-attest/";
+
+/// the prefix signed along with the attestation so the signature can't be reused
+const DOMAIN: &[u8] = b"fleyg-attest:";
+
+/// What an operator says about their node
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub contact: Option<String>,
+    pub purpose: Option<String>,
+    pub website: Option<String>,
+}
+
+impl Metadata {
+    /// true if nothing is set
+    pub fn is_empty(&self) -> bool {
+        self.contact.is_none() && self.purpose.is_none() && self.website.is_none()
+    }
+}
+
+// the signed part of an attestation
+#[derive(Serialize)]
+struct Signed<'a> {
+    peer: &'a PeerId,
+    metadata: &'a Metadata,
+    issued: u64,
+}
+
+/// An attestation as stored in the DHT
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attestation {
+    pub peer: PeerId,
+    pub metadata: Metadata,
+    /// unix seconds when it was signed
+    pub issued: u64,
+    /// the peer's public key in protobuf format
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub signature: Vec<u8>,
+}
+
+/// the record key for a peer's attestation
+pub fn key(peer: &PeerId) -> Vec<u8> {
+    [NAMESPACE, &peer.to_bytes()].concat()
+}
+
+/// check a record under the attestation namespace, none if the key is outside it
+pub fn check_record(key: &[u8], value: &[u8]) -> Option<Result<(), String>> {
+    let peer = key.strip_prefix(NAMESPACE)?;
+    Some(match PeerId::from_bytes(peer) {
+        Ok(peer) => Attestation::from_bytes(value).and_then(|a| a.verify(&peer)),
+        Err(e) => Err(format!("attestation key isn't a peer id: {e}")),
+    })
+}
+
+impl Attestation {
+    /// sign the metadata with our key
+    pub fn sign(key: &Keypair, metadata: Metadata) -> Result<Self, String> {
+        let peer = key.public().to_peer_id();
+        let issued = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let signature = key
+            .sign(&message(&peer, &metadata, issued)?)
+            .map_err(|e| e.to_string())?;
+        Ok(Self {
+            peer,
+            metadata,
+            issued,
+            public_key: key.public().encode_protobuf(),
+            signature,
+        })
+    }
+
+    /// check the attestation was signed by the expected peer
+    pub fn verify(&self, expected: &PeerId) -> Result<(), String> {
+        if &self.peer != expected {
+            return Err(format!("attestation is for {}, not {expected}", self.peer));
+        }
+        let public = PublicKey::try_decode_protobuf(&self.public_key).map_err(|e| e.to_string())?;
+        if &public.to_peer_id() != expected {
+            return Err(format!("attestation key doesn't belong to {expected}"));
+        }
+        let message = message(&self.peer, &self.metadata, self.issued)?;
+        if !public.verify(&message, &self.signature) {
+            return Err("attestation signature is invalid".to_string());
+        }
+        Ok(())
+    }
+
+    /// the record value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("attestations always serialize")
+    }
+
+    /// parse a record value
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(bytes).map_err(|e| format!("malformed attestation: {e}"))
+    }
+}
+
+fn message(peer: &PeerId, metadata: &Metadata, issued: u64) -> Result<Vec<u8>, String> {
+    let signed = Signed {
+        peer,
+        metadata,
+        issued,
+    };
+    let json = serde_json::to_vec(&signed).map_err(|e| e.to_string())?;
+    Ok([DOMAIN, &json].concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = Keypair::generate_ed25519();
+        let peer = key.public().to_peer_id();
+        let metadata = Metadata {
+            contact: Some("ops@example.com".into()),
+            ..Default::default()
+        };
+        let attestation = Attestation::sign(&key, metadata).unwrap();
+        let parsed = Attestation::from_bytes(&attestation.to_bytes()).unwrap();
+        assert!(parsed.verify(&peer).is_ok());
+
+        let mut forged = parsed.clone();
+        forged.metadata.purpose = Some("research".into());
+        assert!(forged.verify(&peer).is_err());
+        assert!(parsed.verify(&PeerId::random()).is_err());
+    }
+}
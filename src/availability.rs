@@ -0,0 +1,129 @@
+//! Availability timelines of the peers providing a key.
+//!
+//! `fleyg watch-providers` looks up a key's providers every interval and feeds each
+//! round's answer to a [`ProviderWatch`], which turns them into providers appearing and
+//! disappearing. A providers query doesn't always reach every provider, so a provider
+//! only counts as gone after it's been missing from a few rounds in a row.
+
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+/// A provider appearing or disappearing
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    /// unix time of the round that noticed it
+    pub at: u64,
+    pub peer: PeerId,
+    /// true if the provider appeared, false if it disappeared
+    pub up: bool,
+}
+
+/// How available one provider has been
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Provider {
+    /// the rounds the provider was found in
+    pub seen: u32,
+    /// rounds since it was last found
+    pub missed: u32,
+    /// true while it counts as providing
+    pub up: bool,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Tracks the providers of a key over rounds of lookups
+#[derive(Clone, Debug)]
+pub struct ProviderWatch {
+    misses: u32,
+    rounds: u32,
+    providers: BTreeMap<PeerId, Provider>,
+}
+
+impl ProviderWatch {
+    /// a provider counts as gone after missing `misses` rounds in a row
+    pub fn new(misses: u32) -> Self {
+        Self {
+            misses: misses.max(1),
+            rounds: 0,
+            providers: BTreeMap::new(),
+        }
+    }
+
+    /// the rounds observed so far
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// every provider seen so far
+    pub fn providers(&self) -> &BTreeMap<PeerId, Provider> {
+        &self.providers
+    }
+
+    /// the providers found in a round at unix time `at`, returns what changed
+    pub fn observe(&mut self, found: &HashSet<PeerId>, at: u64) -> Vec<Change> {
+        self.rounds += 1;
+        let mut changes = Vec::new();
+        for peer in found {
+            let provider = self.providers.entry(*peer).or_insert_with(|| Provider {
+                first_seen: at,
+                ..Default::default()
+            });
+            provider.seen += 1;
+            provider.missed = 0;
+            provider.last_seen = at;
+            if !provider.up {
+                provider.up = true;
+                changes.push(Change {
+                    at,
+                    peer: *peer,
+                    up: true,
+                });
+            }
+        }
+        for (peer, provider) in &mut self.providers {
+            if found.contains(peer) || !provider.up {
+                continue;
+            }
+            provider.missed += 1;
+            if provider.missed >= self.misses {
+                provider.up = false;
+                changes.push(Change {
+                    at,
+                    peer: *peer,
+                    up: false,
+                });
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gone_after_misses() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut watch = ProviderWatch::new(2);
+        let changes = watch.observe(&HashSet::from([a, b]), 10);
+        assert_eq!(changes.len(), 2);
+        assert!(changes.iter().all(|c| c.up));
+
+        // one missed round isn't enough
+        assert!(watch.observe(&HashSet::from([a]), 20).is_empty());
+        assert_eq!(
+            watch.observe(&HashSet::from([a]), 30),
+            vec![Change {
+                at: 30,
+                peer: b,
+                up: false
+            }]
+        );
+        assert_eq!(watch.observe(&HashSet::from([a, b]), 40).len(), 1);
+        assert_eq!(watch.rounds(), 4);
+        assert_eq!(watch.providers()[&b].seen, 2);
+        assert_eq!(watch.providers()[&a].first_seen, 10);
+    }
+}
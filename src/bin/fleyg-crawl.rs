@@ -0,0 +1,244 @@
+use env_logger::Env;
+use fleyg::{
+    census::{self, CensusRecord},
+    deadline::next_before,
+    diagnose::Diagnosis,
+    identity::KeyType,
+    network::{parse_bootnode, Network},
+    node::{FleygBehaviorEvent, FleygNode},
+    peerwalk::PeerWalk,
+    runtime,
+    shutdown::Shutdown,
+};
+use libp2p::{
+    identify,
+    kad::{GetClosestPeersError, KademliaEvent, Mode, QueryResult},
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        SwarmEvent,
+    },
+    Multiaddr, PeerId,
+};
+use log::*;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, LineWriter, Write},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fleyg-crawl",
+    version = "0.1",
+    author = "Dave Huseby <dwh@linuxprogrammer.org>",
+    about = "walk the DHT and list every peer found, its agent and addresses"
+)]
+struct Opt {
+    /// the network to crawl: ipfs, kusama, lan, polkadot or none
+    #[structopt(long, default_value = "ipfs")]
+    network: String,
+
+    /// a bootstrap peer's address ending in /p2p/<peer id>, repeatable, replaces the
+    /// network's bootstrap peers
+    #[structopt(long = "bootstrap", parse(try_from_str = parse_bootnode))]
+    bootnodes: Vec<Multiaddr>,
+
+    /// write the peers as json lines to this file instead of stdout
+    #[structopt(long, parse(from_os_str))]
+    out: Option<PathBuf>,
+
+    /// the number of random walks to run at once to discover peers
+    #[structopt(long, default_value = "4")]
+    walks: usize,
+
+    /// stop walking after this many walks in a row find no new peers
+    #[structopt(long, default_value = "8")]
+    idle_walks: usize,
+
+    /// the most peers to dial and identify at once
+    #[structopt(long, default_value = "64")]
+    workers: usize,
+
+    /// seconds to wait for a peer to connect and identify
+    #[structopt(long, default_value = "10")]
+    peer_timeout: u64,
+
+    /// seconds to spend crawling
+    #[structopt(long, default_value = "600")]
+    timeout: u64,
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    runtime::block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let opt = Opt::from_args();
+
+    let mut network = Network::preset(&opt.network)?;
+    if !opt.bootnodes.is_empty() {
+        network.bootnodes = opt.bootnodes.clone();
+    }
+    let mut swarm = FleygNode::new(KeyType::default().generate()?)
+        .network(network.clone())
+        .agent("fleyg-crawl/0.0.1")
+        .build()
+        .await?;
+    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Client));
+    let mut out: LineWriter<Box<dyn Write>> = LineWriter::new(match &opt.out {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    });
+    let mut emit = |record: CensusRecord| -> io::Result<()> {
+        serde_json::to_writer(&mut out, &record)?;
+        out.write_all(b"\n")
+    };
+
+    let mut walk = PeerWalk::new();
+    for (peer, addr) in network.bootnode_peers() {
+        walk.discovered(peer, vec![addr]);
+    }
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    let peer_timeout = Duration::from_secs(opt.peer_timeout);
+    let (mut walks, mut idle, mut found_in_walk) = (0, 0, 0);
+    let mut identified = Vec::new();
+    let shutdown = Shutdown::catch();
+
+    loop {
+        // keep the random walks going until they stop finding peers, each starts from a
+        // routing table the peers dialed so far have filled
+        while walks < opt.walks && idle < opt.idle_walks {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .get_closest_peers(PeerId::random());
+            walks += 1;
+        }
+        while walk.dialing() < opt.workers {
+            let Some((peer, addrs)) = walk.next(Instant::now()) else {
+                break;
+            };
+            let opts = DialOpts::peer_id(peer)
+                .addresses(addrs)
+                .condition(PeerCondition::NotDialing)
+                .build();
+            if let Err(e) = swarm.dial(opts) {
+                if let Some(addrs) = walk.finish(&peer) {
+                    emit(CensusRecord::unreachable(peer, addrs, e.to_string()))?;
+                }
+            }
+        }
+        if walks == 0 && walk.queued() == 0 && walk.dialing() == 0 {
+            break;
+        }
+
+        // wake at least once a second to time out slow peers
+        let tick = (Instant::now() + Duration::from_secs(1)).min(deadline);
+        let event = next_before(&mut swarm, tick).await;
+        if Instant::now() >= deadline {
+            info!("Crawl timed out");
+            break;
+        }
+        if shutdown.requested() {
+            info!("Crawl interrupted");
+            break;
+        }
+        for peer in walk.late(Instant::now(), peer_timeout) {
+            if let Some(addrs) = walk.finish(&peer) {
+                emit(CensusRecord::unreachable(peer, addrs, "timed out".into()))?;
+            }
+            let _ = swarm.disconnect_peer_id(peer);
+        }
+
+        match event {
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::RoutingUpdated {
+                    peer, addresses, ..
+                },
+            ))) => {
+                if walk.discovered(peer, addresses.into_vec()) {
+                    found_in_walk += 1;
+                }
+            }
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    result: QueryResult::GetClosestPeers(result),
+                    step,
+                    ..
+                },
+            ))) => {
+                let peers = match result {
+                    Ok(ok) => ok.peers,
+                    Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                for peer in peers {
+                    if walk.discovered(peer, Vec::new()) {
+                        found_in_walk += 1;
+                    }
+                }
+                if step.last() {
+                    walks = walks.saturating_sub(1);
+                    idle = if found_in_walk == 0 { idle + 1 } else { 0 };
+                    found_in_walk = 0;
+                    debug!("{} peers found, {} queued", walk.len(), walk.queued());
+                }
+            }
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(
+                identify::Event::Received { peer_id, info },
+            ))) => {
+                if walk.finish(&peer_id).is_some() {
+                    let record = CensusRecord::identified(peer_id, &info);
+                    emit(record.clone())?;
+                    identified.push(record);
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                }
+            }
+            Some(SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            }) => {
+                let reason = match Diagnosis::from_dial_error(&error).pop() {
+                    Some(d) => d.to_string(),
+                    None => error.to_string(),
+                };
+                if let Some(addrs) = walk.finish(&peer_id) {
+                    emit(CensusRecord::unreachable(peer_id, addrs, reason))?;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // every peer is listed once, the ones the crawl didn't get to without an agent
+    let unfinished: Vec<_> = walk
+        .unfinished()
+        .map(|(peer, found)| (*peer, found.addrs.clone()))
+        .collect();
+    for (peer, addrs) in unfinished {
+        emit(CensusRecord::unreachable(
+            peer,
+            addrs,
+            "not dialed before the crawl ended".into(),
+        ))?;
+    }
+    drop(emit);
+    out.flush()?;
+
+    info!(
+        "Found {} peers, identified {}",
+        walk.len(),
+        identified.len()
+    );
+    let agents = census::count_by(&identified, |r| r.agent.clone());
+    let mut agents: Vec<_> = agents.into_iter().collect();
+    agents.sort_by(|a, b| b.1.cmp(&a.1));
+    for (agent, count) in agents.iter().take(10) {
+        info!("\t{count}\t{agent}");
+    }
+    Ok(())
+}
@@ -0,0 +1,303 @@
+use crate::{bootstrap, put_acked, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    attest::{self, Attestation, Metadata},
+    deadline::{idle_until, next_before},
+    memprofile,
+    progress::Progress,
+    receipt::Receipt,
+    retry::{Failure, RetryPolicy},
+    sessions::now_millis,
+    skew::{self, Skew},
+    table::{Cell, Output, Table},
+};
+use libp2p::{
+    identity::Keypair,
+    kad::{
+        record::{Key, Record},
+        GetRecordOk, KademliaEvent, PeerRecord, QueryResult, Quorum,
+    },
+    swarm::SwarmEvent,
+    PeerId, Swarm,
+};
+use log::*;
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct AttestOpt {
+    /// how to reach the node's operator
+    #[structopt(long)]
+    contact: Option<String>,
+
+    /// what the node is for
+    #[structopt(long)]
+    purpose: Option<String>,
+
+    /// the operator's website
+    #[structopt(long)]
+    website: Option<String>,
+
+    /// store the attestation on each of the closest peers and write a signed receipt of
+    /// the ones that acknowledged it to this file
+    #[structopt(long, parse(from_os_str))]
+    receipt: Option<PathBuf>,
+
+    /// seconds to spend on each attempt at publishing or looking up
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+
+    #[structopt(subcommand)]
+    cmd: Option<AttestCmd>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AttestCmd {
+    /// fetch and verify a peer's attestation
+    Lookup {
+        /// the peer to look up
+        peer: PeerId,
+    },
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: AttestOpt,
+    key: &Keypair,
+    retry: RetryPolicy,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, Instant::now() + timeout).await?;
+    match opt.cmd {
+        Some(AttestCmd::Lookup { peer }) => {
+            let attestation = lookup(&mut swarm, peer, timeout, &retry).await?;
+            print(&attestation, output)
+        }
+        None => {
+            let metadata = Metadata {
+                contact: opt.contact,
+                purpose: opt.purpose,
+                website: opt.website,
+            };
+            if metadata.is_empty() {
+                return Err("give at least one of --contact, --purpose or --website".into());
+            }
+            let receipt = opt.receipt.as_deref();
+            publish(&mut swarm, key, metadata, receipt, timeout, &retry).await
+        }
+    }
+}
+
+async fn publish(
+    swarm: &mut Swarm<FleygBehavior>,
+    key: &Keypair,
+    metadata: Metadata,
+    receipt: Option<&Path>,
+    timeout: Duration,
+    retry: &RetryPolicy,
+) -> Result<(), Box<dyn Error>> {
+    let attestation = Attestation::sign(key, metadata)?;
+    let record = Record::new(
+        Key::new(&attest::key(&attestation.peer)),
+        attestation.to_bytes(),
+    );
+    let mut attempts = 1;
+    loop {
+        let deadline = Instant::now() + timeout;
+        let result = match receipt {
+            Some(path) => put_acked(swarm, record.clone(), Quorum::One, deadline)
+                .await
+                .map(|acks| Some((path, acks))),
+            None => put(swarm, record.clone(), deadline).await.map(|_| None),
+        };
+        let (failure, error) = match result {
+            Ok(acked) => {
+                info!("Published attestation for {}", attestation.peer);
+                if let Some((path, acks)) = acked {
+                    info!("{} peers acknowledged it", acks.len());
+                    Receipt::sign(key, &record.key.to_vec(), &record.value, acks).save(path)?;
+                    info!("Wrote the receipt to {}", path.display());
+                }
+                return Ok(());
+            }
+            Err(failed) => failed,
+        };
+        match retry.retry(attempts, failure) {
+            Some(delay) => {
+                info!("Publishing the attestation failed: {error}, retrying in {delay:?}");
+                idle_until(swarm, Instant::now() + delay).await;
+                attempts += 1;
+            }
+            None => return Err(format!("publishing the attestation failed: {error}").into()),
+        }
+    }
+}
+
+// one attempt at storing the record
+async fn put(
+    swarm: &mut Swarm<FleygBehavior>,
+    record: Record,
+    deadline: Instant,
+) -> Result<(), (Failure, String)> {
+    let query = swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, Quorum::One)
+        .map_err(|e| (Failure::Fatal, e.to_string()))?;
+    let progress = Progress::spinner("publishing attestation");
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::PutRecord(result),
+                ..
+            },
+        )) = event
+        {
+            if id != query {
+                continue;
+            }
+            progress.finish();
+            return result
+                .map(|_| ())
+                .map_err(|e| (Failure::from(&e), e.to_string()));
+        }
+    }
+    progress.finish();
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    Err((Failure::Timeout, "timed out".to_string()))
+}
+
+async fn lookup(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    timeout: Duration,
+    retry: &RetryPolicy,
+) -> Result<Attestation, Box<dyn Error>> {
+    let mut rejected = 0;
+    let mut attempts = 1;
+    loop {
+        let deadline = Instant::now() + timeout;
+        let failure = match get(swarm, peer, deadline, &mut rejected).await {
+            Ok(attestation) => return Ok(attestation),
+            Err(failure) => failure,
+        };
+        match retry.retry(attempts, failure) {
+            Some(delay) => {
+                info!("Looking up the attestation failed: {failure}, retrying in {delay:?}");
+                idle_until(swarm, Instant::now() + delay).await;
+                attempts += 1;
+            }
+            None if failure == Failure::Timeout => {
+                return Err(format!("timed out looking up the attestation for {peer}").into())
+            }
+            None => {
+                return Err(match rejected {
+                    0 => format!("no attestation found for {peer}"),
+                    n => format!("no valid attestation found for {peer}, {n} rejected"),
+                }
+                .into())
+            }
+        }
+    }
+}
+
+// one attempt at finding a valid attestation, counting the invalid ones
+async fn get(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    deadline: Instant,
+    rejected: &mut usize,
+) -> Result<Attestation, Failure> {
+    let _phase = memprofile::phase("query");
+    let query = swarm
+        .behaviour_mut()
+        .kademlia
+        .get_record(Key::new(&attest::key(&peer)));
+    let progress = Progress::spinner("looking up attestation");
+    while let Some(event) = next_before(swarm, deadline).await {
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetRecord(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        if id != query {
+            continue;
+        }
+        match result {
+            Ok(GetRecordOk::FoundRecord(PeerRecord { peer: from, record })) => {
+                let checked =
+                    Attestation::from_bytes(&record.value).and_then(|a| a.verify(&peer).map(|_| a));
+                match checked {
+                    Ok(attestation) => {
+                        progress.finish();
+                        if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                            query.finish();
+                        }
+                        return Ok(attestation);
+                    }
+                    Err(e) => {
+                        warn!("Rejected attestation from {from:?}: {e}");
+                        *rejected += 1;
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                progress.finish();
+                return Err(Failure::from(&e));
+            }
+        }
+        if step.last() {
+            break;
+        }
+    }
+    progress.finish();
+    if Instant::now() < deadline {
+        return Err(Failure::NotFound);
+    }
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    Err(Failure::Timeout)
+}
+
+fn print(attestation: &Attestation, output: &Output) -> Result<(), Box<dyn Error>> {
+    info!("Verified attestation for {}", attestation.peer);
+    let mut table = Table::new(&["field", "value"]);
+    let m = &attestation.metadata;
+    for (field, value) in [
+        ("contact", &m.contact),
+        ("purpose", &m.purpose),
+        ("website", &m.website),
+    ] {
+        if let Some(value) = value {
+            table.push(vec![field.into(), value.clone().into()]);
+        }
+    }
+    table.push(vec!["issued".into(), attestation.issued.to_string().into()]);
+    // issued in the future means the peer's clock runs ahead
+    let skew = Skew::record(now_millis(), attestation.issued * 1000);
+    if let Some(ms) = skew.estimate() {
+        let cell = if skew.is_large(skew::LARGE_SKEW_MS) {
+            Cell::bad(skew::format_ms(ms))
+        } else {
+            Cell::warn(skew::format_ms(ms))
+        };
+        table.push(vec!["clock skew".into(), cell]);
+    }
+    output.print(table)?;
+    Ok(())
+}
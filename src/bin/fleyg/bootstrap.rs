@@ -0,0 +1,107 @@
+use crate::{bootstrap, FleygBehavior};
+use fleyg::{
+    deadline::idle_until,
+    network::Network,
+    readiness::{BootstrapGoal, Readiness},
+    table::{Output, Table},
+};
+use libp2p::{kad::K_VALUE, Swarm};
+use log::*;
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how long --wait lets the connections a bootstrap opened settle before the next
+const RETRY_PAUSE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, StructOpt)]
+pub struct BootstrapOpt {
+    /// keep bootstrapping until the routing table reaches the goal, failing if it doesn't
+    /// by the timeout
+    #[structopt(long)]
+    wait: bool,
+
+    /// peers the routing table has to hold [default: the config's, 20]
+    #[structopt(long)]
+    min_peers: Option<usize>,
+
+    /// non-empty buckets the peers have to be spread over [default: the config's, 4]
+    #[structopt(long)]
+    min_buckets: Option<usize>,
+
+    /// how long to wait for the goal, e.g. "2m" [default: the config's, 60s]
+    #[structopt(long)]
+    timeout: Option<String>,
+}
+
+/// bootstrap into the DHT, show how full each bucket of the routing table got and how
+/// long it took, and report whether it reached the goal
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: BootstrapOpt,
+    mut goal: BootstrapGoal,
+    network: &Network,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(n) = opt.min_peers {
+        goal.min_peers = n;
+    }
+    if let Some(n) = opt.min_buckets {
+        goal.min_buckets = n;
+    }
+    if let Some(timeout) = opt.timeout {
+        goal.timeout = timeout;
+    }
+    let started = Instant::now();
+    let deadline = started + goal.timeout()?;
+    let mut readiness = Readiness::new(goal.clone());
+    let (rt, ready) = loop {
+        let rt = bootstrap(&mut swarm, deadline).await?;
+        if readiness.update(&rt, Instant::now()).is_some() {
+            break (rt, true);
+        }
+        if !opt.wait || Instant::now() >= deadline {
+            break (rt, false);
+        }
+        info!("Not bootstrapped yet: {}", goal.fill(&rt));
+        idle_until(&mut swarm, (Instant::now() + RETRY_PAUSE).min(deadline)).await;
+    };
+
+    let bootnodes = network.bootnode_peers();
+    let connected = bootnodes
+        .iter()
+        .filter(|(peer, _)| swarm.is_connected(peer))
+        .count();
+    info!(
+        "Connected to {connected} of {} bootstrap peers",
+        bootnodes.len()
+    );
+    let mut table = Table::new(&["bucket", "peers", "fill"]);
+    for (index, peers) in &rt.buckets {
+        let fill = peers.len() as f64 / K_VALUE.get() as f64;
+        table.push(vec![
+            index.to_string().into(),
+            peers.len().to_string().into(),
+            format!("{:.0}%", fill * 100.0).into(),
+        ]);
+    }
+    output.print(table)?;
+
+    let fill = goal.fill(&rt);
+    let elapsed = started.elapsed().as_secs_f64();
+    if ready {
+        info!("Bootstrapped in {elapsed:.1}s: {fill}");
+        return Ok(());
+    }
+    let short = format!(
+        "{fill} after {elapsed:.1}s, short of {} peers in {} buckets",
+        goal.min_peers, goal.min_buckets
+    );
+    if opt.wait {
+        return Err(format!("not bootstrapped by the timeout: {short}").into());
+    }
+    warn!("Not bootstrapped: {short}");
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use fleyg::{
+    capture::{self, Frame},
+    table::{Output, Table},
+};
+use log::*;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum CaptureOpt {
+    /// list the streams in a capture directory, or show the frames of one stream
+    Show {
+        /// a capture directory or one stream's file
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+
+        /// show every byte of each frame instead of the first 32
+        #[structopt(long)]
+        full: bool,
+    },
+}
+
+pub fn run(opt: CaptureOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    match opt {
+        CaptureOpt::Show { path, full } => {
+            if path.is_dir() {
+                streams(&path, output)
+            } else {
+                frames(&path, full, output)
+            }
+        }
+    }
+}
+
+// a row for each stream under the directory
+fn streams(dir: &Path, output: &Output) -> Result<(), Box<dyn Error>> {
+    let mut files = Vec::new();
+    for peer in fs::read_dir(dir)? {
+        let peer = peer?.path();
+        if peer.is_dir() {
+            for file in fs::read_dir(&peer)? {
+                files.push(file?.path());
+            }
+        }
+    }
+    files.sort();
+
+    let mut table = Table::new(&["file", "peer", "stream", "protocol", "frames", "bytes"]);
+    for file in &files {
+        let (header, frames) = match capture::read(file) {
+            Ok(capture) => capture,
+            Err(e) => {
+                warn!("Skipping {}: {e}", file.display());
+                continue;
+            }
+        };
+        let bytes: usize = frames.iter().map(|f| f.len).sum();
+        let name = file.strip_prefix(dir).unwrap_or(file);
+        table.push(vec![
+            name.display().to_string().into(),
+            header.peer.to_string().into(),
+            format!("{} {}", header.endpoint, header.stream).into(),
+            capture::protocol(&frames).unwrap_or_default().into(),
+            frames.len().to_string().into(),
+            bytes.to_string().into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// a row for each frame of one stream
+fn frames(path: &Path, full: bool, output: &Output) -> Result<(), Box<dyn Error>> {
+    let (header, frames) = capture::read(path)?;
+    info!(
+        "{} stream with {} ({}), opened {}",
+        header.stream, header.peer, header.endpoint, header.opened
+    );
+    if let Some(protocol) = capture::protocol(&frames) {
+        info!("Protocol: {protocol}");
+    }
+    let mut table = Table::new(&["t", "dir", "len", "data"]);
+    for frame in &frames {
+        table.push(vec![
+            format!("{:.3}ms", frame.t as f64 / 1000.0).into(),
+            frame.dir.clone().into(),
+            frame.len.to_string().into(),
+            data(frame, full).into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// the frame's bytes as hex and any printable text in it
+fn data(frame: &Frame, full: bool) -> String {
+    let Some(bytes) = frame.bytes() else {
+        return "(redacted)".to_string();
+    };
+    let shown = if full {
+        &bytes[..]
+    } else {
+        &bytes[..bytes.len().min(32)]
+    };
+    let text: String = shown
+        .iter()
+        .map(|b| match b {
+            0x20..=0x7e => *b as char,
+            _ => '.',
+        })
+        .collect();
+    let more = if shown.len() < bytes.len() {
+        " ..."
+    } else {
+        ""
+    };
+    format!("{} {text}{more}", hex::encode(shown))
+}
@@ -0,0 +1,200 @@
+use fleyg::{
+    census::{self, CensusDiff},
+    geoip::{ip_of, AsnDb},
+    skew::{self, LARGE_SKEW_MS},
+    state::StateDir,
+    table::{Cell, Output, Table},
+    timespec::parse_time,
+};
+use log::*;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum CensusOpt {
+    /// show the peers that joined, left, moved or upgraded between two crawl censuses
+    Diff {
+        /// the directory holding the censuses, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// path to a GeoLite2-ASN database to show shifts between autonomous systems
+        #[structopt(long, parse(from_os_str))]
+        asn_db: Option<PathBuf>,
+
+        /// the earlier census: a path, or a time as unix seconds, RFC 3339, or a duration
+        /// ago like "1d"
+        a: String,
+
+        /// the later census, defaults to the latest one
+        #[structopt(default_value = "now")]
+        b: String,
+    },
+
+    /// list the peers in a census
+    Show {
+        /// the directory holding the censuses, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// only show peers whose clock is off by more than 30s
+        #[structopt(long)]
+        skewed: bool,
+
+        /// the census: a path, or a time as unix seconds, RFC 3339, or a duration ago
+        #[structopt(default_value = "now")]
+        census: String,
+    },
+}
+
+pub fn run(state: &StateDir, opt: CensusOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    match opt {
+        CensusOpt::Diff { dir, asn_db, a, b } => {
+            let dir = dir.unwrap_or_else(|| state.crawl_dir());
+            let (a, b) = (resolve(&dir, &a)?, resolve(&dir, &b)?);
+            let before = census::read(&a)?;
+            let after = census::read(&b)?;
+            let diff = CensusDiff::new(&before, &after);
+
+            info!("Census diff {} -> {}", a.display(), b.display());
+            info!(
+                "Peers: {} -> {} ({} joined, {} left), reachable {} -> {}",
+                before.len(),
+                after.len(),
+                diff.joined.len(),
+                diff.left.len(),
+                diff.reachable.0,
+                diff.reachable.1
+            );
+            info!(
+                "Churn: {:.1}%, {} address changes, {} agent changes",
+                diff.churn * 100.0,
+                diff.moved.len(),
+                diff.agents.len()
+            );
+
+            // the agent families, like "kubo", that grew or shrank
+            let family = |r: &census::CensusRecord| {
+                let agent = r.agent.as_deref()?;
+                Some(agent.split('/').next().unwrap_or(agent).to_string())
+            };
+            for (name, (from, to)) in shifts(
+                census::count_by(&before, family),
+                census::count_by(&after, family),
+            ) {
+                info!("\tAgent {name}: {from} -> {to}");
+            }
+            if let Some(path) = asn_db {
+                let db = AsnDb::open(path)?;
+                let asn = |r: &census::CensusRecord| {
+                    r.addrs
+                        .iter()
+                        .filter_map(ip_of)
+                        .find_map(|ip| db.lookup(ip))
+                };
+                for (asn, (from, to)) in shifts(
+                    census::count_by(&before, asn),
+                    census::count_by(&after, asn),
+                ) {
+                    info!("\tAS{asn}: {from} -> {to}");
+                }
+            }
+
+            let mut table = Table::new(&["change", "id", "detail"]);
+            for peer in &diff.joined {
+                table.push(vec![Cell::good("joined"), peer.to_string().into()]);
+            }
+            for peer in &diff.left {
+                table.push(vec![Cell::bad("left"), peer.to_string().into()]);
+            }
+            for peer in &diff.moved {
+                table.push(vec![Cell::warn("moved"), peer.to_string().into()]);
+            }
+            for change in &diff.agents {
+                table.push(vec![
+                    Cell::warn("agent"),
+                    change.peer.to_string().into(),
+                    format!("{} -> {}", change.from, change.to).into(),
+                ]);
+            }
+            output.print(table)?;
+        }
+        CensusOpt::Show {
+            dir,
+            skewed,
+            census: spec,
+        } => {
+            let dir = dir.unwrap_or_else(|| state.crawl_dir());
+            let path = resolve(&dir, &spec)?;
+            info!("Census {}", path.display());
+            let mut table = Table::new(&["id", "agent", "reachable", "skew", "addrs"]);
+            for record in census::read(&path)? {
+                let large = record.skew_ms.is_some_and(|ms| ms.abs() > LARGE_SKEW_MS);
+                if skewed && !large {
+                    continue;
+                }
+                let reachable = if record.reachable {
+                    Cell::good("yes")
+                } else {
+                    Cell::bad(record.error.as_deref().unwrap_or("no"))
+                };
+                let skew = match record.skew_ms {
+                    Some(ms) if large => Cell::bad(skew::format_ms(ms)),
+                    Some(ms) => skew::format_ms(ms).into(),
+                    None => "".into(),
+                };
+                table.push(vec![
+                    record.peer.to_string().into(),
+                    record.agent.unwrap_or_default().into(),
+                    reachable,
+                    skew,
+                    record.addrs.len().to_string().into(),
+                ]);
+            }
+            output.print(table)?;
+        }
+    }
+    Ok(())
+}
+
+/// find a census by path, or the latest one started at or before a time
+fn resolve(dir: &Path, spec: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let path = PathBuf::from(spec);
+    if path.is_file() {
+        return Ok(path);
+    }
+    let at = parse_time(spec)?;
+    census::list(dir)?
+        .into_iter()
+        .rev()
+        .find(|(started, _)| *started <= at)
+        .map(|(_, path)| path)
+        .ok_or_else(|| format!("no census in {} at or before {spec}", dir.display()).into())
+}
+
+/// the keys whose counts changed, biggest change first
+fn shifts<K: Ord + Clone>(
+    before: BTreeMap<K, usize>,
+    after: BTreeMap<K, usize>,
+) -> Vec<(K, (usize, usize))> {
+    let mut keys: Vec<K> = before.keys().chain(after.keys()).cloned().collect();
+    keys.sort();
+    keys.dedup();
+    let mut changed: Vec<(K, (usize, usize))> = keys
+        .into_iter()
+        .map(|k| {
+            let counts = (
+                before.get(&k).copied().unwrap_or(0),
+                after.get(&k).copied().unwrap_or(0),
+            );
+            (k, counts)
+        })
+        .filter(|(_, (from, to))| from != to)
+        .collect();
+    changed.sort_by_key(|(_, (from, to))| std::cmp::Reverse(from.abs_diff(*to)));
+    changed
+}
@@ -0,0 +1,599 @@
+use crate::{bootstrap, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    attest::{self, Attestation},
+    census::{self, CensusRecord, CensusWriter},
+    deadline::next_before,
+    diagnose::Diagnosis,
+    geoip::{ip_of, AsnDb},
+    journal::{self, Journal},
+    memprofile,
+    peerstore::now_secs,
+    progress::Progress,
+    querybudget::{Query, QueryBudget},
+    ratelimit::{Limits, RateLimiter},
+    sessions::now_millis,
+    shutdown::Shutdown,
+    skew::{self, Skew},
+    state::StateDir,
+    timespec::parse_duration,
+};
+use libp2p::{
+    identify,
+    kad::{
+        record::Key, GetClosestPeersError, GetRecordOk, KademliaEvent, PeerRecord, QueryId,
+        QueryResult,
+    },
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how often the crawl wakes to time out slow peers and notice a shutdown
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, StructOpt)]
+pub struct CrawlOpt {
+    /// the census file to write, a new one in the state directory if not given, an
+    /// existing census is resumed
+    #[structopt(long, parse(from_os_str))]
+    out: Option<PathBuf>,
+
+    /// the most peers to connect to and identify at once
+    #[structopt(long, default_value = "64")]
+    workers: usize,
+
+    /// the number of random walks to run at once to discover peers
+    #[structopt(long, default_value = "4")]
+    walks: usize,
+
+    /// stop walking after this many walks in a row find no new peers
+    #[structopt(long, default_value = "8")]
+    idle_walks: usize,
+
+    /// seconds to wait for a peer to connect and identify
+    #[structopt(long, default_value = "15")]
+    peer_timeout: u64,
+
+    /// seconds to spend crawling
+    #[structopt(long, default_value = "1800")]
+    timeout: u64,
+
+    /// crawl politely: cap connection rates and identify as a crawler, the rates default
+    /// to 20 connections per second, 2 per minute per ip and 60 per minute per asn
+    #[structopt(long)]
+    polite: bool,
+
+    /// the most connections per second to start
+    #[structopt(long)]
+    max_rate: Option<f64>,
+
+    /// the most connections per minute to a single ip address
+    #[structopt(long)]
+    per_ip: Option<f64>,
+
+    /// the most connections per minute into a single autonomous system, needs --asn-db
+    #[structopt(long)]
+    per_asn: Option<f64>,
+
+    /// path to a GeoLite2-ASN database for the per-asn limit
+    #[structopt(long, parse(from_os_str))]
+    asn_db: Option<PathBuf>,
+
+    /// a url or email address to include in our agent string so operators can reach us
+    #[structopt(long)]
+    contact: Option<String>,
+
+    /// re-check the peers in a previous census first, the latest one in the state
+    /// directory unless one is given, and only probe peers it doesn't have
+    #[structopt(long, parse(from_os_str))]
+    incremental: Option<Option<PathBuf>>,
+
+    /// resume an interrupted crawl, the one writing --out or the latest census in the
+    /// crawl directory left with a journal, requeueing the peers it found but didn't finish
+    #[structopt(long)]
+    resume: bool,
+
+    /// crawl again this long after each crawl started, e.g. "6h"
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    every: Option<Duration>,
+
+    /// keep only this many censuses in the crawl directory
+    #[structopt(long)]
+    keep: Option<usize>,
+
+    /// estimate each identified peer's clock skew from its attestation's signing time,
+    /// costs a DHT lookup per peer and only finds skew in peers publishing attestations
+    #[structopt(long)]
+    skew: bool,
+}
+
+impl CrawlOpt {
+    /// the agent string to identify with, if the crawl wants something other than the
+    /// default
+    pub fn agent(&self) -> Option<String> {
+        if !self.polite && self.contact.is_none() {
+            return None;
+        }
+        Some(match &self.contact {
+            Some(contact) => format!("fleyg-crawler/0.0.1 (+{contact})"),
+            None => "fleyg-crawler/0.0.1 (+https://github.com/dhuseby/fleyg)".to_string(),
+        })
+    }
+
+    /// the connection rate limits to crawl with
+    fn limits(&self) -> Limits {
+        let polite = |value: Option<f64>, default: f64| match value {
+            Some(value) => Some(value),
+            None if self.polite => Some(default),
+            None => None,
+        };
+        Limits {
+            global: polite(self.max_rate, 20.0),
+            per_ip: polite(self.per_ip, 2.0),
+            per_asn: polite(self.per_asn, 60.0).filter(|_| self.asn_db.is_some()),
+        }
+    }
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: CrawlOpt,
+    state: &StateDir,
+    mut budget: QueryBudget,
+) -> Result<(), Box<dyn Error>> {
+    if opt.every.is_some() && opt.out.is_some() {
+        return Err("--every writes a new census each crawl and can't be used with --out".into());
+    }
+    let dir = state.crawl_dir();
+    let asn_db = match &opt.asn_db {
+        Some(path) => Some(AsnDb::open(path)?),
+        None => None,
+    };
+    let mut limiter = RateLimiter::new(opt.limits());
+    if opt.limits() != Limits::default() {
+        info!("Crawl rate limits: {:?}", opt.limits());
+    }
+    let mut resume = if opt.resume {
+        Some(interrupted(&opt, &dir)?)
+    } else {
+        None
+    };
+
+    bootstrap(
+        &mut swarm,
+        Instant::now() + Duration::from_secs(opt.timeout),
+    )
+    .await?;
+    // a signal while bootstrapping still exits right away, there's nothing to save yet
+    let shutdown = Shutdown::catch();
+
+    while !shutdown.requested() {
+        let started = Instant::now();
+
+        // the previous census to re-check, found before this crawl's census exists
+        let previous = match &opt.incremental {
+            Some(Some(path)) => Some(path.clone()),
+            Some(None) => census::list(&dir)?.pop().map(|(_, path)| path),
+            None => None,
+        };
+        let path = resume
+            .take()
+            .or_else(|| opt.out.clone())
+            .unwrap_or_else(|| dir.join(census::file_name(now_secs())));
+        let seed = match previous.filter(|p| *p != path) {
+            Some(previous) => {
+                let records = census::read(&previous)?;
+                info!(
+                    "Re-checking {} peers from {}",
+                    records.len(),
+                    previous.display()
+                );
+                records
+            }
+            None => Vec::new(),
+        };
+
+        let crawl = Crawl::open(&path)?;
+        crawl_once(
+            &mut swarm,
+            &opt,
+            crawl,
+            seed,
+            &mut limiter,
+            &mut budget,
+            asn_db.as_ref(),
+            &shutdown,
+        )
+        .await?;
+
+        if let Some(keep) = opt.keep {
+            let removed = census::prune(&dir, keep)?;
+            if removed > 0 {
+                info!("Removed {removed} old censuses");
+            }
+        }
+
+        let Some(every) = opt.every else {
+            break;
+        };
+        let next = started + every;
+        info!(
+            "Next crawl in {:?}",
+            next.saturating_duration_since(Instant::now())
+        );
+        // keep the swarm running until then, waking to notice a shutdown
+        while Instant::now() < next && !shutdown.requested() {
+            let _ = next_before(&mut swarm, next.min(Instant::now() + TICK)).await;
+        }
+    }
+    Ok(())
+}
+
+// the census of the crawl to resume
+fn interrupted(opt: &CrawlOpt, dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let path = match &opt.out {
+        Some(path) => Some(path.clone()),
+        None => census::list(dir)?
+            .into_iter()
+            .map(|(_, path)| path)
+            .filter(|path| journal::path_for(path).exists())
+            .last(),
+    };
+    match path {
+        Some(path) if journal::path_for(&path).exists() => Ok(path),
+        Some(path) => Err(format!("{} has no journal to resume", path.display()).into()),
+        None => Err(format!("no interrupted crawl in {}", dir.display()).into()),
+    }
+}
+
+/// crawl once into the census, re-checking the seed peers before any newly found ones
+async fn crawl_once(
+    swarm: &mut Swarm<FleygBehavior>,
+    opt: &CrawlOpt,
+    mut crawl: Crawl,
+    mut seed: Vec<CensusRecord>,
+    limiter: &mut RateLimiter,
+    budget: &mut QueryBudget,
+    asn_db: Option<&AsnDb>,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let _phase = memprofile::phase("crawl");
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    let peer_timeout = Duration::from_secs(opt.peer_timeout);
+    // the peers that were reachable last time go first
+    seed.sort_by_key(|r| !r.reachable);
+    for record in seed {
+        crawl.discovered(record.peer, record.addrs)?;
+    }
+    let mut walks = 0;
+    let mut idle = 0;
+    let mut found_in_walk = 0;
+    let progress = Progress::spinner("crawling");
+
+    loop {
+        // keep the discovery walks going until they stop finding peers
+        while walks < opt.walks && idle < opt.idle_walks {
+            budget.push("crawl", Query::ClosestPeers(PeerId::random()));
+            walks += 1;
+        }
+        for (id, query) in budget.start_ready(&mut swarm.behaviour_mut().kademlia) {
+            if let Query::Record(key) = query {
+                crawl.lookups.insert(id, key.to_vec());
+            }
+        }
+
+        // connect to as many discovered peers as there are workers and the rate limits allow
+        let now = Instant::now();
+        let mut deferred = Vec::new();
+        while crawl.working.len() < opt.workers {
+            let Some((peer, addrs)) = crawl.pending.pop_front() else {
+                break;
+            };
+            let ips: Vec<_> = addrs.iter().filter_map(ip_of).collect();
+            let asns: Vec<_> = match asn_db {
+                Some(db) => ips.iter().filter_map(|ip| db.lookup(*ip)).collect(),
+                None => Vec::new(),
+            };
+            if !limiter.allow(&ips, &asns, now) {
+                deferred.push((peer, addrs));
+                continue;
+            }
+            let opts = DialOpts::peer_id(peer)
+                .addresses(addrs.clone())
+                .condition(PeerCondition::NotDialing)
+                .build();
+            match swarm.dial(opts) {
+                Ok(()) => {
+                    crawl.journal.append(&Step::Contacted { peer })?;
+                    crawl.working.insert(peer, (addrs, Instant::now()));
+                }
+                Err(e) => {
+                    crawl
+                        .census
+                        .write(&CensusRecord::unreachable(peer, addrs, e.to_string()))?;
+                }
+            }
+        }
+
+        crawl.pending.extend(deferred);
+        limiter.prune(now);
+
+        progress.set_message(format!(
+            "{} peers done, {} in flight, {} queued",
+            crawl.census.len(),
+            crawl.working.len(),
+            crawl.pending.len()
+        ));
+        if walks == 0
+            && crawl.pending.is_empty()
+            && crawl.working.is_empty()
+            && crawl.awaiting.is_empty()
+        {
+            break;
+        }
+
+        // wake at least once a second to time out slow peers
+        let tick = (Instant::now() + TICK).min(deadline);
+        let event = next_before(swarm, tick).await;
+        if Instant::now() >= deadline {
+            info!("Crawl timed out");
+            break;
+        }
+        if shutdown.requested() {
+            info!("Crawl interrupted");
+            break;
+        }
+        let now = Instant::now();
+        let late: Vec<PeerId> = crawl
+            .working
+            .iter()
+            .filter(|(_, (_, started))| now.duration_since(*started) > peer_timeout)
+            .map(|(peer, _)| *peer)
+            .collect();
+        for peer in late {
+            crawl.failed(peer, "timed out".to_string())?;
+            let _ = swarm.disconnect_peer_id(peer);
+        }
+
+        match event {
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::RoutingUpdated {
+                    peer, addresses, ..
+                },
+            ))) => {
+                if crawl.discovered(peer, addresses.into_vec())? {
+                    found_in_walk += 1;
+                }
+            }
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetClosestPeers(result),
+                    step,
+                    ..
+                },
+            ))) => {
+                let peers = match result {
+                    Ok(ok) => ok.peers,
+                    Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                for peer in peers {
+                    if crawl.discovered(peer, Vec::new())? {
+                        found_in_walk += 1;
+                    }
+                }
+                if step.last() {
+                    budget.finished(&id);
+                    // walks left over from a crawl that timed out can still finish
+                    walks = walks.saturating_sub(1);
+                    if found_in_walk == 0 {
+                        idle += 1;
+                    } else {
+                        idle = 0;
+                    }
+                    found_in_walk = 0;
+                }
+            }
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(
+                identify::Event::Received { peer_id, info },
+            ))) => {
+                if crawl.working.remove(&peer_id).is_some() {
+                    let record = CensusRecord::identified(peer_id, &info);
+                    if opt.skew {
+                        let key = attest::key(&peer_id);
+                        budget.push("crawl", Query::Record(Key::new(&key)));
+                        crawl.awaiting.insert(key, record);
+                    } else {
+                        crawl.census.write(&record)?;
+                    }
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                }
+            }
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id,
+                    result: QueryResult::GetRecord(result),
+                    step,
+                    ..
+                },
+            ))) => {
+                let Some(key) = crawl.lookups.get(&id) else {
+                    continue;
+                };
+                let skew = match result {
+                    Ok(GetRecordOk::FoundRecord(PeerRecord { record, .. })) => {
+                        match attest::check_record(key, &record.value) {
+                            Some(Ok(())) => Attestation::from_bytes(&record.value)
+                                .ok()
+                                .map(|a| Skew::record(now_millis(), a.issued * 1000)),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                if skew.is_some() || step.last() {
+                    budget.finished(&id);
+                    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                        query.finish();
+                    }
+                    crawl.looked_up(&id, skew)?;
+                }
+            }
+            Some(SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            }) => {
+                let reason = match Diagnosis::from_dial_error(&error).pop() {
+                    Some(d) => d.to_string(),
+                    None => error.to_string(),
+                };
+                crawl.failed(peer_id, reason)?;
+            }
+            _ => {}
+        }
+    }
+    progress.finish();
+
+    // peers whose skew lookups didn't finish are written without it, peers still in
+    // flight or queued are left out of the census and kept in the journal so resuming
+    // retries them
+    for record in std::mem::take(&mut crawl.awaiting).into_values() {
+        crawl.census.write(&record)?;
+    }
+    crawl.census.flush()?;
+    let unfinished = crawl.pending.len() + crawl.working.len();
+    info!(
+        "Crawled {} peers ({unfinished} discovered but not finished) into {}",
+        crawl.census.len(),
+        crawl.census.path().display()
+    );
+    if unfinished == 0 {
+        crawl.journal.finish()?;
+    } else {
+        crawl.journal.checkpoint()?;
+        info!(
+            "Progress is in {}, run again with --resume to finish",
+            crawl.journal.path().display()
+        );
+    }
+    Ok(())
+}
+
+/// A step of a crawl kept in its journal
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Step {
+    Discovered { peer: PeerId, addrs: Vec<Multiaddr> },
+    Contacted { peer: PeerId },
+}
+
+// the peers found and the state of each
+struct Crawl {
+    census: CensusWriter,
+    journal: Journal<Step>,
+    seen: HashSet<PeerId>,
+    pending: VecDeque<(PeerId, Vec<Multiaddr>)>,
+    working: HashMap<PeerId, (Vec<Multiaddr>, Instant)>,
+    // identified peers waiting on their attestation lookup, by attestation key
+    awaiting: HashMap<Vec<u8>, CensusRecord>,
+    lookups: HashMap<QueryId, Vec<u8>>,
+}
+
+impl Crawl {
+    /// open the census and its journal, queueing the peers an interrupted crawl found but
+    /// didn't finish, the ones it had contacted first
+    fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let census = CensusWriter::open(path)?;
+        let (journal, steps) = Journal::open(journal::path_for(path))?;
+        let mut crawl = Self {
+            census,
+            journal,
+            seen: HashSet::new(),
+            pending: VecDeque::new(),
+            working: HashMap::new(),
+            awaiting: HashMap::new(),
+            lookups: HashMap::new(),
+        };
+        let mut found: Vec<(PeerId, Vec<Multiaddr>)> = Vec::new();
+        let mut contacted = HashSet::new();
+        for step in steps {
+            match step {
+                Step::Discovered { peer, addrs } => found.push((peer, addrs)),
+                Step::Contacted { peer } => {
+                    contacted.insert(peer);
+                }
+            }
+        }
+        found.sort_by_key(|(peer, _)| !contacted.contains(peer));
+        for (peer, addrs) in found {
+            if !crawl.census.contains(&peer) && crawl.seen.insert(peer) {
+                crawl.pending.push_back((peer, addrs));
+            }
+        }
+        if !crawl.census.is_empty() || !crawl.pending.is_empty() {
+            info!(
+                "Resuming {} with {} peers done and {} to finish",
+                path.display(),
+                crawl.census.len(),
+                crawl.pending.len()
+            );
+        }
+        Ok(crawl)
+    }
+
+    /// queue a peer if it hasn't been seen, returning true if it is new
+    fn discovered(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) -> Result<bool, Box<dyn Error>> {
+        if self.census.contains(&peer) || !self.seen.insert(peer) {
+            return Ok(false);
+        }
+        self.journal.append(&Step::Discovered {
+            peer,
+            addrs: addrs.clone(),
+        })?;
+        self.pending.push_back((peer, addrs));
+        Ok(true)
+    }
+
+    /// write an identified peer once its attestation lookup finished
+    fn looked_up(&mut self, id: &QueryId, skew: Option<Skew>) -> Result<(), Box<dyn Error>> {
+        let Some(key) = self.lookups.remove(id) else {
+            return Ok(());
+        };
+        let Some(mut record) = self.awaiting.remove(&key) else {
+            return Ok(());
+        };
+        if let Some(skew) = skew {
+            record.skew_ms = skew.estimate();
+            if skew.is_large(skew::LARGE_SKEW_MS) {
+                warn!(
+                    "{} has a clock skew of at least {}",
+                    record.peer,
+                    skew::format_ms(skew.lower)
+                );
+            }
+        }
+        self.census.write(&record)?;
+        Ok(())
+    }
+
+    /// record a peer we couldn't identify
+    fn failed(&mut self, peer: PeerId, error: String) -> Result<(), Box<dyn Error>> {
+        if let Some((addrs, _)) = self.working.remove(&peer) {
+            self.census
+                .write(&CensusRecord::unreachable(peer, addrs, error))?;
+        }
+        Ok(())
+    }
+}
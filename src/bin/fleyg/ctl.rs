@@ -0,0 +1,300 @@
+use crate::tasks::status_table;
+use fleyg::{
+    answer::{Answer, AnswerPeer, SignedAnswer},
+    apilisten::ApiListen,
+    control::{self, Request, Target},
+    encoding::Encoding,
+    keys::KeyEncoding,
+    schedule::TaskStatus,
+    store::Fsck,
+    table::{Cell, Output, Table},
+};
+use libp2p::PeerId;
+use log::*;
+use serde_json::Value;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+/// the exit status when the daemon found no record
+const EXIT_NOT_FOUND: i32 = 2;
+
+#[derive(Debug, StructOpt)]
+pub enum CtlOpt {
+    /// have the daemon dial a peer, by address or peer id
+    Dial { target: Target },
+
+    /// look up a record through the daemon, the key in the --key-encoding
+    Get {
+        key: String,
+
+        /// save the daemon's signed answer to this file, for `fleyg verify --answer`
+        #[structopt(long, parse(from_os_str))]
+        answer: Option<PathBuf>,
+    },
+
+    /// store a record through the daemon, the key in the --key-encoding and the value as
+    /// typed
+    Put { key: String, value: String },
+
+    /// find the providers of a key through the daemon, the key in the --key-encoding
+    Providers {
+        key: String,
+
+        /// save the daemon's signed answer to this file, for `fleyg verify --answer`
+        #[structopt(long, parse(from_os_str))]
+        answer: Option<PathBuf>,
+    },
+
+    /// find the peers closest to a key through the daemon, the key in the --key-encoding
+    Closest {
+        key: String,
+
+        /// save the daemon's signed answer to this file, for `fleyg verify --answer`
+        #[structopt(long, parse(from_os_str))]
+        answer: Option<PathBuf>,
+    },
+
+    /// list the daemon's connections
+    Peers,
+
+    /// show the daemon's peer id and addresses, or what a peer identifies as
+    Identify { peer: Option<PeerId> },
+
+    /// flush the daemon's DNS cache and resolve the bootstrap names again
+    DnsFlush,
+
+    /// write a json snapshot of the daemon's state to attach to bug reports
+    Snapshot {
+        /// write the snapshot to this file instead of stdout
+        #[structopt(long, short, parse(from_os_str))]
+        out: Option<PathBuf>,
+    },
+
+    /// show the last run of each of the daemon's scheduled tasks
+    Tasks,
+
+    /// show the daemon's routing table, each bucket's peers with their addresses and
+    /// whether they're connected
+    Table,
+
+    /// the daemon's record store
+    Store(CtlStoreOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub enum CtlStoreOpt {
+    /// check the running store's write-ahead log against the records it holds
+    Fsck,
+}
+
+/// make a call to the running daemon and show its answer
+pub fn run(
+    api: &ApiListen,
+    opt: CtlOpt,
+    encoding: Encoding,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let decode = |key: &str| keys.decode(key).map_err(|e| format!("bad key {key}: {e}"));
+    let request = match &opt {
+        CtlOpt::Dial { target } => Request::Dial(target.clone()),
+        CtlOpt::Get { key, .. } => Request::Get { key: decode(key)? },
+        CtlOpt::Put { key, value } => Request::Put {
+            key: decode(key)?,
+            value: value.as_bytes().to_vec(),
+        },
+        CtlOpt::Providers { key, .. } => Request::Providers { key: decode(key)? },
+        CtlOpt::Closest { key, .. } => Request::Closest { key: decode(key)? },
+        CtlOpt::Peers => Request::Peers,
+        CtlOpt::Identify { peer } => Request::Identify(*peer),
+        CtlOpt::DnsFlush => Request::FlushDns,
+        CtlOpt::Snapshot { .. } => Request::Snapshot,
+        CtlOpt::Tasks => Request::Tasks,
+        CtlOpt::Table => Request::Table,
+        CtlOpt::Store(CtlStoreOpt::Fsck) => Request::StoreFsck,
+    };
+    let reply = control::call(api, &request)?;
+    match opt {
+        CtlOpt::Dial { target } => info!(
+            "Connected to {} at {} (dialed {target})",
+            text(&reply["peer"]),
+            text(&reply["addr"])
+        ),
+        CtlOpt::Get { key, answer } => {
+            let signed = signed_answer(reply, answer.as_deref())?;
+            let Answer::Record {
+                value, publisher, ..
+            } = signed.answer
+            else {
+                return Err("the daemon answered something other than a record".into());
+            };
+            let Some(value) = value else {
+                error!("no peer has the record {key}");
+                std::process::exit(EXIT_NOT_FOUND);
+            };
+            let mut table = Table::new(&["field", "value"]);
+            table.push(vec!["value".into(), encoding.encode(&value).into()]);
+            if let Some(publisher) = publisher {
+                table.push(vec!["publisher".into(), publisher.to_string().into()]);
+            }
+            table.push(vec!["signer".into(), signed.signer.to_string().into()]);
+            output.print(table)?;
+        }
+        CtlOpt::Put { key, .. } => info!("Stored {key}"),
+        CtlOpt::Providers { key, answer } | CtlOpt::Closest { key, answer } => {
+            let signed = signed_answer(reply, answer.as_deref())?;
+            let (found, peers) = match &signed.answer {
+                Answer::Providers { providers, .. } => ("providers of", providers),
+                Answer::ClosestPeers { peers, .. } => ("peers closest to", peers),
+                Answer::Record { .. } => {
+                    return Err("the daemon answered a record instead of peers".into())
+                }
+            };
+            info!("{} {found} {key}, signed by {}", peers.len(), signed.signer);
+            output.print(peer_table(peers))?;
+        }
+        CtlOpt::Peers => {
+            let mut table = Table::new(&["id", "addr", "agent", "rtt", "dir"]);
+            for peer in reply.as_array().into_iter().flatten() {
+                let rtt = match peer["rtt_ms"].as_u64() {
+                    Some(ms) => format!("{ms}ms"),
+                    None => String::new(),
+                };
+                let dir = match peer["outbound"].as_bool() {
+                    Some(true) => "out",
+                    _ => "in",
+                };
+                table.push(vec![
+                    text(&peer["peer"]).into(),
+                    text(&peer["addr"]).into(),
+                    text(&peer["agent"]).into(),
+                    rtt.into(),
+                    dir.into(),
+                ]);
+            }
+            output.print(table)?;
+        }
+        CtlOpt::Identify { .. } => {
+            let mut table = Table::new(&["field", "value"]);
+            for (field, value) in reply.as_object().into_iter().flatten() {
+                table.push(vec![field.as_str().into(), text(value).into()]);
+            }
+            output.print(table)?;
+        }
+        CtlOpt::DnsFlush => info!(
+            "Flushed {} cached resolutions, the daemon is resolving them again",
+            reply["flushed"]
+        ),
+        CtlOpt::Snapshot { out } => match &out {
+            Some(path) => {
+                fs::write(path, serde_json::to_vec_pretty(&reply)?)?;
+                info!("Wrote a snapshot to {}", path.display());
+            }
+            None => println!("{}", serde_json::to_string_pretty(&reply)?),
+        },
+        CtlOpt::Tasks => {
+            let statuses: Vec<TaskStatus> = serde_json::from_value(reply)?;
+            if statuses.is_empty() {
+                info!("The daemon has no scheduled tasks");
+            }
+            output.print(status_table(statuses))?;
+        }
+        CtlOpt::Table => {
+            let entries = reply.as_array().cloned().unwrap_or_default();
+            let connected = entries.iter().filter(|e| e["connected"] == true).count();
+            let mut buckets: Vec<_> = entries.iter().map(|e| e["bucket"].as_u64()).collect();
+            buckets.dedup();
+            info!(
+                "{} peers in {} buckets, {connected} connected",
+                entries.len(),
+                buckets.len()
+            );
+            let mut table = Table::new(&["bucket", "id", "status", "addrs"]);
+            for entry in &entries {
+                let status = match entry["connected"].as_bool() {
+                    Some(true) => Cell::good("connected"),
+                    _ => Cell::warn("disconnected"),
+                };
+                table.push(vec![
+                    text(&entry["bucket"]).into(),
+                    text(&entry["peer"]).into(),
+                    status,
+                    text(&entry["addrs"]).into(),
+                ]);
+            }
+            output.print(table)?;
+        }
+        CtlOpt::Store(CtlStoreOpt::Fsck) => {
+            let fsck: Fsck = serde_json::from_value(reply)?;
+            let Some(path) = &fsck.path else {
+                info!(
+                    "The daemon keeps its {} records in memory, there's no log to check",
+                    fsck.records
+                );
+                return Ok(());
+            };
+            info!(
+                "{}: {} commits, {} operations, {} records in memory",
+                path.display(),
+                fsck.commits,
+                fsck.ops,
+                fsck.records
+            );
+            for key in &fsck.mismatched {
+                let key = hex::decode(key).map_err(|e| format!("bad key {key}: {e}"))?;
+                warn!("A restart would load {} differently", keys.encode(&key));
+            }
+            if fsck.is_consistent() {
+                info!("The store is consistent");
+            } else if fsck.torn_bytes > 0 {
+                return Err(format!(
+                    "{} bytes at the end of the log are not a complete commit",
+                    fsck.torn_bytes
+                )
+                .into());
+            } else {
+                return Err(format!(
+                    "{} records differ between the log and memory",
+                    fsck.mismatched.len()
+                )
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
+// check the daemon's signed answer, saving it if asked to
+fn signed_answer(reply: Value, path: Option<&Path>) -> Result<SignedAnswer, Box<dyn Error>> {
+    let signed: SignedAnswer = serde_json::from_value(reply)?;
+    signed.verify()?;
+    if let Some(path) = path {
+        signed.save(path)?;
+        info!("Wrote the signed answer to {}", path.display());
+    }
+    Ok(signed)
+}
+
+// the peers of an answer
+fn peer_table(peers: &[AnswerPeer]) -> Table {
+    let mut table = Table::new(&["id", "addrs"]);
+    for p in peers {
+        let addrs: Vec<String> = p.addrs.iter().map(|a| a.to_string()).collect();
+        table.push(vec![p.peer.to_string().into(), addrs.join(" ").into()]);
+    }
+    table
+}
+
+// a json value for a table cell, lists space separated
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(text).collect::<Vec<_>>().join(" "),
+        value => value.to_string(),
+    }
+}
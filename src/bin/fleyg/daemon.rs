@@ -0,0 +1,567 @@
+use crate::{tasks::TaskRunner, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    answer::{Answer, AnswerPeer, SignedAnswer},
+    apilisten::ApiListen,
+    bugreport::{BugReport, StateTracker},
+    census::CensusRecord,
+    config::Config,
+    control::{self, Call, Reply, Request, Target},
+    deadline::next_before,
+    dnscache::{self, DnsCache},
+    metrics::NodeMetrics,
+    peerstore::PeerStore,
+    querystats::QueryConnections,
+    runtime,
+    state::StateDir,
+};
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    prelude::*,
+};
+use libp2p::{
+    identify,
+    identity::Keypair,
+    kad::{
+        record::{Key, Record},
+        GetClosestPeersError, GetProvidersError, GetProvidersOk, GetRecordOk, KademliaEvent,
+        PeerRecord, QueryId, QueryResult, Quorum,
+    },
+    swarm::{dial_opts::DialOpts, ConnectionId, SwarmEvent, THandlerErr},
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use serde_json::json;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, io,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+/// The events serve handles
+type Event = SwarmEvent<FleygBehaviorEvent, THandlerErr<FleygBehavior>>;
+
+/// how long an identify call waits for a connected peer to identify
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The control API of `fleyg daemon`, with the calls waiting on the node
+pub struct Control {
+    calls: mpsc::UnboundedReceiver<Call>,
+    key: Keypair,
+    config: Config,
+    /// the bootstrap addresses with DNS names, resolved again when the cache is flushed
+    names: Vec<Multiaddr>,
+    dns_cache: PathBuf,
+    tracker: StateTracker,
+    dials: HashMap<ConnectionId, Call>,
+    gets: HashMap<QueryId, (Vec<u8>, Call)>,
+    puts: HashMap<QueryId, (Vec<u8>, Call)>,
+    /// the provider lookups, with the providers found so far
+    providers: HashMap<QueryId, (Vec<u8>, HashSet<PeerId>, Call)>,
+    closest: HashMap<QueryId, (Vec<u8>, Call)>,
+    /// the calls waiting for a peer to identify, with when they give up
+    identifies: HashMap<PeerId, Vec<(Call, Instant)>>,
+    /// the connections the calls' queries opened, they should mostly reuse ours
+    connections: QueryConnections,
+}
+
+impl Control {
+    /// listen for calls on the API
+    pub fn listen(
+        api: &ApiListen,
+        key: Keypair,
+        config: Config,
+        names: Vec<Multiaddr>,
+        state: &StateDir,
+    ) -> io::Result<Self> {
+        let calls = control::listen(api)?;
+        info!("Taking control calls on {api}");
+        Ok(Self {
+            calls,
+            key,
+            config,
+            names,
+            dns_cache: state.dns_cache_path(),
+            tracker: StateTracker::default(),
+            dials: HashMap::new(),
+            gets: HashMap::new(),
+            puts: HashMap::new(),
+            providers: HashMap::new(),
+            closest: HashMap::new(),
+            identifies: HashMap::new(),
+            connections: QueryConnections::default(),
+        })
+    }
+
+    /// wait for the swarm's next event before the wake up, starting on the calls that
+    /// come in meanwhile
+    pub async fn next(
+        &mut self,
+        swarm: &mut Swarm<FleygBehavior>,
+        wake: Instant,
+        peers: &PeerStore,
+        tasks: Option<&TaskRunner>,
+    ) -> Option<Event> {
+        loop {
+            self.expire_identifies(Instant::now());
+            let until = self
+                .identifies
+                .values()
+                .flatten()
+                .map(|(_, at)| *at)
+                .fold(wake, Instant::min);
+            let event = Box::pin(next_before(swarm, until));
+            let call = match future::select(self.calls.next(), event).await {
+                Either::Left((Some(call), _)) => call,
+                // the listener stopped, keep serving without it
+                Either::Left((None, event)) => return event.await,
+                // an identify call ran out of time first
+                Either::Right((None, _)) if until < wake => continue,
+                Either::Right((event, _)) => return event,
+            };
+            self.call(swarm, call, peers, tasks);
+        }
+    }
+
+    // start on a call, answering it now unless it waits on the network
+    fn call(
+        &mut self,
+        swarm: &mut Swarm<FleygBehavior>,
+        call: Call,
+        peers: &PeerStore,
+        tasks: Option<&TaskRunner>,
+    ) {
+        // what the call leads to is logged under the caller's trace id
+        let _trace = call.trace.scope();
+        debug!("Control call: {:?}", call.request);
+        match call.request.clone() {
+            Request::Dial(target) => {
+                let opts = match &target {
+                    Target::Addr(addr) => DialOpts::unknown_peer_id().address(addr.clone()).build(),
+                    Target::Peer(peer) => DialOpts::peer_id(*peer).build(),
+                };
+                let id = opts.connection_id();
+                match swarm.dial(opts) {
+                    Ok(()) => {
+                        self.dials.insert(id, call);
+                    }
+                    Err(e) => call.reply(Reply::error(502, format!("can't dial {target}: {e}"))),
+                }
+            }
+            Request::Get { key } => {
+                let query = swarm.behaviour_mut().kademlia.get_record(Key::new(&key));
+                self.connections.started(query);
+                self.gets.insert(query, (key, call));
+            }
+            Request::Put { key, value } => {
+                let record = Record::new(Key::new(&key), value);
+                match swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .put_record(record, Quorum::One)
+                {
+                    Ok(query) => {
+                        self.connections.started(query);
+                        self.puts.insert(query, (key, call));
+                    }
+                    Err(e) => call.reply(Reply::error(500, format!("can't store the record: {e}"))),
+                }
+            }
+            Request::Providers { key } => {
+                let query = swarm.behaviour_mut().kademlia.get_providers(Key::new(&key));
+                self.connections.started(query);
+                self.providers.insert(query, (key, HashSet::new(), call));
+            }
+            Request::Closest { key } => {
+                let query = swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_closest_peers(key.clone());
+                self.connections.started(query);
+                self.closest.insert(query, (key, call));
+            }
+            Request::Peers => {
+                let connected: Vec<_> = self
+                    .tracker
+                    .connections()
+                    .into_iter()
+                    .map(|connection| {
+                        let record = peers.get(&connection.peer);
+                        json!({
+                            "peer": connection.peer,
+                            "addr": connection.addr,
+                            "outbound": connection.outbound,
+                            "agent": record.and_then(|r| r.agent.clone()),
+                            "rtt_ms": record.and_then(|r| r.rtt).map(|rtt| rtt.as_millis() as u64),
+                        })
+                    })
+                    .collect();
+                call.reply(Reply::ok(&connected));
+            }
+            Request::Identify(None) => call.reply(Reply::ok(&json!({
+                "peer": swarm.local_peer_id(),
+                "listen_addrs": swarm.listeners().collect::<Vec<_>>(),
+                "external_addrs": swarm.external_addresses().collect::<Vec<_>>(),
+                "connected_peers": swarm.network_info().num_peers(),
+            }))),
+            Request::Identify(Some(peer)) => {
+                let known = peers.get(&peer).filter(|r| r.agent.is_some());
+                match known {
+                    Some(record) if swarm.is_connected(&peer) => {
+                        call.reply(Reply::ok(&identified(
+                            peer,
+                            &record.agent.clone().unwrap_or_default(),
+                            &record.protocols,
+                            &record.addrs,
+                        )))
+                    }
+                    _ => {
+                        // it identifies when it connects, and again every identify interval
+                        if !swarm.is_connected(&peer) {
+                            if let Err(e) = swarm.dial(peer) {
+                                call.reply(Reply::error(502, format!("can't dial {peer}: {e}")));
+                                return;
+                            }
+                        }
+                        let at = Instant::now() + IDENTIFY_TIMEOUT;
+                        self.identifies.entry(peer).or_default().push((call, at));
+                    }
+                }
+            }
+            Request::FlushDns => match DnsCache::open(&self.dns_cache) {
+                Ok(mut cache) => {
+                    let flushed = cache.flush();
+                    if let Err(e) = cache.save() {
+                        call.reply(Reply::error(500, e));
+                        return;
+                    }
+                    info!("Flushed {flushed} cached resolutions");
+                    let path = self.dns_cache.clone();
+                    let names = self.names.clone();
+                    runtime::spawn(async move {
+                        if let Err(e) = dnscache::refresh(path, names).await {
+                            debug!("Resolving the bootstrap names failed: {e}");
+                        }
+                    });
+                    call.reply(Reply::ok(&json!({ "flushed": flushed })));
+                }
+                Err(e) => call.reply(Reply::error(500, e)),
+            },
+            Request::Snapshot => {
+                let report = BugReport::capture(swarm, &self.key, &self.config, &self.tracker);
+                call.reply(Reply::ok(&report));
+            }
+            Request::Tasks => {
+                let statuses = tasks.map(TaskRunner::statuses).unwrap_or_default();
+                call.reply(Reply::ok(&statuses));
+            }
+            Request::Table => {
+                let mut entries = Vec::new();
+                for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+                    let index = bucket.range().0.ilog2().unwrap_or_default();
+                    for entry in bucket.iter() {
+                        let addrs: Vec<Multiaddr> = entry.node.value.iter().cloned().collect();
+                        entries.push((index, *entry.node.key.preimage(), addrs));
+                    }
+                }
+                let table: Vec<_> = entries
+                    .into_iter()
+                    .map(|(bucket, peer, addrs)| {
+                        json!({
+                            "bucket": bucket,
+                            "peer": peer,
+                            "addrs": addrs,
+                            "connected": swarm.is_connected(&peer),
+                        })
+                    })
+                    .collect();
+                call.reply(Reply::ok(&table));
+            }
+            Request::Census => {
+                let mut census: Vec<_> = peers
+                    .iter()
+                    .map(|(peer, record)| CensusRecord::known(*peer, record))
+                    .collect();
+                census.sort_by_key(|r| r.peer);
+                call.reply(Reply::ok(&census));
+            }
+            Request::StoreFsck => match swarm.behaviour_mut().kademlia.store_mut().fsck() {
+                Ok(fsck) => call.reply(Reply::ok(&fsck)),
+                Err(e) => call.reply(Reply::error(500, format!("can't check the store: {e}"))),
+            },
+        }
+    }
+
+    // answer the identify calls of peers that connected but never identified
+    fn expire_identifies(&mut self, now: Instant) {
+        self.identifies.retain(|peer, calls| {
+            let (expired, waiting): (Vec<_>, Vec<_>) =
+                calls.drain(..).partition(|(_, at)| *at <= now);
+            for (call, _) in expired {
+                call.reply(Reply::error(
+                    504,
+                    format!("{peer} didn't identify within {IDENTIFY_TIMEOUT:?}"),
+                ));
+            }
+            *calls = waiting;
+            !calls.is_empty()
+        });
+    }
+
+    /// answer the calls waiting on a swarm event, counting the connections their queries
+    /// opened into the metrics
+    pub fn observe<E: fmt::Display>(
+        &mut self,
+        swarm: &mut Swarm<FleygBehavior>,
+        event: &SwarmEvent<FleygBehaviorEvent, E>,
+        metrics: Option<&NodeMetrics>,
+    ) {
+        self.tracker.observe(event);
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                if endpoint.is_dialer() {
+                    self.connections.opened();
+                }
+                if let Some(call) = self.dials.remove(connection_id) {
+                    call.reply(Reply::ok(&json!({
+                        "peer": peer_id,
+                        "addr": endpoint.get_remote_address(),
+                    })));
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if let Some(call) = self.dials.remove(connection_id) {
+                    call.reply(Reply::error(502, format!("dialing failed: {error}")));
+                }
+                let waiting = peer_id.and_then(|peer| self.identifies.remove(&peer));
+                for (call, _) in waiting.into_iter().flatten() {
+                    call.reply(Reply::error(502, format!("can't reach the peer: {error}")));
+                }
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                for (call, _) in self.identifies.remove(peer_id).into_iter().flatten() {
+                    call.reply(Reply::error(
+                        502,
+                        "the peer disconnected before identifying",
+                    ));
+                }
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                for (call, _) in self.identifies.remove(peer_id).into_iter().flatten() {
+                    call.reply(Reply::ok(&identified(
+                        *peer_id,
+                        &info.agent_version,
+                        &info.protocols,
+                        &info.listen_addrs,
+                    )));
+                }
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id, result, step, ..
+                },
+            )) => {
+                if step.last {
+                    if let Some(opened) = self.connections.finished(id) {
+                        debug!("Query {id:?} opened {opened} connections");
+                        if let Some(metrics) = metrics {
+                            metrics.query_connections(opened);
+                        }
+                    }
+                }
+                match result {
+                    QueryResult::GetRecord(result) => {
+                        let found = match result {
+                            Ok(GetRecordOk::FoundRecord(PeerRecord { record, .. })) => Some(record),
+                            Ok(_) if !step.last() => return,
+                            _ => None,
+                        };
+                        let Some((key, call)) = self.gets.remove(id) else {
+                            return;
+                        };
+                        if found.is_some() {
+                            if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(id) {
+                                query.finish();
+                            }
+                        }
+                        // a signed answer, so whoever the value is passed on to can check it
+                        let answer = Answer::Record {
+                            key,
+                            value: found.map(|r| r.value.clone()),
+                            publisher: found.and_then(|r| r.publisher),
+                        };
+                        call.reply(Reply::ok(&SignedAnswer::sign(&self.key, answer)));
+                    }
+                    QueryResult::GetProviders(result) => {
+                        let Some((_, found, _)) = self.providers.get_mut(id) else {
+                            return;
+                        };
+                        let finished = match result {
+                            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                                found.extend(providers);
+                                step.last()
+                            }
+                            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. })
+                            | Err(GetProvidersError::Timeout { .. }) => true,
+                        };
+                        if !finished {
+                            return;
+                        }
+                        let Some((key, found, call)) = self.providers.remove(id) else {
+                            return;
+                        };
+                        let mut providers: Vec<_> = found
+                            .into_iter()
+                            .map(|peer| answer_peer(swarm, peer))
+                            .collect();
+                        providers.sort_by_key(|p| p.peer);
+                        let answer = Answer::Providers { key, providers };
+                        call.reply(Reply::ok(&SignedAnswer::sign(&self.key, answer)));
+                    }
+                    QueryResult::GetClosestPeers(result) => {
+                        let Some((key, call)) = self.closest.remove(id) else {
+                            return;
+                        };
+                        // a timed out lookup still answers with the closest peers it found
+                        let found = match result {
+                            Ok(ok) => ok.peers.clone(),
+                            Err(GetClosestPeersError::Timeout { peers, .. }) => peers.clone(),
+                        };
+                        let peers = found
+                            .into_iter()
+                            .map(|peer| answer_peer(swarm, peer))
+                            .collect();
+                        let answer = Answer::ClosestPeers { key, peers };
+                        call.reply(Reply::ok(&SignedAnswer::sign(&self.key, answer)));
+                    }
+                    QueryResult::PutRecord(result) => {
+                        let Some((key, call)) = self.puts.remove(id) else {
+                            return;
+                        };
+                        match result {
+                            Ok(_) => call.reply(Reply::ok(&json!({ "key": hex::encode(key) }))),
+                            Err(e) => call.reply(Reply::error(502, format!("storing failed: {e}"))),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// a peer in an answer, with the addresses our routing table has for it
+fn answer_peer(swarm: &mut Swarm<FleygBehavior>, peer: PeerId) -> AnswerPeer {
+    let addrs = match swarm.behaviour_mut().kademlia.kbucket(peer) {
+        Some(bucket) => bucket
+            .iter()
+            .find(|entry| entry.node.key.preimage() == &peer)
+            .map(|entry| entry.node.value.iter().cloned().collect())
+            .unwrap_or_default(),
+        None => Vec::new(),
+    };
+    AnswerPeer { peer, addrs }
+}
+
+// what a peer identified as
+fn identified<P: ToString>(
+    peer: PeerId,
+    agent: &str,
+    protocols: &[P],
+    addrs: &[Multiaddr],
+) -> serde_json::Value {
+    json!({
+        "peer": peer,
+        "agent": agent,
+        "protocols": protocols.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+        "listen_addrs": addrs,
+    })
+}
+
+#[cfg(all(test, feature = "api"))]
+mod tests {
+    use super::*;
+    use crate::{serve, Dialing, Recording};
+    use fleyg::{alerts::Alerts, encoding::ValueFormat, node::FleygNode, state::StateDir};
+    use futures::channel::oneshot;
+    use std::thread;
+
+    #[test]
+    fn answers_closest_calls_in_a_row() {
+        let dir = std::env::temp_dir().join(format!("fleyg-daemon-{}", std::process::id()));
+        let state = StateDir::open(&dir).unwrap();
+        let api = state.control_api();
+        runtime::block_on(async {
+            let key = Keypair::generate_ed25519();
+            let swarm = FleygNode::new(key.clone()).build().await.unwrap();
+            let control = Control::listen(&api, key, Config::default(), Vec::new(), &state);
+            let recording = Recording {
+                sessions: None,
+                snapshots: None,
+                bundles: None,
+                archive: None,
+                metrics: None,
+                tasks: None,
+                blocks: None,
+                control: Some(control.unwrap()),
+            };
+            let dialing = Dialing {
+                bootnodes: Vec::new(),
+                settings: Default::default(),
+                pex: Default::default(),
+                routing: Default::default(),
+                relays: Vec::new(),
+                lan: None,
+            };
+            let alerts = Alerts::new(Vec::new()).unwrap();
+            let serving = serve(
+                swarm,
+                dialing,
+                PeerStore::memory(),
+                recording,
+                ValueFormat::default(),
+                alerts,
+                Default::default(),
+            );
+
+            // an empty routing table answers right away, serving has to go on after it
+            let (done, answers) = oneshot::channel();
+            let caller = api.clone();
+            thread::spawn(move || {
+                let answers: Vec<_> = [b"one".to_vec(), b"two".to_vec()]
+                    .into_iter()
+                    .map(|key| control::call(&caller, &Request::Closest { key }))
+                    .collect();
+                let _ = done.send(answers);
+            });
+            let answers = match future::select(Box::pin(serving), answers).await {
+                Either::Left((served, _)) => panic!("serve stopped: {:?}", served.err()),
+                Either::Right((answers, _)) => answers.unwrap(),
+            };
+            for (answer, key) in answers.into_iter().zip([&b"one"[..], b"two"]) {
+                let signed: SignedAnswer = serde_json::from_value(answer.unwrap()).unwrap();
+                signed.verify().unwrap();
+                assert_eq!(signed.answer.key(), key);
+            }
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,450 @@
+use crate::{FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    deadline::{idle_until, next_before},
+    diagnose::Diagnosis,
+    dialrace::DialSettings,
+    dialreport::{DialPolicy, DialReport, Outcome},
+    peerstore::PeerStore,
+    progress::Progress,
+    querystats::QueryConnections,
+    relays::{self, RelayStats},
+    retry::RetryPolicy,
+    table::Output,
+};
+use libp2p::{
+    kad::{KademliaEvent, QueryResult},
+    multiaddr::Protocol,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct DialOpt {
+    /// the peer to dial
+    peer: PeerId,
+
+    /// the addresses to try, looked up in the DHT if none are given
+    addrs: Vec<Multiaddr>,
+
+    /// how to attempt the addresses: sequential, concurrent, or race them best first as
+    /// the [dials] config says and keep the first to connect
+    #[structopt(long, default_value = "sequential")]
+    policy: DialPolicy,
+
+    /// seconds to wait on each dial
+    #[structopt(long, default_value = "10")]
+    timeout: u64,
+
+    /// seconds to spend looking up the peer's addresses
+    #[structopt(long, default_value = "60")]
+    lookup_timeout: u64,
+
+    /// keep connections to addresses that authenticate as a different peer
+    #[structopt(long)]
+    keep_wrong_peer: bool,
+
+    /// only dial through this relay, instead of choosing between the peer's relays
+    #[structopt(long)]
+    via: Option<PeerId>,
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: DialOpt,
+    mut peers: PeerStore,
+    retry: RetryPolicy,
+    settings: &DialSettings,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut addrs = if opt.addrs.is_empty() {
+        let deadline = Instant::now() + Duration::from_secs(opt.lookup_timeout);
+        lookup(&mut swarm, opt.peer, deadline).await
+    } else {
+        opt.addrs.clone()
+    };
+    if let Some(relay) = opt.via {
+        addrs = via(&mut swarm, &peers, opt.peer, relay, addrs)?;
+    }
+    if addrs.is_empty() {
+        return Err(format!("no known addresses for {}", opt.peer).into());
+    }
+
+    // direct addresses first, then circuits through the best relays
+    let stats: HashMap<PeerId, RelayStats> = addrs
+        .iter()
+        .filter_map(relays::relay_of)
+        .map(|relay| {
+            let record = peers.get(&relay);
+            let stats = RelayStats {
+                history: record.and_then(|r| r.relayed).unwrap_or_default(),
+                rtt: record.and_then(|r| r.rtt),
+                load: 0,
+            };
+            (relay, stats)
+        })
+        .collect();
+    let mut report = DialReport::new(opt.peer, opt.policy);
+    let mut ranked = Vec::new();
+    for (addr, through) in relays::rank(addrs, &stats) {
+        if let Some((relay, score)) = through {
+            report.record_via(addr.clone(), relay, score);
+        }
+        ranked.push(addr);
+    }
+
+    let timeout = Duration::from_secs(opt.timeout);
+    let rounds: Vec<Vec<Multiaddr>> = match opt.policy {
+        DialPolicy::Sequential => ranked.into_iter().map(|addr| vec![addr]).collect(),
+        DialPolicy::Concurrent => vec![ranked],
+        DialPolicy::Race => vec![settings.rank_by(ranked, |a| peers.confidence(&opt.peer, a))],
+    };
+    let race = opt.policy == DialPolicy::Race;
+    for addrs in rounds {
+        let keep_wrong = opt.keep_wrong_peer;
+        for (addr, outcome) in dial_with_retry(
+            &mut swarm, opt.peer, addrs, timeout, keep_wrong, race, &retry,
+        )
+        .await
+        {
+            report.record(addr, outcome);
+        }
+    }
+
+    // remember how each relay did
+    for (addr, outcome) in &report.entries {
+        if let Some((relay, _)) = report.via.get(addr) {
+            let history = peers
+                .entry(*relay)
+                .relayed
+                .get_or_insert_with(Default::default);
+            history.record(outcome.is_success());
+        }
+    }
+
+    // remember how each address did and which answered as someone else
+    for (addr, outcome) in &report.entries {
+        if !matches!(outcome, Outcome::Canceled | Outcome::Unsupported) {
+            peers.record_dial(opt.peer, addr, outcome.is_success());
+        }
+        if let Outcome::WrongPeerId { obtained } = outcome {
+            peers.record_wrong_peer_id(opt.peer, addr.clone(), *obtained);
+        }
+    }
+    peers.save()?;
+
+    info!(
+        "Dial report for {} ({} of {} addresses working)",
+        report.peer,
+        report.successes(),
+        report.entries.len()
+    );
+    output.print(report.table())?;
+
+    Ok(())
+}
+
+/// the circuit addresses through the relay, made from the relay's own addresses if the
+/// peer didn't advertise any
+fn via(
+    swarm: &mut Swarm<FleygBehavior>,
+    peers: &PeerStore,
+    peer: PeerId,
+    relay: PeerId,
+    addrs: Vec<Multiaddr>,
+) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+    let circuits: Vec<Multiaddr> = addrs
+        .into_iter()
+        .filter(|a| relays::relay_of(a) == Some(relay))
+        .collect();
+    if !circuits.is_empty() {
+        return Ok(circuits);
+    }
+    let mut relay_addrs: Vec<Multiaddr> = peers
+        .get(&relay)
+        .map(|r| r.addrs.clone())
+        .unwrap_or_default();
+    for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            if *entry.node.key.preimage() == relay {
+                relay_addrs.extend(entry.node.value.iter().cloned());
+            }
+        }
+    }
+    relay_addrs.sort();
+    relay_addrs.dedup();
+    if relay_addrs.is_empty() {
+        return Err(format!(
+            "no known addresses for the relay {relay}, give a circuit address like \
+             <addr>/p2p/{relay}/p2p-circuit/p2p/{peer}"
+        )
+        .into());
+    }
+    Ok(relay_addrs
+        .iter()
+        .filter(|a| relays::relay_of(a).is_none())
+        .map(|a| relays::circuit(a, relay, peer))
+        .collect())
+}
+
+/// find the addresses of a peer by looking it up in the DHT
+pub async fn lookup(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    deadline: Instant,
+) -> Vec<Multiaddr> {
+    let mut addrs: Vec<Multiaddr> = Vec::new();
+    info!("Looking up addresses for {peer}...");
+    let query = swarm.behaviour_mut().kademlia.get_closest_peers(peer);
+    debug!("Closest peers query {query:?}");
+    let progress = Progress::spinner("looking up peer");
+    let mut connections = QueryConnections::default();
+    connections.started(query);
+    let mut contacted = 0;
+    while let Some(event) = next_before(swarm, deadline).await {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                contacted += 1;
+                progress.set_message(format!("{contacted} peers contacted"));
+                if endpoint.is_dialer() {
+                    connections.opened();
+                }
+                if peer_id == peer {
+                    addrs.push(endpoint.get_remote_address().clone());
+                }
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::RoutingUpdated {
+                    peer: p, addresses, ..
+                },
+            )) if p == peer => {
+                addrs.extend(addresses.iter().cloned());
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    result: QueryResult::GetClosestPeers(_),
+                    step,
+                    ..
+                },
+            )) if step.last() => break,
+            _ => {}
+        }
+    }
+    progress.finish();
+    if let Some(opened) = connections.finished(&query) {
+        info!("Lookup opened {opened} connections");
+    }
+
+    // include anything the routing table already knew
+    for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            if *entry.node.key.preimage() == peer {
+                addrs.extend(entry.node.value.iter().cloned());
+            }
+        }
+    }
+
+    addrs.sort();
+    addrs.dedup();
+    addrs
+}
+
+/// dial the addresses, or race them, dialing the ones that failed again as the retry
+/// policy allows, each address gets the outcome of its last attempt
+async fn dial_with_retry(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    mut addrs: Vec<Multiaddr>,
+    timeout: Duration,
+    keep_wrong: bool,
+    race: bool,
+    retry: &RetryPolicy,
+) -> Vec<(Multiaddr, Outcome)> {
+    let mut results = Vec::new();
+    let mut attempts = 1;
+    loop {
+        let mut again = Vec::new();
+        let mut wait = Duration::ZERO;
+        let outcomes = match race {
+            true => race_addrs(swarm, peer, addrs, timeout).await,
+            false => dial_addrs(swarm, peer, addrs, timeout, keep_wrong).await,
+        };
+        for (addr, outcome) in outcomes {
+            let delay = outcome.failure().and_then(|f| retry.retry(attempts, f));
+            match delay {
+                Some(delay) => {
+                    info!("Dialing {addr} failed: {outcome}, retrying in {delay:?}");
+                    wait = wait.max(delay);
+                    again.push(addr);
+                }
+                None => results.push((addr, outcome)),
+            }
+        }
+        if again.is_empty() {
+            return results;
+        }
+        idle_until(swarm, Instant::now() + wait).await;
+        addrs = again;
+        attempts += 1;
+    }
+}
+
+/// dial each of the addresses at once and wait for all of them to finish, if `keep_wrong`
+/// is set the addresses are dialed without expecting a peer id so connections that
+/// authenticate as another peer aren't dropped
+async fn dial_addrs(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    addrs: Vec<Multiaddr>,
+    timeout: Duration,
+    keep_wrong: bool,
+) -> Vec<(Multiaddr, Outcome)> {
+    let mut results = Vec::new();
+    let mut pending: HashMap<ConnectionId, (Multiaddr, Instant)> = HashMap::new();
+    for addr in addrs {
+        let opts = if keep_wrong {
+            DialOpts::unknown_peer_id().address(addr.clone()).build()
+        } else {
+            DialOpts::peer_id(peer)
+                .addresses(vec![addr.clone()])
+                .condition(PeerCondition::Always)
+                .build()
+        };
+        let id = opts.connection_id();
+        match swarm.dial(opts) {
+            Ok(()) => {
+                info!("Dialing {addr}");
+                pending.insert(id, (addr, Instant::now()));
+            }
+            Err(e) => results.push((addr, Outcome::from_dial_error(&e))),
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    while !pending.is_empty() {
+        let Some(event) = next_before(swarm, deadline).await else {
+            break;
+        };
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                ..
+            } => {
+                if let Some((addr, started)) = pending.remove(&connection_id) {
+                    if peer_id == peer {
+                        let elapsed = started.elapsed();
+                        results.push((addr, Outcome::Success { elapsed }));
+                        swarm.close_connection(connection_id);
+                    } else {
+                        results.push((addr, Outcome::WrongPeerId { obtained: peer_id }));
+                    }
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                error,
+                ..
+            } => {
+                if let Some((addr, _)) = pending.remove(&connection_id) {
+                    results.push((addr, Outcome::from_dial_error(&error)));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // anything still pending ran out of time
+    results.extend(
+        pending
+            .into_values()
+            .map(|(addr, _)| (addr, Outcome::Timeout)),
+    );
+    results
+}
+
+/// dial the addresses as one race, the swarm dials a few of them at once in order and
+/// cancels the rest when one connects. Addresses that lost aren't reported on, they were
+/// either canceled or failed before the winner connected.
+async fn race_addrs(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    addrs: Vec<Multiaddr>,
+    timeout: Duration,
+) -> Vec<(Multiaddr, Outcome)> {
+    let opts = DialOpts::peer_id(peer)
+        .addresses(addrs.clone())
+        .condition(PeerCondition::Always)
+        .build();
+    let id = opts.connection_id();
+    let started = Instant::now();
+    if let Err(e) = swarm.dial(opts) {
+        let outcome = Outcome::from_dial_error(&e);
+        return addrs.into_iter().map(|a| (a, outcome.clone())).collect();
+    }
+    info!("Racing {} addresses", addrs.len());
+
+    let deadline = started + timeout;
+    while let Some(event) = next_before(swarm, deadline).await {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                connection_id,
+                endpoint,
+                ..
+            } if connection_id == id => {
+                let elapsed = started.elapsed();
+                let winner = without_p2p(endpoint.get_remote_address());
+                swarm.close_connection(connection_id);
+                return addrs
+                    .into_iter()
+                    .map(|a| match without_p2p(&a) == winner {
+                        true => (a, Outcome::Success { elapsed }),
+                        false => (a, Outcome::Canceled),
+                    })
+                    .collect();
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                error,
+                ..
+            } if connection_id == id => {
+                let diagnoses = Diagnosis::from_dial_error(&error);
+                return addrs
+                    .into_iter()
+                    .map(|a| {
+                        let outcome = match diagnoses
+                            .iter()
+                            .find(|d| without_p2p(&d.addr) == without_p2p(&a))
+                        {
+                            Some(d) => Outcome::from_diagnosis(d),
+                            None => Outcome::from_dial_error(&error),
+                        };
+                        (a, outcome)
+                    })
+                    .collect();
+            }
+            _ => {}
+        }
+    }
+    addrs.into_iter().map(|a| (a, Outcome::Timeout)).collect()
+}
+
+// the address without the /p2p/<peer id> the swarm adds when dialing
+fn without_p2p(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr
+}
@@ -0,0 +1,46 @@
+use fleyg::{
+    dnscache::DnsCache,
+    peerstore::now_secs,
+    state::StateDir,
+    table::{Cell, Output, Table},
+};
+use log::*;
+use std::error::Error;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum DnsOpt {
+    /// show the cached bootstrap address resolutions and when they expire
+    Show,
+
+    /// forget every cached resolution, the next command resolves them again
+    Flush,
+}
+
+pub fn run(state: &StateDir, opt: DnsOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    let mut cache = DnsCache::open(state.dns_cache_path())?;
+    match opt {
+        DnsOpt::Show => {
+            let now = now_secs();
+            let mut table = Table::new(&["name", "addrs", "expires"]);
+            for (name, entry) in cache.entries() {
+                let expires = match entry.expires.checked_sub(now) {
+                    Some(left) => Cell::good(format!("in {left}s")),
+                    None => Cell::warn(format!("{}s ago", now - entry.expires)),
+                };
+                table.push(vec![
+                    name.as_str().into(),
+                    entry.addrs.len().to_string().into(),
+                    expires,
+                ]);
+            }
+            output.print(table)?;
+        }
+        DnsOpt::Flush => {
+            let flushed = cache.flush();
+            cache.save()?;
+            info!("Flushed {flushed} cached resolutions");
+        }
+    }
+    Ok(())
+}
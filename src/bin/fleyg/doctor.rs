@@ -0,0 +1,297 @@
+use crate::{default_listen, listen_on, FleygBehaviorEvent};
+use fleyg::{
+    chainspec::ChainSpec,
+    config::Config,
+    deadline::next_before,
+    dnscache,
+    doctor::{self, Finding, Severity},
+    network::{Network, PRESETS},
+    node::FleygNode,
+    peerstore::{now_secs, PeerStore},
+    state::StateDir,
+    table::{Cell, Output, Table},
+};
+use libp2p::{
+    autonat::{self, NatStatus},
+    identity::Keypair,
+    swarm::SwarmEvent,
+    Multiaddr, PeerId,
+};
+use log::*;
+use std::{
+    collections::{BTreeMap, HashSet},
+    error::Error,
+    path::Path,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct DoctorOpt {
+    /// only run the checks that don't need the network
+    #[structopt(long)]
+    offline: bool,
+
+    /// seconds to spend reaching the bootstrap peers and learning the NAT status
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+}
+
+/// The global flags that change what's checked
+pub struct Overrides<'a> {
+    pub network: Option<&'a str>,
+    pub bootnodes: &'a [Multiaddr],
+    pub listen: &'a [Multiaddr],
+    pub identity: Option<&'a Path>,
+}
+
+/// check what fleyg needs to work and print what's wrong and how to fix it, failing if
+/// any check failed
+pub async fn run(
+    opt: DoctorOpt,
+    config_path: &Path,
+    state: &StateDir,
+    peers: &PeerStore,
+    overrides: Overrides<'_>,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut findings = Vec::new();
+    let (finding, config) = doctor::check_config(config_path);
+    findings.push(finding);
+    let config = config.unwrap_or_default();
+
+    let key_path = match overrides.identity {
+        Some(path) => path.to_path_buf(),
+        None => state.key_path(),
+    };
+    findings.push(doctor::check_key(&key_path));
+
+    let listen = match (overrides.listen, &config.listen) {
+        (flags, _) if !flags.is_empty() => flags.to_vec(),
+        (_, Some(listen)) => listen.clone(),
+        _ => default_listen(),
+    };
+    findings.extend(doctor::check_ports(&listen));
+    findings.push(doctor::check_clock(now_secs(), peers));
+
+    if !opt.offline {
+        let network = network(&config, &overrides, &mut findings);
+        let network = resolve(network, &mut findings).await;
+        probe(network, Duration::from_secs(opt.timeout), &mut findings).await?;
+    }
+
+    let mut table = Table::new(&["check", "status", "finding", "fix"]);
+    for finding in &findings {
+        let status = match finding.severity {
+            Severity::Ok => Cell::good("ok"),
+            Severity::Warn => Cell::warn("warn"),
+            Severity::Fail => Cell::bad("fail"),
+        };
+        table.push(vec![
+            finding.check.into(),
+            status,
+            finding.message.as_str().into(),
+            finding.fix.as_deref().unwrap_or("").into(),
+        ]);
+    }
+    output.print(table)?;
+
+    let failed = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Fail)
+        .count();
+    match failed {
+        0 => Ok(()),
+        n => Err(format!("{n} of {} checks failed", findings.len()).into()),
+    }
+}
+
+// the network the node would join, from the flags and the config
+fn network(config: &Config, overrides: &Overrides<'_>, findings: &mut Vec<Finding>) -> Network {
+    const CHECK: &str = "network";
+    // a bad preset in the config is already a config finding
+    let mut network = match overrides.network.map(Network::preset) {
+        Some(Ok(network)) => network,
+        Some(Err(e)) => {
+            let fix = format!("use one of {}", PRESETS.join(", "));
+            findings.push(Finding::fail(CHECK, e, fix));
+            Network::default()
+        }
+        None => config
+            .network
+            .as_deref()
+            .and_then(|name| Network::preset(name).ok())
+            .unwrap_or_default(),
+    };
+    if let Some(path) = &config.chainspec {
+        let joined = ChainSpec::load(path).and_then(|spec| {
+            network
+                .clone()
+                .with_chainspec(&spec, config.genesis_hash.as_deref())
+        });
+        match joined {
+            Ok(joined) => network = joined,
+            Err(e) => findings.push(Finding::fail(
+                CHECK,
+                format!("{}: {e}", path.display()),
+                "fix the config's chainspec or genesis_hash",
+            )),
+        }
+    }
+    if let Some(bootnodes) = &config.bootnodes {
+        network.bootnodes = bootnodes.clone();
+    }
+    if !overrides.bootnodes.is_empty() {
+        network.bootnodes = overrides.bootnodes.to_vec();
+    }
+    network
+}
+
+// resolve the bootstrap domains without the cache, so a cached answer can't hide a DNS
+// that doesn't work
+async fn resolve(mut network: Network, findings: &mut Vec<Finding>) -> Network {
+    const CHECK: &str = "dns";
+    const FIX: &str = "check the resolvers in /etc/resolv.conf answer, or use ip bootnodes";
+    let results = match dnscache::resolve_uncached(&network.bootnodes).await {
+        Ok(results) => results,
+        Err(e) => {
+            findings.push(Finding::fail(
+                CHECK,
+                format!("can't set up DNS resolution: {e}"),
+                FIX,
+            ));
+            return network;
+        }
+    };
+    let mut bootnodes: Vec<Multiaddr> = network
+        .bootnodes
+        .iter()
+        .filter(|addr| !dnscache::is_dns(addr))
+        .cloned()
+        .collect();
+    for (addr, lookup) in results {
+        findings.push(match lookup {
+            Ok(found) if !found.is_empty() => {
+                let finding = Finding::ok(CHECK, format!("{addr} resolves to {}", found.len()));
+                bootnodes.extend(found);
+                finding
+            }
+            Ok(_) => Finding::warn(
+                CHECK,
+                format!("{addr} resolves to no addresses"),
+                "the bootnode may be gone, check the network's bootnode list",
+            ),
+            Err(e) => Finding::fail(CHECK, format!("can't resolve {addr}: {e}"), FIX),
+        });
+    }
+    network.bootnodes = bootnodes;
+    network
+}
+
+// dial the bootstrap peers and wait for AutoNAT to tell whether we're reachable
+async fn probe(
+    network: Network,
+    timeout: Duration,
+    findings: &mut Vec<Finding>,
+) -> Result<(), Box<dyn Error>> {
+    // a throwaway identity, the doctor doesn't create or use the node's key
+    let key = Keypair::generate_ed25519();
+    let mut pending: HashSet<PeerId> = network
+        .bootnode_peers()
+        .into_iter()
+        .map(|(p, _)| p)
+        .collect();
+    let total = pending.len();
+    if total == 0 {
+        findings.push(Finding::warn(
+            "bootstrap",
+            "there are no bootstrap peers to reach",
+            "give some with --bootstrap or the config's bootnodes, or use --mdns on a LAN",
+        ));
+        return Ok(());
+    }
+
+    let mut swarm = FleygNode::new(key)
+        .network(network)
+        .autonat(true)
+        .build()
+        .await?;
+    listen_on(&mut swarm, vec!["/ip4/0.0.0.0/tcp/0".parse()?])?;
+    for peer in &pending {
+        swarm.dial(*peer)?;
+    }
+
+    info!("Reaching {total} bootstrap peers and waiting for the NAT status...");
+    let mut reached = 0;
+    let mut failed = BTreeMap::new();
+    let mut nat = None;
+    let deadline = Instant::now() + timeout;
+    while !pending.is_empty() || (reached > 0 && nat.is_none()) {
+        let Some(event) = next_before(&mut swarm, deadline).await else {
+            break;
+        };
+        match event {
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if pending.remove(&peer_id) => {
+                reached += 1;
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } if pending.remove(&peer_id) => {
+                failed.insert(peer_id, error.to_string());
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Autonat(autonat::Event::StatusChanged {
+                new,
+                ..
+            })) if new != NatStatus::Unknown => nat = Some(new),
+            _ => {}
+        }
+    }
+    for (peer, error) in &failed {
+        debug!("Could not reach bootstrap peer {peer}: {error}");
+    }
+
+    const CHECK: &str = "bootstrap";
+    let unreached = total - reached;
+    findings.push(match reached {
+        0 => Finding::fail(
+            CHECK,
+            format!("none of the {total} bootstrap peers were reachable"),
+            "check outbound TCP isn't blocked by a firewall or proxy",
+        ),
+        _ if unreached == 0 => Finding::ok(CHECK, format!("reached all {total} bootstrap peers")),
+        _ => Finding::warn(
+            CHECK,
+            format!(
+                "reached {reached} of {total} bootstrap peers, {} failed and {} timed out",
+                failed.len(),
+                unreached - failed.len()
+            ),
+            "run fleyg dial on the unreachable ones to see why",
+        ),
+    });
+
+    const NAT: &str = "nat";
+    findings.push(match nat {
+        Some(NatStatus::Public(addr)) => {
+            Finding::ok(NAT, format!("reachable from outside at {addr}"))
+        }
+        Some(_) => Finding::warn(
+            NAT,
+            "behind a NAT, other peers can't dial us",
+            "forward the listen port on the router, or serve with --relay",
+        ),
+        None if reached == 0 => Finding::warn(
+            NAT,
+            "unknown, no peers to ask",
+            "fix reaching the bootstrap peers first",
+        ),
+        None => Finding::warn(
+            NAT,
+            format!("unknown after {}s", timeout.as_secs()),
+            "run again with a longer --timeout",
+        ),
+    });
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use chrono::{TimeZone, Utc};
+use fleyg::{
+    archive::{self, EventQuery},
+    state::StateDir,
+    table::{Output, Table},
+    timespec::parse_time,
+};
+use libp2p::PeerId;
+use log::*;
+use std::{error::Error, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum EventsOpt {
+    /// show the archived events matching all of the given filters, oldest first
+    Query {
+        /// the event archive, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// only events from this time on, e.g. 2h for the last two hours
+        #[structopt(long)]
+        since: Option<String>,
+
+        /// only events up to this time
+        #[structopt(long)]
+        until: Option<String>,
+
+        /// only events naming this peer
+        #[structopt(long)]
+        peer: Option<PeerId>,
+
+        /// only events of this type: identify, kad, ping, relay, relay_server, pex,
+        /// gossipsub, mdns, autonat, connection, dial, listen or swarm
+        #[structopt(long = "type")]
+        kind: Option<String>,
+    },
+}
+
+pub fn run(state: &StateDir, opt: EventsOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    match opt {
+        EventsOpt::Query {
+            dir,
+            since,
+            until,
+            peer,
+            kind,
+        } => {
+            let dir = dir.unwrap_or_else(|| state.events_dir());
+            let query = EventQuery {
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+                peer,
+                kind,
+            };
+            let events = archive::query(&dir, &query)?;
+            info!("{} events in {}", events.len(), dir.display());
+            let mut table = Table::new(&["at", "type", "peer", "detail"]);
+            for event in events {
+                table.push(vec![
+                    format_time(event.at).into(),
+                    event.kind.into(),
+                    event.peer.map(|p| p.to_string()).unwrap_or_default().into(),
+                    event.detail.into(),
+                ]);
+            }
+            output.print(table)?;
+        }
+    }
+    Ok(())
+}
+
+// a unix time in milliseconds for people
+fn format_time(millis: u64) -> String {
+    match Utc.timestamp_millis_opt(millis as i64).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S%.3f").to_string(),
+        None => millis.to_string(),
+    }
+}
@@ -0,0 +1,165 @@
+use crate::{dial, peers::confidence_cell, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    deadline::next_before,
+    diagnose::Diagnosis,
+    dialrace::DialSettings,
+    event::{self, Event},
+    peerstore::PeerStore,
+    table::{Cell, Output, Table},
+};
+use libp2p::{
+    identify,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        DialError, SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::{BTreeSet, HashMap},
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct FindPeerOpt {
+    /// the peer to find
+    peer: PeerId,
+
+    /// seconds to spend looking up the peer's addresses
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+
+    /// seconds to wait for the peer to connect and identify
+    #[structopt(long, default_value = "10")]
+    dial_timeout: u64,
+}
+
+/// How dialing one of the addresses went
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Dialed {
+    Connected,
+    Failed,
+}
+
+/// find a peer's addresses in the DHT, then dial it and show its addresses and what it
+/// identifies as, failing if it can't be reached
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: FindPeerOpt,
+    mut peers: PeerStore,
+    settings: &DialSettings,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let peer = opt.peer;
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    let found = dial::lookup(&mut swarm, peer, deadline).await;
+    if found.is_empty() {
+        return Err(format!("no peer in the DHT knows an address for {peer}").into());
+    }
+    info!("Found {} addresses for {peer}, dialing it", found.len());
+
+    let ranked = settings.rank_by(found.clone(), |a| peers.confidence(&peer, a));
+    let opts = DialOpts::peer_id(peer)
+        .addresses(ranked)
+        .condition(PeerCondition::Always)
+        .build();
+    let id = opts.connection_id();
+    let mut dialed: HashMap<Multiaddr, Dialed> = HashMap::new();
+    let mut failure = None;
+    let mut info = None;
+    match swarm.dial(opts) {
+        Ok(()) => {
+            let deadline = Instant::now() + Duration::from_secs(opt.dial_timeout);
+            while let Some(event) = next_before(&mut swarm, deadline).await {
+                match event {
+                    SwarmEvent::ConnectionEstablished {
+                        connection_id,
+                        endpoint,
+                        ..
+                    } if connection_id == id => {
+                        let addr = endpoint.get_remote_address();
+                        peers.record_dial(peer, addr, true);
+                        dialed.insert(addr.clone(), Dialed::Connected);
+                    }
+                    SwarmEvent::OutgoingConnectionError {
+                        connection_id,
+                        error,
+                        ..
+                    } if connection_id == id => {
+                        if let DialError::Transport(errors) = &error {
+                            for (addr, _) in errors {
+                                peers.record_dial(peer, addr, false);
+                                dialed.insert(addr.clone(), Dialed::Failed);
+                            }
+                        }
+                        failure = Some(match Diagnosis::from_dial_error(&error).first() {
+                            Some(d) => d.to_string(),
+                            None => error.to_string(),
+                        });
+                        break;
+                    }
+                    SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(
+                        identify::Event::Received { peer_id, info: got },
+                    )) if peer_id == peer => {
+                        info = Some(got);
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Err(e) => failure = Some(e.to_string()),
+    }
+
+    if let Some(info) = &info {
+        event::emit(Event::identify(peer, info));
+        info!("{peer} identifies as:");
+        info!("\tAgent: {}", info.agent_version);
+        info!("\tProtocol: {}", info.protocol_version);
+        info!("\tObserved us as: {}", info.observed_addr);
+        info!("\tProtocols:");
+        for protocol in &info.protocols {
+            info!("\t\t{protocol}");
+        }
+        peers.entry(peer).update(info);
+    }
+    peers.save()?;
+
+    // what the DHT said and what the peer says it listens on
+    let found: BTreeSet<Multiaddr> = found.into_iter().collect();
+    let listening: BTreeSet<Multiaddr> = info
+        .as_ref()
+        .map(|info| info.listen_addrs.iter().cloned().collect())
+        .unwrap_or_default();
+    let all: Vec<Multiaddr> = found.union(&listening).cloned().collect();
+    let mut table = Table::new(&["addr", "from", "confidence", "dial"]);
+    for addr in settings.rank_by(all, |a| peers.confidence(&peer, a)) {
+        let from = match (found.contains(&addr), listening.contains(&addr)) {
+            (true, true) => "dht, identify",
+            (true, false) => "dht",
+            (false, _) => "identify",
+        };
+        let dial = match dialed.get(&addr) {
+            Some(Dialed::Connected) => Cell::good("connected"),
+            Some(Dialed::Failed) => Cell::bad("failed"),
+            None => "".into(),
+        };
+        table.push(vec![
+            addr.to_string().into(),
+            from.into(),
+            confidence_cell(peers.confidence(&peer, &addr)),
+            dial,
+        ]);
+    }
+    output.print(table)?;
+
+    match (info, failure) {
+        (Some(_), _) => Ok(()),
+        (None, Some(failure)) => Err(format!("can't reach {peer}: {failure}").into()),
+        (None, None) if dialed.is_empty() => Err(format!("timed out dialing {peer}").into()),
+        (None, None) => Err(format!("{peer} connected but didn't identify in time").into()),
+    }
+}
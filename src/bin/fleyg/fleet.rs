@@ -0,0 +1,129 @@
+use fleyg::{
+    bugreport::BugReport,
+    census::{self, CensusRecord},
+    control::{self, Request},
+    fleet::{Combined, Fleet, FleetNode},
+    table::{Cell, Output, Table},
+};
+use log::*;
+use std::{error::Error, fs, path::PathBuf, thread};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct FleetOpt {
+    /// the toml file listing the daemons, a [[node]] each with a name and api
+    #[structopt(long, parse(from_os_str))]
+    nodes: PathBuf,
+
+    /// write the combined census to this file, for fleyg census
+    #[structopt(long, parse(from_os_str))]
+    out: Option<PathBuf>,
+}
+
+// what a daemon reported
+struct Reading {
+    snapshot: BugReport,
+    census: Vec<CensusRecord>,
+}
+
+/// call every daemon in the fleet and report on them together
+pub fn run(opt: FleetOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    let fleet = Fleet::load(&opt.nodes)?;
+    let readings: Vec<Result<Reading, String>> = thread::scope(|scope| {
+        let calls: Vec<_> = fleet
+            .nodes
+            .iter()
+            .map(|node| scope.spawn(move || read(node)))
+            .collect();
+        calls
+            .into_iter()
+            .map(|call| {
+                call.join()
+                    .unwrap_or_else(|_| Err("the call panicked".into()))
+            })
+            .collect()
+    });
+
+    let censuses: Vec<Vec<CensusRecord>> = readings
+        .iter()
+        .filter_map(|r| r.as_ref().ok())
+        .map(|r| r.census.clone())
+        .collect();
+    let combined = Combined::new(&censuses);
+    let up = censuses.len();
+    info!(
+        "{up} of {} nodes answered, {} unique peers seen, {} reachable, {} by more than one node",
+        fleet.nodes.len(),
+        combined.records.len(),
+        combined.records.iter().filter(|r| r.reachable).count(),
+        combined.seen_by.values().filter(|n| **n > 1).count()
+    );
+    let family = |r: &CensusRecord| {
+        let agent = r.agent.as_deref()?;
+        Some(agent.split('/').next().unwrap_or(agent).to_string())
+    };
+    let mut families: Vec<_> = census::count_by(&combined.records, family)
+        .into_iter()
+        .collect();
+    families.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    for (name, count) in families {
+        info!("\tAgent {name}: {count}");
+    }
+
+    let mut table = Table::new(&[
+        "node", "status", "id", "version", "conns", "rt", "errors", "peers", "only",
+    ]);
+    for (node, reading) in fleet.nodes.iter().zip(&readings) {
+        let reading = match reading {
+            Ok(reading) => reading,
+            Err(e) => {
+                table.push(vec![node.name.as_str().into(), Cell::bad(e.as_str())]);
+                continue;
+            }
+        };
+        let snapshot = &reading.snapshot;
+        let status = if snapshot.connections.is_empty() {
+            Cell::warn("no connections")
+        } else if snapshot.routing.is_empty() {
+            Cell::warn("empty routing table")
+        } else {
+            Cell::good("ok")
+        };
+        table.push(vec![
+            node.name.as_str().into(),
+            status,
+            snapshot.identity.peer.to_string().into(),
+            snapshot.version.fleyg.as_str().into(),
+            snapshot.connections.len().to_string().into(),
+            snapshot.routing.len().to_string().into(),
+            snapshot.errors.len().to_string().into(),
+            reading.census.len().to_string().into(),
+            combined.only_in(&reading.census).to_string().into(),
+        ]);
+    }
+    output.print(table)?;
+
+    if let Some(path) = &opt.out {
+        let mut lines = String::new();
+        for record in &combined.records {
+            lines.push_str(&serde_json::to_string(record)?);
+            lines.push('\n');
+        }
+        fs::write(path, lines)?;
+        info!("Wrote the combined census to {}", path.display());
+    }
+    match fleet.nodes.len() - up {
+        0 => Ok(()),
+        down => Err(format!("{down} of {} nodes didn't answer", fleet.nodes.len()).into()),
+    }
+}
+
+// a daemon's snapshot and census
+fn read(node: &FleetNode) -> Result<Reading, String> {
+    let snapshot = control::call(&node.api, &Request::Snapshot)?;
+    let census = control::call(&node.api, &Request::Census)?;
+    Ok(Reading {
+        snapshot: serde_json::from_value(snapshot).map_err(|e| format!("bad snapshot: {e}"))?,
+        census: serde_json::from_value(census).map_err(|e| format!("bad census: {e}"))?,
+    })
+}
@@ -0,0 +1,729 @@
+use crate::{bootstrap, dial, providers::find_providers, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    answer::{Answer, SignedAnswer},
+    apilisten::ApiListen,
+    blocks::{self, BlockId},
+    control::{self, Request},
+    deadline::{idle_until, next_before},
+    encoding::ValueFormat,
+    event::{self, Event},
+    fastpath::{self, DelegatedSettings, Path},
+    keys::KeyEncoding,
+    memprofile,
+    merge::{self, MergeRule, Strategy},
+    pointer::{Manifest, Pointer},
+    progress::Progress,
+    retry::{Failure, RetryPolicy},
+    runtime,
+    table::{Output, Table},
+    timespec::parse_duration,
+};
+use futures::future::{self, Either};
+use libp2p::{
+    kad::{
+        record::{store::RecordStore, Key, Record},
+        GetRecordOk, KademliaEvent, PeerRecord, QueryResult,
+    },
+    request_response::{self, Message, RequestId},
+    swarm::{dial_opts::DialOpts, SwarmEvent},
+    PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// the exit status when no peer has the record
+const EXIT_NOT_FOUND: i32 = 2;
+
+/// the exit status when the lookup ran out of time
+const EXIT_TIMEOUT: i32 = 3;
+
+/// the most providers of a pointer's blocks to try
+const MAX_SOURCES: usize = 8;
+
+/// the block requests to keep in flight to a provider
+const WINDOW: usize = 8;
+
+#[derive(Debug, StructOpt)]
+pub struct GetOpt {
+    /// the record key, in the --key-encoding
+    key: String,
+
+    /// how the key is encoded: hex, base64, base58, base32, multibase, cid or raw
+    /// [default: the --key-encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// seconds to spend on each attempt at the lookup
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+
+    /// race our store, delegated routing and the DHT for the first verified answer within
+    /// this long, e.g. "2s" or "500ms", instead of bootstrapping and retrying
+    #[structopt(long)]
+    budget: Option<String>,
+
+    /// collect every peer's value and pick one when they differ: last-writer-wins,
+    /// highest-sequence or script, overrides the config's [[merge]] rules
+    #[structopt(long, conflicts_with = "budget")]
+    merge: Option<Strategy>,
+
+    /// the json field the merge strategy compares [default: timestamp or seq]
+    #[structopt(long, requires = "merge")]
+    merge_field: Option<String>,
+
+    /// the command line of the script merge strategy
+    #[structopt(long, requires = "merge")]
+    merge_script: Option<String>,
+
+    /// when the record is a pointer to a large value, fetch the value's blocks from its
+    /// providers and show the value
+    #[structopt(long, conflicts_with_all = &["budget", "merge"])]
+    follow: bool,
+}
+
+impl GetOpt {
+    /// true if a running daemon can do the lookup, it doesn't race, merge or follow
+    pub fn delegable(&self, merges: &[MergeRule], keys: KeyEncoding) -> bool {
+        let plain = self.budget.is_none() && self.merge.is_none() && !self.follow;
+        match self.key_encoding.unwrap_or(keys).decode(&self.key) {
+            Ok(key) => plain && merge::rule_for(merges, &key).is_none(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// look up a record through the running daemon and show its value, exiting with 2 if it
+/// isn't found
+pub fn delegate(
+    api: &ApiListen,
+    opt: GetOpt,
+    format: ValueFormat,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let key = opt
+        .key_encoding
+        .unwrap_or(format.keys)
+        .decode(&opt.key)
+        .map_err(|e| format!("bad key {}: {e}", opt.key))?;
+    info!("Getting {} through the daemon at {api}", format.key(&key));
+    let reply = control::call(api, &Request::Get { key: key.clone() })?;
+    let signed: SignedAnswer = serde_json::from_value(reply)?;
+    signed.verify()?;
+    let Answer::Record {
+        value, publisher, ..
+    } = signed.answer
+    else {
+        return Err("the daemon answered something other than a record".into());
+    };
+    let Some(value) = value else {
+        error!("no peer has the record {}", format.key(&key));
+        std::process::exit(EXIT_NOT_FOUND);
+    };
+    let mut table = Table::new(&["field", "value"]);
+    table.push(vec!["value".into(), format.value(&key, &value)?.into()]);
+    if let Some(publisher) = publisher {
+        table.push(vec!["publisher".into(), publisher.to_string().into()]);
+    }
+    table.push(vec!["signer".into(), signed.signer.to_string().into()]);
+    output.print(table)?;
+    Ok(())
+}
+
+/// look up a record and show its value, exiting with 2 if it isn't found and 3 if the
+/// lookup timed out
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: GetOpt,
+    retry: RetryPolicy,
+    delegated: &DelegatedSettings,
+    merges: &[MergeRule],
+    format: ValueFormat,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let key = opt
+        .key_encoding
+        .unwrap_or(format.keys)
+        .decode(&opt.key)
+        .map_err(|e| format!("bad key {}: {e}", opt.key))?;
+    let key = Key::new(&key);
+    if let Some(budget) = &opt.budget {
+        let budget = parse_duration(budget)?;
+        return fast_get(swarm, &key, budget, delegated, &format, output).await;
+    }
+    let flagged = opt.merge.map(|strategy| MergeRule {
+        prefix: String::new(),
+        strategy,
+        field: opt.merge_field.clone(),
+        script: opt.merge_script.clone(),
+    });
+    let rule = flagged
+        .as_ref()
+        .or_else(|| merge::rule_for(merges, key.as_ref()));
+    if let Some(rule) = rule {
+        if opt.follow {
+            return Err("--follow doesn't work with merged records".into());
+        }
+        rule.validate()?;
+    }
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, Instant::now() + timeout).await?;
+
+    let mut attempts = 1;
+    let mut answers = loop {
+        let deadline = Instant::now() + timeout;
+        let found = match rule {
+            Some(_) => get_all(&mut swarm, &key, deadline).await,
+            None => get(&mut swarm, &key, deadline)
+                .await
+                .map(|found| vec![found]),
+        };
+        let failure = match found {
+            Ok(found) => break found,
+            Err(failure) => failure,
+        };
+        match retry.retry(attempts, failure) {
+            Some(delay) => {
+                info!("Getting the record failed: {failure}, retrying in {delay:?}");
+                idle_until(&mut swarm, Instant::now() + delay).await;
+                attempts += 1;
+            }
+            None => {
+                let (code, error) = match failure {
+                    Failure::Timeout => (EXIT_TIMEOUT, "timed out getting"),
+                    _ => (EXIT_NOT_FOUND, "no peer has"),
+                };
+                error!("{error} the record {}", format.key(key.as_ref()));
+                std::process::exit(code);
+            }
+        }
+    };
+    if let Some(rule) = rule {
+        return show_merged(&key, rule, answers, &format, output);
+    }
+
+    let (from, record) = answers.remove(0);
+    info!(
+        "Got the record {} from {}",
+        format.key(key.as_ref()),
+        from.map(|p| p.to_string())
+            .unwrap_or_else(|| "our store".into())
+    );
+    let pointer = match opt.follow {
+        true => Pointer::from_value(&record.value),
+        false => None,
+    };
+    let value = match &pointer {
+        Some(pointer) => {
+            pointer.verify(key.as_ref())?;
+            follow(&mut swarm, pointer, Instant::now() + timeout).await?
+        }
+        None => record.value.clone(),
+    };
+    let mut table = Table::new(&["field", "value"]);
+    table.push(vec![
+        "value".into(),
+        format.value(key.as_ref(), &value)?.into(),
+    ]);
+    if let Some(pointer) = &pointer {
+        table.push(vec![
+            "pointer".into(),
+            format!(
+                "{} bytes in blocks under {}",
+                pointer.size,
+                hex::encode(pointer.root)
+            )
+            .into(),
+        ]);
+    }
+    if let Some(publisher) = record.publisher {
+        table.push(vec!["publisher".into(), publisher.to_string().into()]);
+    }
+    if let Some(from) = from {
+        table.push(vec!["from".into(), from.to_string().into()]);
+    }
+    if let Some(expires) = record.expires {
+        let left = expires.saturating_duration_since(Instant::now());
+        table.push(vec![
+            "expires in".into(),
+            format!("{}s", left.as_secs()).into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// fetch the value a pointer points at, from its publisher or else the other providers
+/// of its blocks, keeping the blocks each sends when the next has to take over
+async fn follow(
+    swarm: &mut Swarm<FleygBehavior>,
+    pointer: &Pointer,
+    deadline: Instant,
+) -> Result<Vec<u8>, String> {
+    let mut sources = vec![pointer.publisher];
+    let root = Key::new(&pointer.root);
+    find_providers(swarm, root, deadline, Some(MAX_SOURCES), |_, peer| {
+        if !sources.contains(&peer) {
+            sources.push(peer);
+        }
+    })
+    .await;
+    let local = *swarm.local_peer_id();
+    sources.retain(|peer| *peer != local);
+
+    let mut have = HashMap::new();
+    for source in sources {
+        let manifest = match fetch_value(swarm, source, pointer.root, &mut have, deadline).await {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("Fetching the value from {source} failed: {e}");
+                continue;
+            }
+        };
+        let chunks = manifest.chunks.iter().map(|id| have[id].clone()).collect();
+        let value = manifest.assemble(chunks)?;
+        pointer.check(&value)?;
+        info!("Fetched the value's {} blocks", manifest.chunks.len() + 1);
+        return Ok(value);
+    }
+    Err("no provider sent the value's blocks".into())
+}
+
+// fetch the manifest and then the chunks from a provider
+async fn fetch_value(
+    swarm: &mut Swarm<FleygBehavior>,
+    source: PeerId,
+    root: BlockId,
+    have: &mut HashMap<BlockId, Vec<u8>>,
+    deadline: Instant,
+) -> Result<Manifest, String> {
+    if !swarm.is_connected(&source) {
+        connect(swarm, source, deadline).await?;
+    }
+    fetch_blocks(swarm, source, &[root], have, deadline).await?;
+    let manifest = Manifest::parse(&have[&root])?;
+    fetch_blocks(swarm, source, &manifest.chunks, have, deadline).await?;
+    Ok(manifest)
+}
+
+// look up a provider and dial it
+async fn connect(
+    swarm: &mut Swarm<FleygBehavior>,
+    peer: PeerId,
+    deadline: Instant,
+) -> Result<(), String> {
+    let addrs = dial::lookup(swarm, peer, deadline).await;
+    if addrs.is_empty() {
+        return Err(format!("no address for {peer}"));
+    }
+    let opts = DialOpts::peer_id(peer).addresses(addrs).build();
+    let id = opts.connection_id();
+    swarm.dial(opts).map_err(|e| e.to_string())?;
+    while let Some(event) = next_before(swarm, deadline).await {
+        match event {
+            SwarmEvent::ConnectionEstablished { connection_id, .. } if connection_id == id => {
+                return Ok(());
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                error,
+                ..
+            } if connection_id == id => return Err(error.to_string()),
+            _ => {}
+        }
+    }
+    Err("timed out dialing".into())
+}
+
+// ask a provider for the blocks we don't have yet, a window at a time, checking each
+// against its id
+async fn fetch_blocks(
+    swarm: &mut Swarm<FleygBehavior>,
+    source: PeerId,
+    ids: &[BlockId],
+    have: &mut HashMap<BlockId, Vec<u8>>,
+    deadline: Instant,
+) -> Result<(), String> {
+    let mut wanted = ids.iter().filter(|id| !have.contains_key(*id)).copied();
+    let mut pending: HashMap<RequestId, BlockId> = HashMap::new();
+    let progress = Progress::bar(ids.len() as u64, "fetching blocks");
+    progress.set_position(ids.iter().filter(|id| have.contains_key(*id)).count() as u64);
+    loop {
+        while pending.len() < WINDOW {
+            let Some(id) = wanted.next() else {
+                break;
+            };
+            if let Some(blocks) = swarm.behaviour_mut().blocks.as_mut() {
+                pending.insert(blocks.send_request(&source, id), id);
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+        let Some(event) = next_before(swarm, deadline).await else {
+            progress.finish();
+            return Err("timed out".into());
+        };
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Blocks(event)) = event else {
+            continue;
+        };
+        let result = match event {
+            request_response::Event::Message {
+                message:
+                    Message::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => match (pending.remove(&request_id), response) {
+                (Some(id), Some(block)) if blocks::id(&block) == id => {
+                    have.insert(id, block);
+                    progress.inc(1);
+                    Ok(())
+                }
+                (Some(id), Some(_)) => Err(format!("sent a bad block for {}", hex::encode(id))),
+                (Some(id), None) => Err(format!("doesn't have block {}", hex::encode(id))),
+                (None, _) => Ok(()),
+            },
+            request_response::Event::OutboundFailure {
+                request_id, error, ..
+            } if pending.contains_key(&request_id) => Err(error.to_string()),
+            _ => Ok(()),
+        };
+        if let Err(e) = result {
+            progress.finish();
+            return Err(e);
+        }
+    }
+    progress.finish();
+    Ok(())
+}
+
+/// pick a value out of the ones peers returned and show it, with the conflicting values
+/// if they didn't agree
+fn show_merged(
+    key: &Key,
+    rule: &MergeRule,
+    answers: Vec<(Option<PeerId>, Record)>,
+    format: &ValueFormat,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let answered = answers.len();
+    let candidates = merge::candidates(
+        answers
+            .into_iter()
+            .map(|(from, record)| (from, record.value, record.publisher)),
+    );
+    let value = merge::merge(rule, &candidates)?;
+    info!(
+        "Got {} values for the record {} from {answered} answers",
+        candidates.len(),
+        format.key(key.as_ref())
+    );
+    let mut table = Table::new(&["field", "value"]);
+    table.push(vec![
+        "value".into(),
+        format.value(key.as_ref(), &value)?.into(),
+    ]);
+    if candidates.len() > 1 {
+        warn!(
+            "Peers returned {} different values, {} picked one",
+            candidates.len(),
+            rule.strategy
+        );
+        event::emit(
+            Event::new("record_conflict", None)
+                .with("key", hex::encode(key))
+                .with("strategy", rule.strategy.to_string())
+                .with("chosen", hex::encode(&value))
+                .with(
+                    "values",
+                    candidates
+                        .iter()
+                        .map(|c| hex::encode(&c.value))
+                        .collect::<Vec<_>>(),
+                ),
+        );
+        table.push(vec!["strategy".into(), rule.strategy.to_string().into()]);
+        for candidate in &candidates {
+            let chosen = match candidate.value == value {
+                true => ", chosen",
+                false => "",
+            };
+            let publisher = match candidate.publisher {
+                Some(publisher) => format!(", published by {publisher}"),
+                None => String::new(),
+            };
+            table.push(vec![
+                "conflict".into(),
+                format!(
+                    "{} from {} peers{publisher}{chosen}",
+                    format.encoding.encode(&candidate.value),
+                    candidate.from.len()
+                )
+                .into(),
+            ]);
+        }
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// show the first verified answer any path gives within the budget and the path it came
+/// from, exiting with 3 if there is none in time
+async fn fast_get(
+    mut swarm: Swarm<FleygBehavior>,
+    key: &Key,
+    budget: Duration,
+    delegated: &DelegatedSettings,
+    format: &ValueFormat,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let Some((path, from, record)) = race(&mut swarm, key, started + budget, delegated).await
+    else {
+        error!(
+            "no verified answer for the record {} within {budget:?}",
+            format.key(key.as_ref())
+        );
+        std::process::exit(EXIT_TIMEOUT);
+    };
+    let took = started.elapsed();
+    info!(
+        "The {path} path answered for {} in {took:?}",
+        format.key(key.as_ref())
+    );
+    let mut table = Table::new(&["field", "value"]);
+    table.push(vec![
+        "value".into(),
+        format.value(key.as_ref(), &record.value)?.into(),
+    ]);
+    table.push(vec!["path".into(), path.to_string().into()]);
+    table.push(vec![
+        "took".into(),
+        format!("{}ms", took.as_millis()).into(),
+    ]);
+    if let Some(publisher) = record.publisher {
+        table.push(vec!["publisher".into(), publisher.to_string().into()]);
+    }
+    if let Some(from) = from {
+        table.push(vec!["from".into(), from.to_string().into()]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// the first verified answer from our store, delegated routing or a DHT lookup before the
+// deadline
+async fn race(
+    swarm: &mut Swarm<FleygBehavior>,
+    key: &Key,
+    deadline: Instant,
+    delegated: &DelegatedSettings,
+) -> Option<(Path, Option<PeerId>, Record)> {
+    // a record we hold needs no network at all
+    if let Some(record) = swarm.behaviour_mut().kademlia.store_mut().get(key) {
+        let record = record.into_owned();
+        let expired = record.expires.is_some_and(|e| e <= Instant::now());
+        match fastpath::verify(key.as_ref(), &record.value) {
+            Ok(()) if !expired => return Some((Path::Cache, None, record)),
+            Ok(()) => debug!("Our copy of the record expired"),
+            Err(e) => warn!("Our copy of the record doesn't verify: {e}"),
+        }
+    }
+    let mut delegated = match (&delegated.url, fastpath::delegated_name(key.as_ref())) {
+        (Some(url), Some(name)) => {
+            let url = url.clone();
+            let limit = deadline.saturating_duration_since(Instant::now());
+            Some(runtime::spawn(async move {
+                fastpath::delegated_get(&url, &name, limit).await
+            }))
+        }
+        _ => None,
+    };
+
+    let _phase = memprofile::phase("query");
+    let query = swarm.behaviour_mut().kademlia.get_record(key.clone());
+    let progress = Progress::spinner("racing for the record");
+    let mut looking = true;
+    let answer = loop {
+        if !looking && delegated.is_none() {
+            break None;
+        }
+        let next = next_before(swarm, deadline);
+        let event = match delegated.as_mut() {
+            Some(handle) => match future::select(Box::pin(next), handle).await {
+                Either::Left((event, _)) => event,
+                Either::Right((result, _)) => {
+                    delegated = None;
+                    match result {
+                        Ok(Some(value)) => match fastpath::verify(key.as_ref(), &value) {
+                            Ok(()) => {
+                                break Some((
+                                    Path::Delegated,
+                                    None,
+                                    Record::new(key.clone(), value),
+                                ))
+                            }
+                            Err(e) => {
+                                warn!("Delegated routing sent a record that doesn't verify: {e}")
+                            }
+                        },
+                        Ok(None) => debug!("Delegated routing doesn't have the record"),
+                        Err(e) => debug!("Delegated routing failed: {e}"),
+                    }
+                    continue;
+                }
+            },
+            None => next.await,
+        };
+        // the budget ran out
+        let Some(event) = event else {
+            break None;
+        };
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetRecord(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        if id != query || !looking {
+            continue;
+        }
+        match result {
+            Ok(GetRecordOk::FoundRecord(PeerRecord { peer, record })) => {
+                match fastpath::verify(key.as_ref(), &record.value) {
+                    Ok(()) => break Some((Path::Dht, peer, record)),
+                    Err(e) => warn!("A peer sent a record that doesn't verify: {e}"),
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                debug!("The DHT lookup failed: {e}");
+                looking = false;
+            }
+        }
+        if step.last() {
+            looking = false;
+        }
+    };
+    progress.finish();
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    answer
+}
+
+/// one attempt at finding the record
+pub async fn get(
+    swarm: &mut Swarm<FleygBehavior>,
+    key: &Key,
+    deadline: Instant,
+) -> Result<(Option<PeerId>, Record), Failure> {
+    let _phase = memprofile::phase("query");
+    let query = swarm.behaviour_mut().kademlia.get_record(key.clone());
+    let progress = Progress::spinner("getting the record");
+    while let Some(event) = next_before(swarm, deadline).await {
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetRecord(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        if id != query {
+            continue;
+        }
+        match result {
+            Ok(GetRecordOk::FoundRecord(PeerRecord { peer, record })) => {
+                progress.finish();
+                if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                    query.finish();
+                }
+                return Ok((peer, record));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                progress.finish();
+                return Err(Failure::from(&e));
+            }
+        }
+        if step.last() {
+            break;
+        }
+    }
+    progress.finish();
+    if Instant::now() < deadline {
+        return Err(Failure::NotFound);
+    }
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    Err(Failure::Timeout)
+}
+
+/// one attempt at collecting every peer's value of the record, until the lookup ends or
+/// the deadline passes
+pub async fn get_all(
+    swarm: &mut Swarm<FleygBehavior>,
+    key: &Key,
+    deadline: Instant,
+) -> Result<Vec<(Option<PeerId>, Record)>, Failure> {
+    let _phase = memprofile::phase("query");
+    let query = swarm.behaviour_mut().kademlia.get_record(key.clone());
+    let progress = Progress::spinner("collecting the record's values");
+    let mut found = Vec::new();
+    let mut failure = None;
+    while let Some(event) = next_before(swarm, deadline).await {
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetRecord(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        if id != query {
+            continue;
+        }
+        match result {
+            Ok(GetRecordOk::FoundRecord(PeerRecord { peer, record })) => found.push((peer, record)),
+            Ok(_) => {}
+            Err(e) => {
+                failure = Some(Failure::from(&e));
+                break;
+            }
+        }
+        if step.last() {
+            break;
+        }
+    }
+    progress.finish();
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    if !found.is_empty() {
+        return Ok(found);
+    }
+    Err(match failure {
+        Some(failure) => failure,
+        None if Instant::now() < deadline => Failure::NotFound,
+        None => Failure::Timeout,
+    })
+}
@@ -0,0 +1,165 @@
+use crate::{bootstrap, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    deadline::next_before,
+    health::{Grade, HealthReport, LookupSample, Measurements, Thresholds},
+    keys::KeyEncoding,
+    memprofile,
+    progress::Progress,
+    querybudget::{Query, QueryBudget},
+    routing::RoutingSnapshot,
+    table::{Cell, Output, Table},
+};
+use libp2p::{
+    kad::{record::Key, GetProvidersOk, KademliaEvent, QueryId, QueryResult},
+    swarm::SwarmEvent,
+    PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct HealthOpt {
+    /// run more lookups for a more complete report
+    #[structopt(long)]
+    full: bool,
+
+    /// the number of random lookups to run [default: 5, or 20 with --full]
+    #[structopt(long)]
+    lookups: Option<usize>,
+
+    /// keys with known providers to check can be found, in the --key-encoding
+    #[structopt(long)]
+    provider_key: Vec<String>,
+
+    /// seconds to spend measuring
+    #[structopt(long, default_value = "120")]
+    timeout: u64,
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: HealthOpt,
+    thresholds: Thresholds,
+    mut budget: QueryBudget,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    let keys = opt
+        .provider_key
+        .iter()
+        .map(|k| keys.decode(k).map(Key::new))
+        .collect::<Result<Vec<_>, _>>()?;
+    if opt.full && keys.is_empty() {
+        info!("No --provider-key given, skipping provider retrieval");
+    }
+
+    bootstrap(&mut swarm, deadline).await?;
+    let _phase = memprofile::phase("query");
+    let mut m = Measurements::default();
+    let snapshot = RoutingSnapshot::capture(&mut swarm.behaviour_mut().kademlia);
+    m.buckets = snapshot
+        .buckets
+        .iter()
+        .map(|(index, peers)| (*index, peers.len()))
+        .collect();
+
+    // queue the lookups and provider samples, they run as the query budget allows
+    let lookups = opt.lookups.unwrap_or(if opt.full { 20 } else { 5 });
+    for _ in 0..lookups {
+        budget.push("health", Query::ClosestPeers(PeerId::random()));
+    }
+    let providers = keys.len();
+    for key in keys {
+        budget.push("health", Query::Providers(key));
+    }
+
+    let mut pending: HashSet<QueryId> = HashSet::new();
+    let mut found: HashMap<QueryId, bool> = HashMap::new();
+    let progress = Progress::bar((lookups + providers) as u64, "measuring");
+    loop {
+        for (id, query) in budget.start_ready(&mut swarm.behaviour_mut().kademlia) {
+            if let Query::Providers(_) = query {
+                found.insert(id, false);
+            }
+            pending.insert(id);
+        }
+        if pending.is_empty() {
+            break;
+        }
+        let Some(event) = next_before(&mut swarm, deadline).await else {
+            info!(
+                "Timed out waiting on {} queries",
+                pending.len() + budget.queued()
+            );
+            break;
+        };
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result,
+                stats,
+                step,
+            },
+        )) = event
+        {
+            if !pending.contains(&id) {
+                continue;
+            }
+            match result {
+                QueryResult::GetClosestPeers(result) => {
+                    m.lookups.push(LookupSample {
+                        ok: matches!(result, Ok(ok) if !ok.peers.is_empty()),
+                        requests: stats.num_requests(),
+                        duration: stats.duration().unwrap_or_default(),
+                    });
+                }
+                QueryResult::GetProviders(Ok(GetProvidersOk::FoundProviders {
+                    providers, ..
+                })) if !providers.is_empty() => {
+                    found.insert(id, true);
+                }
+                _ => {}
+            }
+            if step.last() {
+                pending.remove(&id);
+                budget.finished(&id);
+                progress.inc(1);
+            }
+        }
+    }
+    progress.finish();
+    m.providers = found.into_values().collect();
+
+    let report = HealthReport::new(&m, &thresholds);
+    let mut table = Table::new(&["check", "value", "score", "grade"]);
+    for check in &report.checks {
+        let grade = match check.grade {
+            Grade::Pass => Cell::good(check.grade.to_string()),
+            Grade::Fail => Cell::bad(check.grade.to_string()),
+            Grade::Skipped => Cell::warn(check.grade.to_string()),
+        };
+        table.push(vec![
+            check.name.into(),
+            check.value.clone().into(),
+            check
+                .score
+                .map(|s| format!("{:.0}", s * 100.0))
+                .unwrap_or_default()
+                .into(),
+            grade,
+        ]);
+    }
+    output.print(table)?;
+    info!("Health score: {:.0}/100", report.score);
+
+    if !report.healthy {
+        return Err(format!("the DHT is unhealthy ({:.0}/100)", report.score).into());
+    }
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use crate::listen_on;
+use fleyg::{
+    capture::Capture,
+    deadline::next_before,
+    infra::{self, InfraSettings},
+    network::Network,
+    node::{self, AGENT},
+    querybudget::QuerySettings,
+    routingpolicy::RoutingPolicy,
+    runtime,
+    shutdown::Shutdown,
+    store::{FleygStore, StoreSettings},
+    transport,
+};
+use libp2p::{
+    autonat,
+    identify::{self, Event as IdentifyEvent},
+    identity::Keypair,
+    kad::{Kademlia, KademliaEvent, Mode},
+    ping, relay, rendezvous,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour, SwarmEvent},
+    Multiaddr,
+};
+use log::*;
+use std::{
+    error::Error,
+    fs,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how often the event loop wakes to notice a shutdown
+const TICK: Duration = Duration::from_secs(1);
+
+#[derive(Debug, StructOpt)]
+pub struct InfraOpt {
+    /// a public address clients reach us on, overrides the config's
+    #[structopt(long)]
+    external: Vec<Multiaddr>,
+
+    /// don't run the relay server
+    #[structopt(long)]
+    no_relay: bool,
+
+    /// don't run the rendezvous server
+    #[structopt(long)]
+    no_rendezvous: bool,
+
+    /// don't answer AutoNAT requests
+    #[structopt(long)]
+    no_autonat: bool,
+
+    /// write the client config snippet here instead of printing it
+    #[structopt(long, parse(from_os_str))]
+    snippet: Option<PathBuf>,
+}
+
+// the infrastructure services, any of the servers can be turned off
+#[derive(NetworkBehaviour)]
+struct InfraBehavior {
+    identify: identify::Behaviour,
+    kademlia: Kademlia<FleygStore>,
+    ping: ping::Behaviour,
+    relay: Toggle<relay::Behaviour>,
+    rendezvous: Toggle<rendezvous::server::Behaviour>,
+    autonat: Toggle<autonat::Behaviour>,
+}
+
+pub async fn run(
+    key: Keypair,
+    network: &Network,
+    opt: InfraOpt,
+    mut settings: InfraSettings,
+    listen: Vec<Multiaddr>,
+    capture: Option<Arc<Capture>>,
+    queries: &QuerySettings,
+) -> Result<(), Box<dyn Error>> {
+    if !opt.external.is_empty() {
+        settings.external = opt.external;
+    }
+    settings.relay &= !opt.no_relay;
+    settings.rendezvous &= !opt.no_rendezvous;
+    settings.autonat &= !opt.no_autonat;
+    if settings.external.is_empty() {
+        warn!("No --external address, clients can only find us through identify");
+    }
+
+    let peer = key.public().to_peer_id();
+    info!("Local peer id: {peer}");
+    let transport =
+        transport::build(&key, &network.noise, &network.transports, capture, None).await?;
+    let relay = settings
+        .relay
+        .then(|| relay::Behaviour::new(peer, settings.relay_server().config()));
+    let rendezvous = settings
+        .rendezvous
+        .then(|| rendezvous::server::Behaviour::new(rendezvous::server::Config::default()));
+    let autonat = settings.autonat.then(|| {
+        let cfg = autonat::Config {
+            only_global_ips: false,
+            ..Default::default()
+        };
+        autonat::Behaviour::new(peer, cfg)
+    });
+    let store = FleygStore::memory(peer, &StoreSettings::default());
+    let behavior = InfraBehavior {
+        identify: node::identify(&key, network, AGENT),
+        kademlia: node::kademlia(peer, network, queries, &RoutingPolicy::default(), store)?,
+        ping: ping::Behaviour::new(ping::Config::default()),
+        relay: relay.into(),
+        rendezvous: rendezvous.into(),
+        autonat: autonat.into(),
+    };
+    let mut swarm = runtime::swarm(transport, behavior, peer).build();
+    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
+    listen_on(&mut swarm, listen)?;
+    for addr in &settings.external {
+        swarm.add_external_address(addr.clone());
+    }
+
+    let snippet = infra::client_snippet(peer, &network.name, &settings)?;
+    match &opt.snippet {
+        Some(path) => {
+            fs::write(path, &snippet)?;
+            info!("Wrote the client config to {}", path.display());
+        }
+        None => print!("{snippet}"),
+    }
+    info!(
+        "Serving dht{}{}{}",
+        if settings.relay { ", relay" } else { "" },
+        if settings.rendezvous {
+            ", rendezvous"
+        } else {
+            ""
+        },
+        if settings.autonat { ", autonat" } else { "" }
+    );
+
+    let shutdown = Shutdown::catch();
+    let started = Instant::now();
+    let mut connections = 0;
+    while !shutdown.requested() {
+        let Some(event) = next_before(&mut swarm, Instant::now() + TICK).await else {
+            continue;
+        };
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => info!("Listening on {address}"),
+            SwarmEvent::ConnectionEstablished { .. } => connections += 1,
+            SwarmEvent::Behaviour(InfraBehaviorEvent::Identify(IdentifyEvent::Received {
+                peer_id,
+                info,
+            })) => {
+                // learn the dht servers that connect to us
+                let kad = swarm.behaviour().kademlia.protocol_names().to_vec();
+                if info.protocols.iter().any(|p| kad.contains(p)) {
+                    for addr in info.listen_addrs {
+                        swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(InfraBehaviorEvent::Kademlia(
+                KademliaEvent::RoutingUpdated { peer, .. },
+            )) => debug!("Routing table added {peer}"),
+            SwarmEvent::Behaviour(InfraBehaviorEvent::Relay(event)) => match event {
+                relay::Event::ReservationReqAccepted { src_peer_id, .. } => {
+                    info!("Relay reservation for {src_peer_id}")
+                }
+                relay::Event::CircuitReqAccepted {
+                    src_peer_id,
+                    dst_peer_id,
+                } => info!("Relay circuit {src_peer_id} -> {dst_peer_id}"),
+                relay::Event::ReservationTimedOut { src_peer_id } => {
+                    debug!("Relay reservation for {src_peer_id} timed out")
+                }
+                _ => {}
+            },
+            SwarmEvent::Behaviour(InfraBehaviorEvent::Rendezvous(event)) => match event {
+                rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                    info!("Rendezvous {peer} registered in {}", registration.namespace)
+                }
+                rendezvous::server::Event::DiscoverServed {
+                    enquirer,
+                    registrations,
+                } => debug!(
+                    "Rendezvous served {} registrations to {enquirer}",
+                    registrations.len()
+                ),
+                _ => {}
+            },
+            SwarmEvent::Behaviour(InfraBehaviorEvent::Autonat(autonat::Event::InboundProbe(
+                autonat::InboundProbeEvent::Response { peer, address, .. },
+            ))) => debug!("AutoNAT dialed back {peer} at {address}"),
+            _ => {}
+        }
+    }
+
+    let routing_table: usize = swarm
+        .behaviour_mut()
+        .kademlia
+        .kbuckets()
+        .map(|b| b.num_entries())
+        .sum();
+    info!(
+        "Stopped after {}s: {connections} connections, {routing_table} peers in the routing table",
+        started.elapsed().as_secs()
+    );
+    Ok(())
+}
@@ -0,0 +1,207 @@
+use crate::{default_listen, listen_on, FleygBehaviorEvent};
+use fleyg::{
+    config::Config,
+    deadline::next_before,
+    identity::KeyType,
+    import::{self, Format},
+    network::{Network, PRESETS},
+    node::FleygNode,
+    state::StateDir,
+};
+use libp2p::{identify, identity::Keypair, swarm::SwarmEvent};
+use log::*;
+use std::{
+    collections::HashSet,
+    error::Error,
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct InitOpt {
+    /// the network preset to join
+    #[structopt(long)]
+    network: Option<String>,
+
+    /// take the bootnodes and listen addresses from another tool's config file
+    #[structopt(long, parse(from_os_str))]
+    import_config: Option<PathBuf>,
+
+    /// the format of the imported file: kubo or substrate
+    #[structopt(long, requires = "import-config")]
+    format: Option<Format>,
+
+    /// don't ask any questions, use the flags and defaults
+    #[structopt(long, short)]
+    yes: bool,
+
+    /// overwrite an existing config file
+    #[structopt(long)]
+    force: bool,
+
+    /// skip the reachability test
+    #[structopt(long)]
+    offline: bool,
+
+    /// seconds to spend testing reachability
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+}
+
+pub async fn run(
+    opt: InitOpt,
+    config_path: &Path,
+    state: &StateDir,
+    key_type: Option<KeyType>,
+) -> Result<(), Box<dyn Error>> {
+    if config_path.exists() && !opt.force {
+        return Err(format!(
+            "{} already exists, use --force to overwrite it",
+            config_path.display()
+        )
+        .into());
+    }
+    let interactive = !opt.yes && io::stdin().is_terminal();
+
+    // choose the network
+    let network = match opt.network {
+        Some(name) => name,
+        None if interactive => prompt(&format!("Network preset ({})", PRESETS.join(", ")), "ipfs")?,
+        None => "ipfs".into(),
+    };
+    let mut network = Network::preset(&network)?;
+    let imported = match (&opt.import_config, opt.format) {
+        (Some(path), Some(format)) => {
+            let imported = import::import(path, format)?;
+            info!(
+                "Imported {} bootnodes and {} listen addresses from {}",
+                imported.bootnodes.len(),
+                imported.listen.len(),
+                path.display()
+            );
+            network.bootnodes = imported.bootnodes.clone();
+            Some(imported)
+        }
+        (Some(_), None) => return Err("--format is needed with --import-config".into()),
+        _ => None,
+    };
+    let dial = if interactive && !network.bootnodes.is_empty() {
+        prompt("Dial the bootstrap peers on start up? (y/n)", "y")?.starts_with('y')
+    } else {
+        !network.bootnodes.is_empty()
+    };
+
+    // write the config file
+    let config = Config {
+        network: Some(network.name.clone()),
+        bootnodes: imported.as_ref().map(|i| i.bootnodes.clone()),
+        listen: imported
+            .map(|i| i.listen)
+            .filter(|listen| !listen.is_empty()),
+        dial: Some(dial),
+        ..Default::default()
+    };
+    if let Some(dir) = config_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    config.save(config_path)?;
+    info!("Wrote config to {}", config_path.display());
+
+    // load or generate the identity
+    let key = state.keypair(key_type)?;
+    info!("Peer id: {}", key.public().to_peer_id());
+    info!("Key file: {}", state.key_path().display());
+
+    if !opt.offline {
+        reachability(key, &network, Duration::from_secs(opt.timeout)).await?;
+    }
+
+    Ok(())
+}
+
+/// ask a question on the terminal, returning the default if the answer is empty
+fn prompt(question: &str, default: &str) -> io::Result<String> {
+    print!("{question} [{default}]: ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() {
+        default.to_string()
+    } else {
+        answer.to_lowercase()
+    })
+}
+
+/// check that we can listen and reach the bootstrap peers, and print our addresses
+async fn reachability(
+    key: Keypair,
+    network: &Network,
+    timeout: Duration,
+) -> Result<(), Box<dyn Error>> {
+    let mut swarm = FleygNode::new(key).network(network.clone()).build().await?;
+    listen_on(&mut swarm, default_listen())?;
+
+    let mut pending: HashSet<_> = network
+        .bootnode_peers()
+        .into_iter()
+        .map(|(p, _)| p)
+        .collect();
+    let total = pending.len();
+    // a bootstrap peer we can't even dial is unreachable, the rest are still worth testing
+    pending.retain(|peer| match swarm.dial(*peer) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Could not reach bootstrap peer {peer}: {e}");
+            false
+        }
+    });
+
+    info!("Testing reachability...");
+    let mut listen_addrs = Vec::new();
+    let mut observed = HashSet::new();
+    let mut connected = 0;
+    let deadline = Instant::now() + timeout;
+    while !pending.is_empty() {
+        let Some(event) = next_before(&mut swarm, deadline).await else {
+            break;
+        };
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => listen_addrs.push(address),
+            SwarmEvent::ConnectionEstablished { peer_id, .. } if pending.contains(&peer_id) => {
+                connected += 1;
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                error,
+                ..
+            } if pending.remove(&peer_id) => {
+                warn!("Could not reach bootstrap peer {peer_id}: {error}");
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) if pending.remove(&peer_id) => {
+                observed.insert(info.observed_addr);
+            }
+            _ => {}
+        }
+    }
+
+    for addr in &listen_addrs {
+        info!("Listening on {addr}");
+    }
+    if total > 0 {
+        info!("Reached {connected} of {total} bootstrap peers");
+        if connected == 0 {
+            warn!("No bootstrap peers were reachable, check outbound connectivity");
+        }
+    }
+    for addr in &observed {
+        info!("Observed as {addr}");
+    }
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use fleyg::{
+    hdkey::{self, DerivationPath},
+    state::{self, StateDir},
+};
+use log::*;
+use std::{error::Error, path::PathBuf};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct KeygenOpt {
+    /// the derivation path of this node's key, e.g. m/0/3
+    #[structopt(long)]
+    derive: DerivationPath,
+
+    /// the fleet's BIP-39 seed phrase
+    #[structopt(long, env = "FLEYG_MNEMONIC", hide_env_values = true)]
+    mnemonic: String,
+
+    /// the seed phrase's passphrase, if it has one
+    #[structopt(
+        long,
+        env = "FLEYG_PASSPHRASE",
+        hide_env_values = true,
+        default_value = ""
+    )]
+    passphrase: String,
+
+    /// write the key here instead of the state directory's key file
+    #[structopt(long, parse(from_os_str))]
+    out: Option<PathBuf>,
+
+    /// only print the peer id without writing the key
+    #[structopt(long)]
+    dry_run: bool,
+}
+
+pub fn run(state: &StateDir, opt: KeygenOpt) -> Result<(), Box<dyn Error>> {
+    let key = hdkey::keypair(&opt.mnemonic, &opt.passphrase, &opt.derive)?;
+    info!("Peer id for {}: {}", opt.derive, key.public().to_peer_id());
+    if opt.dry_run {
+        return Ok(());
+    }
+    let path = opt.out.unwrap_or_else(|| state.key_path());
+    state::write_keypair(&path, &key)
+        .map_err(|e| format!("can't write {}: {e}", path.display()))?;
+    info!("Key file: {}", path.display());
+    Ok(())
+}
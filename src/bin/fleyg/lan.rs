@@ -0,0 +1,84 @@
+use fleyg::{
+    event::{self, Event},
+    network::DEFAULT_LAN,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use log::*;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+use structopt::StructOpt;
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct LanOpt {
+    /// the LAN DHT to form, machines only find the ones using the same name
+    #[structopt(long, default_value = DEFAULT_LAN)]
+    name: String,
+
+    /// don't print the join command as a QR code
+    #[structopt(long)]
+    no_qr: bool,
+}
+
+impl LanOpt {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Prints the command other machines join us with, once we listen on a LAN address
+pub struct Announcer {
+    opt: LanOpt,
+    peer: PeerId,
+    announced: bool,
+}
+
+impl Announcer {
+    pub fn new(opt: LanOpt, peer: PeerId) -> Self {
+        Self {
+            opt,
+            peer,
+            announced: false,
+        }
+    }
+
+    /// we listen on a new address, announce the first one other machines can dial
+    pub fn listening(&mut self, addr: &Multiaddr) {
+        if self.announced || !on_lan(addr) {
+            return;
+        }
+        self.announced = true;
+        let join = addr.clone().with(Protocol::P2p(self.peer));
+        let mut command = format!("fleyg --bootstrap {join} lan");
+        if self.opt.name != DEFAULT_LAN {
+            command.push_str(&format!(" --name {}", self.opt.name));
+        }
+        info!(
+            "Forming the {} LAN DHT, machines on this network find us with mDNS or join with:",
+            self.opt.name
+        );
+        println!("{command}");
+        event::emit(Event::new("lan_join", Some(self.peer)).with("command", &command));
+        if self.opt.no_qr {
+            return;
+        }
+        // light modules on a dark terminal, scanners read either way round
+        match QrCode::new(command.as_bytes()) {
+            Ok(code) => println!(
+                "{}",
+                code.render::<Dense1x2>()
+                    .dark_color(Dense1x2::Light)
+                    .light_color(Dense1x2::Dark)
+                    .build()
+            ),
+            Err(e) => warn!("Can't make a QR code of the join command: {e}"),
+        }
+    }
+}
+
+// true for an IPv4 address other machines on the network could dial, IPv6 link local
+// addresses need a scope that doesn't travel in a command
+fn on_lan(addr: &Multiaddr) -> bool {
+    match addr.iter().next() {
+        Some(Protocol::Ip4(ip)) => !ip.is_loopback() && !ip.is_unspecified(),
+        _ => false,
+    }
+}
@@ -0,0 +1,2272 @@
+#![doc = include_str!("../../../README.md")]
+
+use env_logger::Env;
+use fleyg::{
+    alerts::{post_webhook, Action, Alerts, Status},
+    apilisten::ApiListen,
+    archive::{ArchivedEvent, EventArchive},
+    attest,
+    blocks::{BlockId, BlockStore},
+    bundle::{Bundler, Misbehavior},
+    capture::Capture,
+    chainspec::ChainSpec,
+    config::Config,
+    deadline::next_before,
+    diagnose::Diagnosis,
+    dialqueue::{DialQueue, Priority},
+    dialrace::DialSettings,
+    divergence::DivergenceTracker,
+    dnscache::{self, DnsCache},
+    encoding::{Encoding, RawDir, ValueFormat},
+    event::{self, Event},
+    filter::Filter,
+    heartbeat::Heartbeat,
+    identity::{load_keypair, KeyType},
+    keys::KeyEncoding,
+    memprofile::{self, PhaseReport, Tracking},
+    metrics::NodeMetrics,
+    negotiation::NegotiationCache,
+    network::{parse_bootnode, Network},
+    node::{FleygBehavior, FleygBehaviorEvent, FleygNode},
+    peerstore::{now_secs, PeerStore},
+    pex::{self, PexRequest, PexSettings},
+    profile::{self, Profile},
+    progress::{self, Progress},
+    querybudget::QueryBudget,
+    querystats::QueryConnections,
+    readiness::{BootstrapGoal, Readiness},
+    receipt::{self, Ack},
+    retry::{Failure, RetryPolicy},
+    routing::{RoutingSnapshot, SnapshotDir},
+    routingpolicy::{Decision, RoutingDecisions, RoutingPolicy},
+    runtime,
+    sessions::{SessionRecorder, SessionTracker},
+    shutdown::{Shutdown, Summary},
+    state::StateDir,
+    store::StoreKind,
+    table::{self, Format, Output, Table},
+    trace::{self, TraceId},
+    transport::Kind,
+};
+use futures::prelude::*;
+use libp2p::{
+    autonat::{self, NatStatus},
+    core::{transport::TransportError, ConnectedPoint},
+    identify::Event as IdentifyEvent,
+    kad::{
+        record::{store::RecordStore, Key, Record},
+        BootstrapError, BootstrapOk, GetClosestPeersError, InboundRequest, KademliaEvent, Mode,
+        QueryId, QueryResult, Quorum,
+    },
+    mdns,
+    multiaddr::Protocol,
+    ping, relay, request_response,
+    swarm::{
+        dial_opts::DialOpts, DialError, ListenerId, NetworkBehaviour, StreamUpgradeError,
+        SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fmt,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+mod attest;
+mod bootstrap;
+mod capture;
+mod census;
+mod crawl;
+mod ctl;
+mod daemon;
+mod dial;
+mod dns;
+mod doctor;
+mod events;
+mod findpeer;
+mod fleet;
+mod get;
+mod health;
+mod infra;
+mod init;
+mod keygen;
+mod lan;
+mod map;
+mod mirror;
+mod peers;
+mod pingpeer;
+mod providers;
+mod pubsub;
+mod put;
+mod repl;
+mod rt;
+mod service;
+mod snapshot;
+mod soak;
+mod state;
+mod store;
+mod tasks;
+mod verify;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fleyg",
+    version = "0.1",
+    author = "Dave Huseby <dwh@linuxprogrammer.org>",
+    about = "libp2p peer tools"
+)]
+struct Opt {
+    /// use the named profile's identity, config and peer store
+    #[structopt(long)]
+    profile: Option<String>,
+
+    /// keep state in this directory instead of the XDG data directory
+    #[structopt(long, parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// read the config from this file instead of the profile's
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// the network preset to join: ipfs, kusama, lan, polkadot or none, overrides the
+    /// config's [default: ipfs]
+    #[structopt(long)]
+    network: Option<String>,
+
+    /// a bootstrap peer's address ending in /p2p/<peer id>, repeatable, replaces the
+    /// network's bootstrap peers
+    #[structopt(long = "bootstrap", parse(try_from_str = parse_bootnode))]
+    bootnodes: Vec<Multiaddr>,
+
+    /// a relay's address ending in /p2p/<peer id> to reserve a slot on and listen through
+    /// when serving behind a NAT, repeatable, overrides the config's
+    #[structopt(long = "relay", parse(try_from_str = parse_bootnode))]
+    relays: Vec<Multiaddr>,
+
+    /// relay circuits for peers behind NATs, with the config's [relay_server] limits
+    #[structopt(long)]
+    relay_server: bool,
+
+    /// dial bootstrap peers
+    #[structopt(long, short)]
+    dial: bool,
+
+    /// keep the node running and take commands on stdin, dial, get, put, peers and
+    /// table, instead of serving
+    #[structopt(long)]
+    interactive: bool,
+
+    /// find peers on the local network with mDNS and add them to the routing table, for
+    /// LANs without bootstrap peers
+    #[structopt(long)]
+    mdns: bool,
+
+    /// run as a throwaway node for one-shot queries: a random identity, peers kept in
+    /// memory only, kademlia client mode and no listeners
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "identity", "kad-server", "listen", "peer-store", "record-sessions",
+            "relay-server", "rt-snapshots", "store", "store-path"
+        ]
+    )]
+    ephemeral: bool,
+
+    /// answer kademlia queries from the start when serving, instead of once AutoNAT finds
+    /// our listen addresses reachable
+    #[structopt(long)]
+    kad_server: bool,
+
+    /// load the identity keypair from this file instead of the state directory's,
+    /// creating it if there is none, protobuf encoded or a PKCS#8 RSA key
+    #[structopt(long, parse(from_os_str))]
+    identity: Option<PathBuf>,
+
+    /// the type of identity key to create: ed25519, secp256k1, ecdsa or rsa, an existing
+    /// key must be of this type [default: ed25519]
+    #[structopt(long)]
+    key_type: Option<KeyType>,
+
+    /// an address to listen on, repeatable, e.g. /ip4/0.0.0.0/tcp/0 for a random port or
+    /// /ip4/0.0.0.0/tcp/4921/ws, overrides the config's [default: /ip4/0.0.0.0/tcp/4920
+    /// and /ip6/::/tcp/4920]
+    #[structopt(long)]
+    listen: Vec<Multiaddr>,
+
+    /// where the kademlia records are kept: memory, or disk to keep them across
+    /// restarts, overrides the config's [default: memory]
+    #[structopt(long)]
+    store: Option<StoreKind>,
+
+    /// the directory of a disk record store, overrides the config's [default: the state
+    /// directory's records directory]
+    #[structopt(long, parse(from_os_str))]
+    store_path: Option<PathBuf>,
+
+    /// the transports to dial and listen with, tcp and ws, repeatable or comma separated,
+    /// overrides the config's [default: tcp]
+    #[structopt(long, use_delimiter = true)]
+    transport: Vec<Kind>,
+
+    /// how binary values are shown: hex, base64, base58 or raw
+    #[structopt(long, default_value = "hex")]
+    encoding: Encoding,
+
+    /// how record and provider keys are given and shown: hex, base64, base58, base32,
+    /// multibase, cid or raw [default: the --encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// write record values as raw bytes into files in this directory
+    #[structopt(long, parse(from_os_str))]
+    raw_dir: Option<PathBuf>,
+
+    /// the most MiB of values to write into the --raw-dir, serve writes the values other
+    /// peers put so this keeps them from filling the disk
+    #[structopt(long, default_value = "1024")]
+    raw_dir_limit: u64,
+
+    /// the columns to show in result tables, e.g. id,addr,agent,rtt
+    #[structopt(long, use_delimiter = true)]
+    columns: Vec<String>,
+
+    /// text for tables and log lines, or json to write result rows and every identify
+    /// answer, inbound request and query result as json lines on stdout, logging only
+    /// warnings unless RUST_LOG is set
+    #[structopt(long, default_value = "text")]
+    output: Format,
+
+    /// don't color output, also disabled by setting NO_COLOR
+    #[structopt(long)]
+    no_color: bool,
+
+    /// only show result rows matching this expression, e.g. 'agent~kubo && rtt<100ms'
+    #[structopt(long)]
+    filter: Option<Filter>,
+
+    /// skip this many result rows
+    #[structopt(long, default_value = "0")]
+    offset: usize,
+
+    /// show at most this many result rows
+    #[structopt(long)]
+    limit: Option<usize>,
+
+    /// file to remember identify info about peers across sessions
+    #[structopt(long, parse(from_os_str))]
+    peer_store: Option<PathBuf>,
+
+    /// write a parquet record of every peer session, under the state directory unless a
+    /// directory is given
+    #[structopt(long, parse(from_os_str))]
+    record_sessions: Option<Option<PathBuf>>,
+
+    /// periodically save routing table snapshots, into the state directory unless a
+    /// directory is given
+    #[structopt(long, parse(from_os_str))]
+    rt_snapshots: Option<Option<PathBuf>>,
+
+    /// seconds between routing table snapshots [default: 600]
+    #[structopt(long)]
+    rt_snapshot_interval: Option<u64>,
+
+    /// archive every event the node handles, into the state directory unless a directory
+    /// is given, for `fleyg events query`
+    #[structopt(long, parse(from_os_str))]
+    archive_events: Option<Option<PathBuf>>,
+
+    /// serve Prometheus metrics on this loopback address or unix:<path> when serving or
+    /// subscribed with sub, e.g. 127.0.0.1:9091
+    #[structopt(long)]
+    metrics_addr: Option<ApiListen>,
+
+    /// where the daemon's control API listens and ctl calls it, a loopback address,
+    /// unix:<path> or pipe:<name> [default: the state directory's fleyg.sock, its named
+    /// pipe on windows]
+    #[structopt(long)]
+    api_listen: Option<ApiListen>,
+
+    /// look up records and providers on a swarm of our own even when a daemon is running,
+    /// instead of asking the daemon
+    #[structopt(long)]
+    standalone: bool,
+
+    /// record the decrypted bytes of every stream into files in this directory
+    #[structopt(long, parse(from_os_str))]
+    capture: Option<PathBuf>,
+
+    /// only capture protocol negotiation and frame lengths, not the payloads
+    #[structopt(long)]
+    capture_redact: bool,
+
+    /// zip a diagnostic bundle of each peer that sends malformed messages or invalid
+    /// records, into the state directory unless a directory is given
+    #[structopt(long, parse(from_os_str))]
+    bundles: Option<Option<PathBuf>>,
+
+    /// attempts at each dial or query before giving up, overrides the config's
+    #[structopt(long)]
+    retries: Option<u32>,
+
+    /// count allocations and peak memory in each phase of the command, bootstrapping,
+    /// querying, crawling or serving, and show them when it's done
+    #[structopt(long)]
+    profile_mem: bool,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// publish or look up a signed operator attestation
+    Attest(attest::AttestOpt),
+
+    /// bootstrap into the DHT and show the bucket fill and time taken, with --wait
+    /// exiting once the routing table reaches the [bootstrap] goal and failing if it
+    /// doesn't in time
+    Bootstrap(bootstrap::BootstrapOpt),
+
+    /// inspect stream captures
+    Capture(capture::CaptureOpt),
+
+    /// compare crawl censuses
+    Census(census::CensusOpt),
+
+    /// walk the DHT and identify every peer found, writing a census
+    Crawl(crawl::CrawlOpt),
+
+    /// call the running daemon: dial, get, put, providers, closest, peers, identify,
+    /// dns-flush, snapshot, tasks, table or store fsck
+    Ctl(ctl::CtlOpt),
+
+    /// serve with a control API other programs drive the node through, with fleyg ctl or
+    /// HTTP on the control socket
+    Daemon,
+
+    /// dial each of a peer's addresses and report the outcome of each
+    Dial(dial::DialOpt),
+
+    /// show or flush the cache of resolved bootstrap addresses
+    Dns(dns::DnsOpt),
+
+    /// check the config, identity key, ports, DNS, bootstrap peers, NAT and clock, and
+    /// say how to fix what's wrong
+    Doctor(doctor::DoctorOpt),
+
+    /// query the event archive
+    Events(events::EventsOpt),
+
+    /// call the daemons listed in a nodes file and show their health, the peers they saw
+    /// together and a combined census
+    Fleet(fleet::FleetOpt),
+
+    /// look up a peer in the DHT, dial it and show its addresses and what it identifies as
+    FindPeer(findpeer::FindPeerOpt),
+
+    /// get a record's value from the DHT, exiting with 2 if it isn't found and 3 on a
+    /// timeout
+    Get(get::GetOpt),
+
+    /// score how well the DHT is working from here
+    Health(health::HealthOpt),
+
+    /// run a relay, rendezvous point, AutoNAT server and DHT bootstrap node
+    Infra(infra::InfraOpt),
+
+    /// create a config file and identity, and test reachability
+    Init(init::InitOpt),
+
+    /// form a DHT with the machines on the local network, found with mDNS, without any
+    /// bootstrap peers, printing the command other machines join with
+    Lan(lan::LanOpt),
+
+    /// derive this node's identity from a fleet seed phrase
+    Keygen(keygen::KeygenOpt),
+
+    /// copy the records of the config's [mirror] keys onto another network
+    Mirror(mirror::MirrorOpt),
+
+    /// ping a sample of DHT peers and summarize latency by region
+    Map(map::MapOpt),
+
+    /// list the peers in the peer store
+    Peers(peers::PeersOpt),
+
+    /// ping a peer or address a number of times and show the round trip times and loss
+    Ping(pingpeer::PingOpt),
+
+    /// announce that we provide a content key
+    Provide(providers::ProvideOpt),
+
+    /// find the peers providing a content key, or every key in a namespace with scan
+    Providers(providers::ProvidersOpt),
+
+    /// publish a message on a gossipsub topic, with the pubsub feature
+    Pub(pubsub::PubOpt),
+
+    /// store a record on the peers closest to its key, with a quorum
+    Put(put::PutOpt),
+
+    /// inspect routing table snapshots
+    Rt(rt::RtOpt),
+
+    /// run the node as a windows service
+    Service(service::ServiceOpt),
+
+    /// write a json snapshot of the node's state, config and recent errors to attach to
+    /// bug reports
+    Snapshot(snapshot::SnapshotOpt),
+
+    /// keep a server node running for hours while auditing its own resource use, for
+    /// qualifying releases
+    Soak(soak::SoakOpt),
+
+    /// inspect and prune the state directory
+    State(state::StateOpt),
+
+    /// check and maintain the on-disk record store
+    Store(store::StoreOpt),
+
+    /// subscribe to gossipsub topics and show the messages received and who sent them, with
+    /// the pubsub feature
+    Sub(pubsub::SubOpt),
+
+    /// show the last run of each scheduled [[task]] serve runs, or a task's log
+    Tasks(tasks::TasksOpt),
+
+    /// check a publication receipt or a signed query answer
+    Verify(verify::VerifyOpt),
+
+    /// look up a content key's providers periodically and report the ones appearing and
+    /// disappearing
+    WatchProviders(providers::WatchProvidersOpt),
+}
+
+// counts allocations by phase when run with --profile-mem
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static ALLOC: Tracking<tikv_jemallocator::Jemalloc> = Tracking(tikv_jemallocator::Jemalloc);
+
+#[cfg(not(feature = "jemalloc"))]
+#[global_allocator]
+static ALLOC: Tracking<std::alloc::System> = Tracking(std::alloc::System);
+
+/// where nodes listen unless configured otherwise, every IPv4 and IPv6 interface
+const DEFAULT_LISTEN: [&str; 2] = ["/ip4/0.0.0.0/tcp/4920", "/ip6/::/tcp/4920"];
+
+/// how often serve checks the alerting rules
+const ALERT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// serve wakes at least this often to check it isn't stalling
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// how long connections get to close at shutdown
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// how long serve can block or oversleep before it counts as stalled
+const STALL_THRESHOLD: Duration = Duration::from_millis(250);
+
+fn main() -> Result<(), Box<dyn Error>> {
+    runtime::block_on(run())
+}
+
+async fn run() -> Result<(), Box<dyn Error>> {
+    // parse the command line arguments
+    let opt = Opt::from_args();
+
+    // set up logger, json output keeps stdout for the events
+    let level = match opt.output {
+        Format::Text => "info",
+        Format::Json => "warn",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(level))
+        .format(trace::format)
+        .init();
+    if opt.output == Format::Json {
+        event::enable();
+        progress::disable();
+    }
+    if opt.profile_mem {
+        memprofile::enable();
+    }
+
+    if opt.interactive && opt.cmd.is_some() {
+        return Err("--interactive takes its commands on stdin, not on the command line".into());
+    }
+
+    // tag everything a command does with a trace id
+    if opt.cmd.is_some() {
+        let trace = TraceId::new();
+        trace.enter();
+        info!("Trace id: {trace}");
+    }
+
+    // load the profile and its config
+    let profile = opt.profile.as_deref().map(Profile::open).transpose()?;
+    let config_path = match (&opt.config, &profile) {
+        (Some(path), _) => path.clone(),
+        (None, Some(profile)) => {
+            info!(
+                "Using profile {} ({})",
+                profile.name(),
+                profile.dir().display()
+            );
+            profile.config_path()
+        }
+        (None, None) => profile::default_config_path()?,
+    };
+    // the doctor reports a broken config instead of failing on it
+    let config = match Config::load(&config_path) {
+        Err(_) if matches!(opt.cmd, Some(Command::Doctor(_))) => Config::default(),
+        loaded => loaded?,
+    };
+
+    // open the state directory
+    let state = match &opt.data_dir {
+        Some(dir) => StateDir::open(dir)?,
+        None => StateDir::open(StateDir::default_root(opt.profile.as_deref())?)?,
+    };
+
+    // open the peer store, ephemeral nodes forget their peers on exit
+    let peers = if opt.ephemeral {
+        PeerStore::memory()
+    } else {
+        let peer_store = opt
+            .peer_store
+            .or(config.peer_store)
+            .unwrap_or_else(|| state.peer_store_path());
+        PeerStore::open(peer_store)?
+    };
+
+    // how result tables are shown
+    let output = Output {
+        columns: opt.columns,
+        color: table::use_color(opt.no_color),
+        filter: opt.filter,
+        offset: opt.offset,
+        limit: opt.limit,
+        format: opt.output,
+    };
+    let keys = opt.key_encoding.unwrap_or_else(|| opt.encoding.into());
+
+    // commands that don't need a swarm
+    let cmd = match opt.cmd {
+        Some(Command::Capture(capture_opt)) => return capture::run(capture_opt, &output),
+        Some(Command::Census(census_opt)) => return census::run(&state, census_opt, &output),
+        Some(Command::Peers(peers_opt)) => return peers::run(&peers, peers_opt, &output),
+        Some(Command::Dns(dns_opt)) => return dns::run(&state, dns_opt, &output),
+        Some(Command::Doctor(doctor_opt)) => {
+            let overrides = doctor::Overrides {
+                network: opt.network.as_deref(),
+                bootnodes: &opt.bootnodes,
+                listen: &opt.listen,
+                identity: opt.identity.as_deref(),
+            };
+            return doctor::run(doctor_opt, &config_path, &state, &peers, overrides, &output).await;
+        }
+        Some(Command::Events(events_opt)) => return events::run(&state, events_opt, &output),
+        Some(Command::Fleet(fleet_opt)) => return fleet::run(fleet_opt, &output),
+        Some(Command::Rt(rt_opt)) => return rt::run(&state, rt_opt, keys, &output),
+        Some(Command::Service(service_opt)) => {
+            return service::run(&state, &config_path, service_opt)
+        }
+        Some(Command::State(state_opt)) => return state::run(&state, state_opt),
+        Some(Command::Keygen(keygen_opt)) => return keygen::run(&state, keygen_opt),
+        Some(Command::Store(store_opt)) => return store::run(&state, store_opt, keys),
+        Some(Command::Tasks(tasks_opt)) => return tasks::run(&state, tasks_opt, &output),
+        Some(Command::Verify(verify_opt)) => {
+            return verify::run(verify_opt, opt.encoding, keys, &output)
+        }
+        Some(Command::Ctl(ctl_opt)) => {
+            let api = opt.api_listen.unwrap_or_else(|| state.control_api());
+            return ctl::run(&api, ctl_opt, opt.encoding, keys, &output);
+        }
+        Some(Command::Init(init_opt)) => {
+            return init::run(init_opt, &config_path, &state, opt.key_type).await
+        }
+        cmd => cmd,
+    };
+
+    // one-shot lookups go through a running daemon, which is already bootstrapped
+    let delegable = cfg!(feature = "api")
+        && !opt.standalone
+        && match &cmd {
+            Some(Command::Get(get_opt)) => {
+                get_opt.delegable(config.merge.as_deref().unwrap_or_default(), keys)
+            }
+            Some(Command::Providers(providers_opt)) => providers_opt.delegable(),
+            _ => false,
+        };
+    let delegate = delegable
+        && match &opt.api_listen {
+            Some(api) => api.answers(),
+            None => state.daemon_running(),
+        };
+    let cmd = match cmd {
+        Some(Command::Get(get_opt)) if delegate => {
+            let api = opt.api_listen.unwrap_or_else(|| state.control_api());
+            let format = ValueFormat {
+                encoding: opt.encoding,
+                keys,
+                raw_dir: opt
+                    .raw_dir
+                    .map(|dir| RawDir::new(dir, opt.raw_dir_limit.saturating_mul(1 << 20))),
+            };
+            return get::delegate(&api, get_opt, format, &output);
+        }
+        Some(Command::Providers(providers_opt)) if delegate => {
+            let api = opt.api_listen.unwrap_or_else(|| state.control_api());
+            return providers::delegate(&api, providers_opt, keys, &output);
+        }
+        cmd => cmd,
+    };
+
+    // load our identity, or make up one we won't keep
+    let local_key = match &opt.identity {
+        _ if opt.ephemeral => opt.key_type.unwrap_or_default().generate()?,
+        Some(path) => load_keypair(path, opt.key_type)?,
+        None => state.keypair(opt.key_type)?,
+    };
+    info!("Identity key type: {}", KeyType::of(&local_key));
+    let lan = match &cmd {
+        Some(Command::Lan(lan_opt)) => Some(lan_opt.clone()),
+        _ => None,
+    };
+
+    // build the swarm
+    let mut network = match (&lan, opt.network.as_ref().or(config.network.as_ref())) {
+        (Some(lan), _) => Network::lan(lan.name())?,
+        (None, Some(name)) => Network::preset(name)?,
+        (None, None) => Network::default(),
+    };
+    // a LAN DHT never takes public bootstrap peers, only the ones given to join it
+    if let (Some(path), None) = (&config.chainspec, &lan) {
+        let spec = ChainSpec::load(path)?;
+        network = network.with_chainspec(&spec, config.genesis_hash.as_deref())?;
+    }
+    if let (Some(bootnodes), None) = (&config.bootnodes, &lan) {
+        network.bootnodes = bootnodes.clone();
+    }
+    if !opt.bootnodes.is_empty() {
+        network.bootnodes = opt.bootnodes.clone();
+    }
+    if let Some(noise) = &config.noise {
+        network.noise = noise.clone();
+    }
+    if let Some(transports) = &config.transport {
+        network.transports = transports.clone();
+    }
+    if !opt.transport.is_empty() {
+        network.transports.kinds = opt.transport.clone();
+    }
+    // resolve the bootstrap domains through the cache, stale entries are used while
+    // they're resolved again in the background
+    let mut dns = match opt.ephemeral {
+        true => DnsCache::memory(),
+        false => DnsCache::open(state.dns_cache_path())?,
+    };
+    let names: Vec<Multiaddr> = network
+        .bootnodes
+        .iter()
+        .filter(|addr| dnscache::is_dns(addr))
+        .cloned()
+        .collect();
+    let (bootnodes, stale) = dnscache::resolve(&mut dns, &network.bootnodes).await;
+    network.bootnodes = bootnodes;
+    dns.save()?;
+    if !stale.is_empty() && !opt.ephemeral {
+        let path = state.dns_cache_path();
+        runtime::spawn(async move {
+            if let Err(e) = dnscache::refresh(path, stale).await {
+                debug!("Refreshing the DNS cache failed: {e}");
+            }
+        });
+    }
+    let agent = match &cmd {
+        Some(Command::Crawl(crawl_opt)) => crawl_opt.agent(),
+        _ => None,
+    };
+    let capture = match &opt.capture {
+        Some(dir) => Some(Arc::new(Capture::new(dir, opt.capture_redact)?)),
+        None => None,
+    };
+    // listen on all interfaces unless told otherwise
+    let listen = match (opt.listen, config.listen.clone()) {
+        (flags, _) if !flags.is_empty() => flags,
+        (_, Some(listen)) => listen,
+        (_, None) => default_listen(),
+    };
+
+    // infrastructure nodes run their own set of behaviors
+    let cmd = match cmd {
+        Some(Command::Infra(_)) if opt.ephemeral => {
+            return Err("infrastructure nodes can't be --ephemeral".into());
+        }
+        Some(Command::Lan(_)) if opt.ephemeral => {
+            return Err("LAN nodes listen for the other machines and can't be --ephemeral".into());
+        }
+        Some(Command::Soak(_)) if opt.ephemeral => {
+            return Err("soak tests run a full server node and can't be --ephemeral".into());
+        }
+        Some(Command::Infra(infra_opt)) => {
+            let settings = config.infra.clone().unwrap_or_default();
+            let queries = config.queries.clone().unwrap_or_default();
+            return infra::run(
+                local_key, &network, infra_opt, settings, listen, capture, &queries,
+            )
+            .await;
+        }
+        cmd => cmd,
+    };
+    let queries = config.queries.clone().unwrap_or_default();
+    let dials = config.dials.clone().unwrap_or_default();
+    let pex = config.pex.clone().unwrap_or_default();
+    let routing = config.routing.clone().unwrap_or_default();
+    let mut relay_server = config.relay_server.clone().unwrap_or_default();
+    relay_server.enabled |= opt.relay_server;
+    let mut store = config.store.clone().unwrap_or_default();
+    if let Some(kind) = opt.store {
+        store.kind = kind;
+    }
+    if let Some(path) = &opt.store_path {
+        store.path = Some(path.clone());
+    }
+    if opt.ephemeral {
+        store.kind = StoreKind::Memory;
+    }
+    store.path.get_or_insert_with(|| state.records_dir());
+    // a command's own agent beats the config's mask
+    let mut identify = config.identify.clone().unwrap_or_default();
+    if agent.is_some() {
+        identify.agent = agent;
+    }
+    // counting starts with the swarm, gossipsub counts into the metrics as it's built
+    let mut metrics = opt.metrics_addr.as_ref().map(|_| NodeMetrics::new());
+    let mut swarm = FleygNode::new(local_key.clone())
+        .network(network.clone())
+        .capture(capture)
+        .queries(queries.clone())
+        .identify_settings(identify.clone())
+        .dial_settings(dials.clone())
+        .store_settings(store)
+        .pex_settings(pex.clone())
+        .routing_policy(routing.clone())
+        .ping_interval(match &cmd {
+            Some(Command::Ping(ping_opt)) => Some(ping_opt.interval()),
+            _ => None,
+        })
+        .gossipsub(matches!(cmd, Some(Command::Pub(_)) | Some(Command::Sub(_))))
+        .blocks(
+            !opt.interactive
+                && matches!(
+                    cmd,
+                    None | Some(Command::Lan(_)) | Some(Command::Daemon) | Some(Command::Get(_))
+                ),
+        )
+        .mdns(lan.is_some() || opt.mdns || config.mdns.unwrap_or(false))
+        .autonat(
+            matches!(cmd, None | Some(Command::Daemon))
+                && !opt.interactive
+                && !opt.ephemeral
+                && !opt.kad_server,
+        )
+        .relay_server(relay_server)
+        .build_with_metrics(metrics.as_mut())
+        .await?;
+    if opt.ephemeral {
+        swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Client));
+    }
+    // every command's queries share one budget
+    let budget = QueryBudget::new(queries.max_concurrent);
+
+    let result = match cmd {
+        Some(Command::Attest(attest_opt)) => {
+            let retry = retry_policy(&config, opt.retries, "attest")?;
+            attest::run(swarm, attest_opt, &local_key, retry, &output).await
+        }
+        Some(Command::Bootstrap(bootstrap_opt)) => {
+            let goal = config.bootstrap.clone().unwrap_or_default();
+            bootstrap::run(swarm, bootstrap_opt, goal, &network, &output).await
+        }
+        Some(Command::Crawl(crawl_opt)) => crawl::run(swarm, crawl_opt, &state, budget).await,
+        Some(Command::Dial(dial_opt)) => {
+            let retry = retry_policy(&config, opt.retries, "dial")?;
+            dial::run(swarm, dial_opt, peers, retry, &dials, &output).await
+        }
+        Some(Command::FindPeer(find_opt)) => {
+            findpeer::run(swarm, find_opt, peers, &dials, &output).await
+        }
+        Some(Command::Get(get_opt)) => {
+            let retry = retry_policy(&config, opt.retries, "get")?;
+            let format = ValueFormat {
+                encoding: opt.encoding,
+                keys,
+                raw_dir: opt
+                    .raw_dir
+                    .map(|dir| RawDir::new(dir, opt.raw_dir_limit.saturating_mul(1 << 20))),
+            };
+            let delegated = config.delegated.clone().unwrap_or_default();
+            let merges = config.merge.clone().unwrap_or_default();
+            get::run(swarm, get_opt, retry, &delegated, &merges, format, &output).await
+        }
+        Some(Command::Health(health_opt)) => {
+            let thresholds = config.health.clone().unwrap_or_default();
+            health::run(swarm, health_opt, thresholds, budget, keys, &output).await
+        }
+        Some(Command::Map(map_opt)) => map::run(swarm, map_opt, &peers, &output).await,
+        Some(Command::Mirror(mirror_opt)) => {
+            let settings = config.mirror.clone().unwrap_or_default();
+            let mut target_network = Network::preset(&settings.to)?;
+            if let Some(bootnodes) = &settings.bootnodes {
+                target_network.bootnodes = bootnodes.clone();
+            }
+            let (bootnodes, _) = dnscache::resolve(&mut dns, &target_network.bootnodes).await;
+            target_network.bootnodes = bootnodes;
+            dns.save()?;
+            target_network.transports = network.transports.clone();
+            let mut target = FleygNode::new(local_key.clone())
+                .network(target_network)
+                .queries(queries.clone())
+                .identify_settings(identify)
+                .dial_settings(dials.clone())
+                .build()
+                .await?;
+            if opt.ephemeral {
+                target.behaviour_mut().kademlia.set_mode(Some(Mode::Client));
+            }
+            let format = ValueFormat {
+                encoding: opt.encoding,
+                keys,
+                raw_dir: None,
+            };
+            mirror::run(swarm, target, mirror_opt, settings, &format).await
+        }
+        Some(Command::Provide(provide_opt)) => providers::provide(swarm, provide_opt, keys).await,
+        Some(Command::Providers(providers_opt)) => {
+            providers::providers(swarm, providers_opt, &peers, keys, &output).await
+        }
+        Some(Command::Ping(ping_opt)) => pingpeer::run(swarm, ping_opt, peers, &output).await,
+        Some(Command::Pub(pub_opt)) => pubsub::publish(swarm, pub_opt).await,
+        Some(Command::Put(put_opt)) => {
+            let retry = retry_policy(&config, opt.retries, "put")?;
+            put::run(swarm, put_opt, &local_key, &state, retry, keys, &output).await
+        }
+        Some(Command::Snapshot(snapshot_opt)) => {
+            snapshot::run(swarm, snapshot_opt, &local_key, &config).await
+        }
+        Some(Command::Soak(soak_opt)) => soak::run(swarm, soak_opt, listen, &state, &output).await,
+        Some(Command::Sub(sub_opt)) => {
+            if let (Some(listen), Some(metrics)) = (&opt.metrics_addr, &metrics) {
+                metrics.serve(listen)?;
+                info!("Serving gossipsub metrics on {listen}");
+            }
+            pubsub::sub(swarm, sub_opt, opt.encoding, &output).await
+        }
+        Some(Command::WatchProviders(watch_opt)) => {
+            providers::watch_providers(swarm, watch_opt, keys, &output).await
+        }
+        Some(Command::Capture(_))
+        | Some(Command::Census(_))
+        | Some(Command::Ctl(_))
+        | Some(Command::Dns(_))
+        | Some(Command::Doctor(_))
+        | Some(Command::Events(_))
+        | Some(Command::Fleet(_))
+        | Some(Command::Infra(_))
+        | Some(Command::Init(_))
+        | Some(Command::Keygen(_))
+        | Some(Command::Peers(_))
+        | Some(Command::Rt(_))
+        | Some(Command::Service(_))
+        | Some(Command::State(_))
+        | Some(Command::Store(_))
+        | Some(Command::Tasks(_))
+        | Some(Command::Verify(_)) => unreachable!(),
+        None if opt.interactive => repl::run(swarm, peers, opt.encoding, keys, &output).await,
+        None | Some(Command::Lan(_)) | Some(Command::Daemon) => {
+            // the daemon takes calls as soon as it's up
+            let control = match &cmd {
+                Some(Command::Daemon) if cfg!(feature = "api") => {
+                    let api = opt
+                        .api_listen
+                        .clone()
+                        .unwrap_or_else(|| state.control_api());
+                    let key = local_key.clone();
+                    Some(daemon::Control::listen(
+                        &api,
+                        key,
+                        config.clone(),
+                        names,
+                        &state,
+                    )?)
+                }
+                Some(Command::Daemon) => {
+                    warn!("fleyg was built without the api feature, the daemon takes no calls");
+                    None
+                }
+                _ => None,
+            };
+            let bootnodes = if lan.is_some() || opt.dial || config.dial.unwrap_or(false) {
+                network
+                    .bootnode_peers()
+                    .into_iter()
+                    .map(|(p, _)| p)
+                    .collect()
+            } else {
+                Vec::new()
+            };
+            let relays = match opt.relays.is_empty() {
+                true => config.relays.clone().unwrap_or_default(),
+                false => opt.relays.clone(),
+            };
+            let dialing = Dialing {
+                bootnodes,
+                settings: dials.clone(),
+                pex,
+                routing,
+                relays,
+                lan: lan.map(|lan| lan::Announcer::new(lan, *swarm.local_peer_id())),
+            };
+            let recorder = match opt.record_sessions {
+                Some(dir) => Some(dir.unwrap_or_else(|| state.sessions_dir())),
+                None if opt.ephemeral => None,
+                None => config.record_sessions,
+            }
+            .map(SessionRecorder::new);
+            if recorder.is_some() && !cfg!(feature = "sessions") {
+                return Err(
+                    "recording sessions needs fleyg built with the sessions feature".into(),
+                );
+            }
+            let interval = opt
+                .rt_snapshot_interval
+                .or(config.rt_snapshot_interval)
+                .unwrap_or(600);
+            let rt_dir = match opt.rt_snapshots {
+                Some(dir) => Some(dir.unwrap_or_else(|| state.rt_dir())),
+                None if opt.ephemeral => None,
+                None => config.rt_snapshots,
+            };
+            let snapshots = match rt_dir {
+                Some(dir) => Some((SnapshotDir::open(dir)?, Duration::from_secs(interval))),
+                None => None,
+            };
+            let bundles = match opt.bundles {
+                Some(dir) => Some(Bundler::new(
+                    dir.unwrap_or_else(|| state.bundles_dir()),
+                    opt.capture,
+                )?),
+                None => None,
+            };
+            let archive = match opt.archive_events {
+                Some(dir) => Some(dir.unwrap_or_else(|| state.events_dir())),
+                None if opt.ephemeral => None,
+                None => config.archive_events,
+            };
+            let archive = match archive {
+                Some(dir) => Some(EventArchive::open(dir)?),
+                None => None,
+            };
+            let metrics = match (&opt.metrics_addr, metrics) {
+                (Some(listen), Some(metrics)) => {
+                    metrics.serve(listen)?;
+                    info!("Serving metrics on {listen}");
+                    Some(metrics)
+                }
+                _ => None,
+            };
+            let tasks = match opt.ephemeral {
+                true => None,
+                false => tasks::TaskRunner::new(
+                    &config.tasks.clone().unwrap_or_default(),
+                    &config_path,
+                    &state,
+                )?,
+            };
+            let blocks = match opt.ephemeral {
+                true => None,
+                false => Some(BlockStore::open(state.blocks_dir())?),
+            };
+            let recording = Recording {
+                sessions: recorder,
+                snapshots,
+                bundles,
+                archive,
+                metrics,
+                tasks,
+                blocks,
+                control,
+            };
+            let format = ValueFormat {
+                encoding: opt.encoding,
+                keys,
+                raw_dir: opt
+                    .raw_dir
+                    .map(|dir| RawDir::new(dir, opt.raw_dir_limit.saturating_mul(1 << 20))),
+            };
+            if !opt.ephemeral {
+                // with AutoNAT we only query until it finds us reachable
+                let mode = match swarm.behaviour().autonat.is_enabled() {
+                    true => Mode::Client,
+                    false => Mode::Server,
+                };
+                swarm.behaviour_mut().kademlia.set_mode(Some(mode));
+                listen_on(&mut swarm, listen)?;
+            }
+            let alerts = Alerts::new(config.alerts.unwrap_or_default())?;
+            let goal = config.bootstrap.unwrap_or_default();
+            serve(swarm, dialing, peers, recording, format, alerts, goal).await
+        }
+    };
+    if opt.profile_mem {
+        output.print(memory_table(&memprofile::report()))?;
+    }
+    result
+}
+
+/// the allocations of each phase, with the size of allocation most bytes went to
+fn memory_table(phases: &[PhaseReport]) -> Table {
+    let mib = |b: u64| format!("{:.1}MiB", b as f64 / (1024.0 * 1024.0));
+    let mut table = Table::new(&[
+        "phase",
+        "allocs",
+        "allocated",
+        "peak live",
+        "peak rss",
+        "hotspot",
+    ]);
+    for phase in phases {
+        let hotspot = match phase.hotspot() {
+            Some((Some(max), share)) => format!("<= {max}B ({:.0}%)", share * 100.0),
+            Some((None, share)) => format!("> 1MiB ({:.0}%)", share * 100.0),
+            None => String::new(),
+        };
+        table.push(vec![
+            phase.name.into(),
+            phase.allocations.to_string().into(),
+            mib(phase.bytes).into(),
+            mib(phase.peak_live_bytes).into(),
+            phase.peak_rss_bytes.map(mib).unwrap_or_default().into(),
+            hotspot.into(),
+        ]);
+    }
+    table
+}
+
+/// the addresses nodes listen on unless configured otherwise
+fn default_listen() -> Vec<Multiaddr> {
+    DEFAULT_LISTEN
+        .iter()
+        .map(|a| a.parse().expect("valid listen address"))
+        .collect()
+}
+
+/// listen on each of the addresses, warning about the ones we can't use, e.g. IPv6 on a
+/// host without it, and failing if we can't listen at all
+fn listen_on<B: NetworkBehaviour>(
+    swarm: &mut Swarm<B>,
+    listen: Vec<Multiaddr>,
+) -> Result<(), Box<dyn Error>> {
+    let mut listening = false;
+    for addr in listen {
+        match swarm.listen_on(addr.clone()) {
+            Ok(_) => listening = true,
+            Err(e) => warn!("Can't listen on {addr}: {e}"),
+        }
+    }
+    if !listening {
+        return Err("can't listen on any of the listen addresses".into());
+    }
+    Ok(())
+}
+
+/// the retry policy for a command, from the config and the --retries flag
+fn retry_policy(
+    config: &Config,
+    retries: Option<u32>,
+    command: &str,
+) -> Result<RetryPolicy, Box<dyn Error>> {
+    let mut policy = config.retry.clone().unwrap_or_default().policy(command)?;
+    if let Some(n) = retries {
+        policy.max_attempts = n.max(1);
+    }
+    Ok(policy)
+}
+
+/// run a kademlia bootstrap until it finishes or the deadline passes
+async fn bootstrap(
+    swarm: &mut Swarm<FleygBehavior>,
+    deadline: Instant,
+) -> Result<RoutingSnapshot, Box<dyn Error>> {
+    let _phase = memprofile::phase("bootstrap");
+    wait_for_lan_peers(swarm, deadline).await;
+    let query = swarm.behaviour_mut().kademlia.bootstrap()?;
+    info!("Bootstrapping...");
+    debug!("Bootstrap query {query:?}");
+    let progress = Progress::bar(0, "bootstrapping");
+    let mut connections = QueryConnections::default();
+    connections.started(query);
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::ConnectionEstablished { endpoint, .. } = &event {
+            if endpoint.is_dialer() {
+                connections.opened();
+            }
+        }
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Mdns(event)) = event {
+            add_lan_peers(swarm, event);
+            continue;
+        }
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                result: QueryResult::Bootstrap(result),
+                step,
+                ..
+            },
+        )) = event
+        {
+            // each step is a query against one bucket
+            let remaining = match result {
+                Ok(BootstrapOk { num_remaining, .. }) => num_remaining,
+                Err(BootstrapError::Timeout { num_remaining, .. }) => {
+                    num_remaining.unwrap_or_default()
+                }
+            };
+            let done = step.count().get() as u64;
+            progress.set_length(done + remaining as u64);
+            progress.set_position(done);
+            let known: usize = swarm
+                .behaviour_mut()
+                .kademlia
+                .kbuckets()
+                .map(|bucket| bucket.num_entries())
+                .sum();
+            progress.set_message(format!("{known} peers in routing table"));
+            if step.last() {
+                break;
+            }
+        }
+    }
+    progress.finish();
+    if let Some(opened) = connections.finished(&query) {
+        info!("Bootstrap opened {opened} connections");
+    }
+    let rt = RoutingSnapshot::capture(&mut swarm.behaviour_mut().kademlia);
+    info!(
+        "Routing table has {} peers in {} buckets",
+        rt.len(),
+        rt.buckets.len()
+    );
+    Ok(rt)
+}
+
+/// with mDNS and nothing in the routing table, wait for a peer on the local network to
+/// bootstrap from
+async fn wait_for_lan_peers(swarm: &mut Swarm<FleygBehavior>, deadline: Instant) {
+    if !swarm.behaviour().mdns.is_enabled()
+        || swarm.behaviour_mut().kademlia.kbuckets().next().is_some()
+    {
+        return;
+    }
+    info!("Looking for peers on the local network...");
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Mdns(event)) = event {
+            if !add_lan_peers(swarm, event).is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+/// add the peers mDNS found to the routing table, returning the ones discovered
+fn add_lan_peers(swarm: &mut Swarm<FleygBehavior>, event: mdns::Event) -> Vec<(PeerId, Multiaddr)> {
+    match event {
+        mdns::Event::Discovered(found) => {
+            for (peer, addr) in &found {
+                debug!("mDNS discovered {peer} on {addr}");
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(peer, addr.clone());
+            }
+            found
+        }
+        mdns::Event::Expired(gone) => {
+            for (peer, addr) in gone {
+                debug!("mDNS record for {peer} on {addr} expired");
+            }
+            Vec::new()
+        }
+    }
+}
+
+/// store the record on each of the peers closest to its key one at a time, so we learn
+/// which of them acknowledged it, for a publication receipt, failing if fewer than the
+/// quorum did
+async fn put_acked(
+    swarm: &mut Swarm<FleygBehavior>,
+    record: Record,
+    quorum: Quorum,
+    deadline: Instant,
+) -> Result<Vec<Ack>, (Failure, String)> {
+    let _phase = memprofile::phase("query");
+    let query = swarm
+        .behaviour_mut()
+        .kademlia
+        .get_closest_peers(record.key.to_vec());
+    let progress = Progress::spinner("finding the closest peers");
+    let mut closest = None;
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetClosestPeers(result),
+                ..
+            },
+        )) = event
+        {
+            if id == query {
+                closest = Some(match result {
+                    Ok(ok) => ok.peers,
+                    Err(GetClosestPeersError::Timeout { peers, .. }) => peers,
+                });
+                break;
+            }
+        }
+    }
+    let Some(closest) = closest.filter(|peers| !peers.is_empty()) else {
+        progress.finish();
+        return Err((Failure::Timeout, "no closest peers found".to_string()));
+    };
+
+    let required = receipt::required_acks(quorum, closest.len());
+    let mut pending: HashMap<QueryId, PeerId> = closest
+        .into_iter()
+        .map(|peer| {
+            let id = swarm.behaviour_mut().kademlia.put_record_to(
+                record.clone(),
+                std::iter::once(peer),
+                Quorum::One,
+            );
+            (id, peer)
+        })
+        .collect();
+    progress.set_message(format!("storing on {} peers", pending.len()));
+    let mut acks = Vec::new();
+    while !pending.is_empty() {
+        let Some(event) = next_before(swarm, deadline).await else {
+            break;
+        };
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::PutRecord(result),
+                ..
+            },
+        )) = event
+        {
+            let Some(peer) = pending.remove(&id) else {
+                continue;
+            };
+            match result {
+                Ok(_) => acks.push(Ack {
+                    peer,
+                    at: now_secs(),
+                }),
+                Err(e) => debug!("{peer} didn't store the record: {e}"),
+            }
+        }
+    }
+    progress.finish();
+    for id in pending.keys() {
+        if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(id) {
+            query.finish();
+        }
+    }
+    if acks.len() < required {
+        for ack in &acks {
+            info!("{} acknowledged the record", ack.peer);
+        }
+        return Err((
+            Failure::Quorum,
+            format!(
+                "{} peers acknowledged the record, {required} needed",
+                acks.len()
+            ),
+        ));
+    }
+    Ok(acks)
+}
+
+/// start as many queued dials as the queue allows
+fn start_dials(
+    swarm: &mut Swarm<FleygBehavior>,
+    queue: &mut DialQueue,
+    settings: &DialSettings,
+    peers: &PeerStore,
+) {
+    while let Some(request) = queue.next_ready(Instant::now()) {
+        // race the best of the addresses we know, the swarm asks kademlia if we know none
+        let mut addrs = request.addrs;
+        for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+            if let Some(entry) = bucket
+                .iter()
+                .find(|e| *e.node.key.preimage() == request.peer)
+            {
+                addrs.extend(entry.node.value.iter().cloned());
+            }
+        }
+        let confidence = |addr: &Multiaddr| peers.confidence(&request.peer, addr);
+        let opts = match addrs.is_empty() {
+            true => DialOpts::peer_id(request.peer).build(),
+            false => DialOpts::peer_id(request.peer)
+                .addresses(settings.rank_by(addrs, confidence))
+                .build(),
+        };
+        match swarm.dial(opts) {
+            Ok(()) => info!("Dialed via peer id {}", request.peer),
+            Err(e) => {
+                warn!("Failed to dial {}: {e}", request.peer);
+                queue.failed(&request.peer, Instant::now());
+            }
+        }
+    }
+}
+
+/// Who serve dials on start up and how, whether it asks peers for more, which peers it
+/// routes to and the relays it listens through behind a NAT
+struct Dialing {
+    bootnodes: Vec<PeerId>,
+    settings: DialSettings,
+    pex: PexSettings,
+    routing: RoutingPolicy,
+    relays: Vec<Multiaddr>,
+    /// prints how other machines join, in fleyg lan
+    lan: Option<lan::Announcer>,
+}
+
+/// listen through each relay we don't hold a reservation with yet, the relay client asks
+/// for one when the listener starts
+fn reserve(
+    swarm: &mut Swarm<FleygBehavior>,
+    relays: &[Multiaddr],
+    reservations: &mut HashMap<Multiaddr, ListenerId>,
+) {
+    for relay in relays {
+        if reservations.contains_key(relay) {
+            continue;
+        }
+        let circuit = relay.clone().with(Protocol::P2pCircuit);
+        match swarm.listen_on(circuit) {
+            Ok(id) => {
+                info!("Reserving a slot on relay {relay}");
+                reservations.insert(relay.clone(), id);
+            }
+            Err(e) => warn!("Can't listen through relay {relay}: {e}"),
+        }
+    }
+}
+
+/// stop listening through relays once we're reachable without them
+fn release(swarm: &mut Swarm<FleygBehavior>, reservations: &mut HashMap<Multiaddr, ListenerId>) {
+    for (relay, id) in reservations.drain() {
+        info!("Releasing our slot on relay {relay}");
+        swarm.remove_listener(id);
+    }
+}
+
+/// answer a peer exchange request or queue dials to the peers an answer shared
+fn exchange_peers(
+    swarm: &mut Swarm<FleygBehavior>,
+    event: request_response::Event<PexRequest, pex::PexResponse>,
+    peers: &PeerStore,
+    negotiation: &mut NegotiationCache,
+    queue: &mut DialQueue,
+    settings: &PexSettings,
+) {
+    use request_response::{Event, Message, OutboundFailure};
+    match event {
+        Event::Message {
+            peer,
+            message: Message::Request {
+                request, channel, ..
+            },
+        } => {
+            let response = pex::sample(peers, request.max.min(settings.share), &peer);
+            debug!("Sharing {} peers with {peer}", response.peers.len());
+            if let Some(pex) = swarm.behaviour_mut().pex.as_mut() {
+                let _ = pex.send_response(channel, response);
+            }
+        }
+        Event::Message {
+            peer,
+            message: Message::Response { response, .. },
+        } => {
+            let local = *swarm.local_peer_id();
+            let mut learned = 0;
+            for shared in response.peers.into_iter().take(settings.want) {
+                if shared.peer == local
+                    || swarm.is_connected(&shared.peer)
+                    || peers.get(&shared.peer).is_some()
+                {
+                    continue;
+                }
+                queue.push(shared.peer, shared.addrs, Priority::Background);
+                learned += 1;
+            }
+            info!("Learned {learned} new peers from {peer}");
+        }
+        Event::OutboundFailure { peer, error, .. } => {
+            // its identify listed the protocol, ask it again once it identifies again
+            if let OutboundFailure::UnsupportedProtocols = error {
+                negotiation.rejected(&peer, pex::PROTOCOL.as_ref());
+            }
+            debug!("Peer exchange with {peer} failed: {error}")
+        }
+        Event::InboundFailure { peer, error, .. } => {
+            debug!("Peer exchange request from {peer} failed: {error}")
+        }
+        Event::ResponseSent { .. } => {}
+    }
+}
+
+/// answer a peer asking for a block with it, if we have it
+fn send_blocks(
+    swarm: &mut Swarm<FleygBehavior>,
+    event: request_response::Event<BlockId, Option<Vec<u8>>>,
+    store: Option<&BlockStore>,
+) {
+    use request_response::{Event, Message};
+    match event {
+        Event::Message {
+            peer,
+            message: Message::Request {
+                request, channel, ..
+            },
+        } => {
+            let block = match store.map(|store| store.get(&request)) {
+                Some(Ok(block)) => block,
+                Some(Err(e)) => {
+                    warn!("Can't read block {}: {e}", hex::encode(request));
+                    None
+                }
+                None => None,
+            };
+            debug!(
+                "{} block {} for {peer}",
+                if block.is_some() { "Sending" } else { "No" },
+                hex::encode(request)
+            );
+            if let Some(blocks) = swarm.behaviour_mut().blocks.as_mut() {
+                let _ = blocks.send_response(channel, block);
+            }
+        }
+        Event::InboundFailure { peer, error, .. } => {
+            debug!("Block request from {peer} failed: {error}")
+        }
+        event => debug!("Blocks: {event:?}"),
+    }
+}
+
+/// provide the roots of the values we published so peers find us to fetch them
+fn provide_roots(swarm: &mut Swarm<FleygBehavior>, blocks: &BlockStore) {
+    let roots = match blocks.roots() {
+        Ok(roots) => roots,
+        Err(e) => {
+            warn!("Can't list the published values' blocks: {e}");
+            return;
+        }
+    };
+    for root in &roots {
+        if let Err(e) = swarm
+            .behaviour_mut()
+            .kademlia
+            .start_providing(Key::new(root))
+        {
+            warn!("Can't provide block {}: {e}", hex::encode(root));
+        }
+    }
+    if !roots.is_empty() {
+        info!("Providing the blocks of {} published values", roots.len());
+    }
+}
+
+/// What serve keeps a record of, on disk or for scraping, the scheduled tasks it runs
+/// with their logs and the blocks of the values it published
+struct Recording {
+    sessions: Option<SessionRecorder>,
+    /// where routing table snapshots go and how often
+    snapshots: Option<(SnapshotDir, Duration)>,
+    bundles: Option<Bundler>,
+    archive: Option<EventArchive>,
+    metrics: Option<NodeMetrics>,
+    tasks: Option<tasks::TaskRunner>,
+    blocks: Option<BlockStore>,
+    /// the control API, when running as the daemon
+    control: Option<daemon::Control>,
+}
+
+impl Recording {
+    /// archive and count an event the node handled
+    fn handled<E: fmt::Debug>(&mut self, event: &SwarmEvent<FleygBehaviorEvent, E>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record(event);
+        }
+        let Some(archive) = self.archive.as_mut() else {
+            return;
+        };
+        if let Err(e) = archive.append(ArchivedEvent::from_swarm(event)) {
+            warn!("Can't archive events in {}: {e}", archive.dir().display());
+        }
+    }
+
+    /// write out the archived events still buffered
+    fn flush_archive(&mut self) {
+        if let Some(archive) = self.archive.as_mut() {
+            if let Err(e) = archive.flush() {
+                warn!("Can't archive events in {}: {e}", archive.dir().display());
+            }
+        }
+    }
+
+    /// note an event for the peer's bundle
+    fn note<F: FnOnce() -> String>(&mut self, peer: PeerId, event: F) {
+        if let Some(bundles) = self.bundles.as_mut() {
+            bundles.record(peer, event());
+        }
+    }
+
+    /// bundle up a misbehaving peer
+    fn misbehaved(&mut self, peer: PeerId, misbehavior: Misbehavior, peers: &PeerStore) {
+        let Some(bundles) = self.bundles.as_mut() else {
+            return;
+        };
+        bundles.record(peer, misbehavior.to_string());
+        match bundles.bundle(&peer, &misbehavior, peers.get(&peer)) {
+            Ok(Some(path)) => warn!(
+                "{peer} misbehaved ({misbehavior}), bundled into {}",
+                path.display()
+            ),
+            Ok(None) => debug!("{peer} misbehaved again: {misbehavior}"),
+            Err(e) => warn!("Can't bundle {peer}: {e}"),
+        }
+    }
+}
+
+/// what the node has in flight, for explaining a stall
+fn pending_work(swarm: &Swarm<FleygBehavior>, queue: &DialQueue) -> String {
+    let queries = swarm.behaviour().kademlia.iter_queries().count();
+    format!(
+        "{} dials queued, {} in flight, {} peers connected, {queries} kademlia queries running",
+        queue.len(),
+        queue.in_flight(),
+        swarm.network_info().num_peers()
+    )
+}
+
+/// what kind of event woke serve
+fn event_kind<E>(event: &SwarmEvent<FleygBehaviorEvent, E>) -> &'static str {
+    match event {
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(_)) => "an identify event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(_)) => "a kademlia event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(_)) => "a ping",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayClient(_)) => "a relay client event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::RelayServer(_)) => "a relay server event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Pex(_)) => "a peer exchange",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Blocks(_)) => "a block exchange",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(_)) => "a gossipsub event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Mdns(_)) => "an mDNS event",
+        SwarmEvent::Behaviour(FleygBehaviorEvent::Autonat(_)) => "an AutoNAT probe",
+        SwarmEvent::ConnectionEstablished { .. } => "a new connection",
+        SwarmEvent::ConnectionClosed { .. } => "a closed connection",
+        SwarmEvent::OutgoingConnectionError { .. } => "a failed dial",
+        _ => "a swarm event",
+    }
+}
+
+/// stop listening and hang up on every peer, giving the connections a while to close
+async fn close(swarm: &mut Swarm<FleygBehavior>, listeners: &HashSet<ListenerId>) {
+    for id in listeners {
+        swarm.remove_listener(*id);
+    }
+    let connected: Vec<PeerId> = swarm.connected_peers().copied().collect();
+    for peer in connected {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    let deadline = Instant::now() + CLOSE_TIMEOUT;
+    while swarm.network_info().num_peers() > 0 {
+        if next_before(swarm, deadline).await.is_none() {
+            warn!(
+                "Gave up on {} connections still closing",
+                swarm.network_info().connection_counters().num_connections()
+            );
+            break;
+        }
+    }
+}
+
+async fn serve(
+    mut swarm: Swarm<FleygBehavior>,
+    mut dialing: Dialing,
+    mut peers: PeerStore,
+    mut recording: Recording,
+    format: ValueFormat,
+    mut alerts: Alerts,
+    goal: BootstrapGoal,
+) -> Result<(), Box<dyn Error>> {
+    let _phase = memprofile::phase("serve");
+    let started = Instant::now();
+    let shutdown = Shutdown::catch();
+
+    // bootstrap into the DHT
+    //swarm.behaviour_mut().kademlia.bootstrap()?;
+
+    let mut queue = DialQueue::default();
+    for pid in &dialing.bootnodes {
+        queue.push(*pid, Vec::new(), Priority::Bootstrap);
+    }
+    let bootnodes: HashSet<PeerId> = dialing.bootnodes.iter().copied().collect();
+    let mut failed_bootnodes = HashSet::new();
+    // the peers asked for a peer exchange
+    let mut asked = HashSet::new();
+
+    // the addresses we dialed each peer on, for checking against identify
+    let mut dialed = HashMap::new();
+    let mut divergence = DivergenceTracker::default();
+    // the protocols of the peers we've seen, so we don't open streams they'd reject
+    let mut negotiation = NegotiationCache::seed(&peers);
+    let mut routing = RoutingDecisions::new(dialing.routing.clone());
+    let mut sessions = SessionTracker::default();
+    let mut next_snapshot = recording
+        .snapshots
+        .as_ref()
+        .map(|(_, every)| Instant::now() + *every);
+    let mut status = Status::default();
+    let mut readiness = Readiness::new(goal);
+    let mut next_check = Instant::now() + ALERT_INTERVAL;
+    let mut exit = None;
+    let mut heartbeat = Heartbeat::new(HEARTBEAT_INTERVAL, STALL_THRESHOLD);
+    let mut handling = "nothing";
+    // our listeners, closed at shutdown, and how many connections we've had
+    let mut listeners = HashSet::new();
+    let mut connections = 0;
+    // the relays we listen through, without AutoNAT to tell us we're behind a NAT we
+    // always do
+    let mut reservations = HashMap::new();
+    if !swarm.behaviour().autonat.is_enabled() {
+        reserve(&mut swarm, &dialing.relays, &mut reservations);
+    }
+
+    loop {
+        if shutdown.requested() {
+            break;
+        }
+        if let Some(busy) = heartbeat.handled(Instant::now()) {
+            warn!(
+                "Event loop stalled for {busy:?} handling {handling}: {}",
+                pending_work(&swarm, &queue)
+            );
+            if let Some(metrics) = &recording.metrics {
+                metrics.stalled();
+            }
+        }
+        start_dials(&mut swarm, &mut queue, &dialing.settings, &peers);
+        let wake = [
+            next_snapshot,
+            recording.tasks.as_ref().and_then(tasks::TaskRunner::wake),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(next_check, Instant::min)
+        .min(heartbeat.deadline());
+        let event = match recording.control.as_mut() {
+            Some(control) => {
+                let tasks = recording.tasks.as_ref();
+                control.next(&mut swarm, wake, &peers, tasks).await
+            }
+            None => next_before(&mut swarm, wake).await,
+        };
+        let now = Instant::now();
+        if let Some(metrics) = &recording.metrics {
+            metrics.woke(heartbeat.lag(now));
+        }
+        if let Some(late) = heartbeat.woke(now) {
+            warn!(
+                "Event loop woke {late:?} late: {}",
+                pending_work(&swarm, &queue)
+            );
+            if let Some(metrics) = &recording.metrics {
+                metrics.stalled();
+            }
+        }
+        status.stalls = heartbeat.stalls();
+        status.last_stall = heartbeat.last_stall();
+        let Some(e) = event else {
+            handling = "timers";
+            let now = Instant::now();
+            recording.flush_archive();
+            if let Some(tasks) = recording.tasks.as_mut() {
+                tasks.poll(now);
+            }
+            if next_check <= now && !readiness.is_ready() {
+                let rt = RoutingSnapshot::capture(&mut swarm.behaviour_mut().kademlia);
+                if let Some(after) = readiness.update(&rt, now) {
+                    info!(
+                        "Bootstrapped after {}s: {} peers in {} buckets",
+                        after.as_secs(),
+                        rt.len(),
+                        rt.buckets.len()
+                    );
+                    status.bootstrapped = true;
+                    // the DHT can take our provider records for the published values now
+                    if let Some(blocks) = &recording.blocks {
+                        provide_roots(&mut swarm, blocks);
+                    }
+                }
+            }
+            // snapshots of a routing table still filling up are noise
+            if let (Some((dir, every)), Some(at)) = (&recording.snapshots, next_snapshot) {
+                if at <= now {
+                    if readiness.is_ready() {
+                        let snapshot =
+                            RoutingSnapshot::capture(&mut swarm.behaviour_mut().kademlia);
+                        let path = dir.save(&snapshot)?;
+                        debug!("Saved routing table snapshot to {}", path.display());
+                    }
+                    next_snapshot = Some(now + *every);
+                }
+            }
+            if next_check <= now && !alerts.is_empty() {
+                status.routing_table = swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .kbuckets()
+                    .map(|b| b.num_entries())
+                    .sum();
+                status.bootstrap_failing =
+                    !bootnodes.is_empty() && failed_bootnodes.len() == bootnodes.len();
+                for alert in alerts.check(&status, now) {
+                    warn!("Alert {alert}");
+                    match &alert.action {
+                        Action::Log => {}
+                        Action::Webhook(url) => {
+                            // posting can take seconds, the swarm doesn't wait for it
+                            let (url, alert) = (url.clone(), alert.clone());
+                            runtime::spawn(async move {
+                                if let Err(e) = post_webhook(&url, &alert).await {
+                                    warn!("Alert webhook {url} failed: {e}");
+                                }
+                            });
+                        }
+                        Action::Exit(code) => exit = Some(*code),
+                    }
+                }
+            }
+            if next_check <= now {
+                next_check = now + ALERT_INTERVAL;
+            }
+            if exit.is_some() {
+                break;
+            }
+            continue;
+        };
+        handling = event_kind(&e);
+        recording.handled(&e);
+        if let Some(control) = recording.control.as_mut() {
+            control.observe(&mut swarm, &e, recording.metrics.as_ref());
+        }
+        match e {
+            /*
+            SwarmEvent::ExpiredListenAddr { .. }
+            | SwarmEvent::ListenerClosed { .. }
+            | SwarmEvent::ListenerError { .. }
+            | SwarmEvent::ConnectionEstablished { .. }
+            | SwarmEvent::ConnectionClosed { .. }
+            | SwarmEvent::IncomingConnection { .. }
+            | SwarmEvent::IncomingConnectionError { .. }
+            | SwarmEvent::OutgoingConnectionError { .. }
+            | SwarmEvent::NewListenAddr { .. }
+            | SwarmEvent::Dialing { .. } => {}
+            */
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                connections += 1;
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    peers.record_dial(peer_id, address, true);
+                    dialed.insert(peer_id, address.clone());
+                }
+                queue.succeeded(&peer_id);
+                failed_bootnodes.remove(&peer_id);
+                sessions.connected(peer_id, endpoint.get_remote_address());
+                recording.note(peer_id, || {
+                    format!("connected on {}", endpoint.get_remote_address())
+                });
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established,
+                cause,
+                ..
+            } => {
+                if num_established == 0 {
+                    dialed.remove(&peer_id);
+                    routing.disconnected(&peer_id);
+                }
+                recording.note(peer_id, || match &cause {
+                    Some(e) => format!("connection closed: {e}"),
+                    None => "connection closed".to_string(),
+                });
+                if let Some(session) = sessions.closed(&peer_id, num_established) {
+                    if let Some(recorder) = recording.sessions.as_mut() {
+                        recorder.record(session)?;
+                    }
+                }
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(expected),
+                error:
+                    DialError::WrongPeerId {
+                        obtained,
+                        endpoint: ConnectedPoint::Dialer { address, .. },
+                    },
+                ..
+            } => {
+                warn!("!!! {address} published for {expected} authenticated as {obtained}");
+                queue.failed(&expected, Instant::now());
+                if bootnodes.contains(&expected) {
+                    failed_bootnodes.insert(expected);
+                }
+                peers.record_dial(expected, &address, false);
+                peers.record_wrong_peer_id(expected, address, obtained);
+                peers.maybe_save()?;
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                if let Some(peer_id) = peer_id {
+                    queue.failed(&peer_id, Instant::now());
+                    if bootnodes.contains(&peer_id) {
+                        failed_bootnodes.insert(peer_id);
+                    }
+                    if let DialError::Transport(failed) = &error {
+                        for (addr, e) in failed {
+                            if !matches!(e, TransportError::MultiaddrNotSupported(_)) {
+                                peers.record_dial(peer_id, addr, false);
+                            }
+                        }
+                    }
+                }
+                for d in Diagnosis::from_dial_error(&error) {
+                    if let Some(peer_id) = peer_id {
+                        recording.note(peer_id, || format!("dial failed: {d}"));
+                    }
+                    if d.is_upgrade_failure() {
+                        warn!("Dial failed: {d}");
+                        if let Some(peer_id) = peer_id {
+                            recording.misbehaved(
+                                peer_id,
+                                Misbehavior::ProtocolError(d.to_string()),
+                                &peers,
+                            );
+                        }
+                    } else {
+                        debug!("Dial failed: {d}");
+                    }
+                }
+            }
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                listeners.insert(listener_id);
+                if let Some(lan) = dialing.lan.as_mut() {
+                    lan.listening(&address);
+                }
+            }
+            // a relay that turned down or dropped our reservation is tried again the next
+            // time we find ourselves behind a NAT
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                listeners.remove(&listener_id);
+                reservations.retain(|relay, id| {
+                    if *id != listener_id {
+                        return true;
+                    }
+                    match &reason {
+                        Ok(()) => debug!("Stopped listening through relay {relay}"),
+                        Err(e) => warn!("Lost our reservation on relay {relay}: {e}"),
+                    }
+                    false
+                });
+            }
+            SwarmEvent::ExternalAddrConfirmed { .. } => status.reachable = Some(true),
+            SwarmEvent::ExternalAddrExpired { .. } => {
+                status.reachable = Some(swarm.external_addresses().next().is_some());
+            }
+            SwarmEvent::Behaviour(behavior) => match behavior {
+                FleygBehaviorEvent::Ping(ping::Event { peer, result, .. }) => match result {
+                    Ok(rtt) => {
+                        routing.pinged(peer, Some(rtt));
+                        sessions.pinged(&peer, rtt);
+                        peers.entry(peer).rtt = Some(rtt);
+                        recording.note(peer, || format!("ping {rtt:?}"));
+                    }
+                    Err(e) => {
+                        routing.pinged(peer, None);
+                        recording.note(peer, || format!("ping failed: {e}"));
+                    }
+                },
+                FleygBehaviorEvent::Identify(event) => match event {
+                    //IdentifyEvent::Received { info, .. } => {
+                    IdentifyEvent::Received { peer_id, info } => {
+                        info!("Identify Received: {peer_id}");
+                        info!("\tProtocol: {}", info.protocol_version);
+                        info!("\tAgent: {}", info.agent_version);
+                        info!("\tObserved Addr: {}", &info.observed_addr);
+                        info!("\tProtocols:");
+                        for sp in &info.protocols {
+                            info!("\t\t{}", sp);
+                        }
+
+                        let found =
+                            divergence.check(&info, dialed.get(&peer_id), peers.get(&peer_id));
+                        for d in found {
+                            warn!("Identify divergence from {peer_id}: {d}");
+                            if let Some(metrics) = &recording.metrics {
+                                metrics.identify_divergence(d.field());
+                            }
+                        }
+                        event::emit(Event::identify(peer_id, &info));
+                        // every identify, pushed ones too, replaces the protocols we knew
+                        if negotiation.identified(peer_id, &info) {
+                            debug!("{peer_id} speaks {} protocols", info.protocols.len());
+                        }
+                        // kademlia queries would only waste a round trip on peers that
+                        // don't speak it
+                        let kad = swarm.behaviour().kademlia.protocol_names().to_vec();
+                        if negotiation.worth_offering(&peer_id, &kad) {
+                            for addr in routing.identified(&peer_id, &info.listen_addrs) {
+                                debug!("Adding discovered address {addr} for {peer_id}");
+                                swarm.behaviour_mut().kademlia.add_address(&peer_id, addr);
+                            }
+                        } else {
+                            debug!("{peer_id} doesn't speak our kademlia, not routing to it");
+                            swarm.behaviour_mut().kademlia.remove_peer(&peer_id);
+                            if let Some(metrics) = &recording.metrics {
+                                metrics.skipped_offer(kad[0].as_ref());
+                            }
+                        }
+                        peers.entry(peer_id).update(&info);
+                        peers.maybe_save()?;
+                        // ask fleyg nodes that share peers while we know too few
+                        let want = dialing.pex.want.saturating_sub(peers.len());
+                        if want > 0
+                            && negotiation.worth_offering(&peer_id, &[pex::PROTOCOL])
+                            && asked.insert(peer_id)
+                        {
+                            if let Some(pex) = swarm.behaviour_mut().pex.as_mut() {
+                                pex.send_request(&peer_id, PexRequest { max: want });
+                            }
+                        }
+                        sessions.identified(&peer_id, &info);
+                        recording.note(peer_id, || {
+                            format!(
+                                "identified as {} speaking {} protocols",
+                                info.agent_version,
+                                info.protocols.len()
+                            )
+                        });
+
+                        // add our observed address
+                        //info!("Adding {} as swarm external address", &info.observed_addr);
+                        //swarm.add_external_address(info.observed_addr);
+                    }
+                    IdentifyEvent::Sent { .. } => {
+                        //IdentifyEvent::Sent { _peer_id } => {
+                        //info!("Identify Sent: {peer_id}");
+                    }
+                    IdentifyEvent::Pushed { peer_id, info } => {
+                        info!("Pushed to: {peer_id}");
+                        for p in &info.protocols {
+                            info!("\t{p}")
+                        }
+                    }
+                    IdentifyEvent::Error { peer_id, error } => {
+                        recording.note(peer_id, || format!("identify failed: {error}"));
+                        // a reply we can't parse, not just a timeout or missing protocol
+                        if let StreamUpgradeError::Apply(e) = error {
+                            recording.misbehaved(
+                                peer_id,
+                                Misbehavior::ProtocolError(format!("identify: {e}")),
+                                &peers,
+                            );
+                        }
+                    }
+                },
+                FleygBehaviorEvent::Kademlia(kad) => match kad {
+                    KademliaEvent::InboundRequest { request } => {
+                        status.last_inbound = Some(Instant::now());
+                        match request {
+                            InboundRequest::FindNode { num_closer_peers } => event::emit(
+                                Event::new("find_node", None).with("closer", num_closer_peers),
+                            ),
+                            InboundRequest::GetProvider {
+                                num_closer_peers,
+                                num_provider_peers,
+                            } => event::emit(
+                                Event::new("get_providers", None)
+                                    .with("closer", num_closer_peers)
+                                    .with("providers", num_provider_peers),
+                            ),
+                            InboundRequest::AddProvider { record } => {
+                                if let Some(r) = &record {
+                                    event::emit(
+                                        Event::new("add_provider", Some(r.provider))
+                                            .with("key", format.key(&r.key.to_vec())),
+                                    );
+                                }
+                                let store = swarm.behaviour_mut().kademlia.store_mut();
+                                if let Some(Err(e)) = record.map(|r| store.add_provider(r)) {
+                                    debug!("Not storing a provider record: {e:?}");
+                                }
+                            }
+                            InboundRequest::GetRecord {
+                                num_closer_peers,
+                                present_locally,
+                            } => event::emit(
+                                Event::new("get_record", None)
+                                    .with("closer", num_closer_peers)
+                                    .with("found", present_locally),
+                            ),
+                            InboundRequest::PutRecord { source, record, .. } => {
+                                if let Some(rec) = record {
+                                    let key = rec.key.to_vec();
+                                    // anyone can put, so failing to write their value is
+                                    // only worth a warning
+                                    let value =
+                                        format.value(&key, &rec.value).unwrap_or_else(|e| {
+                                            warn!(
+                                                "Can't write the value of {}: {e}",
+                                                format.key(&key)
+                                            );
+                                            format!("<{} bytes>", rec.value.len())
+                                        });
+                                    event::emit(
+                                        Event::new("put_record", Some(source))
+                                            .with("key", format.key(&key))
+                                            .with("value", &value),
+                                    );
+                                    info!("Put: {} -> {value}", format.key(&key));
+                                    recording.note(source, || format!("put {}", format.key(&key)));
+                                    if let Some(Err(e)) = attest::check_record(&key, &rec.value) {
+                                        recording.misbehaved(
+                                            source,
+                                            Misbehavior::InvalidRecord(format!(
+                                                "{}: {e}",
+                                                format.key(&key)
+                                            )),
+                                            &peers,
+                                        );
+                                    } else if let Err(e) =
+                                        swarm.behaviour_mut().kademlia.store_mut().put(rec)
+                                    {
+                                        debug!("Not storing {}: {e:?}", format.key(&key));
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    KademliaEvent::OutboundQueryProgressed { result, .. } => match result {
+                        QueryResult::GetClosestPeers(result) => match result {
+                            Ok(ok) => {
+                                for peer in &ok.peers {
+                                    info!("Closest peer: {:#?}", peer);
+                                }
+                                event::emit(
+                                    Event::new("closest_peers", None).with("peers", &ok.peers),
+                                );
+                            }
+                            Err(GetClosestPeersError::Timeout { peers, .. }) => {
+                                info!("Query timed out...");
+                                for peer in &peers {
+                                    info!("Closest peer: {:#?}", peer);
+                                }
+                                event::emit(
+                                    Event::new("closest_peers", None)
+                                        .with("peers", &peers)
+                                        .with("timed_out", true),
+                                );
+                            }
+                        },
+                        _ => {}
+                    },
+                    /*
+                    KademliaEvent::ModeChanged { new_mode } => {
+                        info!("Kademlia peer mode changed to: {new_mode}");
+                    }
+                    */
+                    KademliaEvent::RoutingUpdated { .. } => {
+                        //KademliaEvent::RoutingUpdated { _peer, .. } => {
+                        //info!("Kademlia Routing Updated: {peer:?}");
+                    }
+                    KademliaEvent::UnroutablePeer { peer } => {
+                        let decision = routing.unroutable(peer);
+                        debug!("Kademlia unroutable peer {peer}: {decision}");
+                    }
+                    KademliaEvent::RoutablePeer { peer, address } => {
+                        let kademlia = &mut swarm.behaviour_mut().kademlia;
+                        let bucket: Vec<PeerId> = kademlia
+                            .kbucket(peer)
+                            .map(|b| b.iter().map(|e| *e.node.key.preimage()).collect())
+                            .unwrap_or_default();
+                        let decision = routing.routable(&bucket);
+                        debug!("Kademlia routable peer {peer} on {address}: {decision}");
+                        match decision {
+                            Decision::Added => {
+                                kademlia.add_address(&peer, address);
+                            }
+                            Decision::Evicted {
+                                peer: evicted,
+                                reason,
+                            } => {
+                                info!("Evicted {evicted} ({reason}) for {peer}");
+                                kademlia.remove_peer(&evicted);
+                                kademlia.add_address(&peer, address);
+                                event::emit(
+                                    Event::new("routing_eviction", Some(peer))
+                                        .with("evicted", evicted)
+                                        .with("reason", reason.to_string()),
+                                );
+                            }
+                            _ => {}
+                        }
+                        if let Some(metrics) = &recording.metrics {
+                            metrics.routable(decision.kind());
+                        }
+                    }
+                    KademliaEvent::PendingRoutablePeer { peer, .. } => {
+                        debug!("Kademlia pending routable peer {peer}");
+                    }
+                },
+                // identify pushes the circuit address to our peers as a new listen address
+                FleygBehaviorEvent::RelayClient(relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id,
+                    renewal,
+                    ..
+                }) => {
+                    if renewal {
+                        debug!("Relay {relay_peer_id} renewed our reservation");
+                    } else {
+                        info!("Relay {relay_peer_id} accepted our reservation");
+                        event::emit(Event::new("relay_reservation", Some(relay_peer_id)));
+                    }
+                }
+                FleygBehaviorEvent::RelayClient(event) => debug!("Relay client: {event:?}"),
+                FleygBehaviorEvent::RelayServer(event) => match event {
+                    relay::Event::ReservationReqAccepted {
+                        src_peer_id,
+                        renewed: false,
+                    } => info!("Holding a relay reservation for {src_peer_id}"),
+                    relay::Event::CircuitReqAccepted {
+                        src_peer_id,
+                        dst_peer_id,
+                    } => info!("Relaying a circuit from {src_peer_id} to {dst_peer_id}"),
+                    event => debug!("Relay server: {event:?}"),
+                },
+                FleygBehaviorEvent::Pex(event) => {
+                    let pex = &dialing.pex;
+                    exchange_peers(&mut swarm, event, &peers, &mut negotiation, &mut queue, pex)
+                }
+                FleygBehaviorEvent::Blocks(event) => {
+                    send_blocks(&mut swarm, event, recording.blocks.as_ref())
+                }
+                FleygBehaviorEvent::Gossipsub(event) => debug!("Gossipsub: {event:?}"),
+                FleygBehaviorEvent::Mdns(event) => {
+                    // the queue merges a peer's addresses into one dial
+                    for (peer, addr) in add_lan_peers(&mut swarm, event) {
+                        if !swarm.is_connected(&peer) {
+                            queue.push(peer, vec![addr], Priority::Background);
+                        }
+                    }
+                }
+                FleygBehaviorEvent::Autonat(autonat::Event::StatusChanged { old, new }) => {
+                    info!("NAT status changed from {old:?} to {new:?}");
+                    event::emit(
+                        Event::new("nat_status", None)
+                            .with("old", format!("{old:?}"))
+                            .with("new", format!("{new:?}")),
+                    );
+                    // an unknown status keeps the mode we're in
+                    let mode = match new {
+                        NatStatus::Public(_) => Some(Mode::Server),
+                        NatStatus::Private => Some(Mode::Client),
+                        NatStatus::Unknown => None,
+                    };
+                    if let Some(mode) = mode {
+                        info!("Switching kademlia to {mode} mode");
+                        swarm.behaviour_mut().kademlia.set_mode(Some(mode));
+                        status.reachable = Some(mode == Mode::Server);
+                        match mode {
+                            Mode::Client => reserve(&mut swarm, &dialing.relays, &mut reservations),
+                            Mode::Server => release(&mut swarm, &mut reservations),
+                        }
+                    }
+                }
+                FleygBehaviorEvent::Autonat(event) => debug!("AutoNAT: {event:?}"),
+            },
+            _ => {}
+        }
+    }
+
+    if shutdown.requested() {
+        info!("Shutting down");
+    }
+    close(&mut swarm, &listeners).await;
+    if let Err(e) = swarm.behaviour_mut().kademlia.store_mut().flush() {
+        warn!("Can't flush the record store: {e}");
+    }
+    let summary = Summary {
+        uptime: started.elapsed(),
+        connections,
+        records: swarm.behaviour_mut().kademlia.store_mut().records().count(),
+        peers: peers.len(),
+    };
+    info!("Stopped: {summary}");
+
+    for (kind, count) in divergence.counts() {
+        info!("Identify divergences ({kind}): {count}");
+    }
+    for (event, decision, count) in routing.counts() {
+        info!("Routing decisions for {event} peers ({decision}): {count}");
+    }
+    if heartbeat.stalls() > 0 {
+        warn!(
+            "The event loop stalled {} times, the longest for {:?}",
+            heartbeat.stalls(),
+            heartbeat.worst()
+        );
+    }
+    if let Some(tasks) = recording.tasks.as_mut() {
+        tasks.stop();
+    }
+    peers.save()?;
+    if let Some(mut recorder) = recording.sessions {
+        for session in sessions.close_all() {
+            recorder.record(session)?;
+        }
+        recorder.flush()?;
+    }
+
+    if let Some(code) = exit {
+        std::process::exit(code);
+    }
+    Ok(())
+}
@@ -0,0 +1,140 @@
+use crate::{bootstrap, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    deadline::next_before,
+    geoip::{GeoIp, Region},
+    latency::LatencyMap,
+    peerstore::PeerStore,
+    progress::Progress,
+    table::{Output, Table},
+};
+use libp2p::{ping, swarm::SwarmEvent, Multiaddr, PeerId, Swarm};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct MapOpt {
+    /// number of peers to sample from each k-bucket
+    #[structopt(long, default_value = "3")]
+    per_bucket: usize,
+
+    /// path to a GeoLite2/GeoIP2 country database
+    #[structopt(long)]
+    geoip: Option<PathBuf>,
+
+    /// also write an html latency map to this file
+    #[structopt(long)]
+    html: Option<PathBuf>,
+
+    /// seconds to spend bootstrapping and measuring
+    #[structopt(long, default_value = "120")]
+    timeout: u64,
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: MapOpt,
+    peers: &PeerStore,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let geoip = match &opt.geoip {
+        Some(path) => Some(GeoIp::open(path)?),
+        None => None,
+    };
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+
+    // fill the routing table so we have peers in as many buckets as possible
+    bootstrap(&mut swarm, deadline).await?;
+
+    // take a sample of peers from each bucket, with an address to place each one by until we
+    // see its connection, as we won't for the peers that are already connected
+    let mut pending = HashSet::new();
+    let mut remote_addrs: HashMap<PeerId, Multiaddr> = HashMap::new();
+    for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+        for entry in bucket.iter().take(opt.per_bucket) {
+            let peer = *entry.node.key.preimage();
+            pending.insert(peer);
+            let known = peers.get(&peer).and_then(|r| r.addrs.first());
+            if let Some(addr) = known.or_else(|| entry.node.value.iter().next()) {
+                remote_addrs.insert(peer, addr.clone());
+            }
+        }
+    }
+    info!("Sampled {} peers", pending.len());
+    for peer in &pending {
+        if swarm.is_connected(peer) {
+            continue;
+        }
+        if let Err(e) = swarm.dial(*peer) {
+            debug!("Failed to dial {peer}: {e}");
+        }
+    }
+
+    // wait for a ping from each of the sampled peers
+    let mut map = LatencyMap::default();
+    let sampled = pending.len() as u64;
+    let progress = Progress::bar(sampled, "pinging sampled peers");
+    while !pending.is_empty() {
+        progress.set_position(sampled - pending.len() as u64);
+        let Some(event) = next_before(&mut swarm, deadline).await else {
+            info!("Timed out waiting on {} peers", pending.len());
+            break;
+        };
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id, endpoint, ..
+            } => {
+                remote_addrs.insert(peer_id, endpoint.get_remote_address().clone());
+            }
+            SwarmEvent::OutgoingConnectionError {
+                peer_id: Some(peer_id),
+                ..
+            } => {
+                pending.remove(&peer_id);
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(ping::Event {
+                peer, result, ..
+            })) => {
+                if !pending.remove(&peer) {
+                    continue;
+                }
+                if let Ok(rtt) = result {
+                    let region = remote_addrs
+                        .get(&peer)
+                        .and_then(|addr| geoip.as_ref()?.lookup_addr(addr))
+                        .unwrap_or_else(Region::unknown);
+                    map.add(region, rtt);
+                }
+            }
+            _ => {}
+        }
+    }
+    progress.finish();
+
+    info!("Latency by region ({} peers):", map.len());
+    let mut table = Table::new(&["region", "peers", "min", "median", "avg", "max"]);
+    for (region, st) in map.summary() {
+        table.push(vec![
+            region.to_string().into(),
+            st.count.to_string().into(),
+            format!("{:?}", st.min).into(),
+            format!("{:?}", st.median).into(),
+            format!("{:?}", st.avg).into(),
+            format!("{:?}", st.max).into(),
+        ]);
+    }
+    output.print(table)?;
+
+    if let Some(path) = &opt.html {
+        fs::write(path, map.to_html())?;
+        info!("Wrote latency map to {}", path.display());
+    }
+
+    Ok(())
+}
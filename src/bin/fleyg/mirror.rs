@@ -0,0 +1,133 @@
+use crate::{bootstrap, get, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    deadline::{idle_until, next_before},
+    encoding::ValueFormat,
+    memprofile,
+    mirror::{Mirror, MirrorSettings},
+    progress::Progress,
+    retry::Failure,
+};
+use futures::join;
+use libp2p::{
+    kad::{
+        record::{Key, Record},
+        KademliaEvent, QueryResult, Quorum,
+    },
+    swarm::SwarmEvent,
+    Swarm,
+};
+use log::*;
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct MirrorOpt {
+    /// mirror the keys once and exit
+    #[structopt(long)]
+    once: bool,
+
+    /// seconds to spend on each lookup and store
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+/// watch the keys on the source network and store their values on the target
+pub async fn run(
+    mut source: Swarm<FleygBehavior>,
+    mut target: Swarm<FleygBehavior>,
+    opt: MirrorOpt,
+    settings: MirrorSettings,
+    format: &ValueFormat,
+) -> Result<(), Box<dyn Error>> {
+    if settings.keys.is_empty() {
+        return Err("no keys to mirror, list them in the config's [mirror] section".into());
+    }
+    let interval = settings.interval()?;
+    let mut mirror = Mirror::new(settings.republish()?);
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut source, Instant::now() + timeout).await?;
+    bootstrap(&mut target, Instant::now() + timeout).await?;
+    info!(
+        "Mirroring {} keys onto {} every {interval:?}",
+        settings.keys.len(),
+        settings.to
+    );
+
+    loop {
+        let started = Instant::now();
+        let mut copied = 0;
+        for key in &settings.keys {
+            let key = Key::new(&key.as_bytes());
+            let shown = format.key(key.as_ref());
+            let value = match get::get(&mut source, &key, Instant::now() + timeout).await {
+                Ok((_, record)) => record.value,
+                Err(failure) => {
+                    warn!("Can't get {shown} from the source network: {failure}");
+                    continue;
+                }
+            };
+            if !mirror.needs_store(key.as_ref(), &value, Instant::now()) {
+                debug!("{shown} is unchanged");
+                continue;
+            }
+            let target_key = Key::new(&settings.target_key(key.as_ref()));
+            let record = Record::new(target_key.clone(), value.clone());
+            match put(&mut target, record, Instant::now() + timeout).await {
+                Ok(()) => {
+                    info!("Mirrored {shown} to {}", format.key(target_key.as_ref()));
+                    mirror.stored(key.as_ref(), &value, Instant::now());
+                    copied += 1;
+                }
+                Err((failure, e)) => warn!("Storing {shown} on the target failed ({failure}): {e}"),
+            }
+        }
+        info!("Mirrored {copied} of {} keys", settings.keys.len());
+        if opt.once {
+            return Ok(());
+        }
+        // keep both networks running until the next round
+        let next = started + interval;
+        join!(idle_until(&mut source, next), idle_until(&mut target, next));
+    }
+}
+
+// one attempt at storing the record on the target network
+async fn put(
+    swarm: &mut Swarm<FleygBehavior>,
+    record: Record,
+    deadline: Instant,
+) -> Result<(), (Failure, String)> {
+    let _phase = memprofile::phase("query");
+    let query = swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, Quorum::One)
+        .map_err(|e| (Failure::Fatal, e.to_string()))?;
+    let progress = Progress::spinner("storing on the target network");
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::PutRecord(result),
+                ..
+            },
+        )) = event
+        {
+            if id != query {
+                continue;
+            }
+            progress.finish();
+            return result
+                .map(|_| ())
+                .map_err(|e| (Failure::from(&e), e.to_string()));
+        }
+    }
+    progress.finish();
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+        query.finish();
+    }
+    Err((Failure::Timeout, "timed out".to_string()))
+}
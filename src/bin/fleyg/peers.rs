@@ -0,0 +1,90 @@
+use fleyg::{
+    peerstore::{now_secs, PeerStore, UNKNOWN_CONFIDENCE},
+    table::{Cell, Output, Table},
+};
+use std::{error::Error, time::Duration};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct PeersOpt {
+    /// only list peers whose agent contains this string
+    #[structopt(long)]
+    agent: Option<String>,
+}
+
+pub fn run(peers: &PeerStore, opt: PeersOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    let mut records: Vec<_> = peers
+        .iter()
+        .filter(|(_, r)| match &opt.agent {
+            Some(agent) => r
+                .agent
+                .as_deref()
+                .unwrap_or_default()
+                .contains(agent.as_str()),
+            None => true,
+        })
+        .collect();
+    records.sort_by_key(|(_, r)| std::cmp::Reverse(r.last_seen));
+
+    let now = now_secs();
+    let mut table = Table::new(&["id", "addr", "conf", "agent", "rtt", "protocols", "seen"]);
+    for (peer, record) in records {
+        // the address most likely to connect, with how likely it is
+        let best = record
+            .addrs
+            .iter()
+            .map(|a| (a, record.confidence(a)))
+            .max_by(|a, b| a.1.total_cmp(&b.1));
+        let (addr, conf) = match best {
+            None => (String::new(), Cell::from("")),
+            Some((addr, conf)) => {
+                let addr = match record.addrs.len() {
+                    1 => addr.to_string(),
+                    n => format!("{addr} (+{})", n - 1),
+                };
+                (addr, confidence_cell(conf))
+            }
+        };
+        table.push(vec![
+            peer.to_string().into(),
+            addr.into(),
+            conf,
+            record.agent.clone().unwrap_or_default().into(),
+            record.rtt.map(rtt_cell).unwrap_or_default(),
+            record.protocols.len().to_string().into(),
+            ago(now.saturating_sub(record.last_seen)).into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// color round trip times by how usable the peer is
+fn rtt_cell(rtt: Duration) -> Cell {
+    let text = format!("{}ms", rtt.as_millis());
+    match rtt.as_millis() {
+        0..=99 => Cell::good(text),
+        100..=299 => Cell::warn(text),
+        _ => Cell::bad(text),
+    }
+}
+
+// color an address's confidence by whether it's worth dialing
+pub fn confidence_cell(conf: f64) -> Cell {
+    let text = format!("{conf:.2}");
+    match conf {
+        c if c > UNKNOWN_CONFIDENCE => Cell::good(text),
+        c if c < UNKNOWN_CONFIDENCE => Cell::bad(text),
+        _ => text.into(),
+    }
+}
+
+// a short human readable age
+fn ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{secs}s ago"),
+        60..=3599 => format!("{}m ago", secs / 60),
+        3600..=86399 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
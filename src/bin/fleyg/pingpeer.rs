@@ -0,0 +1,210 @@
+use crate::{dial, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    control::Target,
+    deadline::next_before,
+    diagnose::Diagnosis,
+    latency::RttStats,
+    peerstore::PeerStore,
+    table::{Cell, Output, Table},
+    timespec::parse_duration,
+};
+use libp2p::{
+    ping,
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        SwarmEvent,
+    },
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    error::Error,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how long libp2p waits for a ping to be answered
+const PING_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// how long to wait for the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, StructOpt)]
+pub struct PingOpt {
+    /// the peer id or address to ping, a peer's addresses are looked up in the DHT if the
+    /// peer store has none
+    target: Target,
+
+    /// how many pings to send
+    #[structopt(long, short = "c", default_value = "5")]
+    count: usize,
+
+    /// how long to wait between pings, e.g. "1s" or "500ms"
+    #[structopt(long, short = "i", default_value = "1s", parse(try_from_str = parse_duration))]
+    interval: Duration,
+
+    /// seconds to spend looking up the peer's addresses
+    #[structopt(long, default_value = "60")]
+    lookup_timeout: u64,
+}
+
+impl PingOpt {
+    /// how often the node has to ping its connections
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+}
+
+/// ping a peer over libp2p and show the round trip times and how many pings were lost
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: PingOpt,
+    mut peers: PeerStore,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut opts = match &opt.target {
+        Target::Addr(addr) => DialOpts::unknown_peer_id().address(addr.clone()).build(),
+        Target::Peer(peer) => {
+            let mut addrs = peers.get(peer).map(|r| r.addrs.clone()).unwrap_or_default();
+            if addrs.is_empty() {
+                let deadline = Instant::now() + Duration::from_secs(opt.lookup_timeout);
+                addrs = dial::lookup(&mut swarm, *peer, deadline).await;
+            }
+            if addrs.is_empty() {
+                return Err(format!("no known addresses for {peer}").into());
+            }
+            DialOpts::peer_id(*peer)
+                .addresses(addrs)
+                .condition(PeerCondition::Always)
+                .build()
+        }
+    };
+
+    let mut remote: Option<(PeerId, Multiaddr)> = None;
+    let mut rtts = Vec::new();
+    let mut sent = 0;
+    while sent < opt.count {
+        // a ping on its own connection, never reused from the lookup
+        let id = opts.connection_id();
+        swarm.dial(opts)?;
+        let mut wait = Instant::now() + CONNECT_TIMEOUT;
+        let closed = loop {
+            if sent == opt.count {
+                break false;
+            }
+            let Some(event) = next_before(&mut swarm, wait).await else {
+                let Some((peer, _)) = &remote else {
+                    return Err(format!("{} didn't connect in time", opt.target).into());
+                };
+                sent += 1;
+                warn!("No answer from {peer}: seq={sent}");
+                wait = Instant::now() + opt.interval + PING_TIMEOUT;
+                continue;
+            };
+            match event {
+                SwarmEvent::ConnectionEstablished {
+                    peer_id,
+                    connection_id,
+                    endpoint,
+                    ..
+                } if connection_id == id => {
+                    let addr = endpoint.get_remote_address().clone();
+                    peers.record_dial(peer_id, &addr, true);
+                    if remote.is_none() {
+                        info!("Pinging {peer_id} at {addr}");
+                    }
+                    remote = Some((peer_id, addr));
+                    // the first ping goes out as soon as it connects
+                    wait = Instant::now() + PING_TIMEOUT;
+                }
+                SwarmEvent::OutgoingConnectionError {
+                    connection_id,
+                    error,
+                    ..
+                } if connection_id == id => {
+                    let reason = match Diagnosis::from_dial_error(&error).first() {
+                        Some(d) => d.to_string(),
+                        None => error.to_string(),
+                    };
+                    match &remote {
+                        None => return Err(format!("can't reach {}: {reason}", opt.target).into()),
+                        Some(_) => {
+                            sent += 1;
+                            warn!("Reconnecting failed: {reason}, seq={sent}");
+                            break true;
+                        }
+                    }
+                }
+                SwarmEvent::ConnectionClosed { connection_id, .. } if connection_id == id => {
+                    break true;
+                }
+                SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(ping::Event {
+                    peer,
+                    connection,
+                    result,
+                })) if connection == id => {
+                    sent += 1;
+                    match result {
+                        Ok(rtt) => {
+                            info!("Answer from {peer}: seq={sent} time={rtt:?}");
+                            peers.entry(peer).rtt = Some(rtt);
+                            rtts.push(rtt);
+                        }
+                        Err(ping::Failure::Unsupported) => {
+                            return Err(format!("{peer} doesn't answer pings").into());
+                        }
+                        Err(e) => warn!("No answer from {peer}: seq={sent} {e}"),
+                    }
+                    wait = Instant::now() + opt.interval + PING_TIMEOUT;
+                }
+                _ => {}
+            }
+        };
+        if !closed {
+            break;
+        }
+        // the connection went idle or dropped, dial the address that answered again
+        let Some((peer, addr)) = &remote else { break };
+        debug!("Connection to {peer} closed, reconnecting");
+        opts = DialOpts::peer_id(*peer)
+            .addresses(vec![addr.clone()])
+            .condition(PeerCondition::Always)
+            .build();
+    }
+    peers.save()?;
+
+    let Some((peer, _)) = remote else {
+        return Err(format!("{} didn't connect", opt.target).into());
+    };
+    let lost = sent - rtts.len();
+    let loss = match sent {
+        0 => 0.0,
+        _ => lost as f64 * 100.0 / sent as f64,
+    };
+    info!(
+        "{peer}: {sent} pings, {} answered, {loss:.0}% lost",
+        rtts.len()
+    );
+    let mut table = Table::new(&["sent", "answered", "lost", "min", "avg", "max", "stddev"]);
+    let lost_cell = match lost {
+        0 => Cell::good(format!("{loss:.0}%")),
+        _ if rtts.is_empty() => Cell::bad(format!("{loss:.0}%")),
+        _ => Cell::warn(format!("{loss:.0}%")),
+    };
+    let mut row = vec![
+        sent.to_string().into(),
+        rtts.len().to_string().into(),
+        lost_cell,
+    ];
+    if let Some(stats) = RttStats::from_samples(&rtts) {
+        for rtt in [stats.min, stats.avg, stats.max, stats.stddev] {
+            row.push(format!("{rtt:?}").into());
+        }
+    }
+    table.push(row);
+    output.print(table)?;
+    match rtts.is_empty() {
+        true => Err(format!("{peer} answered none of the pings").into()),
+        false => Ok(()),
+    }
+}
@@ -0,0 +1,555 @@
+use crate::{bootstrap, FleygBehavior, FleygBehaviorEvent};
+use chrono::{TimeZone, Utc};
+use fleyg::{
+    answer::{Answer, SignedAnswer},
+    apilisten::ApiListen,
+    availability::ProviderWatch,
+    control::{self, Request},
+    deadline::{idle_until, next_before},
+    keys::KeyEncoding,
+    memprofile,
+    namespace::{self, ScanReport},
+    peerstore::{now_secs, PeerStore},
+    progress::Progress,
+    table::{Cell, Output, Table},
+    timespec::parse_duration,
+};
+use futures::prelude::*;
+use libp2p::{
+    kad::{record::Key, GetProvidersOk, KademliaEvent, QueryId, QueryResult},
+    swarm::SwarmEvent,
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::OpenOptions,
+    io::Write,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct ProvideOpt {
+    /// the content key to announce, in the --key-encoding
+    key: String,
+
+    /// how the key is encoded: hex, base64, base58, base32, multibase, cid or raw
+    /// [default: the --key-encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// keep running after the announcement so it's republished until we're stopped
+    #[structopt(long)]
+    keep: bool,
+
+    /// seconds to spend announcing
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ProvidersOpt {
+    /// the content key to find providers of, in the --key-encoding
+    key: Option<String>,
+
+    /// how the key is encoded: hex, base64, base58, base32, multibase, cid or raw
+    /// [default: the --key-encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// stop after finding this many providers
+    #[structopt(long)]
+    max: Option<usize>,
+
+    /// seconds to spend looking
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+
+    #[structopt(subcommand)]
+    cmd: Option<ProvidersCmd>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum ProvidersCmd {
+    /// find the providers of every key in an application namespace and report how many
+    /// of the keys are available
+    Scan(ScanOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ScanOpt {
+    /// the prefix the namespace's keys start with, e.g. /myapp/
+    #[structopt(long)]
+    prefix: String,
+
+    /// a file of the names under the prefix, one per line
+    #[structopt(
+        long,
+        parse(from_os_str),
+        required_unless = "generate",
+        conflicts_with = "generate"
+    )]
+    keys: Option<PathBuf>,
+
+    /// an expression generating the names under the prefix, e.g. 'shard-{000..127}' or
+    /// '{users,posts}/{0..9}'
+    #[structopt(long)]
+    generate: Option<String>,
+
+    /// keys to look up at once
+    #[structopt(long, default_value = "8")]
+    parallel: usize,
+
+    /// only show the keys nobody provides
+    #[structopt(long)]
+    missing: bool,
+
+    /// seconds to spend on each key
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct WatchProvidersOpt {
+    /// the content key to watch, in the --key-encoding, a CID with `--key-encoding cid`
+    key: String,
+
+    /// how the key is encoded: hex, base64, base58, base32, multibase, cid or raw
+    /// [default: the --key-encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// how often to look up the providers, e.g. "5m"
+    #[structopt(long, default_value = "5m")]
+    interval: String,
+
+    /// rounds a provider has to be missing from before it counts as gone
+    #[structopt(long, default_value = "2")]
+    misses: u32,
+
+    /// stop after this many rounds and show each provider's availability
+    #[structopt(long)]
+    rounds: Option<u32>,
+
+    /// append each provider appearing or disappearing to this json lines file
+    #[structopt(long, parse(from_os_str))]
+    timeline: Option<PathBuf>,
+
+    /// seconds to spend on each lookup
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+/// announce that we provide the key
+pub async fn provide(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: ProvideOpt,
+    keys: KeyEncoding,
+) -> Result<(), Box<dyn Error>> {
+    let key = decode_key(&opt.key, opt.key_encoding.unwrap_or(keys))?;
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, deadline).await?;
+
+    let _phase = memprofile::phase("query");
+    let query = swarm
+        .behaviour_mut()
+        .kademlia
+        .start_providing(key.clone())?;
+    let progress = Progress::spinner("announcing");
+    let mut result = None;
+    while let Some(event) = next_before(&mut swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::StartProviding(r),
+                ..
+            },
+        )) = event
+        {
+            if id == query {
+                result = Some(r);
+                break;
+            }
+        }
+    }
+    progress.finish();
+    let shown = keys.encode(key.as_ref());
+    match result {
+        Some(Ok(_)) => info!("Announced that we provide {shown}"),
+        Some(Err(e)) => return Err(format!("announcing {shown} failed: {e}").into()),
+        None => return Err(format!("timed out announcing {shown}").into()),
+    }
+    if opt.keep {
+        // kademlia republishes the provider record while we run
+        info!("Providing {shown} until stopped");
+        while swarm.next().await.is_some() {}
+    }
+    Ok(())
+}
+
+impl ProvidersOpt {
+    /// true if a running daemon can do the lookup, it doesn't scan namespaces
+    pub fn delegable(&self) -> bool {
+        self.cmd.is_none() && self.key.is_some()
+    }
+}
+
+/// find the providers of a key through the running daemon
+pub fn delegate(
+    api: &ApiListen,
+    opt: ProvidersOpt,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let Some(key) = &opt.key else {
+        return Err("a key to find the providers of is needed".into());
+    };
+    let key = decode_key(key, opt.key_encoding.unwrap_or(keys))?;
+    let shown = keys.encode(key.as_ref());
+    info!("Finding the providers of {shown} through the daemon at {api}");
+    let reply = control::call(api, &Request::Providers { key: key.to_vec() })?;
+    let signed: SignedAnswer = serde_json::from_value(reply)?;
+    signed.verify()?;
+    let Answer::Providers { mut providers, .. } = signed.answer else {
+        return Err("the daemon answered something other than providers".into());
+    };
+    if let Some(max) = opt.max {
+        providers.truncate(max);
+    }
+    if providers.is_empty() {
+        return Err(format!("no providers found for {shown}").into());
+    }
+    info!("{} providers of {shown}", providers.len());
+    let mut table = Table::new(&["id", "addrs"]);
+    for provider in providers {
+        table.push(vec![
+            provider.peer.to_string().into(),
+            join(&provider.addrs).into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// find the providers of the key, showing each as it's found. The DHT answers don't
+/// carry the addresses the providers announced, so the addresses shown are what the
+/// routing table and peer store know.
+pub async fn providers(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: ProvidersOpt,
+    peers: &PeerStore,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let key = match (opt.cmd, &opt.key) {
+        (Some(ProvidersCmd::Scan(scan_opt)), _) => return scan(swarm, scan_opt, output).await,
+        (None, Some(key)) => decode_key(key, opt.key_encoding.unwrap_or(keys))?,
+        (None, None) => return Err("a key to find the providers of is needed".into()),
+    };
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, deadline).await?;
+
+    let mut found = Vec::new();
+    find_providers(&mut swarm, key.clone(), deadline, opt.max, |swarm, peer| {
+        let addrs = known_addrs(swarm, peers, &peer);
+        info!("Provider {peer} at {}", join(&addrs));
+        found.push((peer, addrs));
+    })
+    .await;
+
+    let shown = keys.encode(key.as_ref());
+    if found.is_empty() {
+        return Err(format!("no providers found for {shown}").into());
+    }
+    info!("{} providers of {shown}", found.len());
+    let mut table = Table::new(&["id", "addrs"]);
+    for (peer, addrs) in found {
+        table.push(vec![peer.to_string().into(), join(&addrs).into()]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// look up the key's providers every interval and log the ones appearing and
+/// disappearing, writing the changes to a timeline and showing how available each
+/// provider was when done
+pub async fn watch_providers(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: WatchProvidersOpt,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let key = decode_key(&opt.key, opt.key_encoding.unwrap_or(keys))?;
+    let interval = parse_duration(&opt.interval)?;
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, Instant::now() + timeout).await?;
+
+    let mut timeline = match &opt.timeline {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+    let shown = keys.encode(key.as_ref());
+    info!("Watching the providers of {shown} every {}", opt.interval);
+    let mut watch = ProviderWatch::new(opt.misses);
+    loop {
+        let started = Instant::now();
+        let mut found = HashSet::new();
+        find_providers(
+            &mut swarm,
+            key.clone(),
+            started + timeout,
+            None,
+            |_, peer| {
+                found.insert(peer);
+            },
+        )
+        .await;
+        let at = now_secs();
+        for change in watch.observe(&found, at) {
+            let sign = if change.up { "+" } else { "-" };
+            info!("{} {sign}{}", format_time(at), change.peer);
+            if let Some(file) = &mut timeline {
+                serde_json::to_writer(&mut *file, &change)?;
+                file.write_all(b"\n")?;
+            }
+        }
+        debug!("Round {}: {} providers", watch.rounds(), found.len());
+        if opt.rounds.is_some_and(|rounds| watch.rounds() >= rounds) {
+            break;
+        }
+        idle_until(&mut swarm, started + interval).await;
+    }
+
+    let mut table = Table::new(&["id", "up", "seen", "first seen", "last seen"]);
+    for (peer, provider) in watch.providers() {
+        let share = provider.seen as f64 / watch.rounds() as f64;
+        let up = match provider.up {
+            true => Cell::good(format!("{:.0}%", share * 100.0)),
+            false => Cell::bad(format!("{:.0}%", share * 100.0)),
+        };
+        table.push(vec![
+            peer.to_string().into(),
+            up,
+            provider.seen.to_string().into(),
+            format_time(provider.first_seen).into(),
+            format_time(provider.last_seen).into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// find the providers of every key in the namespace, a few keys at a time, and show
+/// which keys nobody provides and who provides the most
+async fn scan(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: ScanOpt,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let names = match (&opt.keys, &opt.generate) {
+        (Some(path), _) => namespace::read_names(path)?,
+        (None, Some(expr)) => namespace::generate(expr)?,
+        (None, None) => unreachable!("structopt requires --keys or --generate"),
+    };
+    if names.is_empty() {
+        return Err("no names to scan".into());
+    }
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, Instant::now() + timeout).await?;
+
+    let _phase = memprofile::phase("query");
+    info!("Scanning {} keys under {}", names.len(), opt.prefix);
+    let mut report = ScanReport::new(names.iter().cloned());
+    let mut waiting = names.iter();
+    // the name each running query is for and when it's given up on
+    let mut running: HashMap<QueryId, (&String, Instant)> = HashMap::new();
+    let progress = Progress::bar(names.len() as u64, "scanning");
+    loop {
+        while running.len() < opt.parallel.max(1) {
+            let Some(name) = waiting.next() else {
+                break;
+            };
+            let key = Key::new(&namespace::key(&opt.prefix, name));
+            let query = swarm.behaviour_mut().kademlia.get_providers(key);
+            running.insert(query, (name, Instant::now() + timeout));
+        }
+        let Some(deadline) = running.values().map(|(_, d)| *d).min() else {
+            break;
+        };
+        let Some(event) = next_before(&mut swarm, deadline).await else {
+            // give up on the queries that ran out of time
+            let now = Instant::now();
+            running.retain(|query, (name, deadline)| {
+                if *deadline > now {
+                    return true;
+                }
+                debug!("Timed out finding providers of {name}");
+                if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(query) {
+                    query.finish();
+                }
+                progress.inc(1);
+                false
+            });
+            continue;
+        };
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetProviders(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        let Some(&(name, _)) = running.get(&id) else {
+            continue;
+        };
+        match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                for peer in providers {
+                    report.found(name, peer);
+                }
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+            Err(e) => debug!("Finding providers of {name} failed: {e}"),
+        }
+        if step.last() {
+            running.remove(&id);
+            progress.inc(1);
+        }
+    }
+    progress.finish();
+
+    let available = report.available();
+    info!(
+        "{available} of {} keys under {} have providers ({:.0}%)",
+        report.len(),
+        opt.prefix,
+        available as f64 * 100.0 / report.len() as f64
+    );
+    for (peer, keys) in report.providers().iter().take(5) {
+        info!("{peer} provides {keys} keys");
+    }
+    let mut table = Table::new(&["key", "providers", "ids"]);
+    for (name, providers) in report.keys() {
+        if opt.missing && !providers.is_empty() {
+            continue;
+        }
+        let count = match providers.len() {
+            0 => Cell::bad("0"),
+            n => Cell::good(n.to_string()),
+        };
+        let ids = providers
+            .iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        table.push(vec![
+            format!("{}{name}", opt.prefix).into(),
+            count,
+            ids.into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// run a providers query, calling found with each provider the first time it's found,
+// until the query finishes, max providers are found or the deadline
+pub async fn find_providers<F>(
+    swarm: &mut Swarm<FleygBehavior>,
+    key: Key,
+    deadline: Instant,
+    max: Option<usize>,
+    mut found: F,
+) where
+    F: FnMut(&mut Swarm<FleygBehavior>, PeerId),
+{
+    let _phase = memprofile::phase("query");
+    let query = swarm.behaviour_mut().kademlia.get_providers(key);
+    let progress = Progress::spinner("finding providers");
+    let mut seen = HashSet::new();
+    let mut finished = false;
+    while let Some(event) = next_before(swarm, deadline).await {
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::GetProviders(result),
+                step,
+                ..
+            },
+        )) = event
+        else {
+            continue;
+        };
+        if id != query {
+            continue;
+        }
+        match result {
+            Ok(GetProvidersOk::FoundProviders { providers, .. }) => {
+                for peer in providers {
+                    if seen.insert(peer) {
+                        found(swarm, peer);
+                    }
+                }
+                progress.set_message(format!("{} providers", seen.len()));
+            }
+            Ok(GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+            Err(e) => warn!("Finding providers failed: {e}"),
+        }
+        if step.last() {
+            finished = true;
+            break;
+        }
+        if max.is_some_and(|max| seen.len() >= max) {
+            break;
+        }
+    }
+    progress.finish();
+    if !finished {
+        if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&query) {
+            query.finish();
+        }
+    }
+}
+
+// a unix time for people
+fn format_time(secs: u64) -> String {
+    match Utc.timestamp_opt(secs as i64, 0).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => secs.to_string(),
+    }
+}
+
+fn decode_key(key: &str, keys: KeyEncoding) -> Result<Key, String> {
+    keys.decode(key)
+        .map(|k| Key::new(&k))
+        .map_err(|e| format!("bad key {key}: {e}"))
+}
+
+// the addresses of a peer in the routing table, or else the peer store
+fn known_addrs(
+    swarm: &mut Swarm<FleygBehavior>,
+    peers: &PeerStore,
+    peer: &PeerId,
+) -> Vec<Multiaddr> {
+    for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+        if let Some(entry) = bucket.iter().find(|e| e.node.key.preimage() == peer) {
+            return entry.node.value.iter().cloned().collect();
+        }
+    }
+    peers.get(peer).map(|r| r.addrs.clone()).unwrap_or_default()
+}
+
+fn join(addrs: &[Multiaddr]) -> String {
+    addrs
+        .iter()
+        .map(|a| a.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
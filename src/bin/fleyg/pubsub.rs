@@ -0,0 +1,247 @@
+use crate::FleygBehavior;
+#[cfg(feature = "pubsub")]
+use crate::{bootstrap, FleygBehaviorEvent};
+#[cfg(feature = "pubsub")]
+use fleyg::{
+    deadline::next_before,
+    event::{self, Event},
+    meshstats::MeshStats,
+    shutdown::Shutdown,
+    table::Table,
+};
+use fleyg::{encoding::Encoding, table::Output};
+use libp2p::Swarm;
+#[cfg(feature = "pubsub")]
+use libp2p::{
+    gossipsub::{self, IdentTopic, PublishError},
+    swarm::SwarmEvent,
+};
+#[cfg(feature = "pubsub")]
+use log::*;
+use std::error::Error;
+#[cfg(feature = "pubsub")]
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how often the meshes are sampled for GRAFTs and PRUNEs, gossipsub's heartbeat
+#[cfg(feature = "pubsub")]
+const SAMPLE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, StructOpt)]
+#[cfg_attr(not(feature = "pubsub"), allow(dead_code))]
+pub struct SubOpt {
+    /// the topics to subscribe to
+    #[structopt(required = true)]
+    topics: Vec<String>,
+
+    /// stop after receiving this many messages
+    #[structopt(long)]
+    count: Option<usize>,
+
+    /// seconds to listen for, until stopped with Ctrl-C if not given
+    #[structopt(long)]
+    timeout: Option<u64>,
+}
+
+#[derive(Debug, StructOpt)]
+#[cfg_attr(not(feature = "pubsub"), allow(dead_code))]
+pub struct PubOpt {
+    /// the topic to publish on
+    topic: String,
+
+    /// the message, in the --message-encoding
+    message: String,
+
+    /// how the message is encoded: hex, base64, base58 or raw
+    #[structopt(long, default_value = "raw")]
+    message_encoding: Encoding,
+
+    /// seconds to wait for peers subscribed to the topic to publish to
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+/// subscribe to topics and show every message received with the peer that sent it, and
+/// the GRAFTs and PRUNEs seen in each topic's mesh and from each peer once stopped
+#[cfg(feature = "pubsub")]
+pub async fn sub(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: SubOpt,
+    encoding: Encoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let deadline = opt
+        .timeout
+        .map(|secs| Instant::now() + Duration::from_secs(secs));
+    let behaviour = gossipsub(&mut swarm)?;
+    for topic in &opt.topics {
+        behaviour.subscribe(&IdentTopic::new(topic))?;
+    }
+    let bootstrapped = Instant::now() + Duration::from_secs(60);
+    bootstrap(
+        &mut swarm,
+        deadline.map_or(bootstrapped, |d| d.min(bootstrapped)),
+    )
+    .await?;
+    info!("Listening on {}", opt.topics.join(", "));
+    let shutdown = Shutdown::catch();
+
+    // messages received on each topic from each sender
+    let mut counts = BTreeMap::new();
+    let mut received = 0;
+    let mut meshes = MeshStats::default();
+    let mut sample = Instant::now() + SAMPLE;
+    // the meshes are sampled often enough to notice a shutdown
+    while !shutdown.requested() {
+        let event = next_before(&mut swarm, deadline.map_or(sample, |d| d.min(sample))).await;
+        if Instant::now() >= sample {
+            let behaviour = gossipsub(&mut swarm)?;
+            for topic in &opt.topics {
+                let hash = IdentTopic::new(topic).hash();
+                meshes.sample(topic, behaviour.mesh_peers(&hash).copied());
+            }
+            sample += SAMPLE;
+        }
+        let Some(event) = event else {
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => break,
+                _ => continue,
+            }
+        };
+        let SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(event)) = event else {
+            continue;
+        };
+        match event {
+            gossipsub::Event::Message {
+                propagation_source,
+                message,
+                ..
+            } => {
+                let sender = message.source.unwrap_or(propagation_source);
+                let data = encoding.encode(&message.data);
+                info!("{} from {sender}: {data}", message.topic);
+                event::emit(
+                    Event::new("gossipsub_message", Some(sender))
+                        .with("topic", message.topic.as_str())
+                        .with("via", propagation_source)
+                        .with("data", &data),
+                );
+                *counts
+                    .entry((message.topic.to_string(), sender.to_string()))
+                    .or_insert(0) += 1;
+                received += 1;
+                if opt.count.is_some_and(|count| received >= count) {
+                    break;
+                }
+            }
+            gossipsub::Event::Subscribed { peer_id, topic } => {
+                debug!("{peer_id} subscribed to {topic}")
+            }
+            gossipsub::Event::Unsubscribed { peer_id, topic } => {
+                debug!("{peer_id} unsubscribed from {topic}")
+            }
+            gossipsub::Event::GossipsubNotSupported { .. } => {}
+        }
+    }
+
+    let mut table = Table::new(&["topic", "sender", "messages"]);
+    for ((topic, sender), count) in counts {
+        table.push(vec![topic.into(), sender.into(), count.to_string().into()]);
+    }
+    output.print(table)?;
+
+    // libp2p 0.52 only shows us the meshes, see fleyg::meshstats
+    info!(
+        "GRAFTs and PRUNEs seen in the meshes, IWANTs are only counted per topic in the \
+         gossipsub metrics and IHAVEs and IDONTWANTs not at all"
+    );
+    let mut table = Table::new(&["topic", "peer", "grafts", "prunes"]);
+    for (topic, churn) in meshes.topics() {
+        table.push(vec![
+            topic.into(),
+            "".into(),
+            churn.grafts.to_string().into(),
+            churn.prunes.to_string().into(),
+        ]);
+    }
+    for (peer, churn) in meshes.peers() {
+        table.push(vec![
+            "".into(),
+            peer.to_string().into(),
+            churn.grafts.to_string().into(),
+            churn.prunes.to_string().into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+/// publish a message on a topic once a peer subscribed to it is connected
+#[cfg(feature = "pubsub")]
+pub async fn publish(mut swarm: Swarm<FleygBehavior>, opt: PubOpt) -> Result<(), Box<dyn Error>> {
+    let data = opt
+        .message_encoding
+        .decode(&opt.message)
+        .map_err(|e| format!("bad message: {e}"))?;
+    let topic = IdentTopic::new(&opt.topic);
+    // subscribing tells our peers we're in the topic's mesh so they take what we publish
+    gossipsub(&mut swarm)?.subscribe(&topic)?;
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, deadline).await?;
+
+    info!("Waiting for peers subscribed to {topic}...");
+    loop {
+        match gossipsub(&mut swarm)?.publish(topic.clone(), data.clone()) {
+            Ok(id) => {
+                info!("Published {id} on {topic}");
+                return Ok(());
+            }
+            Err(PublishError::InsufficientPeers) => {}
+            Err(e) => return Err(e.into()),
+        }
+        // try again whenever a peer joins the topic
+        loop {
+            match next_before(&mut swarm, deadline).await {
+                Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(
+                    gossipsub::Event::Subscribed { peer_id, topic: t },
+                ))) if t == topic.hash() => {
+                    debug!("{peer_id} subscribed to {topic}");
+                    break;
+                }
+                Some(_) => {}
+                None => return Err(format!("no peers subscribed to {topic} found").into()),
+            }
+        }
+    }
+}
+
+// the swarm's gossipsub, the command turned it on
+#[cfg(feature = "pubsub")]
+fn gossipsub(swarm: &mut Swarm<FleygBehavior>) -> Result<&mut gossipsub::Behaviour, String> {
+    swarm
+        .behaviour_mut()
+        .gossipsub
+        .as_mut()
+        .ok_or_else(|| "gossipsub isn't enabled".to_string())
+}
+
+#[cfg(not(feature = "pubsub"))]
+pub async fn sub(
+    _swarm: Swarm<FleygBehavior>,
+    _opt: SubOpt,
+    _encoding: Encoding,
+    _output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+#[cfg(not(feature = "pubsub"))]
+pub async fn publish(_swarm: Swarm<FleygBehavior>, _opt: PubOpt) -> Result<(), Box<dyn Error>> {
+    Err(UNSUPPORTED.into())
+}
+
+#[cfg(not(feature = "pubsub"))]
+const UNSUPPORTED: &str = "fleyg was built without the pubsub feature";
@@ -0,0 +1,213 @@
+use crate::{bootstrap, put_acked, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    blocks::{BlockId, BlockStore},
+    deadline::{idle_until, next_before},
+    encoding::Encoding,
+    keys::KeyEncoding,
+    pointer::{Manifest, Pointer, MAX_INLINE},
+    progress::Progress,
+    receipt::{parse_quorum, Receipt},
+    retry::RetryPolicy,
+    state::StateDir,
+    table::{Output, Table},
+};
+use libp2p::{
+    identity::Keypair,
+    kad::{
+        record::{Key, Record},
+        KademliaEvent, QueryResult, Quorum,
+    },
+    swarm::SwarmEvent,
+    Swarm,
+};
+use log::*;
+use std::{
+    error::Error,
+    fs,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct PutOpt {
+    /// the record key, in the --key-encoding
+    key: String,
+
+    /// the value, in the --value-encoding
+    #[structopt(required_unless_one = &["file", "large"])]
+    value: Option<String>,
+
+    /// read the value from this file instead
+    #[structopt(long, parse(from_os_str), conflicts_with = "value")]
+    file: Option<PathBuf>,
+
+    /// store this file as blocks we serve, with a signed pointer to them as the record,
+    /// for values too large for a record. The blocks are served on fleyg's own
+    /// /fleyg/blocks/1.0.0 protocol, which isn't bitswap compatible, so only fleyg get
+    /// --follow can fetch them
+    #[structopt(long, parse(from_os_str), conflicts_with_all = &["value", "file"])]
+    large: Option<PathBuf>,
+
+    /// how the key is encoded: hex, base64, base58, base32, multibase, cid or raw
+    /// [default: the --key-encoding]
+    #[structopt(long)]
+    key_encoding: Option<KeyEncoding>,
+
+    /// how the value is encoded: hex, base64, base58 or raw
+    #[structopt(long, default_value = "raw")]
+    value_encoding: Encoding,
+
+    /// how many of the closest peers have to store the record: one, majority, all or a
+    /// number
+    #[structopt(long, default_value = "one", parse(try_from_str = parse_quorum))]
+    quorum: Quorum,
+
+    /// write a signed receipt of the peers that acknowledged the record to this file
+    #[structopt(long, parse(from_os_str))]
+    receipt: Option<PathBuf>,
+
+    /// seconds to spend on each attempt at storing the record
+    #[structopt(long, default_value = "60")]
+    timeout: u64,
+}
+
+/// store a record on the peers closest to its key and show which acknowledged it
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: PutOpt,
+    key: &Keypair,
+    state: &StateDir,
+    retry: RetryPolicy,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let record_key = opt
+        .key_encoding
+        .unwrap_or(keys)
+        .decode(&opt.key)
+        .map_err(|e| format!("bad key {}: {e}", opt.key))?;
+    let mut root = None;
+    let value = match (&opt.large, &opt.file, &opt.value) {
+        (Some(path), _, _) => {
+            let (id, pointer) = store_blocks(&fs::read(path)?, key, &record_key, state)?;
+            root = Some(id);
+            pointer.to_value()
+        }
+        (None, Some(path), _) => fs::read(path)?,
+        (None, None, Some(value)) => opt
+            .value_encoding
+            .decode(value)
+            .map_err(|e| format!("bad value: {e}"))?,
+        (None, None, None) => unreachable!(),
+    };
+    if value.len() > MAX_INLINE {
+        return Err(format!(
+            "the value is {} bytes, more than a record holds ({MAX_INLINE}), put it with --large",
+            value.len()
+        )
+        .into());
+    }
+    let record = Record::new(Key::new(&record_key), value);
+    let timeout = Duration::from_secs(opt.timeout);
+    bootstrap(&mut swarm, Instant::now() + timeout).await?;
+
+    let mut attempts = 1;
+    let acks = loop {
+        let deadline = Instant::now() + timeout;
+        let (failure, error) =
+            match put_acked(&mut swarm, record.clone(), opt.quorum, deadline).await {
+                Ok(acks) => break acks,
+                Err(failed) => failed,
+            };
+        match retry.retry(attempts, failure) {
+            Some(delay) => {
+                info!("Storing the record failed: {error}, retrying in {delay:?}");
+                idle_until(&mut swarm, Instant::now() + delay).await;
+                attempts += 1;
+            }
+            None => return Err(format!("storing the record failed: {error}").into()),
+        }
+    };
+
+    info!(
+        "Stored {} on {} peers",
+        keys.encode(&record_key),
+        acks.len()
+    );
+    let mut table = Table::new(&["peer", "at"]);
+    for ack in &acks {
+        table.push(vec![ack.peer.to_string().into(), ack.at.to_string().into()]);
+    }
+    if let Some(path) = &opt.receipt {
+        Receipt::sign(key, &record_key, &record.value, acks).save(path)?;
+        info!("Wrote the receipt to {}", path.display());
+    }
+    if let Some(root) = root {
+        provide(&mut swarm, root, Instant::now() + timeout).await;
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// split a value into blocks in our store, returning its root and a pointer to it
+fn store_blocks(
+    value: &[u8],
+    key: &Keypair,
+    record_key: &[u8],
+    state: &StateDir,
+) -> Result<(BlockId, Pointer), Box<dyn Error>> {
+    let (manifest, chunks) = Manifest::split(value)?;
+    let store = BlockStore::open(state.blocks_dir())?;
+    for chunk in &chunks {
+        store.put(chunk)?;
+    }
+    let root = store.put(&manifest)?;
+    store.add_root(&root)?;
+    info!(
+        "Stored {} bytes as {} blocks under {}",
+        value.len(),
+        chunks.len() + 1,
+        hex::encode(root)
+    );
+    Ok((root, Pointer::sign(key, record_key, root, value)))
+}
+
+// announce we provide a value's blocks, serve keeps announcing them
+async fn provide(swarm: &mut Swarm<FleygBehavior>, root: BlockId, deadline: Instant) {
+    let query = match swarm
+        .behaviour_mut()
+        .kademlia
+        .start_providing(Key::new(&root))
+    {
+        Ok(query) => query,
+        Err(e) => {
+            warn!("Can't provide the value's blocks: {e}");
+            return;
+        }
+    };
+    let progress = Progress::spinner("announcing the blocks");
+    let mut result = None;
+    while let Some(event) = next_before(swarm, deadline).await {
+        if let SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+            KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: QueryResult::StartProviding(r),
+                ..
+            },
+        )) = event
+        {
+            if id == query {
+                result = Some(r);
+                break;
+            }
+        }
+    }
+    progress.finish();
+    match result {
+        Some(Ok(_)) => info!("Announced the value's blocks"),
+        Some(Err(e)) => warn!("Announcing the value's blocks failed: {e}"),
+        None => warn!("Timed out announcing the value's blocks"),
+    }
+    info!("Peers can fetch the value while fleyg serves with this state directory");
+}
@@ -0,0 +1,355 @@
+use crate::{add_lan_peers, bootstrap, FleygBehavior, FleygBehaviorEvent};
+use fleyg::{
+    encoding::Encoding,
+    keys::KeyEncoding,
+    peerstore::PeerStore,
+    table::{Cell, Output, Table},
+};
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    prelude::*,
+};
+use libp2p::{
+    identify,
+    kad::{
+        record::{Key, Record},
+        GetRecordOk, KademliaEvent, QueryId, QueryResult, Quorum,
+    },
+    ping,
+    swarm::{dial_opts::DialOpts, ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use log::*;
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    io, thread,
+    time::{Duration, Instant},
+};
+
+/// how long to spend bootstrapping before taking commands
+const BOOTSTRAP_TIMEOUT: Duration = Duration::from_secs(60);
+
+const HELP: &str = "commands:
+  dial <addr|peer id>    connect to a peer
+  get <key>              look up a record, key in the --key-encoding
+  put <key> <value>      store a record, the value as typed
+  peers                  list the connected peers
+  table                  list the routing table and which peers are connected
+  help                   show this
+  quit                   stop the node";
+
+/// A command typed into the REPL
+#[derive(Debug, PartialEq, Eq)]
+enum Line {
+    Dial(DialTarget),
+    Get(String),
+    Put(String, String),
+    Peers,
+    Table,
+    Help,
+    Quit,
+    Blank,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum DialTarget {
+    Addr(Multiaddr),
+    Peer(PeerId),
+}
+
+impl Line {
+    fn parse(line: &str) -> Result<Self, String> {
+        let line = line.trim();
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        match (word, rest) {
+            ("", _) => Ok(Line::Blank),
+            ("dial", "") => Err("usage: dial <addr|peer id>".into()),
+            ("dial", target) => match target.parse::<PeerId>() {
+                Ok(peer) => Ok(Line::Dial(DialTarget::Peer(peer))),
+                Err(_) => target
+                    .parse()
+                    .map(|addr| Line::Dial(DialTarget::Addr(addr)))
+                    .map_err(|e| format!("bad address {target}: {e}")),
+            },
+            ("get", "") => Err("usage: get <key>".into()),
+            ("get", key) => Ok(Line::Get(key.into())),
+            ("put", rest) => match rest.split_once(char::is_whitespace) {
+                Some((key, value)) => Ok(Line::Put(key.into(), value.trim_start().into())),
+                None => Err("usage: put <key> <value>".into()),
+            },
+            ("peers", _) => Ok(Line::Peers),
+            ("table", _) => Ok(Line::Table),
+            ("help", _) | ("?", _) => Ok(Line::Help),
+            ("quit", _) | ("exit", _) => Ok(Line::Quit),
+            (word, _) => Err(format!(
+                "unknown command {word}, type help for the commands"
+            )),
+        }
+    }
+}
+
+/// What's next, a typed line or a swarm event
+enum Next<E> {
+    Line(Option<String>),
+    Event(E),
+}
+
+/// The commands waiting on events
+struct Repl {
+    peers: PeerStore,
+    encoding: Encoding,
+    keys: KeyEncoding,
+    /// the dials started, by what was typed
+    dials: HashMap<ConnectionId, String>,
+    /// the record lookups, by key with the values shown so far
+    gets: HashMap<QueryId, (Vec<u8>, HashSet<Vec<u8>>)>,
+    puts: HashMap<QueryId, Vec<u8>>,
+    /// the address of each peer connected since we took commands
+    connected: HashMap<PeerId, Multiaddr>,
+}
+
+/// keep the node running, taking commands on stdin and printing their results as the
+/// events come in, until stdin closes or quit
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    peers: PeerStore,
+    encoding: Encoding,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    if let Err(e) = bootstrap(&mut swarm, Instant::now() + BOOTSTRAP_TIMEOUT).await {
+        warn!("Bootstrapping failed: {e}, dial a peer to join the DHT");
+    }
+
+    // stdin blocks, read it on its own thread
+    let (send, mut lines) = mpsc::unbounded();
+    thread::spawn(move || {
+        for line in io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if send.unbounded_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    info!("Ready, type help for the commands");
+
+    let mut repl = Repl {
+        peers,
+        encoding,
+        keys,
+        dials: HashMap::new(),
+        gets: HashMap::new(),
+        puts: HashMap::new(),
+        connected: HashMap::new(),
+    };
+    loop {
+        let next = match future::select(lines.next(), swarm.select_next_some()).await {
+            Either::Left((line, _)) => Next::Line(line),
+            Either::Right((event, _)) => Next::Event(event),
+        };
+        match next {
+            Next::Line(None) => break,
+            Next::Line(Some(line)) => match Line::parse(&line) {
+                Ok(Line::Quit) => break,
+                Ok(line) => repl.command(&mut swarm, line, output)?,
+                Err(e) => warn!("{e}"),
+            },
+            Next::Event(event) => repl.event(&mut swarm, event, output)?,
+        }
+        repl.peers.maybe_save()?;
+    }
+
+    info!("Stopping");
+    repl.peers.save()?;
+    Ok(())
+}
+
+impl Repl {
+    // start what a typed command asks for
+    fn command(
+        &mut self,
+        swarm: &mut Swarm<FleygBehavior>,
+        line: Line,
+        output: &Output,
+    ) -> Result<(), String> {
+        match line {
+            Line::Blank | Line::Quit => {}
+            Line::Help => println!("{HELP}"),
+            Line::Dial(target) => {
+                let (opts, name) = match target {
+                    DialTarget::Addr(addr) => (DialOpts::from(addr.clone()), addr.to_string()),
+                    DialTarget::Peer(peer) => (DialOpts::peer_id(peer).build(), peer.to_string()),
+                };
+                let id = opts.connection_id();
+                match swarm.dial(opts) {
+                    Ok(()) => {
+                        info!("Dialing {name}...");
+                        self.dials.insert(id, name);
+                    }
+                    Err(e) => warn!("Can't dial {name}: {e}"),
+                }
+            }
+            Line::Get(key) => match self.keys.decode(&key) {
+                Ok(key) => {
+                    let query = swarm.behaviour_mut().kademlia.get_record(Key::new(&key));
+                    self.gets.insert(query, (key, HashSet::new()));
+                }
+                Err(e) => warn!("Bad key {key}: {e}"),
+            },
+            Line::Put(key, value) => match self.keys.decode(&key) {
+                Ok(key) => {
+                    let record = Record::new(Key::new(&key), value.into_bytes());
+                    let kademlia = &mut swarm.behaviour_mut().kademlia;
+                    match kademlia.put_record(record, Quorum::One) {
+                        Ok(query) => {
+                            self.puts.insert(query, key);
+                        }
+                        Err(e) => warn!("Can't store the record: {e}"),
+                    }
+                }
+                Err(e) => warn!("Bad key {key}: {e}"),
+            },
+            Line::Peers => {
+                let mut table = Table::new(&["id", "addr", "agent", "rtt"]);
+                for peer in swarm.connected_peers() {
+                    let addr = self.connected.get(peer).map(|a| a.to_string());
+                    let record = self.peers.get(peer);
+                    let agent = record.and_then(|r| r.agent.clone()).unwrap_or_default();
+                    let rtt = record
+                        .and_then(|r| r.rtt)
+                        .map(|rtt| format!("{}ms", rtt.as_millis()))
+                        .unwrap_or_default();
+                    table.push(vec![
+                        peer.to_string().into(),
+                        addr.unwrap_or_default().into(),
+                        agent.into(),
+                        rtt.into(),
+                    ]);
+                }
+                output.print(table)?;
+            }
+            Line::Table => {
+                let mut entries = Vec::new();
+                for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+                    let index = bucket.range().0.ilog2().unwrap_or_default();
+                    for entry in bucket.iter() {
+                        let addrs: Vec<String> =
+                            entry.node.value.iter().map(|a| a.to_string()).collect();
+                        entries.push((index, *entry.node.key.preimage(), addrs));
+                    }
+                }
+                let mut table = Table::new(&["bucket", "id", "status", "addrs"]);
+                for (index, peer, addrs) in entries {
+                    let status = match swarm.is_connected(&peer) {
+                        true => Cell::good("connected"),
+                        false => Cell::warn("disconnected"),
+                    };
+                    table.push(vec![
+                        index.to_string().into(),
+                        peer.to_string().into(),
+                        status,
+                        addrs.join(" ").into(),
+                    ]);
+                }
+                output.print(table)?;
+            }
+        }
+        Ok(())
+    }
+
+    // print what an event means for the commands waiting on it
+    fn event<E>(
+        &mut self,
+        swarm: &mut Swarm<FleygBehavior>,
+        event: SwarmEvent<FleygBehaviorEvent, E>,
+        output: &Output,
+    ) -> Result<(), String> {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                let addr = endpoint.get_remote_address();
+                if let Some(name) = self.dials.remove(&connection_id) {
+                    info!("Connected to {peer_id} at {addr} (dialed {name})");
+                    self.peers.record_dial(peer_id, addr, true);
+                }
+                self.connected.insert(peer_id, addr.clone());
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            } => {
+                self.connected.remove(&peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                error,
+                ..
+            } => {
+                if let Some(name) = self.dials.remove(&connection_id) {
+                    warn!("Dialing {name} failed: {error}");
+                }
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => self.peers.entry(peer_id).update(&info),
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            })) => self.peers.entry(peer).rtt = Some(rtt),
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Mdns(event)) => {
+                add_lan_peers(swarm, event);
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(
+                KademliaEvent::OutboundQueryProgressed {
+                    id, result, step, ..
+                },
+            )) => match result {
+                QueryResult::GetRecord(result) => {
+                    let Some((key, shown)) = self.gets.get_mut(&id) else {
+                        return Ok(());
+                    };
+                    // show each different value once, as peers return it
+                    if let Ok(GetRecordOk::FoundRecord(found)) = result {
+                        if shown.insert(found.record.value.clone()) {
+                            let mut table = Table::new(&["key", "value", "from"]);
+                            table.push(vec![
+                                self.keys.encode(key).into(),
+                                self.encoding.encode(&found.record.value).into(),
+                                found.peer.map(|p| p.to_string()).unwrap_or_default().into(),
+                            ]);
+                            output.print(table)?;
+                        }
+                    }
+                    if step.last() && shown.is_empty() {
+                        warn!("{} isn't in the DHT", self.keys.encode(key));
+                    }
+                    if step.last() {
+                        self.gets.remove(&id);
+                    }
+                }
+                QueryResult::PutRecord(result) => {
+                    if let Some(key) = self.puts.remove(&id) {
+                        let key = self.keys.encode(&key);
+                        match result {
+                            Ok(_) => info!("Stored {key}"),
+                            Err(e) => warn!("Storing {key} failed: {e}"),
+                        }
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+}
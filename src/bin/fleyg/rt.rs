@@ -0,0 +1,165 @@
+use fleyg::{
+    keys::KeyEncoding,
+    keyspace::{prefix, record_hash, RangeShare, Responsibility},
+    routing::SnapshotDir,
+    state::StateDir,
+    table::{Cell, Output, Table},
+    timespec::parse_time,
+    wal,
+};
+use libp2p::PeerId;
+use log::*;
+use std::{error::Error, path::PathBuf, time::SystemTime};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum RtOpt {
+    /// show the peers added and removed per bucket between two snapshots
+    Diff {
+        /// the directory holding the routing table snapshots, defaults to the one in the
+        /// state directory
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// the earlier time: unix seconds, RFC 3339, or a duration ago like "6h"
+        t1: String,
+
+        /// the later time, defaults to the latest snapshot
+        #[structopt(default_value = "now")]
+        t2: String,
+    },
+
+    /// estimate the key ranges we are among the k closest peers for, and list the stored
+    /// records outside them
+    Responsibility {
+        /// the directory holding the routing table snapshots, defaults to the one in the
+        /// state directory
+        #[structopt(long, parse(from_os_str))]
+        dir: Option<PathBuf>,
+
+        /// the snapshot to use: unix seconds, RFC 3339, or a duration ago like "6h"
+        #[structopt(long, default_value = "now")]
+        at: String,
+
+        /// the node whose responsibility to estimate, defaults to ours
+        #[structopt(long)]
+        peer: Option<PeerId>,
+
+        /// the replication factor
+        #[structopt(long, default_value = "20")]
+        k: usize,
+
+        /// split the keyspace into 2^bits ranges
+        #[structopt(long, default_value = "8")]
+        bits: u8,
+
+        /// keys sampled in each range
+        #[structopt(long, default_value = "16")]
+        samples: usize,
+
+        /// the record store to check, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        store: Option<PathBuf>,
+    },
+}
+
+pub fn run(
+    state: &StateDir,
+    opt: RtOpt,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    match opt {
+        RtOpt::Diff { dir, t1, t2 } => {
+            let snapshots = SnapshotDir::open(dir.unwrap_or_else(|| state.rt_dir()))?;
+            let before = snapshots.load_at(parse_time(&t1)?)?;
+            let after = snapshots.load_at(parse_time(&t2)?)?;
+            let diff = before.diff(&after);
+
+            info!(
+                "Routing table diff {} -> {} ({} -> {} peers)",
+                diff.from,
+                diff.to,
+                before.len(),
+                after.len()
+            );
+            let mut table = Table::new(&["bucket", "change", "id"]);
+            for (index, change) in &diff.buckets {
+                for peer in &change.added {
+                    table.push(vec![
+                        index.to_string().into(),
+                        Cell::good("+"),
+                        peer.to_string().into(),
+                    ]);
+                }
+                for peer in &change.removed {
+                    table.push(vec![
+                        index.to_string().into(),
+                        Cell::bad("-"),
+                        peer.to_string().into(),
+                    ]);
+                }
+            }
+            output.print(table)?;
+            info!("Churn: {:.1}%", diff.churn * 100.0);
+        }
+        RtOpt::Responsibility {
+            dir,
+            at,
+            peer,
+            k,
+            bits,
+            samples,
+            store,
+        } => {
+            let snapshots = SnapshotDir::open(dir.unwrap_or_else(|| state.rt_dir()))?;
+            let snapshot = snapshots.load_at(parse_time(&at)?)?;
+            let local = match peer {
+                Some(peer) => peer,
+                None => state.keypair(None)?.public().to_peer_id(),
+            };
+            let responsibility =
+                Responsibility::new(&local, snapshot.buckets.values().flatten(), k);
+            let ranges = responsibility.ranges(bits, samples);
+            let total = ranges.iter().map(|r| r.share).sum::<f64>() / ranges.len() as f64;
+            info!(
+                "{local} is among the {k} closest of {} known peers for about {:.2}% of the keyspace",
+                snapshot.len(),
+                total * 100.0
+            );
+            let mut table = Table::new(&["range", "share"]);
+            for range in ranges.iter().filter(|r| r.share > 0.0) {
+                table.push(vec![range.label().into(), share_cell(range)]);
+            }
+            output.print(table)?;
+
+            let path = store.unwrap_or_else(|| state.records_dir().join(wal::FILE_NAME));
+            let now = SystemTime::now();
+            let records = wal::scan(&path)?.records();
+            let mut table = Table::new(&["key", "range"]);
+            for record in records.values().filter(|r| !r.is_expired(now)) {
+                let hash = record_hash(&record.key);
+                if responsibility.is_responsible(&hash) {
+                    continue;
+                }
+                let range = &ranges[prefix(&hash, bits) as usize];
+                table.push(vec![keys.encode(&record.key).into(), range.label().into()]);
+            }
+            if !records.is_empty() {
+                info!("Stored records outside our responsibility, candidates for eviction:");
+                output.print(table)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// the share colored by how much of the range is ours
+fn share_cell(range: &RangeShare) -> Cell {
+    let text = format!("{:.0}%", range.share * 100.0);
+    if range.share >= 0.5 {
+        Cell::good(text)
+    } else {
+        Cell::warn(text)
+    }
+}
@@ -0,0 +1,269 @@
+use fleyg::state::StateDir;
+use log::*;
+use std::{
+    error::Error,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+/// the name services are registered under by default
+const DEFAULT_NAME: &str = "fleyg";
+
+#[derive(Debug, StructOpt)]
+pub enum ServiceOpt {
+    /// register a windows service that runs this node, with its config and state directory
+    Install {
+        /// the service's name, for running several nodes
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        name: String,
+
+        /// only start it when asked to, instead of at boot
+        #[structopt(long)]
+        manual: bool,
+    },
+
+    /// stop and remove a service
+    Uninstall {
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        name: String,
+    },
+
+    /// what the service manager starts, not run by hand
+    Run {
+        #[structopt(long, default_value = DEFAULT_NAME)]
+        name: String,
+    },
+}
+
+pub fn run(state: &StateDir, config: &Path, opt: ServiceOpt) -> Result<(), Box<dyn Error>> {
+    // the service runs as another account, with its own idea of where the config and the
+    // state directory are, so it's told where ours are
+    let node = vec![
+        OsString::from("--config"),
+        absolute(config)?.into(),
+        "--data-dir".into(),
+        absolute(state.root())?.into(),
+    ];
+    match opt {
+        ServiceOpt::Install { name, manual } => {
+            let mut args = node;
+            args.extend([
+                "service".into(),
+                "run".into(),
+                "--name".into(),
+                (&name).into(),
+            ]);
+            platform::install(&name, args, !manual)?;
+            info!(
+                "Installed the {name} service for {}, start it with `sc start {name}`",
+                state.root().display()
+            );
+        }
+        ServiceOpt::Uninstall { name } => {
+            platform::uninstall(&name)?;
+            info!("Removed the {name} service");
+        }
+        ServiceOpt::Run { name } => {
+            let log = state.logs_dir().join(format!("{name}.log"));
+            // as the daemon, so fleyg ctl reaches the node on the state directory's pipe
+            let mut args = node;
+            args.push("daemon".into());
+            platform::run(&name, args, log)?;
+        }
+    }
+    Ok(())
+}
+
+// a path that means the same thing from any working directory
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    match path.is_absolute() {
+        true => Ok(path.to_path_buf()),
+        false => Ok(std::env::current_dir()?.join(path)),
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use log::*;
+    use std::{
+        error::Error,
+        ffi::OsString,
+        fs::OpenOptions,
+        path::PathBuf,
+        process::{Command, Stdio},
+        sync::{mpsc, OnceLock},
+        time::Duration,
+    };
+    use windows_service::{
+        define_windows_service,
+        service::{
+            ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl,
+            ServiceExitCode, ServiceInfo, ServiceStartType, ServiceState, ServiceStatus,
+            ServiceType,
+        },
+        service_control_handler::{self, ServiceControlHandlerResult},
+        service_dispatcher,
+        service_manager::{ServiceManager, ServiceManagerAccess},
+    };
+
+    const SERVICE_TYPE: ServiceType = ServiceType::OWN_PROCESS;
+
+    /// how often the service checks on the node
+    const POLL: Duration = Duration::from_secs(1);
+
+    /// What the service runs, set before the process is handed to the service manager
+    struct Node {
+        name: String,
+        args: Vec<OsString>,
+        log: PathBuf,
+    }
+
+    static NODE: OnceLock<Node> = OnceLock::new();
+
+    pub fn install(name: &str, args: Vec<OsString>, at_boot: bool) -> Result<(), Box<dyn Error>> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let info = ServiceInfo {
+            name: name.into(),
+            display_name: format!("fleyg node ({name})").into(),
+            service_type: SERVICE_TYPE,
+            start_type: match at_boot {
+                true => ServiceStartType::AutoStart,
+                false => ServiceStartType::OnDemand,
+            },
+            error_control: ServiceErrorControl::Normal,
+            executable_path: std::env::current_exe()?,
+            launch_arguments: args,
+            dependencies: Vec::new(),
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description("A fleyg DHT node")?;
+        Ok(())
+    }
+
+    pub fn uninstall(name: &str) -> Result<(), Box<dyn Error>> {
+        let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+        let service = manager.open_service(
+            name,
+            ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+        )?;
+        if service.query_status()?.current_state != ServiceState::Stopped {
+            service.stop()?;
+        }
+        service.delete()?;
+        Ok(())
+    }
+
+    pub fn run(name: &str, args: Vec<OsString>, log: PathBuf) -> Result<(), Box<dyn Error>> {
+        let node = Node {
+            name: name.to_string(),
+            args,
+            log,
+        };
+        NODE.set(node)
+            .map_err(|_| "the service is already running")?;
+        service_dispatcher::start(name, ffi_service_main)?;
+        Ok(())
+    }
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    fn service_main(_arguments: Vec<OsString>) {
+        if let Err(e) = supervise() {
+            error!("The service failed: {e}");
+        }
+    }
+
+    // run the node as a child until it exits or the service is stopped
+    fn supervise() -> Result<(), Box<dyn Error>> {
+        let node = NODE.get().ok_or("the service wasn't set up")?;
+        let (stop, stopped) = mpsc::channel();
+        let handler = move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = stop.send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        let status = service_control_handler::register(&node.name, handler)?;
+        let report = |state, controls_accepted, exit| {
+            status.set_service_status(ServiceStatus {
+                service_type: SERVICE_TYPE,
+                current_state: state,
+                controls_accepted,
+                exit_code: ServiceExitCode::Win32(exit),
+                checkpoint: 0,
+                wait_hint: Duration::default(),
+                process_id: None,
+            })
+        };
+
+        let child = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&node.log)
+            .and_then(|log| {
+                Command::new(std::env::current_exe()?)
+                    .args(&node.args)
+                    .stdin(Stdio::null())
+                    .stdout(log.try_clone()?)
+                    .stderr(log)
+                    .spawn()
+            });
+        let mut child = match child {
+            Ok(child) => child,
+            Err(e) => {
+                report(ServiceState::Stopped, ServiceControlAccept::empty(), 1)?;
+                return Err(e.into());
+            }
+        };
+        report(
+            ServiceState::Running,
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            0,
+        )?;
+        let exit = loop {
+            if stopped.recv_timeout(POLL).is_ok() {
+                let _ = child.kill();
+                child.wait()?;
+                break 0;
+            }
+            if let Some(exit) = child.try_wait()? {
+                break exit.code().map_or(1, |code| code as u32);
+            }
+        };
+        report(ServiceState::Stopped, ServiceControlAccept::empty(), exit)?;
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+mod platform {
+    use std::{error::Error, ffi::OsString, path::PathBuf};
+
+    const UNSUPPORTED: &str =
+        "fleyg service registers windows services, use a systemd unit or launchd job here";
+
+    pub fn install(
+        _name: &str,
+        _args: Vec<OsString>,
+        _at_boot: bool,
+    ) -> Result<(), Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn uninstall(_name: &str) -> Result<(), Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+
+    pub fn run(_name: &str, _args: Vec<OsString>, _log: PathBuf) -> Result<(), Box<dyn Error>> {
+        Err(UNSUPPORTED.into())
+    }
+}
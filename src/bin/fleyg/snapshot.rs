@@ -0,0 +1,54 @@
+use crate::FleygBehavior;
+use fleyg::{
+    bugreport::{BugReport, StateTracker},
+    config::Config,
+    deadline::next_before,
+};
+use libp2p::{identity::Keypair, Swarm};
+use log::*;
+use std::{
+    error::Error,
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct SnapshotOpt {
+    /// write the snapshot to this file instead of stdout
+    #[structopt(long, short, parse(from_os_str))]
+    out: Option<PathBuf>,
+
+    /// seconds to run the node for before taking the snapshot
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+}
+
+/// run the node for a while, keeping track of its connections and errors, then write a
+/// snapshot of its state to attach to a bug report
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: SnapshotOpt,
+    key: &Keypair,
+    config: &Config,
+) -> Result<(), Box<dyn Error>> {
+    let mut tracker = StateTracker::default();
+    match swarm.behaviour_mut().kademlia.bootstrap() {
+        Ok(_) => info!("Bootstrapping..."),
+        Err(e) => warn!("Can't bootstrap: {e}"),
+    }
+    let deadline = Instant::now() + Duration::from_secs(opt.timeout);
+    while let Some(event) = next_before(&mut swarm, deadline).await {
+        tracker.observe(&event);
+    }
+
+    let report = BugReport::capture(&mut swarm, key, config, &tracker);
+    match &opt.out {
+        Some(path) => {
+            report.save(path)?;
+            info!("Wrote a snapshot to {}", path.display());
+        }
+        None => println!("{}", serde_json::to_string_pretty(&report)?),
+    }
+    Ok(())
+}
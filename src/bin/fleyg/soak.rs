@@ -0,0 +1,192 @@
+use crate::{bootstrap, listen_on, FleygBehavior};
+use fleyg::{
+    deadline::next_before,
+    peerstore::now_secs,
+    shutdown::Shutdown,
+    soak::{Audit, HourReport, ProcessStats},
+    state::StateDir,
+    table::{Cell, Output, Table},
+};
+use libp2p::{kad::Mode, swarm::SwarmEvent, Multiaddr, Swarm};
+use log::*;
+use std::{
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how often the event loop is woken to measure how late it runs
+const TICK: Duration = Duration::from_secs(1);
+
+/// how long an hour is
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, StructOpt)]
+pub struct SoakOpt {
+    /// hours to keep the node running
+    #[structopt(long, default_value = "24")]
+    hours: u64,
+
+    /// seconds between self-audits
+    #[structopt(long, default_value = "60")]
+    audit_every: u64,
+
+    /// the json lines file for the hourly reports, a new one in the state directory's
+    /// logs if not given
+    #[structopt(long, parse(from_os_str))]
+    report: Option<PathBuf>,
+}
+
+pub async fn run(
+    mut swarm: Swarm<FleygBehavior>,
+    opt: SoakOpt,
+    listen: Vec<Multiaddr>,
+    state: &StateDir,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let report = match opt.report {
+        Some(path) => path,
+        None => {
+            fs::create_dir_all(state.logs_dir())?;
+            state.logs_dir().join(format!("soak-{}.jsonl", now_secs()))
+        }
+    };
+    swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
+    listen_on(&mut swarm, listen)?;
+    let started = Instant::now();
+    let end = started + Duration::from_secs(opt.hours * 60 * 60);
+    bootstrap(&mut swarm, Instant::now() + Duration::from_secs(60)).await?;
+    let shutdown = Shutdown::catch();
+    info!(
+        "Soaking for {} hours, reports go to {}",
+        opt.hours,
+        report.display()
+    );
+
+    let audit_every = Duration::from_secs(opt.audit_every.max(1));
+    let first = audit(&mut swarm);
+    let mut audits = vec![first];
+    let mut lag = Vec::new();
+    let mut events = 0;
+    let mut reports = Vec::new();
+    let mut next_audit = Instant::now() + audit_every;
+    let mut next_report = started + HOUR;
+    let mut tick = Instant::now() + TICK;
+
+    // the loop wakes every tick, often enough to notice a shutdown
+    while Instant::now() < end && !shutdown.requested() {
+        let Some(event) = next_before(&mut swarm, tick.min(end)).await else {
+            // the loop woke for its timer, how late it is shows how busy it was
+            let now = Instant::now();
+            lag.push(now.saturating_duration_since(tick));
+            tick = now + TICK;
+            if now >= next_audit {
+                audits.push(audit(&mut swarm));
+                next_audit = now + audit_every;
+            }
+            if now >= next_report {
+                let hourly =
+                    HourReport::new(reports.len() as u64 + 1, &first, &audits, &lag, events);
+                write(&report, hourly, &mut reports)?;
+                audits.clear();
+                lag.clear();
+                events = 0;
+                next_report += HOUR;
+            }
+            continue;
+        };
+        events += 1;
+        if let SwarmEvent::ListenerError { error, .. } = event {
+            warn!("Listener failed during the soak: {error}");
+        }
+    }
+
+    if shutdown.requested() {
+        info!("Soak stopped early");
+    }
+    if let Err(e) = swarm.behaviour_mut().kademlia.store_mut().flush() {
+        warn!("Can't flush the record store: {e}");
+    }
+    // the part of an hour left at the end
+    if !audits.is_empty() || !lag.is_empty() {
+        audits.push(audit(&mut swarm));
+        let hourly = HourReport::new(reports.len() as u64 + 1, &first, &audits, &lag, events);
+        write(&report, hourly, &mut reports)?;
+    }
+
+    let mut table = Table::new(&[
+        "hour", "rss", "growth", "fds", "rt", "conns", "lag", "events",
+    ]);
+    for r in &reports {
+        let growth = match r.rss_growth_bytes {
+            Some(b) if b > 64 * 1024 * 1024 => Cell::bad(mib(b)),
+            Some(b) => mib(b).into(),
+            None => "".into(),
+        };
+        table.push(vec![
+            r.hour.to_string().into(),
+            r.max_rss_bytes
+                .map(|b| mib(b as i64))
+                .unwrap_or_default()
+                .into(),
+            growth,
+            r.max_fds.map(|n| n.to_string()).unwrap_or_default().into(),
+            r.last.routing_table.to_string().into(),
+            r.last.connections.to_string().into(),
+            r.lag_max_ms
+                .map(|ms| format!("{ms:.0}ms"))
+                .unwrap_or_default()
+                .into(),
+            r.events.to_string().into(),
+        ]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+fn write(path: &Path, report: HourReport, reports: &mut Vec<HourReport>) -> io::Result<()> {
+    report.append(path)?;
+    info!("Soak hour {}: {}", report.hour, summary(&report));
+    reports.push(report);
+    Ok(())
+}
+
+fn audit(swarm: &mut Swarm<FleygBehavior>) -> Audit {
+    Audit {
+        at: now_secs(),
+        process: ProcessStats::sample(),
+        routing_table: swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .map(|b| b.num_entries())
+            .sum(),
+        connections: swarm.network_info().num_peers(),
+    }
+}
+
+fn summary(report: &HourReport) -> String {
+    format!(
+        "rss {}, {} fds, {} peers in the routing table, lag up to {:.0}ms",
+        report
+            .last
+            .process
+            .rss_bytes
+            .map(|b| mib(b as i64))
+            .unwrap_or_else(|| "unknown".to_string()),
+        report
+            .last
+            .process
+            .fds
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| "unknown".to_string()),
+        report.last.routing_table,
+        report.lag_max_ms.unwrap_or_default()
+    )
+}
+
+fn mib(bytes: i64) -> String {
+    format!("{:.1}MiB", bytes as f64 / (1024.0 * 1024.0))
+}
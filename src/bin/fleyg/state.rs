@@ -0,0 +1,52 @@
+use fleyg::{
+    state::{StateDir, PRUNABLE},
+    timespec::parse_duration,
+};
+use log::*;
+use std::{error::Error, time::Duration};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum StateOpt {
+    /// show the state directory and its disk usage
+    Show,
+
+    /// remove old crawl checkpoints, logs, sessions, routing table snapshots, bundles and
+    /// archived events
+    Prune {
+        /// remove files older than this
+        #[structopt(long, default_value = "30d", parse(try_from_str = parse_duration))]
+        older_than: Duration,
+
+        /// only prune these directories (crawl, logs, sessions, rt, bundles, events)
+        #[structopt(long, use_delimiter = true)]
+        only: Vec<String>,
+    },
+}
+
+pub fn run(state: &StateDir, opt: StateOpt) -> Result<(), Box<dyn Error>> {
+    match opt {
+        StateOpt::Show => {
+            info!("State directory: {}", state.root().display());
+            for usage in state.usage()? {
+                info!(
+                    "\t{:<12} {:>6} files {:>12} bytes",
+                    usage.name, usage.files, usage.bytes
+                );
+            }
+        }
+        StateOpt::Prune { older_than, only } => {
+            let dirs: Vec<&str> = if only.is_empty() {
+                PRUNABLE.to_vec()
+            } else {
+                only.iter().map(String::as_str).collect()
+            };
+            let (files, bytes) = state.prune(&dirs, older_than)?;
+            info!(
+                "Pruned {files} files ({bytes} bytes) older than {older_than:?} from {}",
+                dirs.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
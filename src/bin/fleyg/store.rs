@@ -0,0 +1,172 @@
+use fleyg::{
+    keys::KeyEncoding,
+    state::StateDir,
+    timespec::parse_duration,
+    wal::{self, Op, StoredRecord, Wal},
+};
+use log::*;
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub enum StoreOpt {
+    /// verify the record store's write-ahead log
+    Fsck {
+        /// the log to check, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        path: Option<PathBuf>,
+
+        /// drop an incomplete last commit instead of reporting it
+        #[structopt(long)]
+        repair: bool,
+    },
+
+    /// write the stored records out as json lines
+    Export {
+        /// the store's log, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        path: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        select: Select,
+
+        /// write to this file instead of stdout
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+    },
+
+    /// add records from an export to the store in a single commit, with the daemon stopped
+    Import {
+        /// the store's log, defaults to the one in the state directory
+        #[structopt(long, parse(from_os_str))]
+        path: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        select: Select,
+
+        /// the export to read
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+}
+
+/// Which records to export or import, expired records are always skipped
+#[derive(Debug, StructOpt)]
+pub struct Select {
+    /// only records whose key starts with this, in the --key-encoding
+    #[structopt(long)]
+    prefix: Option<String>,
+
+    /// only records the store received within this long, e.g. "1d"
+    #[structopt(long, parse(try_from_str = parse_duration))]
+    newer_than: Option<Duration>,
+}
+
+impl Select {
+    // a predicate for the selected records
+    fn matcher(&self, keys: KeyEncoding) -> Result<impl Fn(&StoredRecord) -> bool, String> {
+        let prefix = self
+            .prefix
+            .as_deref()
+            .map(|p| keys.decode(p))
+            .transpose()?
+            .unwrap_or_default();
+        let now = SystemTime::now();
+        let since = self
+            .newer_than
+            .map(|d| now.checked_sub(d).unwrap_or(UNIX_EPOCH));
+        Ok(move |r: &StoredRecord| {
+            r.key.starts_with(&prefix)
+                && !r.is_expired(now)
+                && since.is_none_or(|since| r.stored_since(since))
+        })
+    }
+}
+
+pub fn run(state: &StateDir, opt: StoreOpt, keys: KeyEncoding) -> Result<(), Box<dyn Error>> {
+    let default_path = || state.records_dir().join(wal::FILE_NAME);
+    match opt {
+        StoreOpt::Fsck { path, repair } => {
+            let path = path.unwrap_or_else(default_path);
+            let scan = wal::fsck(&path)?;
+            let records = scan.records();
+            let now = SystemTime::now();
+            let expired = records.values().filter(|r| r.is_expired(now)).count();
+            info!(
+                "{}: {} commits, {} operations, {} records ({expired} expired)",
+                path.display(),
+                scan.batches.len(),
+                scan.ops(),
+                records.len()
+            );
+            if scan.is_clean() {
+                info!("The store is consistent");
+            } else if repair {
+                Wal::open(&path)?;
+            } else {
+                return Err(format!(
+                    "{} bytes after offset {} are not a complete commit, run with --repair to drop them",
+                    scan.file_len - scan.valid_len,
+                    scan.valid_len
+                )
+                .into());
+            }
+        }
+        StoreOpt::Export { path, select, out } => {
+            let path = path.unwrap_or_else(default_path);
+            let matches = select.matcher(keys)?;
+            let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match &out {
+                Some(out) => Box::new(File::create(out)?),
+                None => Box::new(io::stdout()),
+            });
+            let mut exported = 0;
+            for record in wal::fsck(&path)?.records().values() {
+                if matches(record) {
+                    serde_json::to_writer(&mut writer, record)?;
+                    writer.write_all(b"\n")?;
+                    exported += 1;
+                }
+            }
+            writer.flush()?;
+            info!("Exported {exported} records from {}", path.display());
+        }
+        StoreOpt::Import { path, select, file } => {
+            let path = path.unwrap_or_else(default_path);
+            // the daemon compacts its log to the records it holds, which would drop ours
+            if path == default_path() && state.daemon_running() {
+                return Err("the daemon owns the store, stop it before importing".into());
+            }
+            let matches = select.matcher(keys)?;
+            let batch: Vec<Op> = read_export(&file)?
+                .into_iter()
+                .filter(|r| matches(r))
+                .map(Op::Put)
+                .collect();
+            let (mut wal, _) = Wal::open(&path)?;
+            wal.commit(&batch)?;
+            info!("Imported {} records into {}", batch.len(), path.display());
+        }
+    }
+    Ok(())
+}
+
+// read the records from an export
+fn read_export(path: &Path) -> Result<Vec<StoredRecord>, Box<dyn Error>> {
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line)
+            .map_err(|e| format!("{}:{}: {e}", path.display(), n + 1))?;
+        records.push(record);
+    }
+    Ok(records)
+}
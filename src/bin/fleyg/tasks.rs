@@ -0,0 +1,234 @@
+use fleyg::{
+    peerstore::now_secs,
+    schedule::{self, Scheduler, Task, TaskStatus},
+    state::StateDir,
+    table::{Cell, Output, Table},
+};
+use log::*;
+use std::{
+    collections::HashMap,
+    error::Error,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+
+/// how often serve checks on the tasks it's running
+const TASK_POLL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, StructOpt)]
+pub struct TasksOpt {
+    /// show the end of this task's log instead
+    #[structopt(long)]
+    log: Option<String>,
+
+    /// the number of log lines to show
+    #[structopt(long, default_value = "20")]
+    lines: usize,
+}
+
+/// show the last run of each task serve runs, or the end of a task's log
+pub fn run(state: &StateDir, opt: TasksOpt, output: &Output) -> Result<(), Box<dyn Error>> {
+    if let Some(name) = &opt.log {
+        let path = state.task_logs_dir().join(format!("{name}.log"));
+        let log = fs::read_to_string(&path)
+            .map_err(|e| format!("can't read the log of task {name}: {e}"))?;
+        let lines: Vec<&str> = log.lines().collect();
+        for line in &lines[lines.len().saturating_sub(opt.lines)..] {
+            println!("{line}");
+        }
+        return Ok(());
+    }
+
+    let statuses = schedule::load_statuses(state.tasks_path())?;
+    if statuses.is_empty() {
+        info!("No tasks have run, serve runs the config's [[task]] entries");
+    }
+    output.print(status_table(statuses))?;
+    Ok(())
+}
+
+/// the tasks' last runs as a table
+pub fn status_table(statuses: Vec<TaskStatus>) -> Table {
+    let now = now_secs();
+    let mut table = Table::new(&[
+        "task", "every", "last run", "exit", "took", "runs", "failed", "skipped", "run",
+    ]);
+    for status in statuses {
+        let last_run: Cell = match status.last_run {
+            Some(at) => format!("{}s ago", now.saturating_sub(at)).into(),
+            None => "never".into(),
+        };
+        let exit = match (status.running, status.last_exit) {
+            (true, _) => Cell::warn("running"),
+            (false, Some(0)) => Cell::good("0"),
+            (false, Some(code)) => Cell::bad(code.to_string()),
+            (false, None) if status.runs > 0 => Cell::bad("killed"),
+            (false, None) => "".into(),
+        };
+        let took: Cell = match status.last_duration_ms {
+            Some(ms) => format!("{:.1}s", ms as f64 / 1000.0).into(),
+            None => "".into(),
+        };
+        table.push(vec![
+            status.name.into(),
+            status.every.into(),
+            last_run,
+            exit,
+            took,
+            status.runs.to_string().into(),
+            status.failures.to_string().into(),
+            status.skipped.to_string().into(),
+            status.run.into(),
+        ]);
+    }
+    table
+}
+
+/// Runs the config's tasks as children of serve, each with its output in its own log
+pub struct TaskRunner {
+    scheduler: Scheduler,
+    /// the tasks running and when they started
+    running: HashMap<usize, (Child, Instant)>,
+    exe: PathBuf,
+    /// what every task's command line starts with, so it runs as this node
+    base: Vec<OsString>,
+    statuses: PathBuf,
+    logs: PathBuf,
+}
+
+impl TaskRunner {
+    /// a runner for the tasks, none if there aren't any
+    pub fn new(
+        tasks: &[Task],
+        config: &Path,
+        state: &StateDir,
+    ) -> Result<Option<Self>, Box<dyn Error>> {
+        let scheduler = Scheduler::new(tasks, Instant::now())?;
+        if scheduler.is_empty() {
+            return Ok(None);
+        }
+        let logs = state.task_logs_dir();
+        fs::create_dir_all(&logs)?;
+        let base: Vec<OsString> = vec![
+            "--config".into(),
+            config.into(),
+            "--data-dir".into(),
+            state.root().into(),
+        ];
+        info!("Running {} scheduled tasks", scheduler.len());
+        Ok(Some(Self {
+            scheduler,
+            running: HashMap::new(),
+            exe: std::env::current_exe()?,
+            base,
+            statuses: state.tasks_path(),
+            logs,
+        }))
+    }
+
+    /// when to check on the tasks next
+    pub fn wake(&self) -> Option<Instant> {
+        let poll = match self.running.is_empty() {
+            true => None,
+            false => Some(Instant::now() + TASK_POLL),
+        };
+        match (self.scheduler.next_due(), poll) {
+            (Some(due), Some(poll)) => Some(due.min(poll)),
+            (due, poll) => due.or(poll),
+        }
+    }
+
+    /// note the tasks that finished and start the ones due
+    pub fn poll(&mut self, now: Instant) {
+        let mut changed = false;
+        let mut finished = Vec::new();
+        for (i, (child, started)) in self.running.iter_mut() {
+            match child.try_wait() {
+                Ok(Some(exit)) => finished.push((*i, exit.code(), now - *started)),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Can't check on task {}: {e}", self.scheduler.name(*i));
+                    finished.push((*i, None, now - *started));
+                }
+            }
+        }
+        for (i, code, took) in finished {
+            self.running.remove(&i);
+            let name = self.scheduler.name(i);
+            match code {
+                Some(0) => info!("Task {name} finished after {took:?}"),
+                Some(code) => warn!("Task {name} failed with {code} after {took:?}"),
+                None => warn!("Task {name} was killed after {took:?}"),
+            }
+            self.scheduler.finished(i, code, took);
+            changed = true;
+        }
+        for i in self.scheduler.due(now) {
+            changed = true;
+            match self.start(i) {
+                Ok(child) => {
+                    self.running.insert(i, (child, now));
+                }
+                Err(e) => {
+                    warn!("Can't start task {}: {e}", self.scheduler.name(i));
+                    self.scheduler.finished(i, None, Duration::ZERO);
+                }
+            }
+        }
+        if changed {
+            self.save();
+        }
+    }
+
+    /// the last run of each task
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        self.scheduler.statuses()
+    }
+
+    /// kill the tasks still running, serve is stopping
+    pub fn stop(&mut self) {
+        for (i, (mut child, started)) in self.running.drain() {
+            warn!("Killing task {}", self.scheduler.name(i));
+            let _ = child.kill();
+            let code = child.wait().ok().and_then(|exit| exit.code());
+            self.scheduler.finished(i, code, started.elapsed());
+        }
+        self.save();
+    }
+
+    fn start(&self, i: usize) -> io::Result<Child> {
+        let name = self.scheduler.name(i);
+        let args = self.scheduler.args(i);
+        let mut log = self.log(name)?;
+        writeln!(log, "--- {} fleyg {}", now_secs(), args.join(" "))?;
+        info!("Starting task {name}: {}", args.join(" "));
+        Command::new(&self.exe)
+            .args(&self.base)
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .spawn()
+    }
+
+    fn log(&self, name: &str) -> io::Result<File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.logs.join(format!("{name}.log")))
+    }
+
+    fn save(&self) {
+        if let Err(e) = schedule::save_statuses(&self.statuses, &self.scheduler.statuses()) {
+            warn!(
+                "Can't save task statuses to {}: {e}",
+                self.statuses.display()
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use fleyg::{
+    answer::{Answer, AnswerPeer, SignedAnswer},
+    encoding::Encoding,
+    keys::KeyEncoding,
+    receipt::Receipt,
+    table::{Output, Table},
+};
+use log::*;
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+pub struct VerifyOpt {
+    /// a publication receipt to check
+    #[structopt(long, parse(from_os_str), required_unless = "answer")]
+    receipt: Option<PathBuf>,
+
+    /// the published value, checked against the receipt
+    #[structopt(long, parse(from_os_str), requires = "receipt")]
+    value: Option<PathBuf>,
+
+    /// a signed query result to check instead of a receipt
+    #[structopt(long, parse(from_os_str), conflicts_with = "receipt")]
+    answer: Option<PathBuf>,
+}
+
+pub fn run(
+    opt: VerifyOpt,
+    encoding: Encoding,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let receipt = match (&opt.receipt, &opt.answer) {
+        (Some(path), _) => Receipt::load(path)?,
+        (None, Some(path)) => return answer(path, encoding, keys, output),
+        (None, None) => unreachable!("structopt requires one of them"),
+    };
+    let value = opt.value.as_ref().map(fs::read).transpose()?;
+    receipt.verify(value.as_deref())?;
+    info!(
+        "Valid receipt for {} published by {} at {}, {} peers acknowledged it",
+        keys.encode(&receipt.key),
+        receipt.publisher,
+        receipt.issued,
+        receipt.acks.len()
+    );
+    if value.is_none() {
+        info!("No --value given, the value wasn't checked");
+    }
+    let mut table = Table::new(&["peer", "at"]);
+    for ack in &receipt.acks {
+        table.push(vec![ack.peer.to_string().into(), ack.at.to_string().into()]);
+    }
+    output.print(table)?;
+    Ok(())
+}
+
+// check a signed query result and show what it answered
+fn answer(
+    path: &Path,
+    encoding: Encoding,
+    keys: KeyEncoding,
+    output: &Output,
+) -> Result<(), Box<dyn Error>> {
+    let signed = SignedAnswer::load(path)?;
+    signed.verify()?;
+    let key = keys.encode(signed.answer.key());
+    let peers = |peers: &[AnswerPeer]| {
+        let mut table = Table::new(&["peer", "addrs"]);
+        for p in peers {
+            let addrs: Vec<String> = p.addrs.iter().map(|a| a.to_string()).collect();
+            table.push(vec![p.peer.to_string().into(), addrs.join(",").into()]);
+        }
+        table
+    };
+    let table = match &signed.answer {
+        Answer::Record {
+            value, publisher, ..
+        } => {
+            info!(
+                "Valid answer from {} at {}: the record {key}",
+                signed.signer, signed.issued
+            );
+            let mut table = Table::new(&["value", "publisher"]);
+            table.push(vec![
+                value
+                    .as_deref()
+                    .map(|v| encoding.encode(v))
+                    .unwrap_or_default()
+                    .into(),
+                publisher.map(|p| p.to_string()).unwrap_or_default().into(),
+            ]);
+            table
+        }
+        Answer::Providers { providers, .. } => {
+            info!(
+                "Valid answer from {} at {}: {} providers of {key}",
+                signed.signer,
+                signed.issued,
+                providers.len()
+            );
+            peers(providers)
+        }
+        Answer::ClosestPeers { peers: closest, .. } => {
+            info!(
+                "Valid answer from {} at {}: {} peers closest to {key}",
+                signed.signer,
+                signed.issued,
+                closest.len()
+            );
+            peers(closest)
+        }
+    };
+    output.print(table)?;
+    Ok(())
+}
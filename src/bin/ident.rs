@@ -1,14 +1,37 @@
 #![doc = include_str!("../../README.md")]
 
 use env_logger::Env;
+use fleyg::{
+    apilisten::ApiListen,
+    control::{self, Request},
+    deadline::next_before,
+    diagnose::Diagnosis,
+    divergence::DivergenceTracker,
+    encoding::Encoding,
+    event::{self, Event},
+    identity::{load_keypair, KeyType},
+    network::parse_target,
+    node::{FleygBehavior, FleygBehaviorEvent, FleygNode},
+    peerstore::PeerStore,
+    runtime,
+    state::StateDir,
+    table::Format,
+};
 use futures::prelude::*;
 use libp2p::{
-    development_transport, identify, identity,
-    swarm::{SwarmBuilder, SwarmEvent},
-    Multiaddr, PeerId,
+    core::ConnectedPoint,
+    identify,
+    swarm::{dial_opts::DialOpts, ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
 };
 use log::*;
-use std::error::Error;
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -26,36 +49,101 @@ struct Opt {
     /// addr to dial
     #[structopt(long, short)]
     addr: Option<Multiaddr>,
+
+    /// identify every peer in this file instead, one peer id or address per line, - for
+    /// stdin
+    #[structopt(long, short, parse(from_os_str), conflicts_with_all = &["peer", "addr"])]
+    input: Option<PathBuf>,
+
+    /// the peers identified at once with --input
+    #[structopt(long, default_value = "32")]
+    concurrency: usize,
+
+    /// seconds the peer has to answer, each peer's with --input
+    #[structopt(long, default_value = "30")]
+    timeout: u64,
+
+    /// exit once the peer is identified instead of watching for identify pushes
+    #[structopt(long, conflicts_with = "input")]
+    once: bool,
+
+    /// how public keys are shown: hex, base64, base58 or raw
+    #[structopt(long, default_value = "hex")]
+    encoding: Encoding,
+
+    /// file to remember identify info about peers across sessions
+    #[structopt(long, parse(from_os_str))]
+    peer_store: Option<PathBuf>,
+
+    /// load the identity keypair from this file, creating it if there is none, a random
+    /// identity is used if not given. Protobuf encoded or a PKCS#8 RSA key
+    #[structopt(long, parse(from_os_str))]
+    identity: Option<PathBuf>,
+
+    /// the type of identity key to create: ed25519, secp256k1, ecdsa or rsa [default:
+    /// ed25519]
+    #[structopt(long)]
+    key_type: Option<KeyType>,
+
+    /// text log lines, or json to write each identify answer and failed dial as a json
+    /// line on stdout, logging only warnings unless RUST_LOG is set
+    #[structopt(long, default_value = "text")]
+    output: Format,
+
+    /// identify the peer on a swarm of our own even when a fleyg daemon is running,
+    /// instead of asking the daemon
+    #[structopt(long)]
+    standalone: bool,
 }
 
-#[async_std::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    // set up logger
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+fn main() -> Result<(), Box<dyn Error>> {
+    runtime::block_on(run())
+}
 
+async fn run() -> Result<(), Box<dyn Error>> {
     // parse the command line arguments
     let opt = Opt::from_args();
 
-    // create a random peer id
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    info!("Local peer id: {}", local_peer_id);
-
-    // set up tcp transport
-    let transport = development_transport(local_key.clone()).await?;
-
-    // build the swarm
-    let mut swarm = {
-        // set up the identify behavior
-        let identify = {
-            let cfg = identify::Config::new("ipfs/0.1.0".into(), local_key.public())
-                .with_agent_version("ident/0.0.1".into());
-            identify::Behaviour::new(cfg)
-        };
+    // set up logger, json output keeps stdout for the events
+    let level = match opt.output {
+        Format::Text => "info",
+        Format::Json => "warn",
+    };
+    env_logger::Builder::from_env(Env::default().default_filter_or(level)).init();
+    if opt.output == Format::Json {
+        event::enable();
+    }
 
-        SwarmBuilder::with_async_std_executor(transport, identify, local_peer_id).build()
+    // open the peer store
+    let mut peers = match &opt.peer_store {
+        Some(path) => PeerStore::open(path)?,
+        None => PeerStore::memory(),
     };
 
+    // a running daemon is already connected, so one-shot lookups of a peer go through it
+    let plain =
+        cfg!(feature = "api") && opt.once && opt.addr.is_none() && opt.output == Format::Text;
+    if let (Some(peer), true, false) = (opt.peer, plain, opt.standalone) {
+        if let Some(api) = daemon_api()? {
+            return delegate(&api, peer);
+        }
+    }
+
+    // build the swarm, with a random peer id unless given one
+    let local_key = match &opt.identity {
+        Some(path) => load_keypair(path, opt.key_type)?,
+        None => opt.key_type.unwrap_or_default().generate()?,
+    };
+    let mut swarm = FleygNode::new(local_key)
+        .agent("ident/0.0.1")
+        .build()
+        .await?;
+
+    if let Some(path) = &opt.input {
+        let targets = read_targets(path)?;
+        return batch(swarm, targets, &opt, &mut peers).await;
+    }
+
     if let Some(addr) = opt.addr {
         swarm.dial(addr.clone())?;
         info!("Dialed via addr {}", addr);
@@ -64,12 +152,65 @@ async fn main() -> Result<(), Box<dyn Error>> {
         info!("Dialed via peer {}", peer);
     }
 
+    // the addresses we dialed each peer on, for checking against identify
+    let mut dialed = HashMap::new();
+    let mut divergence = DivergenceTracker::default();
+
+    // until the peer is identified, a failed dial or identify or running out of time is
+    // an error, for scripts and health checks
+    let mut deadline = Some(Instant::now() + Duration::from_secs(opt.timeout));
+
     loop {
-        if let SwarmEvent::Behaviour(event) = swarm.select_next_some().await {
+        let next = match deadline {
+            Some(deadline) => next_before(&mut swarm, deadline).await,
+            None => Some(swarm.select_next_some().await),
+        };
+        let Some(next) = next else {
+            event::emit(Event::new("ident_timeout", opt.peer));
+            return Err(format!("no identify answer within {}s", opt.timeout).into());
+        };
+        let event = match next {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                endpoint: ConnectedPoint::Dialer { address, .. },
+                ..
+            } => {
+                dialed.insert(peer_id, address);
+                continue;
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                event::emit(Event::new("dial_failed", peer_id).with("error", error.to_string()));
+                let diagnoses = Diagnosis::from_dial_error(&error);
+                if diagnoses.is_empty() {
+                    warn!("Dial failed: {error}");
+                }
+                for d in diagnoses {
+                    warn!("Dial failed: {d}");
+                    for e in &d.errors {
+                        debug!("\t{e}");
+                    }
+                }
+                if deadline.is_some() {
+                    return Err(format!("dial failed: {error}").into());
+                }
+                continue;
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(event)) => event,
+            _ => continue,
+        };
+        {
             use identify::Event::*;
             match event {
                 Received { peer_id, info } => {
+                    event::emit(Event::identify(peer_id, &info).with(
+                        "public_key",
+                        opt.encoding.encode(&info.public_key.encode_protobuf()),
+                    ));
                     info!("Identify Received: {peer_id}");
+                    info!(
+                        "\tPublic Key: {}",
+                        opt.encoding.encode(&info.public_key.encode_protobuf())
+                    );
                     info!("\tProtocol: {}", info.protocol_version);
                     info!("\tAgent: {}", info.agent_version);
                     info!("\tAddr: {}", info.observed_addr);
@@ -77,13 +218,30 @@ async fn main() -> Result<(), Box<dyn Error>> {
                     for sp in &info.protocols {
                         info!("\t\t{}", sp);
                     }
-                    //break;
+
+                    let found = divergence.check(&info, dialed.get(&peer_id), peers.get(&peer_id));
+                    for d in found {
+                        warn!("Identify divergence from {peer_id}: {d}");
+                    }
+                    peers.entry(peer_id).update(&info);
+                    peers.save()?;
+                    if opt.once {
+                        return Ok(());
+                    }
+                    deadline = None;
                 }
                 Sent { peer_id } => {
                     info!("Identify Sent: {peer_id}");
                 }
                 Pushed { peer_id, info } => {
+                    let mut pushed = Event::identify(peer_id, &info);
+                    pushed.event = "identify_push".into();
+                    event::emit(pushed);
                     info!("Identify Pushed: {peer_id}");
+                    info!(
+                        "\tPublic Key: {}",
+                        opt.encoding.encode(&info.public_key.encode_protobuf())
+                    );
                     info!("\tProtocol: {}", info.protocol_version);
                     info!("\tAgent: {}", info.agent_version);
                     info!("\tAddr: {}", info.observed_addr);
@@ -94,11 +252,256 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 }
                 Error { peer_id, error } => {
                     info!("Identify Error: {peer_id} - {error}");
-                    //break;
+                    if deadline.is_some() {
+                        return Err(format!("identify failed: {error}").into());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A peer to identify from the --input
+struct Target {
+    line: String,
+    peer: Option<PeerId>,
+    addr: Option<Multiaddr>,
+}
+
+// the peers in a file or stdin, skipping blank lines and # comments
+fn read_targets(path: &Path) -> Result<Vec<Target>, Box<dyn Error>> {
+    let input = match path.to_str() {
+        Some("-") => io::read_to_string(io::stdin())?,
+        _ => fs::read_to_string(path)?,
+    };
+    let mut targets = Vec::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (peer, addr) = parse_target(line).map_err(|e| format!("line {}: {e}", n + 1))?;
+        targets.push(Target {
+            line: line.to_string(),
+            peer,
+            addr,
+        });
+    }
+    Ok(targets)
+}
+
+/// How identifying a peer from the --input went
+enum Outcome {
+    Identified(PeerId, Box<identify::Info>),
+    Failed(String),
+    TimedOut,
+}
+
+/// identify the targets, a bounded number at once, reporting each as it finishes
+async fn batch(
+    mut swarm: Swarm<FleygBehavior>,
+    targets: Vec<Target>,
+    opt: &Opt,
+    peers: &mut PeerStore,
+) -> Result<(), Box<dyn Error>> {
+    let timeout = Duration::from_secs(opt.timeout);
+    let concurrency = opt.concurrency.max(1);
+    info!(
+        "Identifying {} peers, {concurrency} at a time",
+        targets.len()
+    );
+
+    let mut outcomes: Vec<Option<Outcome>> = targets.iter().map(|_| None).collect();
+    let mut started = 0;
+    let mut finished = 0;
+    // the dials in flight and the connected peers yet to identify, by target
+    let mut dialing: HashMap<ConnectionId, usize> = HashMap::new();
+    let mut connected: HashMap<PeerId, usize> = HashMap::new();
+    let mut deadlines: HashMap<usize, Instant> = HashMap::new();
+    let mut dialed = HashMap::new();
+    let mut divergence = DivergenceTracker::default();
+
+    while finished < targets.len() {
+        while started < targets.len() && started - finished < concurrency {
+            let i = started;
+            started += 1;
+            let target = &targets[i];
+            let dial = match (target.peer, &target.addr) {
+                (Some(peer), Some(addr)) => DialOpts::peer_id(peer)
+                    .addresses(vec![addr.clone()])
+                    .build(),
+                (Some(peer), None) => DialOpts::peer_id(peer).build(),
+                (None, Some(addr)) => DialOpts::unknown_peer_id().address(addr.clone()).build(),
+                // parse_target always gives one or the other
+                (None, None) => unreachable!(),
+            };
+            let id = dial.connection_id();
+            match swarm.dial(dial) {
+                Ok(()) => {
+                    dialing.insert(id, i);
+                    deadlines.insert(i, Instant::now() + timeout);
+                }
+                Err(e) => {
+                    outcomes[i] = Some(report(target, Outcome::Failed(e.to_string())));
+                    finished += 1;
                 }
             }
         }
+        let Some(wake) = deadlines.values().min().copied() else {
+            continue;
+        };
+
+        // each event may finish one target
+        let done = match next_before(&mut swarm, wake).await {
+            None => {
+                let now = Instant::now();
+                let late: Vec<usize> = deadlines
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(i, _)| *i)
+                    .collect();
+                for i in &late {
+                    dialing.retain(|_, t| t != i);
+                    connected.retain(|_, t| t != i);
+                    if let Some(peer) = targets[*i].peer {
+                        let _ = swarm.disconnect_peer_id(peer);
+                    }
+                }
+                late.into_iter().map(|i| (i, Outcome::TimedOut)).collect()
+            }
+            Some(SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            }) => {
+                if let Some(i) = dialing.remove(&connection_id) {
+                    connected.insert(peer_id, i);
+                    dialed.insert(peer_id, endpoint.get_remote_address().clone());
+                }
+                Vec::new()
+            }
+            Some(SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                error,
+                ..
+            }) => match dialing.remove(&connection_id) {
+                Some(i) => {
+                    let reason = match Diagnosis::from_dial_error(&error).first() {
+                        Some(d) => d.to_string(),
+                        None => error.to_string(),
+                    };
+                    vec![(i, Outcome::Failed(reason))]
+                }
+                None => Vec::new(),
+            },
+            Some(SwarmEvent::ConnectionClosed {
+                peer_id,
+                num_established: 0,
+                ..
+            }) => match connected.remove(&peer_id) {
+                Some(i) => vec![(i, Outcome::Failed("closed before identifying".into()))],
+                None => Vec::new(),
+            },
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(
+                identify::Event::Received { peer_id, info },
+            ))) => match connected.remove(&peer_id) {
+                Some(i) => {
+                    for d in divergence.check(&info, dialed.get(&peer_id), peers.get(&peer_id)) {
+                        warn!("Identify divergence from {peer_id}: {d}");
+                    }
+                    peers.entry(peer_id).update(&info);
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                    vec![(i, Outcome::Identified(peer_id, Box::new(info)))]
+                }
+                None => Vec::new(),
+            },
+            Some(SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(identify::Event::Error {
+                peer_id,
+                error,
+            }))) => match connected.remove(&peer_id) {
+                Some(i) => {
+                    let _ = swarm.disconnect_peer_id(peer_id);
+                    vec![(i, Outcome::Failed(error.to_string()))]
+                }
+                None => Vec::new(),
+            },
+            Some(_) => Vec::new(),
+        };
+        for (i, outcome) in done {
+            if deadlines.remove(&i).is_some() {
+                outcomes[i] = Some(report(&targets[i], outcome));
+                finished += 1;
+            }
+        }
     }
+    peers.save()?;
+
+    let count = |f: fn(&Outcome) -> bool| outcomes.iter().flatten().filter(|o| f(o)).count();
+    info!(
+        "Identified {} of {} peers, {} failed and {} timed out",
+        count(|o| matches!(o, Outcome::Identified(..))),
+        targets.len(),
+        count(|o| matches!(o, Outcome::Failed(_))),
+        count(|o| matches!(o, Outcome::TimedOut)),
+    );
+    Ok(())
+}
 
-    //Ok(())
+// log and emit how identifying a target went
+fn report(target: &Target, outcome: Outcome) -> Outcome {
+    let line = &target.line;
+    match &outcome {
+        Outcome::Identified(peer, info) => {
+            info!(
+                "{line}: {peer} {} {} with {} protocols",
+                info.agent_version,
+                info.protocol_version,
+                info.protocols.len()
+            );
+            event::emit(Event::identify(*peer, info).with("target", line));
+        }
+        Outcome::Failed(error) => {
+            warn!("{line}: {error}");
+            event::emit(
+                Event::new("ident_failed", target.peer)
+                    .with("target", line)
+                    .with("error", error),
+            );
+        }
+        Outcome::TimedOut => {
+            warn!("{line}: timed out");
+            event::emit(Event::new("ident_timeout", target.peer).with("target", line));
+        }
+    }
+    outcome
+}
+
+// the control API of the default fleyg daemon, if one is running
+fn daemon_api() -> io::Result<Option<ApiListen>> {
+    let Ok(root) = StateDir::default_root(None) else {
+        return Ok(None);
+    };
+    if !root.is_dir() {
+        return Ok(None);
+    }
+    let state = StateDir::open(root)?;
+    Ok(state.daemon_running().then(|| state.control_api()))
+}
+
+// identify the peer through the daemon, which dials it if it isn't connected
+fn delegate(api: &ApiListen, peer: PeerId) -> Result<(), Box<dyn Error>> {
+    info!("Identifying {peer} through the daemon at {api}");
+    let reply = control::call(api, &Request::Identify(Some(peer)))?;
+    info!("Identify Received: {peer}");
+    info!("\tAgent: {}", reply["agent"].as_str().unwrap_or_default());
+    info!("\tAddrs:");
+    for addr in reply["listen_addrs"].as_array().into_iter().flatten() {
+        info!("\t\t{}", addr.as_str().unwrap_or_default());
+    }
+    info!("\tProtocols:");
+    for sp in reply["protocols"].as_array().into_iter().flatten() {
+        info!("\t\t{}", sp.as_str().unwrap_or_default());
+    }
+    Ok(())
 }
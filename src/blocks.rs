@@ -0,0 +1,225 @@
+//! Content addressed blocks, kept on disk and exchanged with peers.
+//!
+//! The chunks of a large value behind a [`crate::pointer`] record are blocks named by the
+//! sha256 of their bytes, so whoever sends one can't change it without the name giving it
+//! away. A node keeps the blocks it published in the state directory's `blocks/` and
+//! answers requests for them on `/fleyg/blocks/1.0.0`: the request is a block's 32 byte
+//! name and the answer its bytes, or nothing if we don't have it. This is fleyg's own
+//! protocol, not bitswap, and IPFS nodes can't fetch the blocks or serve them to us,
+//! rust-libp2p has no bitswap so this want and send exchange stands in for it between
+//! fleyg nodes.
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    request_response::{self, ProtocolSupport},
+    swarm::behaviour::toggle::Toggle,
+    StreamProtocol,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    fs, io, iter,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// the block exchange protocol
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/fleyg/blocks/1.0.0");
+
+/// blocks larger than this are refused
+pub const MAX_BLOCK: usize = 1024 * 1024;
+
+/// how long a peer has to send a block
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// the directory of the blocks whose values are published, below the store's
+const ROOTS_DIR: &str = "roots";
+
+/// The name of a block, the sha256 of its bytes
+pub type BlockId = [u8; 32];
+
+/// the name of a block
+pub fn id(block: &[u8]) -> BlockId {
+    Sha256::digest(block).into()
+}
+
+/// parse a block name from hex
+pub fn parse_id(s: &str) -> Result<BlockId, String> {
+    let bytes = hex::decode(s).map_err(|e| format!("bad block id {s}: {e}"))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("bad block id {s}: not 32 bytes"))
+}
+
+/// Blocks kept in a directory, a file each named by the block's hex id
+#[derive(Clone, Debug)]
+pub struct BlockStore {
+    dir: PathBuf,
+}
+
+impl BlockStore {
+    /// open the store, creating the directory if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(dir.join(ROOTS_DIR))?;
+        Ok(Self { dir })
+    }
+
+    /// keep a block, returning its id
+    pub fn put(&self, block: &[u8]) -> io::Result<BlockId> {
+        let id = id(block);
+        let path = self.path(&id);
+        if !path.exists() {
+            // written aside and renamed so a crash never leaves half a block
+            let partial = path.with_extension("partial");
+            fs::write(&partial, block)?;
+            fs::rename(partial, path)?;
+        }
+        Ok(id)
+    }
+
+    /// a block we have, none if we don't or the file no longer matches its id
+    pub fn get(&self, id: &BlockId) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.path(id)) {
+            Ok(block) if self::id(&block) == *id => Ok(Some(block)),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// remember a block as the root of a published value, so it's provided while serving
+    pub fn add_root(&self, id: &BlockId) -> io::Result<()> {
+        fs::write(self.dir.join(ROOTS_DIR).join(hex::encode(id)), [])
+    }
+
+    /// the roots of the published values
+    pub fn roots(&self) -> io::Result<Vec<BlockId>> {
+        let mut roots = Vec::new();
+        for entry in fs::read_dir(self.dir.join(ROOTS_DIR))? {
+            let name = entry?.file_name();
+            if let Ok(id) = parse_id(&name.to_string_lossy()) {
+                roots.push(id);
+            }
+        }
+        roots.sort();
+        Ok(roots)
+    }
+
+    fn path(&self, id: &BlockId) -> PathBuf {
+        self.dir.join(hex::encode(id))
+    }
+}
+
+/// The block exchange behaviour
+pub type Behaviour = request_response::Behaviour<BlockCodec>;
+
+/// the behaviour, switched off unless enabled
+pub fn behaviour(enabled: bool) -> Toggle<Behaviour> {
+    let behaviour = enabled.then(|| {
+        let mut config = request_response::Config::default();
+        config.set_request_timeout(REQUEST_TIMEOUT);
+        Behaviour::new(
+            BlockCodec,
+            iter::once((PROTOCOL, ProtocolSupport::Full)),
+            config,
+        )
+    });
+    behaviour.into()
+}
+
+/// Block requests as the raw id and answers as the raw block, empty if not found
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BlockCodec;
+
+#[async_trait]
+impl request_response::Codec for BlockCodec {
+    type Protocol = StreamProtocol;
+    type Request = BlockId;
+    type Response = Option<Vec<u8>>;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<BlockId>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut id = [0; 32];
+        io.read_exact(&mut id).await?;
+        Ok(id)
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+    ) -> io::Result<Option<Vec<u8>>>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        let mut block = Vec::new();
+        io.take(MAX_BLOCK as u64 + 1)
+            .read_to_end(&mut block)
+            .await?;
+        if block.len() > MAX_BLOCK {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "block is too large",
+            ));
+        }
+        Ok((!block.is_empty()).then_some(block))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        id: BlockId,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(&id).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        block: Option<Vec<u8>>,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        if let Some(block) = block {
+            io.write_all(&block).await?;
+        }
+        io.close().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+    use request_response::Codec;
+
+    #[test]
+    fn stores_and_sends_blocks() {
+        let dir = std::env::temp_dir().join(format!("fleyg-blocks-{}", std::process::id()));
+        let store = BlockStore::open(&dir).unwrap();
+        let id = store.put(b"a block").unwrap();
+        assert_eq!(store.get(&id).unwrap().unwrap(), b"a block");
+        assert_eq!(store.get(&self::id(b"another")).unwrap(), None);
+        store.add_root(&id).unwrap();
+        assert_eq!(store.roots().unwrap(), vec![id]);
+        assert_eq!(parse_id(&hex::encode(id)).unwrap(), id);
+
+        let mut wire = Cursor::new(Vec::new());
+        block_on(BlockCodec.write_response(&PROTOCOL, &mut wire, Some(b"a block".to_vec())))
+            .unwrap();
+        wire.set_position(0);
+        let read = block_on(BlockCodec.read_response(&PROTOCOL, &mut wire)).unwrap();
+        assert_eq!(read.unwrap(), b"a block");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
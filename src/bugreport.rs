@@ -0,0 +1,283 @@
+//! A snapshot of a node's state for attaching to bug reports.
+//!
+//! `fleyg snapshot` writes everything someone looking into an issue asks for first as
+//! one json file: the version and platform, our identity's public half, the addresses we
+//! listen on and were confirmed at, the config with its secrets redacted, the routing
+//! table, the open connections and the most recent errors. No private key material is
+//! ever included.
+
+use crate::{
+    config::Config,
+    identity::KeyType,
+    node::{FleygBehavior, FleygBehaviorEvent, AGENT},
+    peerstore::now_secs,
+    routing::RoutingSnapshot,
+    sessions::now_millis,
+};
+use libp2p::{
+    identity::Keypair,
+    ping,
+    swarm::{ConnectionId, SwarmEvent},
+    Multiaddr, PeerId, Swarm,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt, fs, io,
+    path::Path,
+};
+
+/// what redacted config values are replaced with
+pub const REDACTED: &str = "<redacted>";
+
+/// the config keys whose values are secrets: the noise prologue acts as a shared key and
+/// webhook urls often carry tokens
+const SECRET_KEYS: &[&str] = &["prologue", "webhook"];
+
+/// the most recent errors kept
+const MAX_ERRORS: usize = 100;
+
+/// What was built and where it runs
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VersionInfo {
+    pub fleyg: String,
+    pub agent: String,
+    pub os: String,
+    pub arch: String,
+}
+
+impl Default for VersionInfo {
+    fn default() -> Self {
+        Self {
+            fleyg: env!("CARGO_PKG_VERSION").to_string(),
+            agent: AGENT.to_string(),
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+/// The public half of our identity
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityInfo {
+    pub peer: PeerId,
+    pub key_type: String,
+    /// the public key in protobuf format
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+}
+
+/// An open connection
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub peer: PeerId,
+    pub addr: Multiaddr,
+    /// true if we dialed it
+    pub outbound: bool,
+    /// unix milliseconds it was opened
+    pub since: u64,
+}
+
+/// An error the node ran into
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecentError {
+    /// unix milliseconds
+    pub at: u64,
+    pub peer: Option<PeerId>,
+    pub error: String,
+}
+
+/// The connections and errors of a running node, kept up to date from its swarm events
+#[derive(Debug, Default)]
+pub struct StateTracker {
+    connections: BTreeMap<ConnectionId, ConnectionInfo>,
+    errors: VecDeque<RecentError>,
+}
+
+impl StateTracker {
+    /// keep track of a swarm event
+    pub fn observe<E: fmt::Display>(&mut self, event: &SwarmEvent<FleygBehaviorEvent, E>) {
+        match event {
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            } => {
+                self.connections.insert(
+                    *connection_id,
+                    ConnectionInfo {
+                        peer: *peer_id,
+                        addr: endpoint.get_remote_address().clone(),
+                        outbound: endpoint.is_dialer(),
+                        since: now_millis(),
+                    },
+                );
+            }
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                cause,
+                ..
+            } => {
+                self.connections.remove(connection_id);
+                if let Some(cause) = cause {
+                    self.error(Some(*peer_id), format!("connection closed: {cause}"));
+                }
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                self.error(*peer_id, format!("dial failed: {error}"))
+            }
+            SwarmEvent::IncomingConnectionError {
+                send_back_addr,
+                error,
+                ..
+            } => self.error(
+                None,
+                format!("incoming from {send_back_addr} failed: {error}"),
+            ),
+            SwarmEvent::ListenerError { error, .. } => {
+                self.error(None, format!("listener failed: {error}"))
+            }
+            SwarmEvent::ListenerClosed {
+                addresses,
+                reason: Err(e),
+                ..
+            } => {
+                let addrs: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                self.error(None, format!("listener on {} closed: {e}", addrs.join(" ")))
+            }
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(ping::Event {
+                peer,
+                result: Err(e),
+                ..
+            })) => self.error(Some(*peer), format!("ping failed: {e}")),
+            _ => {}
+        }
+    }
+
+    /// the open connections, oldest first
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self.connections.values().cloned().collect();
+        connections.sort_by_key(|c| c.since);
+        connections
+    }
+
+    /// the most recent errors, oldest first
+    pub fn errors(&self) -> impl Iterator<Item = &RecentError> {
+        self.errors.iter()
+    }
+
+    fn error(&mut self, peer: Option<PeerId>, error: String) {
+        if self.errors.len() == MAX_ERRORS {
+            self.errors.pop_front();
+        }
+        self.errors.push_back(RecentError {
+            at: now_millis(),
+            peer,
+            error,
+        });
+    }
+}
+
+/// Everything a bug report needs about a node
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BugReport {
+    /// unix seconds it was taken
+    pub taken_at: u64,
+    pub version: VersionInfo,
+    pub identity: IdentityInfo,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub external_addrs: Vec<Multiaddr>,
+    /// the config with its secrets redacted
+    pub config: Value,
+    pub routing: RoutingSnapshot,
+    pub connections: Vec<ConnectionInfo>,
+    pub errors: Vec<RecentError>,
+}
+
+impl BugReport {
+    /// take a snapshot of the node
+    pub fn capture(
+        swarm: &mut Swarm<FleygBehavior>,
+        key: &Keypair,
+        config: &Config,
+        tracker: &StateTracker,
+    ) -> Self {
+        Self {
+            taken_at: now_secs(),
+            version: VersionInfo::default(),
+            identity: IdentityInfo {
+                peer: key.public().to_peer_id(),
+                key_type: KeyType::of(key).to_string(),
+                public_key: key.public().encode_protobuf(),
+            },
+            listen_addrs: swarm.listeners().cloned().collect(),
+            external_addrs: swarm.external_addresses().cloned().collect(),
+            config: redact(config),
+            routing: RoutingSnapshot::capture(&mut swarm.behaviour_mut().kademlia),
+            connections: tracker.connections(),
+            errors: tracker.errors().cloned().collect(),
+        }
+    }
+
+    /// write the report as pretty json
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+}
+
+/// the config as json, with the values of secret keys replaced anywhere they appear
+pub fn redact(config: &Config) -> Value {
+    let mut value = serde_json::to_value(config).unwrap_or(Value::Null);
+    redact_value(&mut value);
+    value
+}
+
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, v) in map.iter_mut() {
+                if SECRET_KEYS.contains(&key.as_str()) && !v.is_null() {
+                    *v = Value::String(REDACTED.to_string());
+                } else {
+                    redact_value(v);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact_value),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_secrets() {
+        let config: Config = toml::from_str(
+            r#"
+            network = "ipfs"
+
+            [noise]
+            pattern = "xx"
+            prologue = "shared"
+
+            [[alerts]]
+            name = "lost"
+            when = "reachability_lost"
+            action = { webhook = "https://hooks.example/T0KEN" }
+            "#,
+        )
+        .unwrap();
+        let value = redact(&config);
+        let json = value.to_string();
+        assert!(!json.contains("shared"));
+        assert!(!json.contains("T0KEN"));
+        assert_eq!(value["network"], "ipfs");
+        assert_eq!(value["noise"]["prologue"], REDACTED);
+        assert_eq!(value["alerts"][0]["action"]["webhook"], REDACTED);
+    }
+}
@@ -0,0 +1,270 @@
+//! Diagnostic bundles for peers that misbehave.
+//!
+//! The last few events involving each peer are kept in memory. When a peer triggers a
+//! protocol error or sends us an invalid record, its events, what we know about it from
+//! identify and its stream captures, if capturing is on, are zipped into
+//! `<dir>/<peer>-<unix secs>.zip` for attaching to an upstream libp2p bug report:
+//!
+//! ```text
+//! report.json        the peer, what it did and its peer store record
+//! events.jsonl       its recent events, oldest first
+//! captures/*.jsonl   its stream captures
+//! ```
+//!
+//! Each peer gets at most one bundle a run, a peer that keeps misbehaving doesn't fill
+//! the disk. Writing bundles needs the `bundles` feature.
+
+use crate::{peerstore::PeerRecord, sessions::now_millis};
+use libp2p::PeerId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+#[cfg(feature = "bundles")]
+use std::{fs::File, io::Write};
+#[cfg(feature = "bundles")]
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// One thing that happened with a peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerEvent {
+    /// unix milliseconds
+    pub at: u64,
+    pub event: String,
+}
+
+/// The recent events of each peer
+#[derive(Debug)]
+pub struct EventLog {
+    per_peer: usize,
+    max_peers: usize,
+    peers: HashMap<PeerId, VecDeque<PeerEvent>>,
+}
+
+impl Default for EventLog {
+    fn default() -> Self {
+        Self::new(64, 4096)
+    }
+}
+
+impl EventLog {
+    /// keep the last `per_peer` events of at most `max_peers` peers, the peers heard from
+    /// least recently are forgotten first
+    pub fn new(per_peer: usize, max_peers: usize) -> Self {
+        Self {
+            per_peer: per_peer.max(1),
+            max_peers: max_peers.max(1),
+            peers: HashMap::new(),
+        }
+    }
+
+    /// note an event involving the peer
+    pub fn record<S: Into<String>>(&mut self, peer: PeerId, event: S) {
+        if !self.peers.contains_key(&peer) && self.peers.len() >= self.max_peers {
+            let stalest = self
+                .peers
+                .iter()
+                .min_by_key(|(_, events)| events.back().map(|e| e.at))
+                .map(|(peer, _)| *peer);
+            if let Some(stalest) = stalest {
+                self.peers.remove(&stalest);
+            }
+        }
+        let events = self.peers.entry(peer).or_default();
+        if events.len() >= self.per_peer {
+            events.pop_front();
+        }
+        events.push_back(PeerEvent {
+            at: now_millis(),
+            event: event.into(),
+        });
+    }
+
+    /// the peer's recent events, oldest first
+    pub fn events(&self, peer: &PeerId) -> impl Iterator<Item = &PeerEvent> {
+        self.peers.get(peer).into_iter().flatten()
+    }
+}
+
+/// What a peer did wrong
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum Misbehavior {
+    /// a protocol failed in a way only a broken peer causes
+    ProtocolError(String),
+    /// a record that doesn't validate
+    InvalidRecord(String),
+}
+
+impl fmt::Display for Misbehavior {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Misbehavior::ProtocolError(e) => write!(f, "protocol error: {e}"),
+            Misbehavior::InvalidRecord(e) => write!(f, "invalid record: {e}"),
+        }
+    }
+}
+
+// the report at the top of a bundle
+#[derive(Serialize)]
+struct Report<'a> {
+    peer: &'a PeerId,
+    misbehavior: &'a Misbehavior,
+    /// unix milliseconds
+    at: u64,
+    fleyg: &'static str,
+    record: Option<&'a PeerRecord>,
+}
+
+/// Keeps the event log and writes bundles into a directory
+#[derive(Debug)]
+pub struct Bundler {
+    dir: PathBuf,
+    captures: Option<PathBuf>,
+    log: EventLog,
+    bundled: HashSet<PeerId>,
+}
+
+impl Bundler {
+    /// bundle into `dir`, adding stream captures from the capture directory if given
+    pub fn new<P: Into<PathBuf>>(dir: P, captures: Option<PathBuf>) -> io::Result<Self> {
+        supported()?;
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            captures,
+            log: EventLog::default(),
+            bundled: HashSet::new(),
+        })
+    }
+
+    /// note an event involving the peer
+    pub fn record<S: Into<String>>(&mut self, peer: PeerId, event: S) {
+        self.log.record(peer, event);
+    }
+
+    /// write a bundle for the peer, none if it already has one
+    pub fn bundle(
+        &mut self,
+        peer: &PeerId,
+        misbehavior: &Misbehavior,
+        record: Option<&PeerRecord>,
+    ) -> io::Result<Option<PathBuf>> {
+        if !self.bundled.insert(*peer) {
+            return Ok(None);
+        }
+        let at = now_millis();
+        let path = self.dir.join(format!("{peer}-{}.zip", at / 1000));
+        let report = Report {
+            peer,
+            misbehavior,
+            at,
+            fleyg: env!("CARGO_PKG_VERSION"),
+            record,
+        };
+        // captures are kept in a directory per peer
+        let captures = match &self.captures {
+            Some(dir) => capture_files(&dir.join(peer.to_string()))?,
+            None => Vec::new(),
+        };
+        write_zip(&path, &report, self.log.events(peer), &captures)?;
+        Ok(Some(path))
+    }
+}
+
+#[cfg(feature = "bundles")]
+fn write_zip<'a>(
+    path: &Path,
+    report: &Report,
+    events: impl Iterator<Item = &'a PeerEvent>,
+    captures: &[PathBuf],
+) -> io::Result<()> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file("report.json", options)?;
+    zip.write_all(&serde_json::to_vec_pretty(report)?)?;
+
+    zip.start_file("events.jsonl", options)?;
+    for event in events {
+        serde_json::to_writer(&mut zip, event)?;
+        zip.write_all(b"\n")?;
+    }
+
+    for path in captures {
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        zip.start_file(format!("captures/{name}"), options)?;
+        zip.write_all(&fs::read(path)?)?;
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "bundles"))]
+fn write_zip<'a>(
+    _path: &Path,
+    _report: &Report,
+    _events: impl Iterator<Item = &'a PeerEvent>,
+    _captures: &[PathBuf],
+) -> io::Result<()> {
+    supported()
+}
+
+#[cfg(feature = "bundles")]
+fn supported() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(feature = "bundles"))]
+fn supported() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "fleyg was built without the bundles feature",
+    ))
+}
+
+// the capture files in a peer's capture directory, none if it has none
+fn capture_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "jsonl") {
+            files.push(path);
+        } else {
+            debug!("Not bundling {}", path.display());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_recent_events() {
+        let mut log = EventLog::new(2, 2);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        for n in 0..3 {
+            log.record(a, format!("a{n}"));
+        }
+        let events: Vec<_> = log.events(&a).map(|e| e.event.as_str()).collect();
+        assert_eq!(events, ["a1", "a2"]);
+
+        log.record(b, "b0");
+        log.record(c, "c0");
+        assert_eq!(log.peers.len(), 2);
+        assert!(log.events(&c).next().is_some());
+    }
+}
@@ -0,0 +1,391 @@
+//! Capture of the decrypted bytes on every stream, for wire level debugging.
+//!
+//! The capture sits between the muxer and the protocols so each file holds one stream,
+//! starting with the multistream-select negotiation. A file is json lines, a header
+//! then one frame per read or write:
+//!
+//! ```text
+//! {"peer":"12D3...","endpoint":"dialer","stream":"outbound","opened":1700000000}
+//! {"t":12,"dir":"out","len":20,"data":"132f6d756c746973..."}
+//! ```
+//!
+//! With redaction only the negotiation is kept, every other frame just has its length.
+
+use futures::{AsyncRead, AsyncWrite};
+use libp2p::{
+    core::{
+        muxing::{StreamMuxerBox, StreamMuxerEvent, SubstreamBox},
+        StreamMuxer, StreamMuxerExt,
+    },
+    PeerId,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// the multistream-select protocol id
+const MULTISTREAM: &str = "/multistream/1.0.0";
+
+/// Where captures go and how much of them to keep
+#[derive(Debug)]
+pub struct Capture {
+    dir: PathBuf,
+    redact: bool,
+    next: AtomicU64,
+}
+
+impl Capture {
+    /// capture into the given directory, keeping only negotiation and frame lengths if
+    /// redacting
+    pub fn new<P: AsRef<Path>>(dir: P, redact: bool) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            redact,
+            next: AtomicU64::new(0),
+        })
+    }
+
+    // start the file for a new stream
+    fn open(&self, header: &Header) -> io::Result<BufWriter<File>> {
+        let dir = self.dir.join(header.peer.to_string());
+        fs::create_dir_all(&dir)?;
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}-{n}-{}.jsonl", header.opened, header.stream);
+        let mut file = BufWriter::new(File::create(dir.join(name))?);
+        serde_json::to_writer(&mut file, header)?;
+        file.write_all(b"\n")?;
+        Ok(file)
+    }
+}
+
+/// The first line of a capture
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Header {
+    pub peer: PeerId,
+    /// whether we dialed the connection, "dialer" or "listener"
+    pub endpoint: String,
+    /// who opened the stream, "outbound" or "inbound"
+    pub stream: String,
+    /// unix seconds when the stream opened
+    pub opened: u64,
+}
+
+/// One read or write on a stream
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame {
+    /// microseconds since the stream opened
+    pub t: u64,
+    /// "in" for bytes read, "out" for bytes written
+    pub dir: String,
+    pub len: usize,
+    /// the bytes, none if redacted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+}
+
+impl Frame {
+    /// the frame's bytes, none if redacted
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        self.data.as_deref().and_then(|d| hex::decode(d).ok())
+    }
+}
+
+/// A muxer recording every stream it opens or accepts
+pub struct CaptureMuxer {
+    inner: StreamMuxerBox,
+    capture: Arc<Capture>,
+    peer: PeerId,
+    endpoint: &'static str,
+}
+
+impl CaptureMuxer {
+    /// wrap a connection's muxer, `dialer` is true if we dialed it
+    pub fn new(inner: StreamMuxerBox, capture: Arc<Capture>, peer: PeerId, dialer: bool) -> Self {
+        Self {
+            inner,
+            capture,
+            peer,
+            endpoint: if dialer { "dialer" } else { "listener" },
+        }
+    }
+
+    fn wrap(&self, stream: SubstreamBox, direction: &str) -> SubstreamBox {
+        let header = Header {
+            peer: self.peer,
+            endpoint: self.endpoint.to_string(),
+            stream: direction.to_string(),
+            opened: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        let file = self
+            .capture
+            .open(&header)
+            .map_err(|e| warn!("Can't capture a stream with {}: {e}", self.peer))
+            .ok();
+        SubstreamBox::new(CaptureStream {
+            inner: stream,
+            file,
+            redact: self.capture.redact,
+            opened: Instant::now(),
+        })
+    }
+}
+
+impl StreamMuxer for CaptureMuxer {
+    type Substream = SubstreamBox;
+    type Error = io::Error;
+
+    fn poll_inbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        this.inner
+            .poll_inbound_unpin(cx)
+            .map_ok(|s| this.wrap(s, "inbound"))
+    }
+
+    fn poll_outbound(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Self::Substream, Self::Error>> {
+        let this = self.get_mut();
+        this.inner
+            .poll_outbound_unpin(cx)
+            .map_ok(|s| this.wrap(s, "outbound"))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().inner.poll_close_unpin(cx)
+    }
+
+    fn poll(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<StreamMuxerEvent, Self::Error>> {
+        self.get_mut().inner.poll_unpin(cx)
+    }
+}
+
+// a stream writing what passes through it to its capture file
+struct CaptureStream {
+    inner: SubstreamBox,
+    file: Option<BufWriter<File>>,
+    redact: bool,
+    opened: Instant,
+}
+
+impl CaptureStream {
+    fn record(&mut self, dir: &str, bytes: &[u8]) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let keep = !self.redact || is_negotiation(bytes);
+        let frame = Frame {
+            t: self.opened.elapsed().as_micros() as u64,
+            dir: dir.to_string(),
+            len: bytes.len(),
+            data: keep.then(|| hex::encode(bytes)),
+        };
+        let written = serde_json::to_writer(&mut *file, &frame)
+            .map_err(io::Error::from)
+            .and_then(|_| file.write_all(b"\n"));
+        if let Err(e) = written {
+            warn!("Stopped capturing a stream: {e}");
+            self.file = None;
+        }
+    }
+
+    fn flush_file(&mut self) {
+        if let Some(file) = self.file.as_mut() {
+            let _ = file.flush();
+        }
+    }
+}
+
+impl AsyncRead for CaptureStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n > 0 {
+                this.record("in", &buf[..n]);
+            }
+        }
+        poll
+    }
+}
+
+impl AsyncWrite for CaptureStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            this.record("out", &buf[..n]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.flush_file();
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.flush_file();
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+/// read a capture file
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<(Header, Vec<Frame>)> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    let header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?)?,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "empty capture",
+            ))
+        }
+    };
+    let mut frames = Vec::new();
+    for line in lines {
+        let line = line?;
+        // the last line may be cut short if the stream was still open
+        match serde_json::from_str(&line) {
+            Ok(frame) => frames.push(frame),
+            Err(_) => break,
+        }
+    }
+    Ok((header, frames))
+}
+
+/// the protocol the stream negotiated, the first one proposed that the other side echoed
+pub fn protocol(frames: &[Frame]) -> Option<String> {
+    let messages = |dir: &str| {
+        let bytes: Vec<u8> = frames
+            .iter()
+            .filter(|f| f.dir == dir)
+            .map_while(|f| f.bytes())
+            .flatten()
+            .collect();
+        messages(&bytes)
+    };
+    let sent = messages("out");
+    let received = messages("in");
+    sent.into_iter()
+        .filter(|m| m != MULTISTREAM && m != "na")
+        .find(|m| received.contains(m))
+}
+
+// true if the bytes are only multistream-select messages
+fn is_negotiation(bytes: &[u8]) -> bool {
+    let mut rest = bytes;
+    let mut any = false;
+    while !rest.is_empty() {
+        match message(rest) {
+            Some((_, used)) => rest = &rest[used..],
+            None => return false,
+        }
+        any = true;
+    }
+    any
+}
+
+// the multistream-select messages at the start of the bytes
+fn messages(mut bytes: &[u8]) -> Vec<String> {
+    let mut found = Vec::new();
+    while let Some((m, used)) = message(bytes) {
+        found.push(m);
+        bytes = &bytes[used..];
+    }
+    found
+}
+
+// one varint length prefixed, newline terminated message and the bytes it used
+fn message(bytes: &[u8]) -> Option<(String, usize)> {
+    let (len, prefix) = uvarint(bytes)?;
+    let body = bytes.get(prefix..prefix + len)?;
+    let text = std::str::from_utf8(body.strip_suffix(b"\n")?).ok()?;
+    if !(text.starts_with('/') || text == "na" || text == "ls") {
+        return None;
+    }
+    Some((text.to_string(), prefix + len))
+}
+
+fn uvarint(bytes: &[u8]) -> Option<(usize, usize)> {
+    let mut value = 0usize;
+    for (i, b) in bytes.iter().enumerate().take(4) {
+        value |= usize::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(dir: &str, bytes: &[u8]) -> Frame {
+        Frame {
+            t: 0,
+            dir: dir.into(),
+            len: bytes.len(),
+            data: Some(hex::encode(bytes)),
+        }
+    }
+
+    fn msg(text: &str) -> Vec<u8> {
+        let mut bytes = vec![text.len() as u8 + 1];
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.push(b'\n');
+        bytes
+    }
+
+    #[test]
+    fn negotiated_protocol() {
+        let out = [
+            msg(MULTISTREAM),
+            msg("/ipfs/kad/2.0.0"),
+            msg("/ipfs/kad/1.0.0"),
+        ]
+        .concat();
+        let reply = [msg(MULTISTREAM), msg("na"), msg("/ipfs/kad/1.0.0")].concat();
+        let frames = vec![
+            frame("out", &out),
+            frame("in", &reply),
+            frame("out", &[0x08, 0x01, 0x02]),
+        ];
+        assert_eq!(protocol(&frames).as_deref(), Some("/ipfs/kad/1.0.0"));
+        assert!(is_negotiation(&out));
+        assert!(!is_negotiation(&[0x08, 0x01, 0x02]));
+    }
+}
@@ -0,0 +1,343 @@
+//! Crawl results, streamed to disk one peer at a time.
+//!
+//! A census is a file of json lines, one [`CensusRecord`] per peer. Records are appended
+//! as each peer is finished so a crawl that dies part way through loses nothing but the
+//! peers in flight, and reopening the file resumes the crawl without contacting the peers
+//! already in it.
+
+use crate::peerstore::{now_secs, PeerRecord};
+use libp2p::{identify, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, LineWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// What a crawl learned about one peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CensusRecord {
+    pub peer: PeerId,
+    /// the listen addresses the peer reported, or the ones we tried if it was unreachable
+    pub addrs: Vec<Multiaddr>,
+    pub agent: Option<String>,
+    pub protocol_version: Option<String>,
+    pub protocols: Vec<String>,
+    /// true if the peer answered identify
+    pub reachable: bool,
+    /// why the peer couldn't be reached
+    pub error: Option<String>,
+    /// unix time in seconds the peer was checked
+    pub seen: u64,
+    /// how far ahead of ours the peer's clock is estimated to be, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skew_ms: Option<i64>,
+}
+
+impl CensusRecord {
+    /// a peer that answered identify
+    pub fn identified(peer: PeerId, info: &identify::Info) -> Self {
+        Self {
+            peer,
+            addrs: info.listen_addrs.clone(),
+            agent: Some(info.agent_version.clone()),
+            protocol_version: Some(info.protocol_version.clone()),
+            protocols: info.protocols.iter().map(|p| p.to_string()).collect(),
+            reachable: true,
+            error: None,
+            seen: now_secs(),
+            skew_ms: None,
+        }
+    }
+
+    /// a peer from a peer store, reachable if it ever identified
+    pub fn known(peer: PeerId, record: &PeerRecord) -> Self {
+        Self {
+            peer,
+            addrs: record.addrs.clone(),
+            agent: record.agent.clone(),
+            protocol_version: None,
+            protocols: record.protocols.clone(),
+            reachable: record.agent.is_some(),
+            error: None,
+            seen: record.last_seen,
+            skew_ms: None,
+        }
+    }
+
+    /// a peer we couldn't identify
+    pub fn unreachable(peer: PeerId, addrs: Vec<Multiaddr>, error: String) -> Self {
+        Self {
+            peer,
+            addrs,
+            agent: None,
+            protocol_version: None,
+            protocols: Vec::new(),
+            reachable: false,
+            error: Some(error),
+            seen: now_secs(),
+            skew_ms: None,
+        }
+    }
+}
+
+/// A peer whose agent string changed between two censuses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AgentChange {
+    pub peer: PeerId,
+    pub from: String,
+    pub to: String,
+}
+
+/// The changes between two censuses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CensusDiff {
+    /// peers only in the later census
+    pub joined: Vec<PeerId>,
+    /// peers only in the earlier census
+    pub left: Vec<PeerId>,
+    /// peers in both whose listen addresses changed
+    pub moved: Vec<PeerId>,
+    /// peers in both whose agent changed
+    pub agents: Vec<AgentChange>,
+    /// reachable peers in each census
+    pub reachable: (usize, usize),
+    /// the fraction of all peers seen in either census that are only in one of them
+    pub churn: f64,
+}
+
+impl CensusDiff {
+    /// compare an earlier census with a later one
+    pub fn new(before: &[CensusRecord], after: &[CensusRecord]) -> Self {
+        let index = |records: &[CensusRecord]| -> HashMap<PeerId, CensusRecord> {
+            records.iter().map(|r| (r.peer, r.clone())).collect()
+        };
+        let (a, b) = (index(before), index(after));
+
+        let mut diff = CensusDiff {
+            reachable: (
+                a.values().filter(|r| r.reachable).count(),
+                b.values().filter(|r| r.reachable).count(),
+            ),
+            ..Default::default()
+        };
+        for (peer, later) in &b {
+            let Some(earlier) = a.get(peer) else {
+                diff.joined.push(*peer);
+                continue;
+            };
+            // only compare what both censuses actually learned from the peer
+            if !(earlier.reachable && later.reachable) {
+                continue;
+            }
+            let mut addrs = (earlier.addrs.clone(), later.addrs.clone());
+            addrs.0.sort();
+            addrs.1.sort();
+            if addrs.0 != addrs.1 {
+                diff.moved.push(*peer);
+            }
+            if let (Some(from), Some(to)) = (&earlier.agent, &later.agent) {
+                if from != to {
+                    diff.agents.push(AgentChange {
+                        peer: *peer,
+                        from: from.clone(),
+                        to: to.clone(),
+                    });
+                }
+            }
+        }
+        diff.left = a.keys().filter(|p| !b.contains_key(p)).copied().collect();
+        diff.joined.sort();
+        diff.left.sort();
+        diff.moved.sort();
+        diff.agents.sort_by_key(|c| c.peer);
+
+        let union = a.len() + diff.joined.len();
+        let changed = diff.joined.len() + diff.left.len();
+        diff.churn = if union == 0 {
+            0.0
+        } else {
+            changed as f64 / union as f64
+        };
+        diff
+    }
+}
+
+/// count the peers in a census by some key, skipping those without one
+pub fn count_by<K, F>(records: &[CensusRecord], key: F) -> BTreeMap<K, usize>
+where
+    K: Ord,
+    F: Fn(&CensusRecord) -> Option<K>,
+{
+    let mut counts = BTreeMap::new();
+    for record in records {
+        if let Some(k) = key(record) {
+            *counts.entry(k).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// An append only census file
+#[derive(Debug)]
+pub struct CensusWriter {
+    path: PathBuf,
+    out: LineWriter<File>,
+    done: HashSet<PeerId>,
+}
+
+impl CensusWriter {
+    /// open a census for appending, remembering the peers it already holds and dropping a
+    /// partial last line left by a crash
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let done = match read_valid(&path) {
+            Ok((records, len)) => {
+                if fs::metadata(&path)?.len() > len {
+                    OpenOptions::new().write(true).open(&path)?.set_len(len)?;
+                }
+                records.into_iter().map(|r| r.peer).collect()
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+            Err(e) => return Err(e),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            out: LineWriter::new(file),
+            done,
+        })
+    }
+
+    /// the path of the census file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// true if the census already has a record for the peer
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.done.contains(peer)
+    }
+
+    /// append a record
+    pub fn write(&mut self, record: &CensusRecord) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        self.out.write_all(b"\n")?;
+        self.done.insert(record.peer);
+        Ok(())
+    }
+
+    /// the number of peers in the census
+    pub fn len(&self) -> usize {
+        self.done.len()
+    }
+
+    /// true if the census is empty
+    pub fn is_empty(&self) -> bool {
+        self.done.is_empty()
+    }
+
+    /// make sure everything written is on disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        self.out.get_ref().sync_data()
+    }
+}
+
+/// the name of a census file started at the given unix time
+pub fn file_name(started: u64) -> String {
+    format!("census-{started}.jsonl")
+}
+
+/// the census files in a directory and the unix time each was started, oldest first
+pub fn list<P: AsRef<Path>>(dir: P) -> io::Result<Vec<(u64, PathBuf)>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let started = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("census-")?.strip_suffix(".jsonl"))
+            .and_then(|t| t.parse().ok());
+        if let Some(started) = started {
+            found.push((started, path));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// remove all but the newest `keep` census files in a directory, returning how many were
+/// removed
+pub fn prune<P: AsRef<Path>>(dir: P, keep: usize) -> io::Result<usize> {
+    let found = list(dir)?;
+    let remove = found.len().saturating_sub(keep);
+    for (_, path) in &found[..remove] {
+        fs::remove_file(path)?;
+    }
+    Ok(remove)
+}
+
+/// read every record in a census, stopping at a partial last line left by a crash
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<CensusRecord>> {
+    Ok(read_valid(path.as_ref())?.0)
+}
+
+// the records up to the first bad line and the length in bytes of the good ones
+fn read_valid(path: &Path) -> io::Result<(Vec<CensusRecord>, u64)> {
+    let mut records = Vec::new();
+    let mut len = 0;
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(record) => {
+                records.push(record);
+                len += line.len() as u64 + 1;
+            }
+            Err(_) => break,
+        }
+    }
+    Ok((records, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(peer: PeerId, agent: &str, addr: &str) -> CensusRecord {
+        CensusRecord {
+            peer,
+            addrs: vec![addr.parse().unwrap()],
+            agent: Some(agent.to_string()),
+            protocol_version: None,
+            protocols: Vec::new(),
+            reachable: true,
+            error: None,
+            seen: 0,
+            skew_ms: None,
+        }
+    }
+
+    #[test]
+    fn diff() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let before = vec![
+            record(a, "kubo/0.20.0", "/ip4/10.0.0.1/tcp/4001"),
+            record(b, "kubo/0.20.0", "/ip4/10.0.0.2/tcp/4001"),
+        ];
+        let after = vec![
+            record(b, "kubo/0.21.0", "/ip4/10.0.0.3/tcp/4001"),
+            record(c, "go-ipfs/0.8.0", "/ip4/10.0.0.4/tcp/4001"),
+        ];
+        let diff = CensusDiff::new(&before, &after);
+        assert_eq!(diff.joined, vec![c]);
+        assert_eq!(diff.left, vec![a]);
+        assert_eq!(diff.moved, vec![b]);
+        assert_eq!(diff.agents[0].to, "kubo/0.21.0");
+        assert!((diff.churn - 2.0 / 3.0).abs() < f64::EPSILON);
+
+        let agents = count_by(&after, |r| r.agent.clone());
+        assert_eq!(agents.get("kubo/0.21.0"), Some(&1));
+    }
+}
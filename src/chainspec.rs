@@ -0,0 +1,61 @@
+//! Substrate chain spec files, for the bootnodes and protocol names of a chain.
+
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::{fs, path::Path};
+
+/// The parts of a chain spec fleyg uses
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChainSpec {
+    pub name: String,
+    pub id: String,
+    #[serde(default)]
+    pub boot_nodes: Vec<String>,
+    /// the legacy protocol id, used in the old kademlia protocol name
+    pub protocol_id: Option<String>,
+    /// set when the chain was forked from another with the same genesis
+    pub fork_id: Option<String>,
+}
+
+impl ChainSpec {
+    /// read a chain spec, the big genesis section is ignored
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let json = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+        serde_json::from_str(&json).map_err(|e| format!("{}: {e}", path.display()))
+    }
+
+    /// the bootnode addresses, an error naming the first that doesn't parse
+    pub fn bootnodes(&self) -> Result<Vec<Multiaddr>, String> {
+        self.boot_nodes
+            .iter()
+            .map(|a| {
+                a.parse()
+                    .map_err(|e| format!("invalid bootnode in {}: {a}: {e}", self.id))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        let spec: ChainSpec = serde_json::from_str(
+            r#"{
+                "name": "Polkadot",
+                "id": "polkadot",
+                "chainType": "Live",
+                "bootNodes": ["/dns/boot.example.com/tcp/30333/p2p/12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"],
+                "protocolId": "dot",
+                "genesis": {"raw": {}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(spec.protocol_id.as_deref(), Some("dot"));
+        assert_eq!(spec.bootnodes().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+//! The fleyg configuration file.
+//!
+//! Every setting is optional, command line flags take precedence over the file.
+
+use crate::{
+    alerts::Rule,
+    dialrace::DialSettings,
+    fastpath::DelegatedSettings,
+    health::Thresholds,
+    identifyfilter::IdentifySettings,
+    infra::{InfraSettings, RelayServerSettings},
+    merge::MergeRule,
+    mirror::MirrorSettings,
+    pex::PexSettings,
+    querybudget::QuerySettings,
+    readiness::BootstrapGoal,
+    retry::RetryConfig,
+    routingpolicy::RoutingPolicy,
+    schedule::Task,
+    store::StoreSettings,
+    transport::{NoiseSettings, TransportSettings},
+};
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, io, path::Path, path::PathBuf};
+
+/// The settings read from a config file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// the network preset to join
+    pub network: Option<String>,
+    /// a substrate chain spec to take the bootnodes and protocol names from
+    pub chainspec: Option<PathBuf>,
+    /// the genesis hash of the chain spec's chain, built in chains know theirs
+    pub genesis_hash: Option<String>,
+    /// bootstrap peers to use instead of the network's
+    pub bootnodes: Option<Vec<Multiaddr>>,
+    /// the addresses to listen on
+    pub listen: Option<Vec<Multiaddr>>,
+    /// dial the bootstrap peers on start up
+    pub dial: Option<bool>,
+    /// find peers on the local network with mDNS
+    pub mdns: Option<bool>,
+    /// the relays to reserve a slot on when we're behind a NAT
+    pub relays: Option<Vec<Multiaddr>>,
+    /// whether to relay circuits for other peers and with what limits
+    pub relay_server: Option<RelayServerSettings>,
+    /// file to remember identify info about peers across sessions
+    pub peer_store: Option<PathBuf>,
+    /// directory to record peer sessions into
+    pub record_sessions: Option<PathBuf>,
+    /// directory to save routing table snapshots into
+    pub rt_snapshots: Option<PathBuf>,
+    /// seconds between routing table snapshots
+    pub rt_snapshot_interval: Option<u64>,
+    /// directory to archive every event into
+    pub archive_events: Option<PathBuf>,
+    /// the noise handshake settings for a private network
+    pub noise: Option<NoiseSettings>,
+    /// the transports to run and the certificate for wss listeners
+    pub transport: Option<TransportSettings>,
+    /// the services `fleyg infra` runs
+    pub infra: Option<InfraSettings>,
+    /// the levels the health report checks have to reach
+    pub health: Option<Thresholds>,
+    /// rules that report a degraded node
+    pub alerts: Option<Vec<Rule>>,
+    /// how many DHT queries run at once
+    pub queries: Option<QuerySettings>,
+    /// how many of a peer's addresses are dialed at once and which go first
+    pub dials: Option<DialSettings>,
+    /// how failed dials and queries are retried, by default and per command
+    pub retry: Option<RetryConfig>,
+    /// the keys `fleyg mirror` copies onto another network
+    pub mirror: Option<MirrorSettings>,
+    /// what our identify answers leave out or mask
+    pub identify: Option<IdentifySettings>,
+    /// how full the routing table has to be for the node to count as bootstrapped
+    pub bootstrap: Option<BootstrapGoal>,
+    /// where the kademlia records are kept and how many
+    pub store: Option<StoreSettings>,
+    /// whether to swap peer store samples with other fleyg nodes
+    pub pex: Option<PexSettings>,
+    /// what to do with the peers kademlia leaves out of its routing table
+    pub routing: Option<RoutingPolicy>,
+    /// the delegated routing server `get --budget` races the DHT with
+    pub delegated: Option<DelegatedSettings>,
+    /// how get picks a value when peers return different ones, by key prefix
+    pub merge: Option<Vec<MergeRule>>,
+    /// the commands serve runs on a schedule, each a `[[task]]`
+    #[serde(rename = "task")]
+    pub tasks: Option<Vec<Task>>,
+}
+
+/// Errors from loading or saving a config file
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Serialize(toml::ser::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "config i/o error: {e}"),
+            Error::Parse(e) => write!(f, "config parse error: {e}"),
+            Error::Serialize(e) => write!(f, "config serialize error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl Config {
+    /// load the config file at the given path, a missing file is an empty config
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        match fs::read_to_string(path) {
+            Ok(s) => toml::from_str(&s).map_err(Error::Parse),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// save the config to the given path
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let s = toml::to_string_pretty(self).map_err(Error::Serialize)?;
+        fs::write(path, s)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let cfg: Config = toml::from_str("dial = true\nrt_snapshot_interval = 60\n").unwrap();
+        assert_eq!(cfg.dial, Some(true));
+        assert_eq!(cfg.rt_snapshot_interval, Some(60));
+        assert!(toml::from_str::<Config>("bogus = 1\n").is_err());
+
+        let cfg: Config = toml::from_str(
+            "[[alerts]]\nname = \"small\"\nwhen = { routing_table_below = 50 }\nfor = \"5m\"\n",
+        )
+        .unwrap();
+        assert_eq!(cfg.alerts.unwrap()[0].hold.as_deref(), Some("5m"));
+    }
+}
@@ -0,0 +1,651 @@
+//! The control API other programs drive a running `fleyg daemon` through.
+//!
+//! The daemon answers HTTP requests with json on its control socket, `fleyg.sock` in the
+//! state directory or on windows the state directory's named pipe, or wherever
+//! `--api-listen` says, and `fleyg ctl` is its client. Keys and values are hex:
+//!
+//! ```text
+//! POST /dial       {"target": "<multiaddr or peer id>"}
+//! POST /get        {"key": "2f666f6f"}, a signed answer with the value
+//! POST /put        {"key": "2f666f6f", "value": "626172"}
+//! POST /providers  {"key": "2f666f6f"}, a signed answer with the key's providers
+//! POST /closest    {"key": "2f666f6f"}, a signed answer with the peers closest to the key
+//! GET  /peers      the connected peers
+//! GET  /identify   this node, or ?peer=<id> what a peer identifies as
+//! POST /dns/flush  forget the cached bootstrap resolutions and resolve them again
+//! GET  /snapshot   a bug report snapshot of the node
+//! GET  /tasks      the last run of each scheduled task
+//! GET  /table      the routing table, each bucket's peers and whether they're connected
+//! GET  /census     the peers in the peer store as census records, for `fleyg fleet`
+//! GET  /store/fsck  check the record store's log against the records in memory
+//!
+//! curl --unix-socket ~/.local/share/fleyg/fleyg.sock http://localhost/peers
+//! ```
+//!
+//! Like the metrics endpoint it has no authentication, so it only listens on unix
+//! sockets, named pipes and loopback addresses. Each connection is answered on its own
+//! thread, which hands the request to the node's event loop and waits for the reply.
+//!
+//! A call can carry the trace id of the operation making it in an `X-Fleyg-Trace` header,
+//! `fleyg ctl` sends its own, and the daemon tags its log lines for the call with it and
+//! sends it back. Calls without one get a new trace id.
+//!
+//! The API is only served and called when fleyg is built with the `api` feature, the
+//! requests and replies are always there for the programs that embed fleyg to use.
+
+use crate::{apilisten::ApiListen, trace::TraceId};
+use futures::channel::mpsc;
+use libp2p::{Multiaddr, PeerId};
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{fmt, io, str::FromStr, sync::mpsc as sync_mpsc};
+#[cfg(feature = "api")]
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    thread,
+    time::Duration,
+};
+
+/// requests with larger bodies are refused
+#[cfg(feature = "api")]
+const MAX_BODY: usize = 64 * 1024;
+
+/// how long a connection waits for the node to answer
+#[cfg(feature = "api")]
+const CALL_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// the header carrying the caller's trace id
+pub const TRACE_HEADER: &str = "X-Fleyg-Trace";
+
+/// What to dial
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Target {
+    Addr(Multiaddr),
+    Peer(PeerId),
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse::<PeerId>() {
+            Ok(peer) => Ok(Target::Peer(peer)),
+            Err(_) => s
+                .parse()
+                .map(Target::Addr)
+                .map_err(|e| format!("bad address {s}: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Addr(addr) => write!(f, "{addr}"),
+            Target::Peer(peer) => write!(f, "{peer}"),
+        }
+    }
+}
+
+/// A call to the control API
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Request {
+    Dial(Target),
+    Get {
+        key: Vec<u8>,
+    },
+    Put {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Providers {
+        key: Vec<u8>,
+    },
+    Closest {
+        key: Vec<u8>,
+    },
+    Peers,
+    /// ourselves, or a peer
+    Identify(Option<PeerId>),
+    FlushDns,
+    Snapshot,
+    Tasks,
+    Table,
+    Census,
+    StoreFsck,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DialBody {
+    target: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct KeyBody {
+    #[serde(with = "hex::serde")]
+    key: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PutBody {
+    #[serde(with = "hex::serde")]
+    key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    value: Vec<u8>,
+}
+
+impl Request {
+    /// the call an HTTP request makes
+    pub fn parse(method: &str, target: &str, body: &[u8]) -> Result<Self, Reply> {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let request = match (method, path) {
+            ("POST", "/dial") => {
+                let DialBody { target } = from_json(body)?;
+                Request::Dial(target.parse().map_err(|e| Reply::error(400, e))?)
+            }
+            ("POST", "/get") => {
+                let KeyBody { key } = from_json(body)?;
+                Request::Get { key }
+            }
+            ("POST", "/put") => {
+                let PutBody { key, value } = from_json(body)?;
+                Request::Put { key, value }
+            }
+            ("POST", "/providers") => {
+                let KeyBody { key } = from_json(body)?;
+                Request::Providers { key }
+            }
+            ("POST", "/closest") => {
+                let KeyBody { key } = from_json(body)?;
+                Request::Closest { key }
+            }
+            ("GET", "/peers") => Request::Peers,
+            ("GET", "/identify") => {
+                let peer = query
+                    .split('&')
+                    .find_map(|pair| pair.strip_prefix("peer="))
+                    .map(|peer| peer.parse::<PeerId>())
+                    .transpose()
+                    .map_err(|e| Reply::error(400, format!("bad peer id: {e}")))?;
+                Request::Identify(peer)
+            }
+            ("POST", "/dns/flush") => Request::FlushDns,
+            ("GET", "/snapshot") => Request::Snapshot,
+            ("GET", "/tasks") => Request::Tasks,
+            ("GET", "/table") => Request::Table,
+            ("GET", "/census") => Request::Census,
+            ("GET", "/store/fsck") => Request::StoreFsck,
+            (
+                _,
+                "/dial" | "/get" | "/put" | "/providers" | "/closest" | "/peers" | "/identify"
+                | "/dns/flush" | "/snapshot" | "/tasks" | "/table" | "/census" | "/store/fsck",
+            ) => {
+                return Err(Reply::error(
+                    405,
+                    format!("{method} isn't allowed on {path}"),
+                ))
+            }
+            _ => return Err(Reply::error(404, format!("no such endpoint {path}"))),
+        };
+        Ok(request)
+    }
+
+    /// the method, target and body of the HTTP request making the call
+    pub fn to_http(&self) -> (&'static str, String, Vec<u8>) {
+        match self {
+            Request::Dial(target) => (
+                "POST",
+                "/dial".into(),
+                json(&DialBody {
+                    target: target.to_string(),
+                }),
+            ),
+            Request::Get { key } => ("POST", "/get".into(), json(&KeyBody { key: key.clone() })),
+            Request::Put { key, value } => (
+                "POST",
+                "/put".into(),
+                json(&PutBody {
+                    key: key.clone(),
+                    value: value.clone(),
+                }),
+            ),
+            Request::Providers { key } => (
+                "POST",
+                "/providers".into(),
+                json(&KeyBody { key: key.clone() }),
+            ),
+            Request::Closest { key } => (
+                "POST",
+                "/closest".into(),
+                json(&KeyBody { key: key.clone() }),
+            ),
+            Request::Peers => ("GET", "/peers".into(), Vec::new()),
+            Request::Identify(None) => ("GET", "/identify".into(), Vec::new()),
+            Request::Identify(Some(peer)) => ("GET", format!("/identify?peer={peer}"), Vec::new()),
+            Request::FlushDns => ("POST", "/dns/flush".into(), Vec::new()),
+            Request::Snapshot => ("GET", "/snapshot".into(), Vec::new()),
+            Request::Tasks => ("GET", "/tasks".into(), Vec::new()),
+            Request::Table => ("GET", "/table".into(), Vec::new()),
+            Request::Census => ("GET", "/census".into(), Vec::new()),
+            Request::StoreFsck => ("GET", "/store/fsck".into(), Vec::new()),
+        }
+    }
+}
+
+fn json<T: Serialize>(body: &T) -> Vec<u8> {
+    serde_json::to_vec(body).expect("request bodies always serialize")
+}
+
+fn from_json<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Reply> {
+    serde_json::from_slice(body).map_err(|e| Reply::error(400, format!("bad body: {e}")))
+}
+
+/// The answer to a call, an HTTP status and a json body
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reply {
+    pub status: u16,
+    pub body: Value,
+}
+
+impl Reply {
+    pub fn ok<T: Serialize>(body: &T) -> Self {
+        match serde_json::to_value(body) {
+            Ok(body) => Self { status: 200, body },
+            Err(e) => Self::error(500, e),
+        }
+    }
+
+    /// a failure, with what went wrong in the body's error
+    pub fn error<E: fmt::Display>(status: u16, error: E) -> Self {
+        Self {
+            status,
+            body: serde_json::json!({ "error": error.to_string() }),
+        }
+    }
+
+    #[cfg(feature = "api")]
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            502 => "Bad Gateway",
+            504 => "Gateway Timeout",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// A call waiting for the node to answer it
+#[derive(Debug)]
+pub struct Call {
+    pub request: Request,
+    /// the caller's trace id, or a new one if it sent none
+    pub trace: TraceId,
+    reply: sync_mpsc::Sender<Reply>,
+}
+
+impl Call {
+    /// answer the call, the connection may have given up on it already
+    pub fn reply(self, reply: Reply) {
+        let _trace = self.trace.scope();
+        debug!("Control reply: {}", reply.status);
+        let _ = self.reply.send(reply);
+    }
+}
+
+/// listen for calls on the API, returning the stream of calls for the node to answer
+#[cfg(feature = "api")]
+pub fn listen(api: &ApiListen) -> io::Result<mpsc::UnboundedReceiver<Call>> {
+    api.check("control")
+        .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+    let (calls, received) = mpsc::unbounded();
+    match api {
+        ApiListen::Tcp(addr) => {
+            let listener = std::net::TcpListener::bind(addr)?;
+            thread::spawn(move || accept(listener.incoming(), calls));
+        }
+        #[cfg(unix)]
+        ApiListen::Unix(path) => {
+            let listener = crate::apilisten::unix::bind(path)?;
+            thread::spawn(move || accept(listener.incoming(), calls));
+        }
+        #[cfg(not(unix))]
+        ApiListen::Unix(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "unix sockets aren't supported here",
+            ))
+        }
+        #[cfg(windows)]
+        ApiListen::Pipe(name) => {
+            // the first instance is created here so a name in use fails now
+            let first = pipe::create(name, true)?;
+            let name = name.clone();
+            thread::spawn(move || pipe::accept(first, &name, calls));
+        }
+        #[cfg(not(windows))]
+        ApiListen::Pipe(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "named pipes are only supported on windows",
+            ))
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(not(feature = "api"))]
+pub fn listen(_api: &ApiListen) -> io::Result<mpsc::UnboundedReceiver<Call>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, UNSUPPORTED))
+}
+
+#[cfg(not(feature = "api"))]
+const UNSUPPORTED: &str = "fleyg was built without the api feature";
+
+// answer each connection on its own thread
+#[cfg(feature = "api")]
+fn accept<I, S>(incoming: I, calls: mpsc::UnboundedSender<Call>)
+where
+    I: Iterator<Item = io::Result<S>>,
+    S: Read + Write + Send + 'static,
+{
+    for stream in incoming {
+        match stream {
+            Ok(mut stream) => {
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    if let Err(e) = respond(&mut stream, &calls) {
+                        debug!("Control connection failed: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Can't accept a control connection: {e}"),
+        }
+    }
+}
+
+// read a request, hand it to the node and write its reply
+#[cfg(feature = "api")]
+fn respond<S: Read + Write>(stream: &mut S, calls: &mpsc::UnboundedSender<Call>) -> io::Result<()> {
+    let (line, trace, body) = read_message(&mut *stream)?;
+    let trace = trace.unwrap_or_default();
+    let mut words = line.split_whitespace();
+    let reply = match (words.next(), words.next()) {
+        (Some(method), Some(target)) => match Request::parse(method, target, &body) {
+            Ok(request) => {
+                let (reply, replied) = sync_mpsc::channel();
+                let call = Call {
+                    request,
+                    trace,
+                    reply,
+                };
+                match calls.unbounded_send(call) {
+                    Ok(()) => replied
+                        .recv_timeout(CALL_TIMEOUT)
+                        .unwrap_or_else(|_| Reply::error(504, "the node didn't answer in time")),
+                    Err(_) => Reply::error(500, "the node is stopping"),
+                }
+            }
+            Err(reply) => reply,
+        },
+        _ => Reply::error(400, "bad request line"),
+    };
+    let body = serde_json::to_vec(&reply.body)?;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{TRACE_HEADER}: {trace}\r\nConnection: close\r\n\r\n",
+        reply.status,
+        reply.reason(),
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
+
+/// make a call to the daemon listening on the API, returning the body of its reply
+#[cfg(feature = "api")]
+pub fn call(api: &ApiListen, request: &Request) -> Result<Value, String> {
+    let connect = |e: io::Error| format!("can't reach the daemon on {api}: {e}");
+    match api {
+        ApiListen::Tcp(addr) => call_on(
+            std::net::TcpStream::connect(addr).map_err(connect)?,
+            request,
+        ),
+        #[cfg(unix)]
+        ApiListen::Unix(path) => {
+            let stream = std::os::unix::net::UnixStream::connect(path).map_err(connect)?;
+            call_on(stream, request)
+        }
+        #[cfg(not(unix))]
+        ApiListen::Unix(_) => Err("unix sockets aren't supported here".into()),
+        // opening a pipe connects to it
+        ApiListen::Pipe(name) => {
+            let pipe = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(name)
+                .map_err(connect)?;
+            call_on(pipe, request)
+        }
+    }
+}
+
+#[cfg(not(feature = "api"))]
+pub fn call(_api: &ApiListen, _request: &Request) -> Result<Value, String> {
+    Err(UNSUPPORTED.into())
+}
+
+#[cfg(feature = "api")]
+fn call_on<S: Read + Write>(mut stream: S, request: &Request) -> Result<Value, String> {
+    let (method, target, body) = request.to_http();
+    let trace = match TraceId::current() {
+        Some(trace) => format!("{TRACE_HEADER}: {trace}\r\n"),
+        None => String::new(),
+    };
+    let sent = write!(
+        stream,
+        "{method} {target} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n{trace}\r\n",
+        body.len()
+    )
+    .and_then(|_| stream.write_all(&body))
+    .and_then(|_| stream.flush());
+    sent.map_err(|e| format!("can't send the call: {e}"))?;
+    let (line, _, body) = read_message(&mut stream).map_err(|e| format!("bad reply: {e}"))?;
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| format!("bad reply status line: {line}"))?;
+    let body: Value = serde_json::from_slice(&body).map_err(|e| format!("bad reply: {e}"))?;
+    match status {
+        200 => Ok(body),
+        _ => Err(match body.get("error").and_then(Value::as_str) {
+            Some(error) => error.to_string(),
+            None => format!("the daemon answered {line}"),
+        }),
+    }
+}
+
+// the first line, trace id and body of an HTTP message, the body's length from its
+// Content-Length or else the rest of the stream
+#[cfg(feature = "api")]
+fn read_message<R: Read>(reader: R) -> io::Result<(String, Option<TraceId>, Vec<u8>)> {
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut length = None;
+    let mut trace = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse::<usize>().ok();
+            } else if name.trim().eq_ignore_ascii_case(TRACE_HEADER) {
+                trace = value.trim().parse().ok();
+            }
+        }
+    }
+    let mut body = Vec::new();
+    match length {
+        Some(length) if length > MAX_BODY => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the body is too large",
+            ))
+        }
+        Some(length) => {
+            body.resize(length, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None => {
+            reader.take(MAX_BODY as u64).read_to_end(&mut body)?;
+        }
+    }
+    Ok((line.trim_end().to_string(), trace, body))
+}
+
+/// Serving on a named pipe that refuses remote clients
+#[cfg(all(windows, feature = "api"))]
+mod pipe {
+    use super::*;
+    use std::{
+        ffi::OsStr,
+        fs::File,
+        os::windows::{ffi::OsStrExt, io::FromRawHandle},
+        ptr,
+    };
+    use windows_sys::Win32::{
+        Foundation::{ERROR_PIPE_CONNECTED, INVALID_HANDLE_VALUE},
+        Storage::FileSystem::{FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX},
+        System::Pipes::{
+            ConnectNamedPipe, CreateNamedPipeW, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+        },
+    };
+
+    const BUFFER: u32 = 64 * 1024;
+
+    /// a new instance of the pipe, the first one failing if the pipe exists
+    pub fn create(name: &str, first: bool) -> io::Result<File> {
+        let wide: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+        let open = match first {
+            true => PIPE_ACCESS_DUPLEX | FILE_FLAG_FIRST_PIPE_INSTANCE,
+            false => PIPE_ACCESS_DUPLEX,
+        };
+        let mode = PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS;
+        let handle = unsafe {
+            CreateNamedPipeW(
+                wide.as_ptr(),
+                open,
+                mode,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER,
+                BUFFER,
+                0,
+                ptr::null(),
+            )
+        };
+        if handle == INVALID_HANDLE_VALUE {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { File::from_raw_handle(handle as _) })
+    }
+
+    // wait for a client on each instance and answer it on its own thread, a new instance
+    // takes the next client
+    pub fn accept(mut instance: File, name: &str, calls: mpsc::UnboundedSender<Call>) {
+        use std::os::windows::io::AsRawHandle;
+        loop {
+            let connected =
+                unsafe { ConnectNamedPipe(instance.as_raw_handle() as _, ptr::null_mut()) };
+            let error = io::Error::last_os_error();
+            if connected != 0 || error.raw_os_error() == Some(ERROR_PIPE_CONNECTED as i32) {
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    if let Err(e) = respond(&mut instance, &calls) {
+                        debug!("Control connection failed: {e}");
+                    }
+                    // closing drops what the client hasn't read yet
+                    let _ = instance.sync_all();
+                });
+            } else {
+                warn!("Can't accept a control connection: {error}");
+            }
+            instance = match create(name, false) {
+                Ok(instance) => instance,
+                Err(e) => {
+                    warn!("Can't take more control connections on {name}: {e}");
+                    return;
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "api")]
+    use std::io::Cursor;
+
+    #[test]
+    fn requests_round_trip() {
+        let peer = PeerId::random();
+        let requests = [
+            Request::Dial(Target::Peer(peer)),
+            Request::Dial(Target::Addr("/ip4/127.0.0.1/tcp/4920".parse().unwrap())),
+            Request::Get {
+                key: b"/foo".to_vec(),
+            },
+            Request::Put {
+                key: b"/foo".to_vec(),
+                value: b"bar".to_vec(),
+            },
+            Request::Providers {
+                key: b"/foo".to_vec(),
+            },
+            Request::Closest {
+                key: b"/foo".to_vec(),
+            },
+            Request::Peers,
+            Request::Identify(None),
+            Request::Identify(Some(peer)),
+            Request::FlushDns,
+            Request::Snapshot,
+            Request::Tasks,
+            Request::Table,
+            Request::Census,
+            Request::StoreFsck,
+        ];
+        for request in requests {
+            let (method, target, body) = request.to_http();
+            assert_eq!(Request::parse(method, &target, &body).unwrap(), request);
+        }
+        assert_eq!(Request::parse("GET", "/dial", b"").unwrap_err().status, 405);
+        assert_eq!(Request::parse("GET", "/nope", b"").unwrap_err().status, 404);
+        assert_eq!(
+            Request::parse("POST", "/get", b"{}").unwrap_err().status,
+            400
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "api")]
+    fn reads_messages() {
+        let message = "POST /get HTTP/1.1\r\nHost: localhost\r\ncontent-length: 4\r\n\
+                       x-fleyg-trace: 00000000000000ff\r\n\r\n{}{}";
+        let (line, trace, body) = read_message(Cursor::new(message)).unwrap();
+        assert_eq!(line, "POST /get HTTP/1.1");
+        assert_eq!(trace, "ff".parse::<TraceId>().ok());
+        assert_eq!(body, b"{}{}");
+        let (_, trace, body) = read_message(Cursor::new("HTTP/1.1 200 OK\r\n\r\n[1]")).unwrap();
+        assert_eq!(trace, None);
+        assert_eq!(body, b"[1]");
+    }
+}
@@ -0,0 +1,30 @@
+//! Helpers for driving event streams against a deadline.
+
+use crate::runtime;
+use futures::prelude::*;
+use std::time::Instant;
+
+/// wait for the next item from the stream, returns None once the deadline passes
+pub async fn next_before<S>(stream: &mut S, deadline: Instant) -> Option<S::Item>
+where
+    S: Stream + Unpin,
+{
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    runtime::timeout(remaining, stream.next())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// keep polling the stream until the deadline, dropping its items, so a swarm keeps
+/// running while we wait
+pub async fn idle_until<S>(stream: &mut S, deadline: Instant)
+where
+    S: Stream + Unpin,
+{
+    while Instant::now() < deadline {
+        if next_before(stream, deadline).await.is_none() {
+            break;
+        }
+    }
+}
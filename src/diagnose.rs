@@ -0,0 +1,186 @@
+//! Diagnostics for dials that fail while upgrading a connection.
+//!
+//! The transport boxes every error into an `io::Error`, so the stage a dial failed at is
+//! recovered from the shape of the error chain: each upgrade layer wraps the errors of the
+//! layers below it in "Transport error: ..." and reports its own failures as
+//! "Upgrade error: ...".
+
+use libp2p::{core::transport::TransportError, swarm::DialError, Multiaddr};
+use std::{error::Error, fmt, io};
+
+/// the security protocols we offer when upgrading a connection
+pub const SECURITY_PROTOCOLS: &[&str] = &["/noise"];
+
+/// the stream multiplexers we offer when upgrading a connection
+pub const MUXER_PROTOCOLS: &[&str] = &["/yamux/1.0.0"];
+
+/// The stage of connection establishment a dial failed at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stage {
+    /// the underlying transport (tcp, dns, websocket) failed to connect
+    Transport,
+    /// the security protocol negotiation or handshake failed
+    Security,
+    /// the stream multiplexer negotiation failed
+    Muxer,
+    /// the upgrade didn't finish before the timeout
+    Timeout,
+    /// the address isn't supported by our transport
+    Unsupported,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Stage::Transport => "transport",
+            Stage::Security => "security",
+            Stage::Muxer => "muxer",
+            Stage::Timeout => "timeout",
+            Stage::Unsupported => "unsupported",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// What went wrong dialing a single address
+#[derive(Clone, Debug)]
+pub struct Diagnosis {
+    /// the address that was dialed
+    pub addr: Multiaddr,
+    /// where in the upgrade the dial failed
+    pub stage: Stage,
+    /// true if the failure was the remote rejecting every protocol we offered
+    pub negotiation_failed: bool,
+    /// the protocols we offered at the failing stage
+    pub offered: Vec<String>,
+    /// every error in the chain, outermost first
+    pub errors: Vec<String>,
+}
+
+impl Diagnosis {
+    /// diagnose the failure of a transport to dial an address
+    pub fn new(addr: &Multiaddr, error: &TransportError<io::Error>) -> Self {
+        let e = match error {
+            TransportError::MultiaddrNotSupported(_) => {
+                return Self {
+                    addr: addr.clone(),
+                    stage: Stage::Unsupported,
+                    negotiation_failed: false,
+                    offered: Vec::new(),
+                    errors: vec![error.to_string()],
+                }
+            }
+            TransportError::Other(e) => e,
+        };
+
+        // collect the messages of the whole error chain
+        let mut errors = Vec::new();
+        let mut next: Option<&(dyn Error + 'static)> =
+            e.get_ref().map(|e| e as &(dyn Error + 'static));
+        if next.is_none() {
+            errors.push(e.to_string());
+        }
+        while let Some(err) = next {
+            errors.push(err.to_string());
+            next = err.source();
+        }
+        let text = errors.join(": ");
+
+        let stage = classify(&text);
+        let offered = match stage {
+            Stage::Security => SECURITY_PROTOCOLS,
+            Stage::Muxer => MUXER_PROTOCOLS,
+            _ => &[],
+        }
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+        Self {
+            addr: addr.clone(),
+            stage,
+            negotiation_failed: text.contains("Multistream select failed")
+                || text.contains("negotiation failed"),
+            offered,
+            errors,
+        }
+    }
+
+    /// diagnose every address that failed in a dial error
+    pub fn from_dial_error(error: &DialError) -> Vec<Self> {
+        match error {
+            DialError::Transport(errors) => {
+                errors.iter().map(|(addr, e)| Self::new(addr, e)).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// true if the transport connected but the upgrade failed
+    pub fn is_upgrade_failure(&self) -> bool {
+        matches!(self.stage, Stage::Security | Stage::Muxer)
+    }
+}
+
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed at {} stage", self.addr, self.stage)?;
+        if !self.offered.is_empty() {
+            write!(f, ", we offered [{}]", self.offered.join(", "))?;
+            if self.negotiation_failed {
+                write!(f, ", remote accepted none of them")?;
+            }
+        }
+        if let Some(root) = self.errors.last() {
+            write!(f, ": {root}")?;
+        }
+        Ok(())
+    }
+}
+
+/// work out the failing stage from the error chain text
+fn classify(text: &str) -> Stage {
+    if text.contains("Timeout has been reached") {
+        return Stage::Timeout;
+    }
+
+    // the muxer upgrade is the outermost layer so its own failures come first, the
+    // security upgrade's failures are wrapped once by the muxer layer
+    match text.find("Upgrade error") {
+        Some(pos) => {
+            let lower = text.to_lowercase();
+            if lower.contains("noise") {
+                Stage::Security
+            } else if lower.contains("yamux") || lower.contains("mplex") {
+                Stage::Muxer
+            } else if text[..pos].contains("Transport error") {
+                Stage::Security
+            } else {
+                Stage::Muxer
+            }
+        }
+        None => Stage::Transport,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_stages() {
+        assert_eq!(
+            classify("Connection refused (os error 111)"),
+            Stage::Transport
+        );
+        assert_eq!(
+            classify("Transport error: Upgrade error: Multistream select failed"),
+            Stage::Security
+        );
+        assert_eq!(
+            classify("Upgrade error: Multistream select failed"),
+            Stage::Muxer
+        );
+        assert_eq!(classify("Timeout has been reached"), Stage::Timeout);
+    }
+}
@@ -0,0 +1,202 @@
+//! A prioritized queue of outbound dials.
+//!
+//! Requests are started highest priority first, at most `max_concurrent` at a time, with
+//! some of those slots reserved for user initiated dials so background work can't starve
+//! interactive commands. Peers that fail to dial are backed off exponentially, except when
+//! the user asks for them directly.
+
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+/// the first backoff after a failed dial
+const BASE_BACKOFF: Duration = Duration::from_secs(5);
+
+/// the longest a peer is backed off for
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+/// Who asked for a dial, later variants are dialed first
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    /// discovery and crawling
+    Background,
+    /// joining the network
+    Bootstrap,
+    /// a command the user is waiting on
+    User,
+}
+
+/// A dial waiting for a slot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DialRequest {
+    pub peer: PeerId,
+    /// addresses to try in addition to the ones the swarm already knows
+    pub addrs: Vec<Multiaddr>,
+    pub priority: Priority,
+}
+
+/// Failure history for a peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Backoff {
+    /// failed dials in a row
+    pub failures: u32,
+    /// don't dial again before this unless the user asks
+    pub until: Instant,
+}
+
+/// A dial scheduler with priorities, a concurrency cap and per-peer backoff
+#[derive(Debug)]
+pub struct DialQueue {
+    max_concurrent: usize,
+    reserved: usize,
+    // queued requests in the order they were pushed
+    queue: Vec<DialRequest>,
+    in_flight: HashSet<PeerId>,
+    backoff: HashMap<PeerId, Backoff>,
+}
+
+impl Default for DialQueue {
+    fn default() -> Self {
+        Self::new(32, 4)
+    }
+}
+
+impl DialQueue {
+    /// a queue running at most `max_concurrent` dials, `reserved` of which only user dials
+    /// may use
+    pub fn new(max_concurrent: usize, reserved: usize) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            reserved: reserved.min(max_concurrent.saturating_sub(1)),
+            queue: Vec::new(),
+            in_flight: HashSet::new(),
+            backoff: HashMap::new(),
+        }
+    }
+
+    /// queue a dial, a peer already queued keeps one request with the higher priority and
+    /// all of the addresses
+    pub fn push(&mut self, peer: PeerId, addrs: Vec<Multiaddr>, priority: Priority) {
+        if let Some(queued) = self.queue.iter_mut().find(|r| r.peer == peer) {
+            queued.priority = queued.priority.max(priority);
+            for addr in addrs {
+                if !queued.addrs.contains(&addr) {
+                    queued.addrs.push(addr);
+                }
+            }
+            return;
+        }
+        self.queue.push(DialRequest {
+            peer,
+            addrs,
+            priority,
+        });
+    }
+
+    /// take the next request that may be dialed now and mark it in flight
+    pub fn next_ready(&mut self, now: Instant) -> Option<DialRequest> {
+        let free = self.max_concurrent.saturating_sub(self.in_flight.len());
+        if free == 0 {
+            return None;
+        }
+        let index = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| !self.in_flight.contains(&r.peer))
+            .filter(|(_, r)| r.priority == Priority::User || free > self.reserved)
+            .filter(|(_, r)| r.priority == Priority::User || !self.backed_off(&r.peer, now))
+            // highest priority first, then oldest first
+            .max_by_key(|(i, r)| (r.priority, std::cmp::Reverse(*i)))
+            .map(|(i, _)| i)?;
+        let request = self.queue.remove(index);
+        self.in_flight.insert(request.peer);
+        Some(request)
+    }
+
+    /// the dial to a peer connected, clearing its backoff
+    pub fn succeeded(&mut self, peer: &PeerId) {
+        self.in_flight.remove(peer);
+        self.backoff.remove(peer);
+    }
+
+    /// the dial to a peer failed, backing it off for longer each time
+    pub fn failed(&mut self, peer: &PeerId, now: Instant) {
+        self.in_flight.remove(peer);
+        let failures = self.backoff.get(peer).map_or(0, |b| b.failures) + 1;
+        let delay = BASE_BACKOFF
+            .saturating_mul(2u32.saturating_pow(failures - 1))
+            .min(MAX_BACKOFF);
+        self.backoff.insert(
+            *peer,
+            Backoff {
+                failures,
+                until: now + delay,
+            },
+        );
+    }
+
+    /// the backoff state of a peer, if it has failed recently
+    pub fn backoff(&self, peer: &PeerId) -> Option<&Backoff> {
+        self.backoff.get(peer)
+    }
+
+    /// true if the peer shouldn't be dialed again yet
+    pub fn backed_off(&self, peer: &PeerId, now: Instant) -> bool {
+        self.backoff.get(peer).is_some_and(|b| b.until > now)
+    }
+
+    /// the number of dials in flight
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// the number of queued dials
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// true if nothing is queued
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn priorities_and_backoff() {
+        let now = Instant::now();
+        let mut queue = DialQueue::new(2, 1);
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        queue.push(a, vec![], Priority::Background);
+        queue.push(b, vec![], Priority::Background);
+        queue.push(c, vec![], Priority::User);
+
+        // the user dial goes first, then only one background dial is left a slot
+        assert_eq!(queue.next_ready(now).unwrap().peer, c);
+        assert_eq!(queue.next_ready(now), None);
+        queue.failed(&c, now);
+        assert_eq!(queue.next_ready(now).unwrap().peer, a);
+        assert_eq!(queue.in_flight(), 1);
+
+        // background dials respect backoff, user dials don't
+        queue.push(c, vec![], Priority::Background);
+        queue.succeeded(&a);
+        assert_eq!(queue.next_ready(now).unwrap().peer, b);
+        queue.succeeded(&b);
+        assert_eq!(queue.next_ready(now), None);
+        assert!(queue.backed_off(&c, now));
+        queue.push(c, vec![], Priority::User);
+        assert_eq!(queue.next_ready(now).unwrap().peer, c);
+
+        // backoff doubles with each failure
+        queue.failed(&c, now);
+        assert_eq!(queue.backoff(&c).unwrap().failures, 2);
+        assert_eq!(queue.backoff(&c).unwrap().until, now + BASE_BACKOFF * 2);
+    }
+}
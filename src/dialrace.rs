@@ -0,0 +1,129 @@
+//! Racing a peer's addresses against each other.
+//!
+//! A peer with many addresses connects fastest when a few of them are dialed at once and
+//! the first to connect wins, libp2p cancels the rest. The `[dials]` config section sets
+//! how many run at once and which transports go first. Addresses are dialed best first,
+//! the ones that connected before ahead of the ones that didn't and the transport
+//! preference breaking ties:
+//!
+//! ```toml
+//! [dials]
+//! race = 3
+//! prefer = ["tcp", "ws", "circuit"]
+//! ```
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroU8;
+
+/// The `[dials]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DialSettings {
+    /// addresses of a peer dialed at once
+    pub race: u8,
+    /// transports in order of preference: tcp, ws, quic, webrtc and circuit, others go
+    /// last
+    pub prefer: Vec<String>,
+}
+
+impl Default for DialSettings {
+    fn default() -> Self {
+        Self {
+            race: 3,
+            prefer: vec!["tcp".into(), "ws".into(), "circuit".into()],
+        }
+    }
+}
+
+impl DialSettings {
+    /// how many addresses the swarm dials at once
+    pub fn concurrency(&self) -> NonZeroU8 {
+        NonZeroU8::new(self.race).unwrap_or(NonZeroU8::MIN)
+    }
+
+    /// the addresses best first, keeping the order of those on the same transport
+    pub fn rank(&self, mut addrs: Vec<Multiaddr>) -> Vec<Multiaddr> {
+        let mut seen = Vec::new();
+        addrs.retain(|a| {
+            let new = !seen.contains(a);
+            seen.push(a.clone());
+            new
+        });
+        addrs.sort_by_key(|a| {
+            let transport = transport_of(a);
+            self.prefer
+                .iter()
+                .position(|p| p == transport)
+                .unwrap_or(self.prefer.len())
+        });
+        addrs
+    }
+
+    /// the addresses best first by the confidence they'll connect, then by transport
+    pub fn rank_by<F>(&self, addrs: Vec<Multiaddr>, confidence: F) -> Vec<Multiaddr>
+    where
+        F: Fn(&Multiaddr) -> f64,
+    {
+        let mut addrs = self.rank(addrs);
+        addrs.sort_by(|a, b| confidence(b).total_cmp(&confidence(a)));
+        addrs
+    }
+}
+
+/// the name of the transport an address is dialed with
+pub fn transport_of(addr: &Multiaddr) -> &'static str {
+    let mut transport = "other";
+    for protocol in addr.iter() {
+        transport = match protocol {
+            Protocol::P2pCircuit => return "circuit",
+            Protocol::Ws(_) | Protocol::Wss(_) => "ws",
+            Protocol::Quic | Protocol::QuicV1 => "quic",
+            Protocol::WebRTC | Protocol::WebRTCDirect => "webrtc",
+            Protocol::Tcp(_) if transport == "other" => "tcp",
+            _ => continue,
+        };
+    }
+    transport
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn preferred_first() {
+        let addrs: Vec<Multiaddr> = [
+            "/ip4/1.2.3.4/udp/4001/quic-v1",
+            "/ip4/1.2.3.4/tcp/4001/ws",
+            "/ip4/1.2.3.4/tcp/4001",
+            "/ip4/5.6.7.8/tcp/4001",
+            "/ip4/1.2.3.4/tcp/4001",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let ranked = DialSettings::default().rank(addrs.clone());
+        assert_eq!(
+            ranked,
+            vec![
+                addrs[2].clone(),
+                addrs[3].clone(),
+                addrs[1].clone(),
+                addrs[0].clone(),
+            ]
+        );
+        assert_eq!(transport_of(&addrs[1]), "ws");
+
+        // an address that stopped working goes last whatever its transport
+        let stale = addrs[2].clone();
+        let ranked = DialSettings::default().rank_by(addrs.clone(), |a| match a == &stale {
+            true => 0.1,
+            false => 0.5,
+        });
+        assert_eq!(
+            ranked,
+            vec![addrs[3].clone(), addrs[1].clone(), addrs[0].clone(), stale]
+        );
+    }
+}
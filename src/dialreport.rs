@@ -0,0 +1,265 @@
+//! Per-address outcome reports for dialing a peer with several known addresses.
+
+use crate::{
+    diagnose::{Diagnosis, Stage},
+    retry::Failure,
+    table::{Cell, Table},
+};
+use libp2p::{swarm::DialError, Multiaddr, PeerId};
+use std::{collections::HashMap, fmt, str::FromStr, time::Duration};
+
+/// How the addresses of a peer are attempted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DialPolicy {
+    /// dial one address at a time, in order
+    #[default]
+    Sequential,
+    /// dial every address at once
+    Concurrent,
+    /// dial the best few at once and keep the first to connect, as nodes connect
+    Race,
+}
+
+impl FromStr for DialPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(DialPolicy::Sequential),
+            "concurrent" => Ok(DialPolicy::Concurrent),
+            "race" => Ok(DialPolicy::Race),
+            _ => Err(format!("unknown dial policy: {s}")),
+        }
+    }
+}
+
+/// The result of dialing a single address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// the connection was established
+    Success { elapsed: Duration },
+    /// no answer before the deadline
+    Timeout,
+    /// the remote refused the connection
+    Refused,
+    /// the address authenticated as a different peer
+    WrongPeerId { obtained: PeerId },
+    /// the transport connected but the security or muxer upgrade failed
+    UpgradeFailed { stage: Stage, error: String },
+    /// the address isn't supported by our transports
+    Unsupported,
+    /// another address won the race first
+    Canceled,
+    /// any other failure
+    Failed { error: String },
+}
+
+impl Outcome {
+    /// classify a dial error for a dial of a single address
+    pub fn from_dial_error(error: &DialError) -> Self {
+        match error {
+            DialError::WrongPeerId { obtained, .. } => Outcome::WrongPeerId {
+                obtained: *obtained,
+            },
+            DialError::Transport(_) => match Diagnosis::from_dial_error(error).pop() {
+                Some(d) => Self::from_diagnosis(&d),
+                None => Outcome::Failed {
+                    error: error.to_string(),
+                },
+            },
+            e => Outcome::Failed {
+                error: e.to_string(),
+            },
+        }
+    }
+
+    /// classify a transport failure diagnosis
+    pub fn from_diagnosis(d: &Diagnosis) -> Self {
+        let error = d.errors.last().cloned().unwrap_or_default();
+        match d.stage {
+            Stage::Timeout => Outcome::Timeout,
+            Stage::Unsupported => Outcome::Unsupported,
+            Stage::Security | Stage::Muxer => Outcome::UpgradeFailed {
+                stage: d.stage,
+                error,
+            },
+            Stage::Transport if error.to_lowercase().contains("refused") => Outcome::Refused,
+            Stage::Transport if error.to_lowercase().contains("timed out") => Outcome::Timeout,
+            Stage::Transport => Outcome::Failed { error },
+        }
+    }
+
+    /// true if the dial succeeded
+    pub fn is_success(&self) -> bool {
+        matches!(self, Outcome::Success { .. })
+    }
+
+    /// why the dial failed, for deciding whether to retry it, none if it succeeded
+    pub fn failure(&self) -> Option<Failure> {
+        match self {
+            Outcome::Success { .. } | Outcome::Canceled => None,
+            Outcome::Timeout => Some(Failure::Timeout),
+            Outcome::Refused | Outcome::UpgradeFailed { .. } | Outcome::Failed { .. } => {
+                Some(Failure::Unreachable)
+            }
+            Outcome::WrongPeerId { .. } | Outcome::Unsupported => Some(Failure::Fatal),
+        }
+    }
+
+    /// the outcome as a table cell colored by how bad it is
+    pub fn cell(&self) -> Cell {
+        match self {
+            Outcome::Success { .. } => Cell::good(self.to_string()),
+            Outcome::Timeout | Outcome::Unsupported | Outcome::Canceled => {
+                Cell::warn(self.to_string())
+            }
+            _ => Cell::bad(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Success { elapsed } => write!(f, "success ({elapsed:?})"),
+            Outcome::Timeout => write!(f, "timeout"),
+            Outcome::Refused => write!(f, "refused"),
+            Outcome::WrongPeerId { obtained } => write!(f, "wrong peer id ({obtained})"),
+            Outcome::UpgradeFailed { stage, error } => {
+                write!(f, "{stage} upgrade failed ({error})")
+            }
+            Outcome::Unsupported => write!(f, "unsupported address"),
+            Outcome::Canceled => write!(f, "canceled, another address won"),
+            Outcome::Failed { error } => write!(f, "failed ({error})"),
+        }
+    }
+}
+
+/// The outcome of dialing each of a peer's addresses
+#[derive(Clone, Debug)]
+pub struct DialReport {
+    pub peer: PeerId,
+    pub policy: DialPolicy,
+    pub entries: Vec<(Multiaddr, Outcome)>,
+    /// the relay and its score for each circuit address
+    pub via: HashMap<Multiaddr, (PeerId, f64)>,
+}
+
+impl DialReport {
+    /// start an empty report
+    pub fn new(peer: PeerId, policy: DialPolicy) -> Self {
+        Self {
+            peer,
+            policy,
+            entries: Vec::new(),
+            via: HashMap::new(),
+        }
+    }
+
+    /// record the outcome for an address
+    pub fn record(&mut self, addr: Multiaddr, outcome: Outcome) {
+        self.entries.push((addr, outcome));
+    }
+
+    /// note the relay a circuit address went through and the score it was chosen with
+    pub fn record_via(&mut self, addr: Multiaddr, relay: PeerId, score: f64) {
+        self.via.insert(addr, (relay, score));
+    }
+
+    /// the number of addresses that worked
+    pub fn successes(&self) -> usize {
+        self.entries.iter().filter(|(_, o)| o.is_success()).count()
+    }
+
+    /// the outcomes as a table with addr, via, score and outcome columns
+    pub fn table(&self) -> Table {
+        let mut table = Table::new(&["addr", "via", "score", "outcome"]);
+        for (addr, outcome) in &self.entries {
+            let (via, score) = match self.via.get(addr) {
+                Some((relay, score)) => (relay.to_string(), format!("{score:.2}")),
+                None => (String::new(), String::new()),
+            };
+            table.push(vec![
+                addr.to_string().into(),
+                via.into(),
+                score.into(),
+                outcome.cell(),
+            ]);
+        }
+        table
+    }
+}
+
+impl fmt::Display for DialReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Dial report for {} ({} of {} addresses working):",
+            self.peer,
+            self.successes(),
+            self.entries.len()
+        )?;
+        for (addr, outcome) in &self.entries {
+            match self.via.get(addr) {
+                Some((relay, _)) => writeln!(f, "\t{addr} (via {relay}): {outcome}")?,
+                None => writeln!(f, "\t{addr}: {outcome}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::core::{transport::TransportError, ConnectedPoint, Endpoint};
+    use std::io;
+
+    #[test]
+    fn classifies_dial_failures() {
+        let addr: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        let transport = |error| DialError::Transport(vec![(addr.clone(), error)]);
+
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "Connection refused");
+        let outcome = Outcome::from_dial_error(&transport(TransportError::Other(refused)));
+        assert_eq!(outcome, Outcome::Refused);
+        assert_eq!(outcome.failure(), Some(Failure::Unreachable));
+        let unsupported = TransportError::MultiaddrNotSupported(addr.clone());
+        let outcome = Outcome::from_dial_error(&transport(unsupported));
+        assert_eq!(outcome, Outcome::Unsupported);
+        assert_eq!(outcome.failure(), Some(Failure::Fatal));
+        let timeout = io::Error::new(io::ErrorKind::Other, "Timeout has been reached");
+        let outcome = Outcome::from_dial_error(&transport(TransportError::Other(timeout)));
+        assert_eq!(outcome.failure(), Some(Failure::Timeout));
+
+        // an address that authenticates as someone else is never worth retrying
+        let obtained = PeerId::random();
+        let wrong = DialError::WrongPeerId {
+            obtained,
+            endpoint: ConnectedPoint::Dialer {
+                address: addr.clone(),
+                role_override: Endpoint::Dialer,
+            },
+        };
+        let outcome = Outcome::from_dial_error(&wrong);
+        assert_eq!(outcome, Outcome::WrongPeerId { obtained });
+        assert_eq!(outcome.failure(), Some(Failure::Fatal));
+
+        let peer = PeerId::random();
+        let mut report = DialReport::new(peer, DialPolicy::Sequential);
+        report.record(addr.clone(), outcome);
+        report.record(
+            "/ip4/192.0.2.2/tcp/4001".parse().unwrap(),
+            Outcome::Success {
+                elapsed: Duration::from_millis(30),
+            },
+        );
+        assert_eq!(report.successes(), 1);
+        let text = report.to_string();
+        assert!(text.contains("1 of 2 addresses working"), "{text}");
+        assert!(
+            text.contains(&format!("wrong peer id ({obtained})")),
+            "{text}"
+        );
+    }
+}
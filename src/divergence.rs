@@ -0,0 +1,161 @@
+//! Detection of peers whose identify answers don't match what we observe.
+
+use crate::peerstore::PeerRecord;
+use libp2p::{identify, multiaddr::Protocol, Multiaddr};
+use std::{collections::BTreeMap, fmt};
+
+/// A way in which a peer's identify answer diverges from what we observed
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// we reached the peer on an address it doesn't report listening on
+    UnlistedDialAddr { dialed: Multiaddr },
+    /// the set of protocols changed since the last session
+    ProtocolsChanged {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+}
+
+impl Divergence {
+    /// a short name for the kind of divergence
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Divergence::UnlistedDialAddr { .. } => "unlisted_dial_addr",
+            Divergence::ProtocolsChanged { .. } => "protocols_changed",
+        }
+    }
+
+    /// the part of the identify answer that diverged, listen_addr or protocols
+    pub fn field(&self) -> &'static str {
+        match self {
+            Divergence::UnlistedDialAddr { .. } => "listen_addr",
+            Divergence::ProtocolsChanged { .. } => "protocols",
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::UnlistedDialAddr { dialed } => {
+                write!(
+                    f,
+                    "dialed address {dialed} is not in the reported listen addrs"
+                )
+            }
+            Divergence::ProtocolsChanged { added, removed } => {
+                write!(
+                    f,
+                    "protocols changed, added [{}] removed [{}]",
+                    added.join(", "),
+                    removed.join(", ")
+                )
+            }
+        }
+    }
+}
+
+/// Checks identify answers and counts the divergences found
+#[derive(Debug, Default)]
+pub struct DivergenceTracker {
+    counts: BTreeMap<&'static str, u64>,
+}
+
+impl DivergenceTracker {
+    /// compare an identify answer against the address we dialed (if we dialed) and the
+    /// record from the previous session (if any)
+    pub fn check(
+        &mut self,
+        info: &identify::Info,
+        dialed: Option<&Multiaddr>,
+        previous: Option<&PeerRecord>,
+    ) -> Vec<Divergence> {
+        let mut found = Vec::new();
+
+        if let Some(dialed) = dialed {
+            let dialed = strip_p2p(dialed);
+            let comparable = !dialed.iter().any(|p| matches!(p, Protocol::Dnsaddr(_)));
+            if comparable && !info.listen_addrs.iter().any(|a| strip_p2p(a) == dialed) {
+                found.push(Divergence::UnlistedDialAddr { dialed });
+            }
+        }
+
+        if let Some(previous) = previous.filter(|p| !p.protocols.is_empty()) {
+            let current: Vec<String> = info.protocols.iter().map(|p| p.to_string()).collect();
+            let added: Vec<String> = current
+                .iter()
+                .filter(|p| !previous.protocols.contains(p))
+                .cloned()
+                .collect();
+            let removed: Vec<String> = previous
+                .protocols
+                .iter()
+                .filter(|p| !current.contains(p))
+                .cloned()
+                .collect();
+            if !added.is_empty() || !removed.is_empty() {
+                found.push(Divergence::ProtocolsChanged { added, removed });
+            }
+        }
+
+        for d in &found {
+            *self.counts.entry(d.kind()).or_default() += 1;
+        }
+        found
+    }
+
+    /// the number of divergences seen so far, by kind
+    pub fn counts(&self) -> &BTreeMap<&'static str, u64> {
+        &self.counts
+    }
+}
+
+/// remove any trailing /p2p/<peer id> from an address
+fn strip_p2p(addr: &Multiaddr) -> Multiaddr {
+    addr.iter()
+        .filter(|p| !matches!(p, Protocol::P2p(_)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity;
+
+    fn info(listen: &[&str], protocols: &[&'static str]) -> identify::Info {
+        identify::Info {
+            public_key: identity::Keypair::generate_ed25519().public(),
+            protocol_version: "ipfs/0.1.0".into(),
+            agent_version: "test/0.0.1".into(),
+            listen_addrs: listen.iter().map(|a| a.parse().unwrap()).collect(),
+            protocols: protocols
+                .iter()
+                .map(|p| libp2p::StreamProtocol::new(p))
+                .collect(),
+            observed_addr: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn detects_unlisted_dial_addr_and_protocol_changes() {
+        let mut tracker = DivergenceTracker::default();
+        let dialed: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let info = info(&["/ip4/10.0.0.1/tcp/4001"], &["/ipfs/id/1.0.0"]);
+        let previous = PeerRecord {
+            protocols: vec!["/ipfs/kad/1.0.0".into()],
+            ..Default::default()
+        };
+        let found = tracker.check(&info, Some(&dialed), Some(&previous));
+        assert_eq!(found.len(), 2);
+        assert_eq!(
+            (found[0].field(), found[1].field()),
+            ("listen_addr", "protocols")
+        );
+        assert_eq!(tracker.counts()["protocols_changed"], 1);
+
+        let info = self::info(&["/ip4/1.2.3.4/tcp/4001"], &["/ipfs/kad/1.0.0"]);
+        assert!(tracker
+            .check(&info, Some(&dialed), Some(&previous))
+            .is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+//! A disk cache of the DNS names in bootstrap addresses.
+//!
+//! Short-lived commands would otherwise resolve the bootstrap domains on every run, and a
+//! `/dnsaddr` name takes a TXT lookup for every level of nesting. Resolved addresses are
+//! kept in the state directory's `dns.json` until their TTL runs out. An expired entry is
+//! still used for up to a day, while it's resolved again in the background, and whenever
+//! resolving fails. Names that can't be resolved at all are left for the transport to
+//! resolve at dial time.
+
+use crate::{
+    peerstore::now_secs,
+    runtime::{self, Resolver},
+};
+use futures::future::BoxFuture;
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// how long past its TTL an entry is still used
+pub const STALE_FOR: u64 = 24 * 60 * 60;
+
+/// how deeply `/dnsaddr` names may refer to each other
+const MAX_DEPTH: usize = 4;
+
+/// What an address resolved to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    pub addrs: Vec<Multiaddr>,
+    /// unix time it was resolved
+    pub resolved: u64,
+    /// unix time its TTL runs out
+    pub expires: u64,
+}
+
+/// A cache lookup
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Lookup<'a> {
+    Fresh(&'a [Multiaddr]),
+    /// past its TTL but still usable while it's resolved again
+    Stale(&'a [Multiaddr]),
+    Missing,
+}
+
+/// The resolved addresses, by the address they were resolved from
+#[derive(Clone, Debug, Default)]
+pub struct DnsCache {
+    path: Option<PathBuf>,
+    entries: BTreeMap<String, Entry>,
+}
+
+impl DnsCache {
+    /// open the cache file, a missing or unreadable one is an empty cache
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring the unreadable DNS cache {}: {e}", path.display());
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            entries,
+        })
+    }
+
+    /// a cache that isn't saved, for ephemeral nodes
+    pub fn memory() -> Self {
+        Self::default()
+    }
+
+    /// the cached entries
+    pub fn entries(&self) -> &BTreeMap<String, Entry> {
+        &self.entries
+    }
+
+    /// what the address resolved to
+    pub fn lookup(&self, addr: &Multiaddr, now: u64) -> Lookup<'_> {
+        match self.entries.get(&addr.to_string()) {
+            Some(entry) if now < entry.expires => Lookup::Fresh(&entry.addrs),
+            Some(entry) if now < entry.expires + STALE_FOR => Lookup::Stale(&entry.addrs),
+            _ => Lookup::Missing,
+        }
+    }
+
+    /// remember what the address resolved to for `ttl` seconds
+    pub fn insert(&mut self, addr: &Multiaddr, addrs: Vec<Multiaddr>, ttl: u64, now: u64) {
+        let entry = Entry {
+            addrs,
+            resolved: now,
+            expires: now + ttl,
+        };
+        self.entries.insert(addr.to_string(), entry);
+    }
+
+    /// forget everything, returns how many entries there were
+    pub fn flush(&mut self) -> usize {
+        let flushed = self.entries.len();
+        self.entries.clear();
+        flushed
+    }
+
+    /// write the cache out, dropping entries too old to use
+    pub fn save(&mut self) -> io::Result<()> {
+        let now = now_secs();
+        self.entries.retain(|_, e| now < e.expires + STALE_FOR);
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&self.entries)?)?;
+        fs::rename(&tmp, path)
+    }
+}
+
+/// true if the address starts with a DNS name
+pub fn is_dns(addr: &Multiaddr) -> bool {
+    matches!(
+        addr.iter().next(),
+        Some(Protocol::Dns(_) | Protocol::Dns4(_) | Protocol::Dns6(_) | Protocol::Dnsaddr(_))
+    )
+}
+
+/// resolve the DNS names in the addresses from the cache, resolving the missing ones now.
+/// Returns the addresses and the ones whose entries are stale, for [`refresh`].
+pub async fn resolve(
+    cache: &mut DnsCache,
+    addrs: &[Multiaddr],
+) -> (Vec<Multiaddr>, Vec<Multiaddr>) {
+    let now = now_secs();
+    let mut resolved = Vec::new();
+    let mut stale = Vec::new();
+    let mut missing = Vec::new();
+    for addr in addrs {
+        if !is_dns(addr) {
+            resolved.push(addr.clone());
+            continue;
+        }
+        match cache.lookup(addr, now) {
+            Lookup::Fresh(found) => resolved.extend(found.iter().cloned()),
+            Lookup::Stale(found) => {
+                resolved.extend(found.iter().cloned());
+                stale.push(addr.clone());
+            }
+            Lookup::Missing => missing.push(addr.clone()),
+        }
+    }
+    if missing.is_empty() {
+        return (resolved, stale);
+    }
+
+    let resolver = match runtime::resolver().await {
+        Ok(resolver) => Some(resolver),
+        Err(e) => {
+            warn!("Can't set up DNS resolution: {e}");
+            None
+        }
+    };
+    for addr in missing {
+        let lookup = match &resolver {
+            Some(resolver) => resolve_addr(resolver, addr.clone(), MAX_DEPTH).await,
+            None => Err("no resolver".to_string()),
+        };
+        match lookup {
+            Ok((found, ttl)) if !found.is_empty() => {
+                debug!("Resolved {addr} to {} addresses", found.len());
+                resolved.extend(found.iter().cloned());
+                cache.insert(&addr, found, ttl, now);
+            }
+            Ok(_) => {
+                warn!("{addr} resolved to no addresses");
+                resolved.push(addr);
+            }
+            Err(e) => {
+                warn!("Can't resolve {addr}: {e}");
+                resolved.push(addr);
+            }
+        }
+    }
+    (resolved, stale)
+}
+
+/// resolve the addresses again and update the cache file with them, for the stale
+/// entries [`resolve`] used
+pub async fn refresh(path: PathBuf, addrs: Vec<Multiaddr>) -> io::Result<()> {
+    let resolver = runtime::resolver().await?;
+    let mut results = Vec::new();
+    for addr in addrs {
+        match resolve_addr(&resolver, addr.clone(), MAX_DEPTH).await {
+            Ok((found, ttl)) if !found.is_empty() => results.push((addr, found, ttl)),
+            Ok(_) => debug!("{addr} resolved to no addresses, keeping the stale entry"),
+            Err(e) => debug!("Can't resolve {addr}, keeping the stale entry: {e}"),
+        }
+    }
+    // the cache may have changed on disk while we resolved
+    let mut cache = DnsCache::open(path)?;
+    let now = now_secs();
+    for (addr, found, ttl) in results {
+        cache.insert(&addr, found, ttl, now);
+    }
+    cache.save()
+}
+
+/// resolve the DNS names in the addresses without the cache, with what each one resolved
+/// to or why it couldn't be, for checking DNS works
+pub async fn resolve_uncached(
+    addrs: &[Multiaddr],
+) -> io::Result<Vec<(Multiaddr, Result<Vec<Multiaddr>, String>)>> {
+    let resolver = runtime::resolver().await?;
+    let mut results = Vec::new();
+    for addr in addrs.iter().filter(|addr| is_dns(addr)) {
+        let lookup = resolve_addr(&resolver, addr.clone(), MAX_DEPTH).await;
+        results.push((addr.clone(), lookup.map(|(found, _)| found)));
+    }
+    Ok(results)
+}
+
+// resolve the DNS name an address starts with, returning the addresses and the
+// smallest TTL of the records involved
+fn resolve_addr(
+    resolver: &Resolver,
+    addr: Multiaddr,
+    depth: usize,
+) -> BoxFuture<'_, Result<(Vec<Multiaddr>, u64), String>> {
+    Box::pin(async move {
+        let mut rest = addr.iter();
+        let first = rest.next();
+        let rest: Multiaddr = rest.collect();
+        match &first {
+            Some(Protocol::Dnsaddr(name)) => {
+                if depth == 0 {
+                    return Err(format!("{addr} nests /dnsaddr too deeply"));
+                }
+                let txt = resolver
+                    .txt_lookup(format!("_dnsaddr.{name}"))
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let mut ttl = ttl_of(txt.valid_until());
+                let mut found = Vec::new();
+                for record in txt.iter() {
+                    for data in record.txt_data() {
+                        let text = String::from_utf8_lossy(data);
+                        let Some(target) = text.strip_prefix("dnsaddr=") else {
+                            continue;
+                        };
+                        let Ok(target) = target.parse::<Multiaddr>() else {
+                            continue;
+                        };
+                        // the record has to end with what follows the name, e.g. a /p2p
+                        if !ends_with(&target, &rest) {
+                            continue;
+                        }
+                        if is_dns(&target) {
+                            match resolve_addr(resolver, target, depth - 1).await {
+                                Ok((nested, nested_ttl)) => {
+                                    found.extend(nested);
+                                    ttl = ttl.min(nested_ttl);
+                                }
+                                Err(e) => debug!("Skipping a {name} entry: {e}"),
+                            }
+                        } else {
+                            found.push(target);
+                        }
+                    }
+                }
+                Ok((found, ttl))
+            }
+            Some(Protocol::Dns(name) | Protocol::Dns4(name) | Protocol::Dns6(name)) => {
+                let ips = resolver
+                    .lookup_ip(name.to_string())
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let ttl = ttl_of(ips.valid_until());
+                let want_v4 = !matches!(first, Some(Protocol::Dns6(_)));
+                let want_v6 = !matches!(first, Some(Protocol::Dns4(_)));
+                let found = ips
+                    .iter()
+                    .filter_map(|ip| match ip {
+                        IpAddr::V4(ip) if want_v4 => Some(Protocol::Ip4(ip)),
+                        IpAddr::V6(ip) if want_v6 => Some(Protocol::Ip6(ip)),
+                        _ => None,
+                    })
+                    .map(|ip| std::iter::once(ip).chain(rest.iter()).collect())
+                    .collect();
+                Ok((found, ttl))
+            }
+            _ => Err(format!("{addr} doesn't start with a DNS name")),
+        }
+    })
+}
+
+// true if the address ends with the protocols of the suffix
+fn ends_with(addr: &Multiaddr, suffix: &Multiaddr) -> bool {
+    let addr: Vec<_> = addr.iter().collect();
+    let suffix: Vec<_> = suffix.iter().collect();
+    addr.ends_with(&suffix)
+}
+
+// seconds until a lookup's records expire
+fn ttl_of(valid_until: Instant) -> u64 {
+    valid_until
+        .saturating_duration_since(Instant::now())
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fresh_then_stale() {
+        let addr: Multiaddr = "/dnsaddr/bootstrap.libp2p.io".parse().unwrap();
+        let ip: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let mut cache = DnsCache::memory();
+        assert_eq!(cache.lookup(&addr, 100), Lookup::Missing);
+        cache.insert(&addr, vec![ip.clone()], 60, 100);
+        assert_eq!(cache.lookup(&addr, 150), Lookup::Fresh(&[ip.clone()]));
+        assert_eq!(cache.lookup(&addr, 200), Lookup::Stale(&[ip.clone()]));
+        assert_eq!(cache.lookup(&addr, 160 + STALE_FOR), Lookup::Missing);
+        assert!(is_dns(&addr) && !is_dns(&ip));
+        assert_eq!(cache.flush(), 1);
+    }
+}
@@ -0,0 +1,319 @@
+//! The checks `fleyg doctor` runs, the first thing to try when fleyg isn't working.
+//!
+//! Each check ends in a finding: fine, worth a warning or a failure, with what to do
+//! about it. The checks here look at the local machine, the config, the identity key, the
+//! listen ports and the clock. The ones that need the network, DNS resolution, reaching
+//! the bootstrap peers and the NAT status, are run by the command with a swarm and report
+//! findings the same way.
+
+use crate::{
+    alerts::Alerts,
+    config::Config,
+    identity::{self, KeyType},
+    network::Network,
+    peerstore::PeerStore,
+    schedule::Scheduler,
+};
+use libp2p::{multiaddr::Protocol, Multiaddr};
+use std::{
+    fmt, fs, io,
+    net::{IpAddr, SocketAddr, TcpListener, UdpSocket},
+    path::Path,
+    time::Instant,
+};
+
+/// a clock before this, the start of 2024, is certainly wrong
+pub const CLOCK_FLOOR: u64 = 1_704_067_200;
+
+/// how far in the future the peer store may have seen a peer before the clock counts as
+/// having gone backwards
+const CLOCK_SLACK: u64 = 300;
+
+/// How bad a finding is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Ok => write!(f, "ok"),
+            Severity::Warn => write!(f, "warn"),
+            Severity::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// What a check found
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub check: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    /// what to do about it
+    pub fix: Option<String>,
+}
+
+impl Finding {
+    pub fn ok<S: Into<String>>(check: &'static str, message: S) -> Self {
+        Self {
+            check,
+            severity: Severity::Ok,
+            message: message.into(),
+            fix: None,
+        }
+    }
+
+    pub fn warn<S: Into<String>, F: Into<String>>(check: &'static str, message: S, fix: F) -> Self {
+        Self {
+            check,
+            severity: Severity::Warn,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    pub fn fail<S: Into<String>, F: Into<String>>(check: &'static str, message: S, fix: F) -> Self {
+        Self {
+            check,
+            severity: Severity::Fail,
+            message: message.into(),
+            fix: Some(fix.into()),
+        }
+    }
+}
+
+/// check the config file parses and its sections make sense, with the config if it
+/// could be read
+pub fn check_config(path: &Path) -> (Finding, Option<Config>) {
+    const CHECK: &str = "config";
+    if !path.exists() {
+        let finding = Finding::warn(
+            CHECK,
+            format!("no config at {}, using the defaults", path.display()),
+            "run fleyg init to write one",
+        );
+        return (finding, Some(Config::default()));
+    }
+    let config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            let fix = format!(
+                "fix {} or write a new one with fleyg init --force",
+                path.display()
+            );
+            return (Finding::fail(CHECK, e.to_string(), fix), None);
+        }
+    };
+    let mut problems = Vec::new();
+    if let Some(name) = &config.network {
+        if let Err(e) = Network::preset(name) {
+            problems.push(e.to_string());
+        }
+    }
+    if let Err(e) = Alerts::new(config.alerts.clone().unwrap_or_default()) {
+        problems.push(format!("[[alerts]]: {e}"));
+    }
+    for rule in config.merge.iter().flatten() {
+        if let Err(e) = rule.validate() {
+            problems.push(format!("[[merge]]: {e}"));
+        }
+    }
+    if let Err(e) = Scheduler::new(&config.tasks.clone().unwrap_or_default(), Instant::now()) {
+        problems.push(format!("[[task]]: {e}"));
+    }
+    let finding = match problems.is_empty() {
+        true => Finding::ok(CHECK, format!("{} is valid", path.display())),
+        false => Finding::fail(
+            CHECK,
+            problems.join(", "),
+            format!("fix {}", path.display()),
+        ),
+    };
+    (finding, Some(config))
+}
+
+/// check the identity key decodes and only its owner can read it
+pub fn check_key(path: &Path) -> Finding {
+    const CHECK: &str = "identity key";
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Finding::ok(
+                CHECK,
+                format!("no key at {} yet, one is made on first use", path.display()),
+            )
+        }
+        Err(e) => {
+            return Finding::fail(
+                CHECK,
+                format!("can't read {}: {e}", path.display()),
+                "check the state directory belongs to the user running fleyg",
+            )
+        }
+    };
+    let key = match identity::decode(bytes) {
+        Ok(key) => key,
+        Err(e) => {
+            return Finding::fail(
+                CHECK,
+                format!("{}: {e}", path.display()),
+                "restore the key from a backup, or move it away to start a new identity",
+            )
+        }
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(meta) = fs::metadata(path) {
+            let mode = meta.permissions().mode() & 0o777;
+            if mode & 0o077 != 0 {
+                return Finding::fail(
+                    CHECK,
+                    format!("{} can be read by others (mode {mode:o})", path.display()),
+                    format!("chmod 600 {}", path.display()),
+                );
+            }
+        }
+    }
+    Finding::ok(
+        CHECK,
+        format!(
+            "{} key {} for {}",
+            KeyType::of(&key),
+            path.display(),
+            key.public().to_peer_id()
+        ),
+    )
+}
+
+/// check the ip addresses we listen on can be bound, by binding them for a moment
+pub fn check_ports(listen: &[Multiaddr]) -> Vec<Finding> {
+    const CHECK: &str = "listen port";
+    let mut findings = Vec::new();
+    for addr in listen {
+        let Some((socket, udp)) = socket_addr(addr) else {
+            continue;
+        };
+        let bound = match udp {
+            true => UdpSocket::bind(socket).map(|_| ()),
+            false => TcpListener::bind(socket).map(|_| ()),
+        };
+        findings.push(match bound {
+            Ok(()) => Finding::ok(CHECK, format!("{addr} is free")),
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse => Finding::fail(
+                CHECK,
+                format!("{addr} is in use, another fleyg may be running"),
+                "stop whatever listens there or change --listen or the config's listen",
+            ),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Finding::fail(
+                CHECK,
+                format!("not allowed to listen on {addr}"),
+                "ports below 1024 need privileges, listen on a higher port",
+            ),
+            Err(e) => Finding::warn(
+                CHECK,
+                format!("can't listen on {addr}: {e}"),
+                "leave it out of the listen addresses if this host doesn't have it",
+            ),
+        });
+    }
+    findings
+}
+
+// the socket an ip address with a tcp or udp port binds, and whether it's udp
+fn socket_addr(addr: &Multiaddr) -> Option<(SocketAddr, bool)> {
+    let mut protocols = addr.iter();
+    let ip: IpAddr = match protocols.next()? {
+        Protocol::Ip4(ip) => ip.into(),
+        Protocol::Ip6(ip) => ip.into(),
+        _ => return None,
+    };
+    match protocols.next()? {
+        Protocol::Tcp(port) => Some((SocketAddr::new(ip, port), false)),
+        Protocol::Udp(port) => Some((SocketAddr::new(ip, port), true)),
+        _ => None,
+    }
+}
+
+/// check the clock isn't obviously wrong: long before fleyg was written, or behind when
+/// the peer store last saw peers
+pub fn check_clock(now: u64, peers: &PeerStore) -> Finding {
+    const CHECK: &str = "clock";
+    const FIX: &str = "set the system clock, or run an NTP client";
+    if now < CLOCK_FLOOR {
+        return Finding::fail(
+            CHECK,
+            format!("the clock says {now}, years in the past"),
+            FIX,
+        );
+    }
+    let newest = peers.iter().map(|(_, r)| r.last_seen).max().unwrap_or(0);
+    if newest > now + CLOCK_SLACK {
+        return Finding::fail(
+            CHECK,
+            format!(
+                "the peer store saw a peer {}s in the future, the clock went backwards",
+                newest - now
+            ),
+            FIX,
+        );
+    }
+    Finding::ok(CHECK, "the clock looks right")
+}
+
+/// the worst finding's severity
+pub fn worst(findings: &[Finding]) -> Severity {
+    findings
+        .iter()
+        .map(|f| f.severity)
+        .max()
+        .unwrap_or(Severity::Ok)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peerstore::now_secs;
+
+    #[test]
+    fn checks() {
+        let dir = std::env::temp_dir().join(format!("fleyg-doctor-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = dir.join("config.toml");
+        assert_eq!(check_config(&config).0.severity, Severity::Warn);
+        fs::write(&config, "network = \"bogus\"\n").unwrap();
+        assert_eq!(check_config(&config).0.severity, Severity::Fail);
+        fs::write(&config, "network = \"ipfs\"\n").unwrap();
+        assert_eq!(check_config(&config).0.severity, Severity::Ok);
+
+        let key = dir.join("key");
+        assert_eq!(check_key(&key).severity, Severity::Ok);
+        fs::write(&key, b"garbage").unwrap();
+        assert_eq!(check_key(&key).severity, Severity::Fail);
+
+        let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let listen: Vec<Multiaddr> = [
+            format!("/ip4/127.0.0.1/tcp/{port}"),
+            "/ip4/127.0.0.1/tcp/0".to_string(),
+            "/dns/example.com/tcp/1".to_string(),
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let findings = check_ports(&listen);
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].severity, Severity::Fail);
+        assert_eq!(worst(&findings), Severity::Fail);
+
+        let peers = PeerStore::memory();
+        assert_eq!(check_clock(now_secs(), &peers).severity, Severity::Ok);
+        assert_eq!(check_clock(1_000, &peers).severity, Severity::Fail);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
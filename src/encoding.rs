@@ -0,0 +1,198 @@
+//! Text encodings for binary values (record keys and values, public keys).
+
+use crate::keys::KeyEncoding;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::*;
+use sha2::{Digest, Sha256};
+use std::{
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// keys longer than this are named by their hash, hex doubles their length and most file
+/// systems stop at 255 bytes
+const MAX_NAMED_KEY: usize = 64;
+
+/// How binary values are shown in output
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Hex,
+    Base64,
+    Base58,
+    /// the bytes as (lossy) utf-8 text
+    Raw,
+}
+
+impl Encoding {
+    /// encode the bytes as text
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Hex => hex::encode(bytes),
+            Encoding::Base64 => STANDARD.encode(bytes),
+            Encoding::Base58 => bs58::encode(bytes).into_string(),
+            Encoding::Raw => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+
+    /// decode text in this encoding back into bytes
+    pub fn decode(&self, s: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Hex => hex::decode(s).map_err(|e| e.to_string()),
+            Encoding::Base64 => STANDARD.decode(s).map_err(|e| e.to_string()),
+            Encoding::Base58 => bs58::decode(s).into_vec().map_err(|e| e.to_string()),
+            Encoding::Raw => Ok(s.as_bytes().to_vec()),
+        }
+    }
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(Encoding::Hex),
+            "base64" => Ok(Encoding::Base64),
+            "base58" => Ok(Encoding::Base58),
+            "raw" => Ok(Encoding::Raw),
+            _ => Err(format!("unknown encoding: {s} (hex, base64, base58, raw)")),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Encoding::Hex => "hex",
+            Encoding::Base64 => "base64",
+            Encoding::Base58 => "base58",
+            Encoding::Raw => "raw",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// A directory values are written into as raw bytes, up to a total size
+///
+/// Clones share the count of bytes written, so the limit holds for the whole run even when
+/// the values come from other peers' puts.
+#[derive(Clone, Debug)]
+pub struct RawDir {
+    pub path: PathBuf,
+    /// the most bytes to write
+    pub limit: u64,
+    written: Arc<AtomicU64>,
+    full: Arc<AtomicBool>,
+}
+
+impl RawDir {
+    pub fn new(path: PathBuf, limit: u64) -> Self {
+        Self {
+            path,
+            limit,
+            written: Arc::default(),
+            full: Arc::default(),
+        }
+    }
+
+    /// the file a key's value goes in, its hex encoding or for long keys that of its hash
+    pub fn file_name(key: &[u8]) -> String {
+        match key.len() {
+            0..=MAX_NAMED_KEY => hex::encode(key),
+            _ => format!("sha256-{}", hex::encode(Sha256::digest(key))),
+        }
+    }
+
+    /// write a value, none if that would go over the limit
+    fn write(&self, key: &[u8], value: &[u8]) -> io::Result<Option<PathBuf>> {
+        let len = value.len() as u64;
+        if self.written.fetch_add(len, Ordering::SeqCst) + len > self.limit {
+            self.written.fetch_sub(len, Ordering::SeqCst);
+            if !self.full.swap(true, Ordering::SeqCst) {
+                warn!(
+                    "Not writing any more values into {}, it holds {} of the {} bytes allowed",
+                    self.path.display(),
+                    self.written.load(Ordering::SeqCst),
+                    self.limit
+                );
+            }
+            return Ok(None);
+        }
+        let path = self.path.join(Self::file_name(key));
+        let written = fs::create_dir_all(&self.path).and_then(|_| fs::write(&path, value));
+        if let Err(e) = written {
+            self.written.fetch_sub(len, Ordering::SeqCst);
+            return Err(e);
+        }
+        Ok(Some(path))
+    }
+}
+
+/// Formats binary values for output, optionally writing payloads to files as raw bytes
+#[derive(Clone, Debug, Default)]
+pub struct ValueFormat {
+    pub encoding: Encoding,
+    /// how keys are shown
+    pub keys: KeyEncoding,
+    /// write values into this directory, named by their hex encoded key, instead of
+    /// printing them
+    pub raw_dir: Option<RawDir>,
+}
+
+impl ValueFormat {
+    /// format a key or other short binary value
+    pub fn key(&self, key: &[u8]) -> String {
+        self.keys.encode(key)
+    }
+
+    /// format a value, writing it to a file if a raw directory is set
+    pub fn value(&self, key: &[u8], value: &[u8]) -> io::Result<String> {
+        match &self.raw_dir {
+            Some(dir) => Ok(match dir.write(key, value)? {
+                Some(path) => format!("<{} bytes in {}>", value.len(), path.display()),
+                None => format!("<{} bytes, over the raw directory limit>", value.len()),
+            }),
+            None => Ok(self.encoding.encode(value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let bytes = b"\x00fleyg\xff";
+        for enc in [Encoding::Hex, Encoding::Base64, Encoding::Base58] {
+            assert_eq!(enc.decode(&enc.encode(bytes)).unwrap(), bytes);
+        }
+        assert_eq!(Encoding::Hex.encode(b"hi"), "6869");
+        assert_eq!(Encoding::Raw.encode(b"hi"), "hi");
+
+        let path = std::env::temp_dir().join(format!("fleyg-raw-{}", std::process::id()));
+        let format = ValueFormat {
+            raw_dir: Some(RawDir::new(path.clone(), 5)),
+            ..Default::default()
+        };
+        let long = [7u8; 200];
+        assert!(format.value(&long, b"1234").unwrap().contains("sha256-"));
+        assert!(RawDir::file_name(&long).len() < 100);
+        // the clones share the limit
+        let again = format.clone();
+        assert!(again
+            .value(b"k", b"56")
+            .unwrap()
+            .contains("over the raw directory limit"));
+        assert!(format
+            .value(b"k", b"5")
+            .unwrap()
+            .contains(&hex::encode(b"k")));
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
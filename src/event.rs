@@ -0,0 +1,110 @@
+//! Structured events for `--output json`.
+//!
+//! With json output every identify answer, inbound DHT request and query result a node
+//! sees is written to stdout as one json object per line, for piping into jq or other
+//! programs, while the log lines go to stderr as usual. Events are only written once
+//! [`enable`] has been called, [`emit`] is free otherwise:
+//!
+//! ```text
+//! {"at":1697040000000,"event":"identify","peer":"12D3KooW...","agent":"kubo/0.23.0",...}
+//! ```
+
+use crate::sessions::now_millis;
+use libp2p::{identify, PeerId};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// write events to stdout from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// true if events are being written
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// One thing that happened, with the fields of its kind
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    /// unix time in milliseconds
+    pub at: u64,
+    /// the kind of event, e.g. identify or put_record
+    pub event: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peer: Option<PeerId>,
+    #[serde(flatten)]
+    pub fields: Map<String, Value>,
+}
+
+impl Event {
+    /// an event of the given kind happening now
+    pub fn new<S: Into<String>>(event: S, peer: Option<PeerId>) -> Self {
+        Self {
+            at: now_millis(),
+            event: event.into(),
+            peer,
+            fields: Map::new(),
+        }
+    }
+
+    /// add a field, one that can't be serialized is left out
+    pub fn with<T: Serialize>(mut self, name: &str, value: T) -> Self {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.fields.insert(name.to_string(), value);
+        }
+        self
+    }
+
+    /// a peer's identify answer
+    pub fn identify(peer: PeerId, info: &identify::Info) -> Self {
+        let protocols: Vec<String> = info.protocols.iter().map(|p| p.to_string()).collect();
+        Self::new("identify", Some(peer))
+            .with("agent", &info.agent_version)
+            .with("protocol_version", &info.protocol_version)
+            .with("observed_addr", &info.observed_addr)
+            .with("listen_addrs", &info.listen_addrs)
+            .with("protocols", protocols)
+    }
+}
+
+/// write the event to stdout if events are enabled
+pub fn emit(event: Event) {
+    if !enabled() {
+        return;
+    }
+    let mut stdout = io::stdout().lock();
+    if let Ok(line) = serde_json::to_string(&event) {
+        // a closed pipe isn't worth failing the node over
+        let _ = writeln!(stdout, "{line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_fields() {
+        let peer = PeerId::random();
+        let mut event = Event::new("put_record", Some(peer)).with("key", "abcd");
+        event.at = 1;
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "at": 1,
+                "event": "put_record",
+                "peer": peer.to_string(),
+                "key": "abcd",
+            })
+        );
+        assert!(!enabled());
+    }
+}
@@ -0,0 +1,152 @@
+//! Racing several ways of getting a record within a latency budget.
+//!
+//! `fleyg get --budget 2s` tries each path a record could come from at once and takes
+//! the first answer that checks out:
+//!
+//! - the cache, the records our own store holds
+//! - delegated routing, the `/routing/v1` HTTP API of a node that does the DHT walk for
+//!   us, set in the `[delegated]` section, only IPNS records are served by it
+//! - a DHT lookup, bounded by the budget
+//!
+//! An answer checks out if it's a record namespace fleyg can verify and the signature is
+//! good, or it's a namespace fleyg can't verify at all.
+//!
+//! ```toml
+//! [delegated]
+//! url = "http://127.0.0.1:8080"
+//! ```
+
+use crate::{attest, runtime};
+use futures::prelude::*;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{fmt, io, time::Duration};
+
+/// the IPNS record key namespace
+const IPNS: &[u8] = b"/ipns/";
+
+/// The `[delegated]` config section
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DelegatedSettings {
+    /// the http url of a delegated routing server, without the /routing/v1 path
+    pub url: Option<String>,
+}
+
+/// Where an answer came from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Path {
+    Cache,
+    Delegated,
+    Dht,
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Path::Cache => write!(f, "cache"),
+            Path::Delegated => write!(f, "delegated"),
+            Path::Dht => write!(f, "dht"),
+        }
+    }
+}
+
+/// check an answer before taking it
+pub fn verify(key: &[u8], value: &[u8]) -> Result<(), String> {
+    attest::check_record(key, value).unwrap_or(Ok(()))
+}
+
+/// the name delegated routing knows a record key by, if it serves the key's namespace
+pub fn delegated_name(key: &[u8]) -> Option<String> {
+    let peer = key.strip_prefix(IPNS)?;
+    PeerId::from_bytes(peer).ok().map(|p| p.to_string())
+}
+
+/// ask a delegated routing server for an IPNS record, none if it doesn't have it
+pub async fn delegated_get(url: &str, name: &str, limit: Duration) -> io::Result<Option<Vec<u8>>> {
+    runtime::timeout(limit, get(url, name)).await?
+}
+
+async fn get(url: &str, name: &str) -> io::Result<Option<Vec<u8>>> {
+    let (host, base) =
+        split_url(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let path = format!("{}/routing/v1/ipns/{name}", base.trim_end_matches('/'));
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nAccept: application/vnd.ipfs.ipns-record\r\nConnection: close\r\n\r\n"
+    );
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{host}:80"),
+    };
+    let mut stream = runtime::connect(&addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    parse_response(&response).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// the body of a 200 response, none for a 404
+fn parse_response(response: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("truncated http response")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head
+        .lines()
+        .next()
+        .and_then(|l| l.split_whitespace().nth(1))
+        .ok_or("bad http status line")?;
+    let mut body = response[end + 4..].to_vec();
+    let length = head.lines().find_map(|l| {
+        let (name, value) = l.split_once(':')?;
+        match name.eq_ignore_ascii_case("content-length") {
+            true => value.trim().parse::<usize>().ok(),
+            false => None,
+        }
+    });
+    if let Some(length) = length {
+        body.truncate(length);
+    }
+    match status {
+        "200" => Ok(Some(body)),
+        "404" => Ok(None),
+        _ => Err(format!("delegated routing answered {status}")),
+    }
+}
+
+// split an http url into its host and path
+fn split_url(url: &str) -> Result<(&str, &str), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| format!("only http:// delegated routing urls are supported: {url}"))?;
+    Ok(match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, ""),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delegated_answers() {
+        let peer = PeerId::random();
+        let key = [IPNS, &peer.to_bytes()].concat();
+        assert_eq!(delegated_name(&key), Some(peer.to_string()));
+        assert_eq!(delegated_name(b"/pk/whatever"), None);
+
+        let ok = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcdef";
+        assert_eq!(parse_response(ok).unwrap(), Some(b"abc".to_vec()));
+        let missing = b"HTTP/1.1 404 Not Found\r\n\r\n";
+        assert_eq!(parse_response(missing).unwrap(), None);
+        assert!(parse_response(b"HTTP/1.1 500 Oops\r\n\r\n").is_err());
+        assert!(parse_response(b"HTTP/1.1 200").is_err());
+        assert_eq!(
+            split_url("http://localhost:8080/api").unwrap(),
+            ("localhost:8080", "/api")
+        );
+    }
+}
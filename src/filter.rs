@@ -0,0 +1,311 @@
+//! Filter expressions for narrowing large result listings.
+//!
+//! An expression compares named fields against values and combines the comparisons with
+//! `&&`, `||`, `!` and parentheses, e.g. `agent~kubo && rtt<100ms`. The operators are
+//! `~` (contains), `!~` (doesn't contain), `=`, `!=`, `<`, `<=`, `>` and `>=`. Ordering
+//! compares durations like `100ms` or `2h` as durations, other numbers as numbers, and
+//! anything else as text.
+
+use crate::timespec::parse_duration;
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+/// A comparison operator
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+    Contains,
+    NotContains,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Op::Contains => "~",
+            Op::NotContains => "!~",
+            Op::Eq => "=",
+            Op::Ne => "!=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+}
+
+/// A parsed filter expression
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filter {
+    Compare {
+        field: String,
+        op: Op,
+        value: String,
+    },
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+impl Filter {
+    /// the names of the fields the expression refers to
+    pub fn fields(&self) -> Vec<&str> {
+        match self {
+            Filter::Compare { field, .. } => vec![field.as_str()],
+            Filter::Not(f) => f.fields(),
+            Filter::And(a, b) | Filter::Or(a, b) => {
+                let mut fields = a.fields();
+                fields.extend(b.fields());
+                fields
+            }
+        }
+    }
+
+    /// evaluate the expression, looking up field values with `get`, a missing field
+    /// never matches a comparison
+    pub fn matches<'a, F>(&self, get: &F) -> bool
+    where
+        F: Fn(&str) -> Option<&'a str>,
+    {
+        match self {
+            Filter::Compare { field, op, value } => match get(field) {
+                Some(actual) => compare(actual, *op, value),
+                None => false,
+            },
+            Filter::Not(f) => !f.matches(get),
+            Filter::And(a, b) => a.matches(get) && b.matches(get),
+            Filter::Or(a, b) => a.matches(get) || b.matches(get),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(token) => Err(format!("unexpected {token} in filter")),
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Compare { field, op, value } => write!(f, "{field}{}{value}", op.symbol()),
+            Filter::Not(inner) => write!(f, "!({inner})"),
+            Filter::And(a, b) => write!(f, "({a} && {b})"),
+            Filter::Or(a, b) => write!(f, "({a} || {b})"),
+        }
+    }
+}
+
+fn compare(actual: &str, op: Op, value: &str) -> bool {
+    let ordering = || -> Option<Ordering> {
+        let is_number = |s: &str| s.parse::<f64>().is_ok();
+        if !is_number(value) {
+            if let (Ok(a), Ok(b)) = (parse_duration(actual), parse_duration(value)) {
+                return Some(a.cmp(&b));
+            }
+        }
+        if let (Ok(a), Ok(b)) = (actual.parse::<f64>(), value.parse::<f64>()) {
+            return a.partial_cmp(&b);
+        }
+        Some(actual.cmp(value))
+    };
+    match op {
+        Op::Contains => actual.contains(value),
+        Op::NotContains => !actual.contains(value),
+        Op::Eq => ordering() == Some(Ordering::Equal),
+        Op::Ne => ordering() != Some(Ordering::Equal),
+        Op::Lt => ordering() == Some(Ordering::Less),
+        Op::Le => matches!(ordering(), Some(Ordering::Less | Ordering::Equal)),
+        Op::Gt => ordering() == Some(Ordering::Greater),
+        Op::Ge => matches!(ordering(), Some(Ordering::Greater | Ordering::Equal)),
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) => write!(f, "'{w}'"),
+            Token::Op(op) => write!(f, "'{}'", op.symbol()),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Not => write!(f, "'!'"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '~' => Token::Op(Op::Contains),
+            '&' | '|' if chars.next_if_eq(&c).is_none() => {
+                return Err(format!("expected {c}{c} in filter"))
+            }
+            '&' => Token::And,
+            '|' => Token::Or,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ne),
+            '!' if chars.next_if_eq(&'~').is_some() => Token::Op(Op::NotContains),
+            '!' => Token::Not,
+            '=' => {
+                chars.next_if_eq(&'=');
+                Token::Op(Op::Eq)
+            }
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Le),
+            '<' => Token::Op(Op::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Op(Op::Ge),
+            '>' => Token::Op(Op::Gt),
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some(ch) => word.push(ch),
+                        None => return Err("unterminated quote in filter".to_string()),
+                    }
+                }
+                Token::Word(word)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some(ch) =
+                    chars.next_if(|ch| !ch.is_whitespace() && !"()&|!~=<>\"'".contains(*ch))
+                {
+                    word.push(ch);
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+// or := and ("||" and)*, and := unary ("&&" unary)*, unary := "!" unary | "(" or ")" | cmp
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut left = self.and()?;
+        while self.eat(&Token::Or) {
+            left = Filter::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut left = self.unary()?;
+        while self.eat(&Token::And) {
+            left = Filter::And(Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Filter, String> {
+        match self.next() {
+            Some(Token::Not) => Ok(Filter::Not(Box::new(self.unary()?))),
+            Some(Token::Open) => {
+                let inner = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err("missing ')' in filter".to_string());
+                }
+                Ok(inner)
+            }
+            Some(Token::Word(field)) => {
+                let op = match self.next() {
+                    Some(Token::Op(op)) => op,
+                    _ => return Err(format!("expected an operator after {field} in filter")),
+                };
+                match self.next() {
+                    Some(Token::Word(value)) => Ok(Filter::Compare { field, op, value }),
+                    _ => Err(format!(
+                        "expected a value after {field}{} in filter",
+                        op.symbol()
+                    )),
+                }
+            }
+            Some(token) => Err(format!("unexpected {token} in filter")),
+            None => Err("unexpected end of filter".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parse_and_match() {
+        let filter: Filter = "agent~kubo && (rtt<100ms || !protocols>=10)"
+            .parse()
+            .unwrap();
+        assert_eq!(filter.fields(), vec!["agent", "rtt", "protocols"]);
+
+        let row: HashMap<&str, &str> = [
+            ("agent", "kubo/0.21.0"),
+            ("rtt", "12ms"),
+            ("protocols", "12"),
+        ]
+        .into_iter()
+        .collect();
+        assert!(filter.matches(&|f| row.get(f).copied()));
+
+        let row: HashMap<&str, &str> =
+            [("agent", "kubo/0.21.0"), ("rtt", "2s"), ("protocols", "12")]
+                .into_iter()
+                .collect();
+        assert!(!filter.matches(&|f| row.get(f).copied()));
+
+        assert!(!"missing=1".parse::<Filter>().unwrap().matches(&|_| None));
+        assert!("agent ~ 'go ipfs'".parse::<Filter>().is_ok());
+        assert!("agent~".parse::<Filter>().is_err());
+        assert!("(agent~kubo".parse::<Filter>().is_err());
+        assert!("agent~kubo &".parse::<Filter>().is_err());
+    }
+}
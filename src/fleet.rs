@@ -0,0 +1,164 @@
+//! Many daemons read together, for operators running fleets of measurement nodes.
+//!
+//! `fleyg fleet --nodes nodes.toml` calls the control API of every daemon listed and
+//! combines what they report:
+//!
+//! ```toml
+//! [[node]]
+//! name = "fra1"
+//! api = "127.0.0.1:9921"
+//!
+//! [[node]]
+//! name = "home"
+//! api = "unix:/var/lib/fleyg/fleyg.sock"
+//! ```
+//!
+//! The control API only listens locally, so daemons on other hosts are reached through a
+//! tunnel to their socket or loopback port. Each node's peer store comes back as a census
+//! and the censuses are merged into one holding a record per peer any node saw.
+
+use crate::{apilisten::ApiListen, census::CensusRecord};
+use libp2p::PeerId;
+use serde::{de::Error as _, Deserialize, Deserializer};
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+/// A daemon in the fleet
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FleetNode {
+    /// what the node is called in reports
+    pub name: String,
+    /// where its control API listens
+    #[serde(deserialize_with = "api")]
+    pub api: ApiListen,
+}
+
+/// The daemons of a fleet
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fleet {
+    #[serde(default, rename = "node")]
+    pub nodes: Vec<FleetNode>,
+}
+
+impl Fleet {
+    /// read a fleet from a nodes file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let text =
+            fs::read_to_string(path).map_err(|e| format!("can't read {}: {e}", path.display()))?;
+        Self::parse(&text).map_err(|e| format!("bad nodes file {}: {e}", path.display()))
+    }
+
+    /// parse a nodes file
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let fleet: Fleet = toml::from_str(text).map_err(|e| e.to_string())?;
+        if fleet.nodes.is_empty() {
+            return Err("no [[node]]s".to_string());
+        }
+        let mut names = HashSet::new();
+        for node in &fleet.nodes {
+            if !names.insert(&node.name) {
+                return Err(format!("two nodes are named {}", node.name));
+            }
+        }
+        Ok(fleet)
+    }
+}
+
+fn api<'de, D: Deserializer<'de>>(d: D) -> Result<ApiListen, D::Error> {
+    String::deserialize(d)?.parse().map_err(D::Error::custom)
+}
+
+/// The censuses of a fleet merged into one
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Combined {
+    /// a record per peer, the freshest one that reached it
+    pub records: Vec<CensusRecord>,
+    /// how many nodes saw each peer
+    pub seen_by: HashMap<PeerId, usize>,
+}
+
+impl Combined {
+    /// merge the nodes' censuses
+    pub fn new(censuses: &[Vec<CensusRecord>]) -> Self {
+        let mut best: HashMap<PeerId, CensusRecord> = HashMap::new();
+        let mut seen_by: HashMap<PeerId, usize> = HashMap::new();
+        for census in censuses {
+            let peers: HashSet<PeerId> = census.iter().map(|r| r.peer).collect();
+            for peer in peers {
+                *seen_by.entry(peer).or_default() += 1;
+            }
+            for record in census {
+                let better = match best.get(&record.peer) {
+                    Some(kept) => (record.reachable, record.seen) > (kept.reachable, kept.seen),
+                    None => true,
+                };
+                if better {
+                    best.insert(record.peer, record.clone());
+                }
+            }
+        }
+        let mut records: Vec<_> = best.into_values().collect();
+        records.sort_by_key(|r| r.peer);
+        Self { records, seen_by }
+    }
+
+    /// the peers in a census no other node saw
+    pub fn only_in(&self, census: &[CensusRecord]) -> usize {
+        let peers: HashSet<PeerId> = census.iter().map(|r| r.peer).collect();
+        peers
+            .iter()
+            .filter(|peer| self.seen_by.get(peer) == Some(&1))
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_censuses() {
+        let fleet = Fleet::parse(
+            "[[node]]\nname = \"a\"\napi = \"127.0.0.1:9921\"\n\n\
+             [[node]]\nname = \"b\"\napi = \"unix:/tmp/b.sock\"\n",
+        )
+        .unwrap();
+        assert_eq!(fleet.nodes[1].api, ApiListen::Unix("/tmp/b.sock".into()));
+        assert!(Fleet::parse("").is_err());
+        assert!(Fleet::parse("[[node]]\nname = \"a\"\napi = \"nowhere\"\n").is_err());
+        assert!(Fleet::parse(
+            "[[node]]\nname = \"a\"\napi = \"unix:/a\"\n[[node]]\nname = \"a\"\napi = \"unix:/b\"\n"
+        )
+        .is_err());
+
+        let (shared, lone) = (PeerId::random(), PeerId::random());
+        let unreachable = CensusRecord::unreachable(shared, Vec::new(), "down".into());
+        let mut identified = unreachable.clone();
+        identified.reachable = true;
+        identified.agent = Some("kubo/0.21.0".into());
+        identified.seen = 1;
+        let censuses = [
+            vec![unreachable.clone()],
+            vec![
+                identified.clone(),
+                CensusRecord {
+                    peer: lone,
+                    ..unreachable
+                },
+            ],
+        ];
+        let combined = Combined::new(&censuses);
+        assert_eq!(combined.records.len(), 2);
+        let kept = combined.records.iter().find(|r| r.peer == shared).unwrap();
+        assert_eq!(kept, &identified);
+        assert_eq!(combined.seen_by[&shared], 2);
+        assert_eq!(combined.only_in(&censuses[0]), 0);
+        assert_eq!(combined.only_in(&censuses[1]), 1);
+    }
+}
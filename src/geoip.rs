@@ -0,0 +1,151 @@
+//! GeoIP lookups for peer addresses backed by a MaxMind (GeoLite2/GeoIP2) database.
+//!
+//! The databases are only read when built with the `geoip` feature, without it opening
+//! one fails.
+
+use libp2p::{multiaddr::Protocol, Multiaddr};
+#[cfg(feature = "geoip")]
+use maxminddb::geoip2;
+use std::{fmt, io, net::IpAddr, path::Path};
+
+/// The coarse location of an address
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Region {
+    /// two letter continent code (e.g. "EU")
+    pub continent: String,
+    /// ISO 3166-1 country code (e.g. "DE")
+    pub country: String,
+}
+
+impl Region {
+    /// the region used when an address can't be located
+    pub fn unknown() -> Self {
+        Self {
+            continent: "??".into(),
+            country: "??".into(),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.continent, self.country)
+    }
+}
+
+/// A handle to an open GeoIP country database
+pub struct GeoIp {
+    reader: Reader,
+}
+
+impl GeoIp {
+    /// open the .mmdb file at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            reader: open(path.as_ref())?,
+        })
+    }
+
+    /// look up the region for an ip address
+    #[cfg(feature = "geoip")]
+    pub fn lookup(&self, ip: IpAddr) -> Option<Region> {
+        let country: geoip2::Country = self.reader.lookup(ip).ok()?;
+        let continent = country
+            .continent
+            .and_then(|c| c.code)
+            .unwrap_or("??")
+            .to_string();
+        let country = country
+            .country
+            .and_then(|c| c.iso_code)
+            .unwrap_or("??")
+            .to_string();
+        Some(Region { continent, country })
+    }
+
+    /// look up the region for an ip address
+    #[cfg(not(feature = "geoip"))]
+    pub fn lookup(&self, _ip: IpAddr) -> Option<Region> {
+        match self.reader {}
+    }
+
+    /// look up the region for the ip address in a multiaddr
+    pub fn lookup_addr(&self, addr: &Multiaddr) -> Option<Region> {
+        self.lookup(ip_of(addr)?)
+    }
+}
+
+/// A handle to an open GeoIP autonomous system database
+pub struct AsnDb {
+    reader: Reader,
+}
+
+impl AsnDb {
+    /// open the GeoLite2-ASN .mmdb file at the given path
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            reader: open(path.as_ref())?,
+        })
+    }
+
+    /// look up the autonomous system number for an ip address
+    #[cfg(feature = "geoip")]
+    pub fn lookup(&self, ip: IpAddr) -> Option<u32> {
+        let asn: geoip2::Asn = self.reader.lookup(ip).ok()?;
+        asn.autonomous_system_number
+    }
+
+    /// look up the autonomous system number for an ip address
+    #[cfg(not(feature = "geoip"))]
+    pub fn lookup(&self, _ip: IpAddr) -> Option<u32> {
+        match self.reader {}
+    }
+}
+
+#[cfg(feature = "geoip")]
+type Reader = maxminddb::Reader<Vec<u8>>;
+
+// without the feature no database can be opened
+#[cfg(not(feature = "geoip"))]
+enum Reader {}
+
+#[cfg(feature = "geoip")]
+fn open(path: &Path) -> io::Result<Reader> {
+    Reader::open_readfile(path).map_err(|e| match e {
+        maxminddb::MaxMindDBError::IoError(e) => io::Error::other(e),
+        e => io::Error::new(io::ErrorKind::InvalidData, e),
+    })
+}
+
+#[cfg(not(feature = "geoip"))]
+fn open(path: &Path) -> io::Result<Reader> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "can't open {}, fleyg was built without the geoip feature",
+            path.display()
+        ),
+    ))
+}
+
+/// get the ip address from a multiaddr, if it has one
+pub fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|p| match p {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_from_multiaddr() {
+        let addr: Multiaddr = "/ip4/10.1.2.3/tcp/4001".parse().unwrap();
+        assert_eq!(ip_of(&addr), Some("10.1.2.3".parse().unwrap()));
+        let addr: Multiaddr = "/dnsaddr/bootstrap.libp2p.io".parse().unwrap();
+        assert_eq!(ip_of(&addr), None);
+    }
+}
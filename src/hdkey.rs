@@ -0,0 +1,114 @@
+//! Deterministic ed25519 identities for node fleets.
+//!
+//! A BIP-39 seed phrase is turned into a seed and each node's key is derived from it
+//! along a path like `m/0/3` following SLIP-0010. Ed25519 only has hardened children so
+//! every index is hardened whether or not it is written with a `'`.
+
+use bip39::Mnemonic;
+use hmac::{Hmac, Mac};
+use libp2p::identity::Keypair;
+use sha2::Sha512;
+use std::{fmt, str::FromStr};
+
+type HmacSha512 = Hmac<Sha512>;
+
+/// the offset of the hardened indexes
+const HARDENED: u32 = 1 << 31;
+
+/// A derivation path, the indexes below the master key
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DerivationPath(pub Vec<u32>);
+
+impl FromStr for DerivationPath {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('/');
+        if parts.next() != Some("m") {
+            return Err(format!("derivation paths start with m/: {s}"));
+        }
+        parts
+            .map(|part| {
+                let index = part.trim_end_matches(['\'', 'h', 'H']);
+                match index.parse::<u32>() {
+                    Ok(i) if i < HARDENED => Ok(i),
+                    _ => Err(format!("invalid index {part} in {s}")),
+                }
+            })
+            .collect::<Result<_, _>>()
+            .map(DerivationPath)
+    }
+}
+
+impl fmt::Display for DerivationPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "m")?;
+        for i in &self.0 {
+            write!(f, "/{i}'")?;
+        }
+        Ok(())
+    }
+}
+
+/// the seed for a seed phrase and optional passphrase
+pub fn seed(mnemonic: &str, passphrase: &str) -> Result<[u8; 64], String> {
+    let mnemonic = Mnemonic::parse_normalized(mnemonic).map_err(|e| e.to_string())?;
+    Ok(mnemonic.to_seed(passphrase))
+}
+
+/// the ed25519 secret key at the path below the seed's master key
+pub fn derive(seed: &[u8], path: &DerivationPath) -> [u8; 32] {
+    let (mut key, mut chain) = split(hmac(b"ed25519 seed", &[seed]));
+    for index in &path.0 {
+        let hardened = (index | HARDENED).to_be_bytes();
+        (key, chain) = split(hmac(&chain, &[&[0], &key, &hardened]));
+    }
+    key
+}
+
+/// the identity at the path for a seed phrase
+pub fn keypair(mnemonic: &str, passphrase: &str, path: &DerivationPath) -> Result<Keypair, String> {
+    let seed = seed(mnemonic, passphrase)?;
+    Keypair::ed25519_from_bytes(derive(&seed, path)).map_err(|e| e.to_string())
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 64] {
+    let mut mac = HmacSha512::new_from_slice(key).expect("hmac takes keys of any length");
+    for d in data {
+        mac.update(d);
+    }
+    let mut out = [0; 64];
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+// split an hmac output into the key and chain code
+fn split(i: [u8; 64]) -> ([u8; 32], [u8; 32]) {
+    let mut key = [0; 32];
+    let mut chain = [0; 32];
+    key.copy_from_slice(&i[..32]);
+    chain.copy_from_slice(&i[32..]);
+    (key, chain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slip10_vectors() {
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master: DerivationPath = "m".parse().unwrap();
+        assert_eq!(
+            hex::encode(derive(&seed, &master)),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        let child: DerivationPath = "m/0'".parse().unwrap();
+        assert_eq!(child, "m/0".parse().unwrap());
+        assert_eq!(
+            hex::encode(derive(&seed, &child)),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert!("0/3".parse::<DerivationPath>().is_err());
+    }
+}
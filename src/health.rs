@@ -0,0 +1,224 @@
+//! A scored report card for how well the DHT is working from this node.
+//!
+//! The report combines the lookup success rate, the cost of a lookup, how full the far
+//! routing table buckets are and whether known provider records can be found. libp2p
+//! doesn't expose the path length of a query so the number of requests a lookup made
+//! stands in for its hop count.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+/// the bucket size
+const K: usize = 20;
+
+/// the far buckets that should be full in a network of any size
+const FAR_BUCKETS: std::ops::RangeInclusive<u32> = 246..=255;
+
+/// The levels each check has to reach to pass
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Thresholds {
+    /// the fraction of lookups that have to succeed
+    pub lookup_success: f64,
+    /// the most requests the median lookup may take
+    pub lookup_requests: f64,
+    /// how full the far buckets have to be, from 0 to 1
+    pub bucket_fullness: f64,
+    /// the fraction of provider samples that have to be found
+    pub provider_success: f64,
+    /// the lowest overall score, out of 100, that is healthy
+    pub min_score: f64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self {
+            lookup_success: 0.9,
+            lookup_requests: 60.0,
+            bucket_fullness: 0.5,
+            provider_success: 0.8,
+            min_score: 70.0,
+        }
+    }
+}
+
+/// The outcome of one lookup
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LookupSample {
+    pub ok: bool,
+    /// the requests the lookup sent
+    pub requests: u32,
+    pub duration: Duration,
+}
+
+/// Everything measured for a report
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Measurements {
+    pub lookups: Vec<LookupSample>,
+    /// the number of peers in each routing table bucket, keyed by bucket index
+    pub buckets: BTreeMap<u32, usize>,
+    /// whether each provider sample was found
+    pub providers: Vec<bool>,
+}
+
+/// How a check came out
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grade {
+    Pass,
+    Fail,
+    /// nothing was measured
+    Skipped,
+}
+
+impl fmt::Display for Grade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Grade::Pass => write!(f, "pass"),
+            Grade::Fail => write!(f, "fail"),
+            Grade::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+/// One line of the report
+#[derive(Clone, Debug, PartialEq)]
+pub struct Check {
+    pub name: &'static str,
+    /// the measured value, for display
+    pub value: String,
+    /// from 0 to 1, none if skipped
+    pub score: Option<f64>,
+    pub grade: Grade,
+}
+
+/// The scored report
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthReport {
+    pub checks: Vec<Check>,
+    /// the mean of the check scores out of 100
+    pub score: f64,
+    /// true if every check passed and the score is at least the minimum
+    pub healthy: bool,
+}
+
+impl HealthReport {
+    /// score the measurements against the thresholds
+    pub fn new(m: &Measurements, t: &Thresholds) -> Self {
+        let mut checks = Vec::new();
+
+        let ok = m.lookups.iter().filter(|l| l.ok).count();
+        checks.push(match rate(ok, m.lookups.len()) {
+            Some(r) => graded(
+                "lookup success",
+                format!("{ok}/{}", m.lookups.len()),
+                r,
+                r >= t.lookup_success,
+            ),
+            None => skipped("lookup success"),
+        });
+
+        let mut requests: Vec<u32> = m.lookups.iter().map(|l| l.requests).collect();
+        requests.sort_unstable();
+        checks.push(match requests.get(requests.len() / 2) {
+            Some(&median) => {
+                let median = f64::from(median);
+                let score = if median <= t.lookup_requests {
+                    1.0
+                } else {
+                    t.lookup_requests / median
+                };
+                graded(
+                    "lookup requests",
+                    format!("{median} median"),
+                    score,
+                    score >= 1.0,
+                )
+            }
+            None => skipped("lookup requests"),
+        });
+
+        let fullness = bucket_fullness(&m.buckets);
+        checks.push(graded(
+            "bucket fullness",
+            format!("{:.0}%", fullness * 100.0),
+            (fullness / t.bucket_fullness).min(1.0),
+            fullness >= t.bucket_fullness,
+        ));
+
+        let found = m.providers.iter().filter(|f| **f).count();
+        checks.push(match rate(found, m.providers.len()) {
+            Some(r) => graded(
+                "provider retrieval",
+                format!("{found}/{}", m.providers.len()),
+                r,
+                r >= t.provider_success,
+            ),
+            None => skipped("provider retrieval"),
+        });
+
+        let scores: Vec<f64> = checks.iter().filter_map(|c| c.score).collect();
+        let score = 100.0 * scores.iter().sum::<f64>() / scores.len().max(1) as f64;
+        let healthy = score >= t.min_score && checks.iter().all(|c| c.grade != Grade::Fail);
+        Self {
+            checks,
+            score,
+            healthy,
+        }
+    }
+}
+
+/// the mean fill of the far buckets from 0 to 1
+pub fn bucket_fullness(buckets: &BTreeMap<u32, usize>) -> f64 {
+    let filled: usize = FAR_BUCKETS
+        .map(|i| buckets.get(&i).copied().unwrap_or(0).min(K))
+        .sum();
+    filled as f64 / (FAR_BUCKETS.count() * K) as f64
+}
+
+fn rate(n: usize, of: usize) -> Option<f64> {
+    (of > 0).then(|| n as f64 / of as f64)
+}
+
+fn graded(name: &'static str, value: String, score: f64, pass: bool) -> Check {
+    Check {
+        name,
+        value,
+        score: Some(score),
+        grade: if pass { Grade::Pass } else { Grade::Fail },
+    }
+}
+
+fn skipped(name: &'static str) -> Check {
+    Check {
+        name,
+        value: "-".to_string(),
+        score: None,
+        grade: Grade::Skipped,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score() {
+        let lookup = |ok, requests| LookupSample {
+            ok,
+            requests,
+            duration: Duration::from_secs(1),
+        };
+        let m = Measurements {
+            lookups: vec![lookup(true, 30), lookup(true, 40), lookup(false, 90)],
+            buckets: FAR_BUCKETS.map(|i| (i, K)).collect(),
+            providers: Vec::new(),
+        };
+        let report = HealthReport::new(&m, &Thresholds::default());
+        assert_eq!(report.checks[0].grade, Grade::Fail);
+        assert_eq!(report.checks[1].grade, Grade::Pass);
+        assert_eq!(report.checks[2].score, Some(1.0));
+        assert_eq!(report.checks[3].grade, Grade::Skipped);
+        assert!((report.score - 100.0 * (2.0 / 3.0 + 2.0) / 3.0).abs() < 1e-9);
+        assert!(!report.healthy);
+    }
+}
@@ -0,0 +1,116 @@
+//! Detecting a stalled event loop.
+//!
+//! An event loop that wakes for a swarm event or its own timer should handle it quickly
+//! and go back to waiting. Two things show it isn't: handling one wake taking longer
+//! than the threshold, a blocking handler, and waking later than the timer asked for,
+//! the executor too busy to run us. Either counts as a stall.
+
+use std::time::{Duration, Instant};
+
+/// Times the wakes of an event loop
+#[derive(Clone, Debug)]
+pub struct Heartbeat {
+    interval: Duration,
+    threshold: Duration,
+    next: Instant,
+    woke: Option<Instant>,
+    stalls: u64,
+    last_stall: Option<Instant>,
+    worst: Duration,
+}
+
+impl Heartbeat {
+    /// a loop that should wake at least every `interval` and never be more than
+    /// `threshold` late or busy
+    pub fn new(interval: Duration, threshold: Duration) -> Self {
+        Self {
+            interval,
+            threshold,
+            next: Instant::now() + interval,
+            woke: None,
+            stalls: 0,
+            last_stall: None,
+            worst: Duration::ZERO,
+        }
+    }
+
+    /// the latest the loop should wake up
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// how late the loop is for its timer, zero if it woke in time
+    pub fn lag(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.next)
+    }
+
+    /// the loop woke, returns how late it was if that's a stall
+    pub fn woke(&mut self, now: Instant) -> Option<Duration> {
+        self.woke = Some(now);
+        self.stalled(self.lag(now), now)
+    }
+
+    /// the loop finished handling the wake and goes back to waiting, returns how long it
+    /// took if that's a stall
+    pub fn handled(&mut self, now: Instant) -> Option<Duration> {
+        self.next = now + self.interval;
+        let busy = now.saturating_duration_since(self.woke.take()?);
+        self.stalled(busy, now)
+    }
+
+    fn stalled(&mut self, lag: Duration, now: Instant) -> Option<Duration> {
+        if lag <= self.threshold {
+            return None;
+        }
+        self.stalls += 1;
+        self.last_stall = Some(now);
+        self.worst = self.worst.max(lag);
+        Some(lag)
+    }
+
+    /// the stalls so far
+    pub fn stalls(&self) -> u64 {
+        self.stalls
+    }
+
+    /// when the last stall happened
+    pub fn last_stall(&self) -> Option<Instant> {
+        self.last_stall
+    }
+
+    /// the longest stall so far
+    pub fn worst(&self) -> Duration {
+        self.worst
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_stalls() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_millis(100));
+        let start = heartbeat.deadline() - Duration::from_secs(1);
+
+        // an event handled quickly
+        assert_eq!(heartbeat.woke(start + Duration::from_millis(10)), None);
+        assert_eq!(heartbeat.handled(start + Duration::from_millis(20)), None);
+
+        // a handler that blocked
+        heartbeat.woke(start + Duration::from_millis(30));
+        let busy = heartbeat.handled(start + Duration::from_millis(530));
+        assert_eq!(busy, Some(Duration::from_millis(500)));
+
+        // a timer that fired late, a second after the loop went back to waiting
+        assert_eq!(heartbeat.deadline(), start + Duration::from_millis(1530));
+        assert_eq!(
+            heartbeat.lag(start + Duration::from_millis(1000)),
+            Duration::ZERO
+        );
+        let late = heartbeat.woke(start + Duration::from_millis(1830));
+        assert_eq!(late, Some(Duration::from_millis(300)));
+        assert_eq!(heartbeat.stalls(), 2);
+        assert_eq!(heartbeat.worst(), Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,480 @@
+//! Trimming what our identify answers tell other peers.
+//!
+//! Measurement nodes want to be seen as little as possible. The `[identify]` config
+//! section masks the agent version and protocol version we send and can hide our listen
+//! and external addresses and some of our protocols:
+//!
+//! ```toml
+//! [identify]
+//! agent = "kubo/0.24.0"
+//! hide_listen_addrs = true
+//! hide_protocols = ["/ipfs/kad/1.0.0", "/ipfs/ping/1.0.0"]
+//! ```
+//!
+//! Addresses are hidden by keeping the swarm's address events from reaching identify,
+//! so it never learns them. libp2p hands identify the protocols we accept streams for,
+//! so a hidden protocol is hidden by not accepting streams for it: [`Unlisted`] wraps a
+//! behaviour and leaves them out of what its connections listen for. We still open
+//! streams for them, peers asking us get refused as if we didn't speak them.
+
+use libp2p::{
+    core::{
+        upgrade::{InboundUpgrade, UpgradeInfo},
+        Endpoint,
+    },
+    identify,
+    swarm::{
+        behaviour::ConnectionClosed,
+        handler::{
+            ConnectionEvent, FullyNegotiatedInbound, InboundUpgradeSend, ListenUpgradeError,
+            UpgradeInfoSend,
+        },
+        ConnectionDenied, ConnectionHandler, ConnectionHandlerEvent, ConnectionId, FromSwarm,
+        KeepAlive, NetworkBehaviour, PollParameters, Stream, SubstreamProtocol, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+/// The `[identify]` config section
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdentifySettings {
+    /// the agent version to send instead of ours
+    pub agent: Option<String>,
+    /// the protocol version to send instead of the network's
+    pub protocol_version: Option<String>,
+    /// don't tell peers the addresses we listen on or are reachable at
+    pub hide_listen_addrs: bool,
+    /// protocols to leave out of the ones we tell peers we speak
+    pub hide_protocols: Vec<String>,
+}
+
+impl IdentifySettings {
+    /// the protocols to hide, for each [`Unlisted`] behaviour
+    pub fn hidden_protocols(&self) -> Arc<HashSet<String>> {
+        Arc::new(self.hide_protocols.iter().cloned().collect())
+    }
+}
+
+/// Identify, keeping addresses from it if they are to be hidden
+pub struct FilteredIdentify {
+    inner: identify::Behaviour,
+    hide_listen_addrs: bool,
+}
+
+impl FilteredIdentify {
+    pub fn new(inner: identify::Behaviour, settings: &IdentifySettings) -> Self {
+        Self {
+            inner,
+            hide_listen_addrs: settings.hide_listen_addrs,
+        }
+    }
+}
+
+impl NetworkBehaviour for FilteredIdentify {
+    type ConnectionHandler = <identify::Behaviour as NetworkBehaviour>::ConnectionHandler;
+    type ToSwarm = identify::Event;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_outbound_connection(connection_id, peer, addr, role_override)
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        if self.hide_listen_addrs && reveals_address(&event) {
+            return;
+        }
+        self.inner.on_swarm_event(event)
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx, params)
+    }
+}
+
+// the events identify learns our own addresses from
+fn reveals_address<H>(event: &FromSwarm<H>) -> bool
+where
+    H: libp2p::swarm::ConnectionHandler,
+{
+    matches!(
+        event,
+        FromSwarm::NewListenAddr(_)
+            | FromSwarm::ExpiredListenAddr(_)
+            | FromSwarm::NewExternalAddrCandidate(_)
+            | FromSwarm::ExternalAddrConfirmed(_)
+            | FromSwarm::ExternalAddrExpired(_)
+    )
+}
+
+/// A behaviour whose connections don't accept streams for the hidden protocols, so identify
+/// doesn't list them
+pub struct Unlisted<B> {
+    inner: B,
+    hidden: Arc<HashSet<String>>,
+}
+
+impl<B> Unlisted<B> {
+    pub fn new(inner: B, hidden: &Arc<HashSet<String>>) -> Self {
+        Self {
+            inner,
+            hidden: hidden.clone(),
+        }
+    }
+}
+
+impl<B> Deref for Unlisted<B> {
+    type Target = B;
+
+    fn deref(&self) -> &B {
+        &self.inner
+    }
+}
+
+impl<B> DerefMut for Unlisted<B> {
+    fn deref_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+}
+
+impl<B: NetworkBehaviour> NetworkBehaviour for Unlisted<B> {
+    type ConnectionHandler = UnlistedHandler<THandler<B>>;
+    type ToSwarm = B::ToSwarm;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let inner = self.inner.handle_established_inbound_connection(
+            connection_id,
+            peer,
+            local_addr,
+            remote_addr,
+        )?;
+        Ok(UnlistedHandler::new(inner, &self.hidden))
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        let inner = self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+        )?;
+        Ok(UnlistedHandler::new(inner, &self.hidden))
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm<Self::ConnectionHandler>) {
+        self.inner.on_swarm_event(unwrapped(event))
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+        params: &mut impl PollParameters,
+    ) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx, params)
+    }
+}
+
+// the swarm event for the behaviour inside, with its own handler if a connection closed
+fn unwrapped<H: ConnectionHandler>(event: FromSwarm<UnlistedHandler<H>>) -> FromSwarm<H> {
+    match event {
+        FromSwarm::ConnectionEstablished(e) => FromSwarm::ConnectionEstablished(e),
+        FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            connection_id,
+            endpoint,
+            handler,
+            remaining_established,
+        }) => FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            connection_id,
+            endpoint,
+            handler: handler.inner,
+            remaining_established,
+        }),
+        FromSwarm::AddressChange(e) => FromSwarm::AddressChange(e),
+        FromSwarm::DialFailure(e) => FromSwarm::DialFailure(e),
+        FromSwarm::ListenFailure(e) => FromSwarm::ListenFailure(e),
+        FromSwarm::NewListener(e) => FromSwarm::NewListener(e),
+        FromSwarm::NewListenAddr(e) => FromSwarm::NewListenAddr(e),
+        FromSwarm::ExpiredListenAddr(e) => FromSwarm::ExpiredListenAddr(e),
+        FromSwarm::ListenerError(e) => FromSwarm::ListenerError(e),
+        FromSwarm::ListenerClosed(e) => FromSwarm::ListenerClosed(e),
+        FromSwarm::NewExternalAddrCandidate(e) => FromSwarm::NewExternalAddrCandidate(e),
+        FromSwarm::ExternalAddrConfirmed(e) => FromSwarm::ExternalAddrConfirmed(e),
+        FromSwarm::ExternalAddrExpired(e) => FromSwarm::ExternalAddrExpired(e),
+    }
+}
+
+/// A connection handler listening for all of its protocols but the hidden ones
+pub struct UnlistedHandler<H> {
+    inner: H,
+    hidden: Arc<HashSet<String>>,
+}
+
+impl<H> UnlistedHandler<H> {
+    fn new(inner: H, hidden: &Arc<HashSet<String>>) -> Self {
+        Self {
+            inner,
+            hidden: hidden.clone(),
+        }
+    }
+}
+
+impl<H: ConnectionHandler> ConnectionHandler for UnlistedHandler<H> {
+    type FromBehaviour = H::FromBehaviour;
+    type ToBehaviour = H::ToBehaviour;
+    type Error = H::Error;
+    type InboundProtocol = Hiding<H::InboundProtocol>;
+    type OutboundProtocol = H::OutboundProtocol;
+    type InboundOpenInfo = H::InboundOpenInfo;
+    type OutboundOpenInfo = H::OutboundOpenInfo;
+
+    fn listen_protocol(&self) -> SubstreamProtocol<Self::InboundProtocol, Self::InboundOpenInfo> {
+        self.inner.listen_protocol().map_upgrade(|inner| Hiding {
+            inner,
+            hidden: self.hidden.clone(),
+        })
+    }
+
+    fn connection_keep_alive(&self) -> KeepAlive {
+        self.inner.connection_keep_alive()
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<
+        ConnectionHandlerEvent<
+            Self::OutboundProtocol,
+            Self::OutboundOpenInfo,
+            Self::ToBehaviour,
+            Self::Error,
+        >,
+    > {
+        self.inner.poll(cx)
+    }
+
+    fn on_behaviour_event(&mut self, event: Self::FromBehaviour) {
+        self.inner.on_behaviour_event(event)
+    }
+
+    fn on_connection_event(
+        &mut self,
+        event: ConnectionEvent<
+            Self::InboundProtocol,
+            Self::OutboundProtocol,
+            Self::InboundOpenInfo,
+            Self::OutboundOpenInfo,
+        >,
+    ) {
+        let event = match event {
+            ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info }) => {
+                ConnectionEvent::FullyNegotiatedInbound(FullyNegotiatedInbound { protocol, info })
+            }
+            ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error }) => {
+                ConnectionEvent::ListenUpgradeError(ListenUpgradeError { info, error })
+            }
+            ConnectionEvent::FullyNegotiatedOutbound(e) => {
+                ConnectionEvent::FullyNegotiatedOutbound(e)
+            }
+            ConnectionEvent::AddressChange(e) => ConnectionEvent::AddressChange(e),
+            ConnectionEvent::DialUpgradeError(e) => ConnectionEvent::DialUpgradeError(e),
+            ConnectionEvent::LocalProtocolsChange(e) => ConnectionEvent::LocalProtocolsChange(e),
+            ConnectionEvent::RemoteProtocolsChange(e) => ConnectionEvent::RemoteProtocolsChange(e),
+        };
+        self.inner.on_connection_event(event)
+    }
+}
+
+/// An inbound upgrade without the hidden protocols
+pub struct Hiding<U> {
+    inner: U,
+    hidden: Arc<HashSet<String>>,
+}
+
+impl<U: UpgradeInfoSend> UpgradeInfo for Hiding<U> {
+    type Info = U::Info;
+    type InfoIter = Vec<U::Info>;
+
+    fn protocol_info(&self) -> Self::InfoIter {
+        UpgradeInfoSend::protocol_info(&self.inner)
+            .into_iter()
+            .filter(|protocol| !self.hidden.contains(protocol.as_ref()))
+            .collect()
+    }
+}
+
+impl<U: InboundUpgradeSend> InboundUpgrade<Stream> for Hiding<U> {
+    type Output = U::Output;
+    type Error = U::Error;
+    type Future = U::Future;
+
+    fn upgrade_inbound(self, stream: Stream, info: U::Info) -> Self::Future {
+        InboundUpgradeSend::upgrade_inbound(self.inner, stream, info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{
+        core::{
+            transport::ListenerId,
+            upgrade::{ReadyUpgrade, SelectUpgrade},
+        },
+        swarm::{
+            behaviour::{ListenerClosed, NewListenAddr},
+            dummy,
+        },
+        StreamProtocol,
+    };
+
+    #[test]
+    fn hides_address_events() {
+        let addr: Multiaddr = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        let listener_id = ListenerId::next();
+        let new = FromSwarm::<dummy::ConnectionHandler>::NewListenAddr(NewListenAddr {
+            listener_id,
+            addr: &addr,
+        });
+        assert!(reveals_address(&new));
+        let closed = FromSwarm::<dummy::ConnectionHandler>::ListenerClosed(ListenerClosed {
+            listener_id,
+            reason: Ok(()),
+        });
+        assert!(!reveals_address(&closed));
+    }
+
+    #[test]
+    fn hides_protocols() {
+        let settings: IdentifySettings =
+            toml::from_str(r#"hide_protocols = ["/ipfs/kad/1.0.0"]"#).unwrap();
+        let upgrade = Hiding {
+            inner: SelectUpgrade::new(
+                ReadyUpgrade::new(StreamProtocol::new("/ipfs/kad/1.0.0")),
+                ReadyUpgrade::new(StreamProtocol::new("/ipfs/ping/1.0.0")),
+            ),
+            hidden: settings.hidden_protocols(),
+        };
+        let listed: Vec<String> = UpgradeInfo::protocol_info(&upgrade)
+            .iter()
+            .map(|protocol| protocol.as_ref().to_string())
+            .collect();
+        assert_eq!(listed, ["/ipfs/ping/1.0.0"]);
+    }
+}
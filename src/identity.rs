@@ -0,0 +1,160 @@
+//! Identity keypairs of every type libp2p peer ids are made from.
+//!
+//! Most of the IPFS DHT runs on ed25519 peer ids, but older go-ipfs nodes use RSA and
+//! some networks use secp256k1 or ECDSA keys. Key files are protobuf encoded keypairs,
+//! like go-ipfs and rust-libp2p write them. libp2p can neither generate nor protobuf
+//! encode RSA keys, so those are loaded from a PKCS#8 file, DER or PEM, made with e.g.
+//!
+//! ```text
+//! openssl genpkey -algorithm RSA -pkeyopt rsa_keygen_bits:2048 -outform DER -out key
+//! ```
+
+use crate::state::write_keypair;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use libp2p::identity::{self, Keypair};
+use std::{fmt, fs, io, path::Path, str::FromStr};
+
+/// The type of an identity key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyType {
+    #[default]
+    Ed25519,
+    Secp256k1,
+    Ecdsa,
+    Rsa,
+}
+
+impl KeyType {
+    /// the type of a keypair
+    pub fn of(key: &Keypair) -> Self {
+        match key.key_type() {
+            identity::KeyType::Ed25519 => KeyType::Ed25519,
+            identity::KeyType::Secp256k1 => KeyType::Secp256k1,
+            identity::KeyType::Ecdsa => KeyType::Ecdsa,
+            identity::KeyType::RSA => KeyType::Rsa,
+        }
+    }
+
+    /// a new random keypair of this type
+    pub fn generate(&self) -> io::Result<Keypair> {
+        match self {
+            KeyType::Ed25519 => Ok(Keypair::generate_ed25519()),
+            KeyType::Secp256k1 => Ok(Keypair::generate_secp256k1()),
+            KeyType::Ecdsa => Ok(Keypair::generate_ecdsa()),
+            KeyType::Rsa => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "RSA keys can't be generated, make a PKCS#8 one with openssl genpkey and \
+                 load it with --identity",
+            )),
+        }
+    }
+}
+
+impl FromStr for KeyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(KeyType::Ed25519),
+            "secp256k1" => Ok(KeyType::Secp256k1),
+            "ecdsa" => Ok(KeyType::Ecdsa),
+            "rsa" => Ok(KeyType::Rsa),
+            _ => Err(format!(
+                "unknown key type: {s} (ed25519, secp256k1, ecdsa, rsa)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::Ecdsa => "ecdsa",
+            KeyType::Rsa => "rsa",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// load a keypair file, generating and saving a key of the given type, ed25519 if none,
+/// if there is no file. A key of another type than the one asked for is an error.
+pub fn load_keypair(path: &Path, key_type: Option<KeyType>) -> io::Result<Keypair> {
+    let key = match fs::read(path) {
+        Ok(bytes) => decode(bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {e}", path.display()),
+            )
+        })?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let key = key_type.unwrap_or_default().generate()?;
+            write_keypair(path, &key)?;
+            return Ok(key);
+        }
+        Err(e) => return Err(e),
+    };
+    match key_type {
+        Some(wanted) if wanted != KeyType::of(&key) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} holds a {} key, not {wanted}",
+                path.display(),
+                KeyType::of(&key)
+            ),
+        )),
+        _ => Ok(key),
+    }
+}
+
+/// decode a protobuf encoded keypair, or an RSA key in PKCS#8
+pub fn decode(bytes: Vec<u8>) -> Result<Keypair, String> {
+    if let Ok(key) = Keypair::from_protobuf_encoding(&bytes) {
+        return Ok(key);
+    }
+    let mut der = match std::str::from_utf8(&bytes) {
+        Ok(pem) if pem.contains("-----BEGIN") => pem_body(pem)?,
+        _ => bytes,
+    };
+    Keypair::rsa_from_pkcs8(&mut der)
+        .map_err(|_| "not a protobuf encoded keypair or a PKCS#8 RSA key".to_string())
+}
+
+// the base64 between a PEM file's header and footer
+fn pem_body(pem: &str) -> Result<Vec<u8>, String> {
+    if pem.contains("ENCRYPTED") {
+        return Err("encrypted keys aren't supported".to_string());
+    }
+    let body: String = pem
+        .lines()
+        .skip_while(|l| !l.starts_with("-----BEGIN"))
+        .skip(1)
+        .take_while(|l| !l.starts_with("-----END"))
+        .collect();
+    STANDARD.decode(body).map_err(|e| format!("bad PEM: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_by_type() {
+        let dir = std::env::temp_dir().join(format!("fleyg-identity-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("key");
+        let _ = fs::remove_file(&path);
+
+        let key = load_keypair(&path, Some(KeyType::Secp256k1)).unwrap();
+        assert_eq!(KeyType::of(&key), KeyType::Secp256k1);
+        let again = load_keypair(&path, None).unwrap();
+        assert_eq!(key.public(), again.public());
+        assert!(load_keypair(&path, Some(KeyType::Ed25519)).is_err());
+
+        assert_eq!("ecdsa".parse(), Ok(KeyType::Ecdsa));
+        assert!(KeyType::Rsa.generate().is_err());
+        assert!(decode(b"not a key".to_vec()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,162 @@
+//! Bootnodes and listen addresses from other tools' config files.
+
+use crate::chainspec::ChainSpec;
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::{fmt, fs, path::Path, str::FromStr};
+
+/// The file formats that can be imported
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// a kubo (go-ipfs) config file
+    Kubo,
+    /// a substrate chain spec
+    Substrate,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "kubo" => Ok(Format::Kubo),
+            "substrate" => Ok(Format::Substrate),
+            _ => Err(format!(
+                "unknown config format: {s} (known: kubo, substrate)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Kubo => write!(f, "kubo"),
+            Format::Substrate => write!(f, "substrate"),
+        }
+    }
+}
+
+/// What was found in an imported file
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    pub bootnodes: Vec<Multiaddr>,
+    pub listen: Vec<Multiaddr>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KuboConfig {
+    #[serde(default)]
+    bootstrap: Vec<String>,
+    #[serde(default)]
+    addresses: KuboAddresses,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct KuboAddresses {
+    #[serde(default)]
+    swarm: Vec<String>,
+}
+
+/// read the bootnodes and listen addresses from a file in the given format
+pub fn import<P: AsRef<Path>>(path: P, format: Format) -> Result<Imported, String> {
+    let path = path.as_ref();
+    match format {
+        Format::Kubo => {
+            let json = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+            let config: KuboConfig =
+                serde_json::from_str(&json).map_err(|e| format!("{}: {e}", path.display()))?;
+            Ok(Imported {
+                bootnodes: parse_all(&config.bootstrap)?,
+                listen: parse_all(&config.addresses.swarm)?,
+            })
+        }
+        Format::Substrate => Ok(Imported {
+            bootnodes: ChainSpec::load(path)?.bootnodes()?,
+            listen: Vec::new(),
+        }),
+    }
+}
+
+fn parse_all(addrs: &[String]) -> Result<Vec<Multiaddr>, String> {
+    addrs
+        .iter()
+        .map(|a| a.parse().map_err(|e| format!("invalid address {a}: {e}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: &str = "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN";
+
+    // write a fixture into a fresh directory for the test
+    fn fixture(test: &str, contents: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("fleyg-import-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.json");
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn imports_kubo_config() {
+        let path = fixture(
+            "kubo",
+            &format!(
+                r#"{{
+                    "Identity": {{"PeerID": "{PEER}"}},
+                    "Addresses": {{
+                        "Swarm": ["/ip4/0.0.0.0/tcp/4001", "/ip6/::/udp/4001/quic-v1"],
+                        "API": "/ip4/127.0.0.1/tcp/5001"
+                    }},
+                    "Bootstrap": ["/dnsaddr/bootstrap.libp2p.io/p2p/{PEER}"]
+                }}"#
+            ),
+        );
+        let imported = import(&path, Format::Kubo).unwrap();
+        let bootnode: Multiaddr = format!("/dnsaddr/bootstrap.libp2p.io/p2p/{PEER}")
+            .parse()
+            .unwrap();
+        assert_eq!(imported.bootnodes, [bootnode]);
+        assert_eq!(imported.listen.len(), 2);
+        assert_eq!(imported.listen[0], "/ip4/0.0.0.0/tcp/4001".parse().unwrap());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn imports_substrate_chain_spec() {
+        let path = fixture(
+            "substrate",
+            &format!(
+                r#"{{
+                    "name": "Polkadot",
+                    "id": "polkadot",
+                    "chainType": "Live",
+                    "bootNodes": ["/dns/boot.example.com/tcp/30333/p2p/{PEER}"],
+                    "protocolId": "dot",
+                    "genesis": {{"raw": {{}}}}
+                }}"#
+            ),
+        );
+        let imported = import(&path, Format::Substrate).unwrap();
+        assert_eq!(imported.bootnodes.len(), 1);
+        assert!(imported.listen.is_empty());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let path = fixture("malformed", r#"{"Bootstrap": ["/ip4/not-an-ip/tcp/4001"]}"#);
+        let e = import(&path, Format::Kubo).unwrap_err();
+        assert!(e.contains("invalid address /ip4/not-an-ip/tcp/4001"), "{e}");
+        fs::write(&path, r#"{"Bootstrap": "#).unwrap();
+        assert!(import(&path, Format::Kubo).is_err());
+        assert!(import(&path, Format::Substrate).is_err());
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+    }
+}
@@ -0,0 +1,136 @@
+//! Settings for running community infrastructure: a relay, rendezvous point, AutoNAT
+//! server and DHT bootstrap node in one process, or a relay alongside a regular node with
+//! `--relay-server`.
+
+use crate::config::Config;
+use libp2p::{multiaddr::Protocol, relay, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Which services to run and how to reach them
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InfraSettings {
+    /// run a circuit relay v2 server
+    pub relay: bool,
+    /// run a rendezvous server
+    pub rendezvous: bool,
+    /// answer AutoNAT dial back requests
+    pub autonat: bool,
+    /// the public addresses clients reach us on
+    pub external: Vec<Multiaddr>,
+    /// the most relay reservations held at once, the libp2p default if not set
+    pub max_reservations: Option<usize>,
+    /// the most relayed circuits open at once, the libp2p default if not set
+    pub max_circuits: Option<usize>,
+}
+
+impl Default for InfraSettings {
+    fn default() -> Self {
+        Self {
+            relay: true,
+            rendezvous: true,
+            autonat: true,
+            external: Vec::new(),
+            max_reservations: None,
+            max_circuits: None,
+        }
+    }
+}
+
+impl InfraSettings {
+    /// the relay server limits these settings give
+    pub fn relay_server(&self) -> RelayServerSettings {
+        RelayServerSettings {
+            enabled: self.relay,
+            max_reservations: self.max_reservations,
+            max_circuits: self.max_circuits,
+            ..Default::default()
+        }
+    }
+}
+
+/// The `[relay_server]` config section, limits are the libp2p defaults if not set
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RelayServerSettings {
+    /// relay circuits for other peers
+    pub enabled: bool,
+    /// the most reservations held at once
+    pub max_reservations: Option<usize>,
+    /// the most reservations one peer may hold
+    pub max_reservations_per_peer: Option<usize>,
+    /// seconds a reservation lasts before it has to be renewed
+    pub reservation_duration: Option<u64>,
+    /// the most circuits open at once
+    pub max_circuits: Option<usize>,
+    /// the most circuits open to or from one peer
+    pub max_circuits_per_peer: Option<usize>,
+    /// seconds a circuit may stay open
+    pub max_circuit_duration: Option<u64>,
+    /// bytes a circuit may carry each way before it's closed
+    pub max_circuit_bytes: Option<u64>,
+}
+
+impl RelayServerSettings {
+    /// the relay server config with these limits
+    pub fn config(&self) -> relay::Config {
+        let mut cfg = relay::Config::default();
+        if let Some(max) = self.max_reservations {
+            cfg.max_reservations = max;
+        }
+        if let Some(max) = self.max_reservations_per_peer {
+            cfg.max_reservations_per_peer = max;
+        }
+        if let Some(secs) = self.reservation_duration {
+            cfg.reservation_duration = Duration::from_secs(secs);
+        }
+        if let Some(max) = self.max_circuits {
+            cfg.max_circuits = max;
+        }
+        if let Some(max) = self.max_circuits_per_peer {
+            cfg.max_circuits_per_peer = max;
+        }
+        if let Some(secs) = self.max_circuit_duration {
+            cfg.max_circuit_duration = Duration::from_secs(secs);
+        }
+        if let Some(max) = self.max_circuit_bytes {
+            cfg.max_circuit_bytes = max;
+        }
+        cfg
+    }
+}
+
+/// a config file for clients of the infrastructure node on the named network,
+/// bootstrapping from it
+pub fn client_snippet(
+    peer: PeerId,
+    network: &str,
+    settings: &InfraSettings,
+) -> Result<String, String> {
+    let addrs: Vec<Multiaddr> = settings
+        .external
+        .iter()
+        .map(|a| a.clone().with(Protocol::P2p(peer)))
+        .collect();
+    let config = Config {
+        network: Some(network.into()),
+        bootnodes: Some(addrs.clone()),
+        dial: Some(true),
+        ..Default::default()
+    };
+    let mut snippet = String::new();
+    if settings.relay || settings.rendezvous {
+        snippet.push_str("# also serving\n");
+        for addr in &addrs {
+            if settings.relay {
+                snippet.push_str(&format!("#   relay: {addr}/p2p-circuit\n"));
+            }
+            if settings.rendezvous {
+                snippet.push_str(&format!("#   rendezvous: {addr}\n"));
+            }
+        }
+    }
+    snippet.push_str(&toml::to_string_pretty(&config).map_err(|e| e.to_string())?);
+    Ok(snippet)
+}
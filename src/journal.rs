@@ -0,0 +1,155 @@
+//! Journals of a long running operation's progress, so an interrupted run can pick up
+//! where it stopped.
+//!
+//! A journal is json lines next to the operation's output, `<output>.journal`. Entries are
+//! buffered and written out at checkpoints, at most a few seconds of progress is lost if
+//! the process dies. Finishing the operation removes the journal, so a journal left on
+//! disk means the run was interrupted.
+
+use log::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// how often buffered entries are written out
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// the journal for an operation writing to `output`
+pub fn path_for<P: AsRef<Path>>(output: P) -> PathBuf {
+    let mut name = OsString::from(output.as_ref().as_os_str());
+    name.push(".journal");
+    PathBuf::from(name)
+}
+
+/// An append only log of progress entries
+#[derive(Debug)]
+pub struct Journal<T> {
+    path: PathBuf,
+    file: BufWriter<File>,
+    last_checkpoint: Instant,
+    entries: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Journal<T> {
+    /// open the journal, returning the entries already in it
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<T>)> {
+        let path = path.as_ref().to_path_buf();
+        let (entries, valid) = read(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() > valid {
+            file.set_len(valid)?;
+        }
+        let journal = Self {
+            path,
+            file: BufWriter::new(file),
+            last_checkpoint: Instant::now(),
+            entries: PhantomData,
+        };
+        Ok((journal, entries))
+    }
+
+    /// the path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// add an entry, checkpointing if one is due
+    pub fn append(&mut self, entry: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(b"\n")?;
+        if self.last_checkpoint.elapsed() >= CHECKPOINT_INTERVAL {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    /// write every entry so far to disk
+    pub fn checkpoint(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.file.get_ref().sync_data()?;
+        self.last_checkpoint = Instant::now();
+        Ok(())
+    }
+
+    /// the operation finished, remove the journal
+    pub fn finish(self) -> io::Result<()> {
+        let path = self.path.clone();
+        drop(self);
+        fs::remove_file(path)
+    }
+}
+
+// read the entries in a journal and the length of the file they fill, a missing journal is
+// empty and anything after the last whole entry is left out
+fn read<T: DeserializeOwned>(path: &Path) -> io::Result<(Vec<T>, u64)> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    let mut valid = 0;
+    while let Some(end) = bytes[valid..].iter().position(|b| *b == b'\n') {
+        match serde_json::from_slice(&bytes[valid..valid + end]) {
+            Ok(entry) => entries.push(entry),
+            Err(e) => {
+                warn!(
+                    "Ignoring the rest of {} after a bad entry: {e}",
+                    path.display()
+                );
+                break;
+            }
+        }
+        valid += end + 1;
+    }
+    if valid < bytes.len() {
+        warn!(
+            "Dropping {} bytes of a torn entry from {}",
+            bytes.len() - valid,
+            path.display()
+        );
+    }
+    Ok((entries, valid as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_and_finish() {
+        let dir = std::env::temp_dir().join(format!("fleyg-journal-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = path_for(dir.join("census.jsonl"));
+        assert!(path.ends_with("census.jsonl.journal"));
+
+        let (mut journal, entries) = Journal::<u32>::open(&path).unwrap();
+        assert!(entries.is_empty());
+        journal.append(&1).unwrap();
+        journal.append(&2).unwrap();
+        journal.checkpoint().unwrap();
+        drop(journal);
+
+        // a torn write at the end is dropped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"3").unwrap();
+        file.write_all(b"{").unwrap();
+        drop(file);
+        let (mut journal, entries) = Journal::<u32>::open(&path).unwrap();
+        assert_eq!(entries, vec![1, 2]);
+        journal.append(&3).unwrap();
+        journal.checkpoint().unwrap();
+        drop(journal);
+        let (journal, entries) = Journal::<u32>::open(&path).unwrap();
+        assert_eq!(entries, vec![1, 2, 3]);
+        journal.finish().unwrap();
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
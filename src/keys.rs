@@ -0,0 +1,147 @@
+//! Text forms of record and provider keys, including the ones IPFS users write.
+//!
+//! Keys are shown in the `--encoding` unless `--key-encoding` says otherwise. IPFS content
+//! keys are multihashes, usually written as CIDs: with `cid` a key can be given as a
+//! CIDv0 like `Qm...` or a CIDv1 in any multibase like `bafy...`, the multihash inside it
+//! being the key, and multihash keys are shown as CIDv1s of raw blocks. `multibase` takes
+//! any multibase prefixed text and shows base32, and `base58` (base58btc) and `base32` are
+//! the bare encodings.
+
+use crate::encoding::Encoding;
+use cid::{
+    multibase::{self, Base},
+    multihash::Multihash,
+    Cid,
+};
+use std::{fmt, str::FromStr};
+
+/// the raw binary multicodec, what the CIDs of keys say they address
+const RAW: u64 = 0x55;
+
+/// How record and provider keys are given and shown
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyEncoding {
+    #[default]
+    Hex,
+    Base64,
+    Base58,
+    Base32,
+    Multibase,
+    /// a CID, naming the multihash key
+    Cid,
+    /// the key as (lossy) utf-8 text
+    Raw,
+}
+
+impl KeyEncoding {
+    /// show a key
+    pub fn encode(&self, key: &[u8]) -> String {
+        match self {
+            KeyEncoding::Hex => Encoding::Hex.encode(key),
+            KeyEncoding::Base64 => Encoding::Base64.encode(key),
+            KeyEncoding::Base58 => Encoding::Base58.encode(key),
+            KeyEncoding::Base32 => Base::Base32Lower.encode(key),
+            KeyEncoding::Multibase => multibase::encode(Base::Base32Lower, key),
+            // keys that aren't multihashes have no CID, so they're shown in multibase
+            KeyEncoding::Cid => match Multihash::<64>::from_bytes(key) {
+                Ok(hash) => Cid::new_v1(RAW, hash).to_string(),
+                Err(_) => multibase::encode(Base::Base32Lower, key),
+            },
+            KeyEncoding::Raw => Encoding::Raw.encode(key),
+        }
+    }
+
+    /// read a key given in this encoding
+    pub fn decode(&self, s: &str) -> Result<Vec<u8>, String> {
+        match self {
+            KeyEncoding::Hex => Encoding::Hex.decode(s),
+            KeyEncoding::Base64 => Encoding::Base64.decode(s),
+            KeyEncoding::Base58 => Encoding::Base58.decode(s),
+            KeyEncoding::Base32 => Base::Base32Lower
+                .decode(s.to_lowercase())
+                .map_err(|e| e.to_string()),
+            KeyEncoding::Multibase => multibase_key(s),
+            KeyEncoding::Cid => match Cid::try_from(s) {
+                Ok(cid) => Ok(cid.hash().to_bytes()),
+                Err(e) => multibase_key(s).map_err(|_| format!("not a CID: {e}")),
+            },
+            KeyEncoding::Raw => Encoding::Raw.decode(s),
+        }
+    }
+}
+
+fn multibase_key(s: &str) -> Result<Vec<u8>, String> {
+    multibase::decode(s)
+        .map(|(_, key)| key)
+        .map_err(|e| e.to_string())
+}
+
+impl From<Encoding> for KeyEncoding {
+    fn from(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Hex => KeyEncoding::Hex,
+            Encoding::Base64 => KeyEncoding::Base64,
+            Encoding::Base58 => KeyEncoding::Base58,
+            Encoding::Raw => KeyEncoding::Raw,
+        }
+    }
+}
+
+impl FromStr for KeyEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(KeyEncoding::Hex),
+            "base64" => Ok(KeyEncoding::Base64),
+            "base58" | "base58btc" => Ok(KeyEncoding::Base58),
+            "base32" => Ok(KeyEncoding::Base32),
+            "multibase" => Ok(KeyEncoding::Multibase),
+            "cid" => Ok(KeyEncoding::Cid),
+            "raw" => Ok(KeyEncoding::Raw),
+            _ => Err(format!(
+                "unknown key encoding: {s} (hex, base64, base58, base32, multibase, cid, raw)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KeyEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            KeyEncoding::Hex => "hex",
+            KeyEncoding::Base64 => "base64",
+            KeyEncoding::Base58 => "base58",
+            KeyEncoding::Base32 => "base32",
+            KeyEncoding::Multibase => "multibase",
+            KeyEncoding::Cid => "cid",
+            KeyEncoding::Raw => "raw",
+        };
+        write!(f, "{s}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_cids_and_multibase() {
+        let v0 = "QmYwAPJzv5CZsnA625s3Xf2nemtYgPpHdWEz79ojWnPbdG";
+        let hash = KeyEncoding::Cid.decode(v0).unwrap();
+        assert_eq!(hash, KeyEncoding::Base58.decode(v0).unwrap());
+        assert_eq!(&hash[..2], &[0x12, 0x20]);
+        let v1 = KeyEncoding::Cid.encode(&hash);
+        assert!(v1.starts_with("bafkrei"), "{v1}");
+        assert_eq!(KeyEncoding::Cid.decode(&v1).unwrap(), hash);
+
+        let key = b"/fleyg/key";
+        for enc in ["hex", "base64", "base58btc", "base32", "multibase", "cid"] {
+            let enc: KeyEncoding = enc.parse().unwrap();
+            assert_eq!(enc.decode(&enc.encode(key)).unwrap(), key, "{enc}");
+        }
+        assert_eq!(KeyEncoding::Multibase.decode("f2f6869").unwrap(), b"/hi");
+        assert_eq!(KeyEncoding::Multibase.encode(b"/hi"), "bf5ugs");
+        assert!(KeyEncoding::Cid.decode("?nope").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Estimating the parts of the keyspace a node is responsible for.
+//!
+//! A node stores the records whose keys it is among the k closest peers to. Knowing only
+//! the peers in our routing table, we count a key as ours if fewer than k of them are
+//! closer to it than we are. The keyspace is split into ranges by the top bits of the
+//! hashed key and each range is sampled at a few keys, so a range can be partly ours.
+//!
+//! Distances are between sha256 hashes of the peer ids and record keys, as kademlia
+//! uses.
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+/// A point in the keyspace
+pub type KeyHash = [u8; 32];
+
+/// the keyspace position of a peer
+pub fn peer_hash(peer: &PeerId) -> KeyHash {
+    record_hash(&peer.to_bytes())
+}
+
+/// the keyspace position of a record key
+pub fn record_hash(key: &[u8]) -> KeyHash {
+    let mut hash = [0; 32];
+    hash.copy_from_slice(&Sha256::digest(key));
+    hash
+}
+
+/// the range of 2^bits ranges the hash falls in
+pub fn prefix(hash: &KeyHash, bits: u8) -> u32 {
+    let bits = bits.clamp(1, 16);
+    u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]) >> (32 - u32::from(bits))
+}
+
+fn distance(a: &KeyHash, b: &KeyHash) -> KeyHash {
+    let mut d = [0; 32];
+    for ((byte, a), b) in d.iter_mut().zip(a).zip(b) {
+        *byte = a ^ b;
+    }
+    d
+}
+
+/// How much of a keyspace range we are responsible for
+#[derive(Clone, Debug, PartialEq)]
+pub struct RangeShare {
+    /// the top bits every key in the range starts with
+    pub prefix: u32,
+    pub bits: u8,
+    /// the fraction of sampled keys we are among the k closest for
+    pub share: f64,
+}
+
+impl RangeShare {
+    /// the range as a hex prefix and its length in bits, e.g. "3a/8"
+    pub fn label(&self) -> String {
+        let width = usize::from(self.bits).div_ceil(4);
+        let shift = width * 4 - usize::from(self.bits);
+        format!("{:0width$x}/{}", self.prefix << shift, self.bits)
+    }
+}
+
+/// Our view of who is closest to each key
+#[derive(Clone, Debug)]
+pub struct Responsibility {
+    local: KeyHash,
+    peers: Vec<KeyHash>,
+    k: usize,
+}
+
+impl Responsibility {
+    /// work out responsibility for `local` among the known peers with replication `k`
+    pub fn new<'a, I>(local: &PeerId, peers: I, k: usize) -> Self
+    where
+        I: IntoIterator<Item = &'a PeerId>,
+    {
+        Self {
+            local: peer_hash(local),
+            peers: peers
+                .into_iter()
+                .filter(|p| *p != local)
+                .map(peer_hash)
+                .collect(),
+            k: k.max(1),
+        }
+    }
+
+    /// true if we are among the k closest known peers to the key
+    pub fn is_responsible(&self, key: &KeyHash) -> bool {
+        let ours = distance(&self.local, key);
+        self.peers
+            .iter()
+            .filter(|p| distance(p, key) < ours)
+            .take(self.k)
+            .count()
+            < self.k
+    }
+
+    /// split the keyspace into 2^bits ranges and sample each one at `samples` keys
+    pub fn ranges(&self, bits: u8, samples: usize) -> Vec<RangeShare> {
+        let bits = bits.clamp(1, 16);
+        let samples = samples.max(1);
+        (0..1u32 << bits)
+            .map(|prefix| {
+                let ours = (0..samples)
+                    .filter(|n| self.is_responsible(&sample(prefix, bits, *n)))
+                    .count();
+                RangeShare {
+                    prefix,
+                    bits,
+                    share: ours as f64 / samples as f64,
+                }
+            })
+            .collect()
+    }
+}
+
+// a key in the range, spread out by hashing the range and sample number
+fn sample(prefix: u32, bits: u8, n: usize) -> KeyHash {
+    let seed = [prefix.to_be_bytes(), (n as u32).to_be_bytes()].concat();
+    let mut key = record_hash(&seed);
+    let top = (prefix << (32 - u32::from(bits))).to_be_bytes();
+    let mask = (u32::MAX << (32 - u32::from(bits))).to_be_bytes();
+    for ((byte, top), mask) in key.iter_mut().zip(top).zip(mask) {
+        *byte = (*byte & !mask) | top;
+    }
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn closest_peers() {
+        let local = PeerId::random();
+        let peers: Vec<PeerId> = (0..40).map(|_| PeerId::random()).collect();
+
+        // with fewer peers than k we are responsible for everything
+        let few = Responsibility::new(&local, &peers[..5], 20);
+        assert!(few.ranges(4, 4).iter().all(|r| r.share == 1.0));
+
+        // our own position is always ours, a peer's position is only ours if k > 1
+        let some = Responsibility::new(&local, &peers, 1);
+        assert!(some.is_responsible(&peer_hash(&local)));
+        assert!(!some.is_responsible(&peer_hash(&peers[0])));
+
+        let range = RangeShare {
+            prefix: 0x5,
+            bits: 3,
+            share: 0.0,
+        };
+        assert_eq!(range.label(), "a/3");
+        assert_eq!(prefix(&sample(0x5, 3, 0), 3), 0x5);
+    }
+}
@@ -0,0 +1,129 @@
+//! Round trip time statistics and the latency-by-region summary used by `fleyg map`.
+
+use crate::geoip::Region;
+use std::{collections::BTreeMap, fmt::Write, time::Duration};
+
+/// Summary statistics over a set of round trip times
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RttStats {
+    pub count: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub avg: Duration,
+    pub median: Duration,
+    pub stddev: Duration,
+}
+
+impl RttStats {
+    /// compute the stats for the given samples, returns None if there are none
+    pub fn from_samples(samples: &[Duration]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let count = sorted.len();
+        let secs: Vec<f64> = sorted.iter().map(Duration::as_secs_f64).collect();
+        let mean = secs.iter().sum::<f64>() / count as f64;
+        let variance = secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / count as f64;
+        let median = if count % 2 == 0 {
+            (sorted[count / 2 - 1] + sorted[count / 2]) / 2
+        } else {
+            sorted[count / 2]
+        };
+        Some(Self {
+            count,
+            min: sorted[0],
+            max: sorted[count - 1],
+            avg: Duration::from_secs_f64(mean),
+            median,
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+        })
+    }
+}
+
+/// RTT samples grouped by the region of the peer they were measured against
+#[derive(Debug, Default)]
+pub struct LatencyMap {
+    regions: BTreeMap<Region, Vec<Duration>>,
+}
+
+impl LatencyMap {
+    /// add an rtt sample for a peer in the given region
+    pub fn add(&mut self, region: Region, rtt: Duration) {
+        self.regions.entry(region).or_default().push(rtt);
+    }
+
+    /// the total number of samples across all regions
+    pub fn len(&self) -> usize {
+        self.regions.values().map(Vec::len).sum()
+    }
+
+    /// true if there are no samples
+    pub fn is_empty(&self) -> bool {
+        self.regions.is_empty()
+    }
+
+    /// per-region stats, sorted by median rtt
+    pub fn summary(&self) -> Vec<(Region, RttStats)> {
+        let mut rows: Vec<(Region, RttStats)> = self
+            .regions
+            .iter()
+            .filter_map(|(r, s)| RttStats::from_samples(s).map(|st| (r.clone(), st)))
+            .collect();
+        rows.sort_by_key(|(_, st)| st.median);
+        rows
+    }
+
+    /// render the summary as a standalone html page with a bar per region
+    pub fn to_html(&self) -> String {
+        let rows = self.summary();
+        let widest = rows
+            .iter()
+            .map(|(_, st)| st.median)
+            .max()
+            .unwrap_or_default()
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let mut html = String::new();
+        html.push_str("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+        html.push_str("<title>fleyg latency map</title>\n<style>\n");
+        html.push_str("body { font-family: sans-serif; }\n");
+        html.push_str("td.bar div { background: #ef65a4; height: 1em; }\n");
+        html.push_str("</style>\n</head>\n<body>\n<h1>Latency by region</h1>\n<table>\n");
+        html.push_str(
+            "<tr><th>Region</th><th>Peers</th><th>Min</th><th>Median</th><th>Max</th><th></th></tr>\n",
+        );
+        for (region, st) in &rows {
+            let width = (st.median.as_secs_f64() / widest * 400.0) as u32;
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{:?}</td><td>{:?}</td><td>{:?}</td>\
+                 <td class=\"bar\"><div style=\"width: {}px\"></div></td></tr>",
+                region, st.count, st.min, st.median, st.max, width
+            );
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rtt_stats() {
+        let samples: Vec<Duration> = [10, 20, 30, 40]
+            .iter()
+            .map(|ms| Duration::from_millis(*ms))
+            .collect();
+        let st = RttStats::from_samples(&samples).unwrap();
+        assert_eq!(st.count, 4);
+        assert_eq!(st.min, Duration::from_millis(10));
+        assert_eq!(st.max, Duration::from_millis(40));
+        assert_eq!(st.median, Duration::from_millis(25));
+        assert_eq!(st.avg.as_millis(), 25);
+        assert!(RttStats::from_samples(&[]).is_none());
+    }
+}
@@ -1,14 +1,84 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
-}
+//! The libp2p node and tools behind the `fleyg` command line and its companion binaries.
+//!
+//! [`node::FleygNode`] builds a swarm speaking the IPFS DHT protocols, or those of another
+//! network from [`network`]. Around it are the pieces the binaries share: the record
+//! [`store`] and [`peerstore`] a node keeps in its [`state`] directory, the [`control`]
+//! API a daemon answers on, signed [`answer`]s, [`metrics`] and the [`table`] output every
+//! command prints. The async executor is picked at build time in [`runtime`].
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
-    }
-}
+pub mod alerts;
+pub mod answer;
+pub mod apilisten;
+pub mod archive;
+pub mod attest;
+pub mod availability;
+pub mod blocks;
+pub mod bugreport;
+pub mod bundle;
+pub mod capture;
+pub mod census;
+pub mod chainspec;
+pub mod config;
+pub mod control;
+pub mod deadline;
+pub mod diagnose;
+pub mod dialqueue;
+pub mod dialrace;
+pub mod dialreport;
+pub mod divergence;
+pub mod dnscache;
+pub mod doctor;
+pub mod encoding;
+pub mod event;
+pub mod fastpath;
+pub mod filter;
+pub mod fleet;
+pub mod geoip;
+pub mod hdkey;
+pub mod health;
+pub mod heartbeat;
+pub mod identifyfilter;
+pub mod identity;
+pub mod import;
+pub mod infra;
+pub mod journal;
+pub mod keys;
+pub mod keyspace;
+pub mod latency;
+pub mod memprofile;
+pub mod merge;
+pub mod meshstats;
+pub mod metrics;
+pub mod mirror;
+pub mod namespace;
+pub mod negotiation;
+pub mod network;
+pub mod node;
+pub mod peerstore;
+pub mod peerwalk;
+pub mod pex;
+pub mod pointer;
+pub mod profile;
+pub mod progress;
+pub mod querybudget;
+pub mod querystats;
+pub mod ratelimit;
+pub mod readiness;
+pub mod receipt;
+pub mod relays;
+pub mod retry;
+pub mod routing;
+pub mod routingpolicy;
+pub mod runtime;
+pub mod schedule;
+pub mod sessions;
+pub mod shutdown;
+pub mod skew;
+pub mod soak;
+pub mod state;
+pub mod store;
+pub mod table;
+pub mod timespec;
+pub mod trace;
+pub mod transport;
+pub mod wal;
@@ -0,0 +1,241 @@
+//! Memory profiling by phase of a command.
+//!
+//! [`Tracking`] wraps the global allocator and, once enabled, counts every allocation
+//! against the phase the process is in, like bootstrapping or crawling. A phase is
+//! entered with [`phase`] and left when the returned guard drops. Each phase reports its
+//! allocations, the bytes allocated, the most bytes live at once, the peak resident set
+//! size by the time it ended and which allocation sizes the bytes went to, which is
+//! where a phase's hotspot is without paying for backtraces.
+//!
+//! Counting is a few relaxed atomic adds per allocation and nothing at all until
+//! [`enable`] is called.
+
+use crate::soak;
+use std::{
+    alloc::{GlobalAlloc, Layout},
+    sync::{
+        atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+};
+
+/// the most phases that can be told apart, later ones are counted as the last
+const MAX_PHASES: usize = 16;
+
+/// the upper bounds of the allocation size buckets, the last bucket takes the rest
+const BUCKETS: [usize; 7] = [64, 256, 1 << 10, 4 << 10, 16 << 10, 64 << 10, 1 << 20];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static LIVE: AtomicI64 = AtomicI64::new(0);
+static PHASES: [Counters; MAX_PHASES] = [const { Counters::new() }; MAX_PHASES];
+static NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+struct Counters {
+    allocations: AtomicU64,
+    bytes: AtomicU64,
+    peak_live: AtomicI64,
+    peak_rss: AtomicU64,
+    sizes: [AtomicU64; BUCKETS.len() + 1],
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Self {
+            allocations: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            peak_live: AtomicI64::new(0),
+            peak_rss: AtomicU64::new(0),
+            sizes: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+        }
+    }
+}
+
+/// A global allocator counting what it hands out
+pub struct Tracking<A>(pub A);
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracking<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.0.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.0.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.0.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            freed(layout.size());
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+fn allocated(size: usize) {
+    if !ENABLED.load(Relaxed) {
+        return;
+    }
+    let phase = &PHASES[CURRENT.load(Relaxed)];
+    phase.allocations.fetch_add(1, Relaxed);
+    phase.bytes.fetch_add(size as u64, Relaxed);
+    phase.sizes[bucket(size)].fetch_add(size as u64, Relaxed);
+    let live = LIVE.fetch_add(size as i64, Relaxed) + size as i64;
+    phase.peak_live.fetch_max(live, Relaxed);
+}
+
+fn freed(size: usize) {
+    if ENABLED.load(Relaxed) {
+        LIVE.fetch_sub(size as i64, Relaxed);
+    }
+}
+
+fn bucket(size: usize) -> usize {
+    BUCKETS
+        .iter()
+        .position(|max| size <= *max)
+        .unwrap_or(BUCKETS.len())
+}
+
+/// start counting allocations, in the "startup" phase
+pub fn enable() {
+    CURRENT.store(index("startup"), Relaxed);
+    ENABLED.store(true, Relaxed);
+}
+
+/// true if allocations are being counted
+pub fn is_enabled() -> bool {
+    ENABLED.load(Relaxed)
+}
+
+// the slot for a phase, registering it on first use
+fn index(name: &'static str) -> usize {
+    let mut names = NAMES.lock().unwrap_or_else(|e| e.into_inner());
+    match names.iter().position(|n| *n == name) {
+        Some(i) => i,
+        None if names.len() < MAX_PHASES => {
+            names.push(name);
+            names.len() - 1
+        }
+        None => MAX_PHASES - 1,
+    }
+}
+
+/// Puts the process back in the phase it was in when dropped
+#[must_use]
+pub struct PhaseGuard {
+    previous: usize,
+}
+
+impl Drop for PhaseGuard {
+    fn drop(&mut self) {
+        if is_enabled() {
+            note_rss(CURRENT.load(Relaxed));
+            CURRENT.store(self.previous, Relaxed);
+        }
+    }
+}
+
+/// enter a phase until the guard drops
+pub fn phase(name: &'static str) -> PhaseGuard {
+    let previous = CURRENT.load(Relaxed);
+    if is_enabled() {
+        note_rss(previous);
+        CURRENT.store(index(name), Relaxed);
+    }
+    PhaseGuard { previous }
+}
+
+fn note_rss(phase: usize) {
+    if let Some(rss) = soak::peak_rss_bytes() {
+        PHASES[phase].peak_rss.fetch_max(rss, Relaxed);
+    }
+}
+
+/// What a phase allocated
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PhaseReport {
+    pub name: &'static str,
+    pub allocations: u64,
+    pub bytes: u64,
+    /// the most bytes live at once while in the phase, across the whole process
+    pub peak_live_bytes: u64,
+    /// the resident set size's high water mark when the phase was last left
+    pub peak_rss_bytes: Option<u64>,
+    /// the bytes allocated in each size bucket, by the bucket's upper bound, none for the
+    /// last
+    pub sizes: Vec<(Option<usize>, u64)>,
+}
+
+impl PhaseReport {
+    /// the size bucket most of the phase's bytes went to and its share of them
+    pub fn hotspot(&self) -> Option<(Option<usize>, f64)> {
+        let (max, bytes) = self.sizes.iter().max_by_key(|(_, b)| *b)?;
+        (*bytes > 0).then(|| (*max, *bytes as f64 / self.bytes.max(1) as f64))
+    }
+}
+
+/// the report of every phase entered so far, in the order they were first entered
+pub fn report() -> Vec<PhaseReport> {
+    note_rss(CURRENT.load(Relaxed));
+    let names = NAMES.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    names
+        .into_iter()
+        .zip(&PHASES)
+        .map(|(name, c)| PhaseReport {
+            name,
+            allocations: c.allocations.load(Relaxed),
+            bytes: c.bytes.load(Relaxed),
+            peak_live_bytes: c.peak_live.load(Relaxed).max(0) as u64,
+            peak_rss_bytes: Some(c.peak_rss.load(Relaxed)).filter(|b| *b > 0),
+            sizes: BUCKETS
+                .iter()
+                .map(|b| Some(*b))
+                .chain([None])
+                .zip(&c.sizes)
+                .map(|(max, bytes)| (max, bytes.load(Relaxed)))
+                .collect(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phases() {
+        assert_eq!(bucket(1), 0);
+        assert_eq!(bucket(64), 0);
+        assert_eq!(bucket(65), 1);
+        assert_eq!(bucket(10 << 20), BUCKETS.len());
+
+        enable();
+        {
+            let _crawl = phase("crawl");
+            allocated(100);
+            allocated(2000);
+        }
+        allocated(10);
+        let report = report();
+        let crawl = report.iter().find(|r| r.name == "crawl").unwrap();
+        assert_eq!(crawl.allocations, 2);
+        assert_eq!(crawl.bytes, 2100);
+        assert_eq!(crawl.hotspot().map(|(max, _)| max), Some(Some(4 << 10)));
+        assert_eq!(report[0].name, "startup");
+    }
+}
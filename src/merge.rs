@@ -0,0 +1,287 @@
+//! Choosing one value when peers return different ones for a record.
+//!
+//! A key several publishers write to can come back from a lookup with a different value
+//! from each peer. With a merge strategy `fleyg get` collects every answer the lookup
+//! gets, counts the values that are equivalent as one, the same bytes or the same json,
+//! and when more than one is left picks a value with the strategy for the key:
+//!
+//! - `last-writer-wins`, the value whose json `field`, by default `timestamp`, is largest
+//! - `highest-sequence`, the value whose json `field`, by default `seq`, is largest
+//! - `script`, a command given each value hex encoded on a line of its stdin, which prints
+//!   the hex of the value to use, one of them or a new one merged from them
+//!
+//! Values without the field lose to values with it, ties go to the value more peers
+//! returned and then to the larger bytes, so every reader picks the same one.
+//!
+//! ```toml
+//! [[merge]]
+//! prefix = "/myapp/status/"
+//! strategy = "last-writer-wins"
+//! field = "updated"
+//!
+//! [[merge]]
+//! prefix = "/myapp/profile/"
+//! strategy = "script"
+//! script = "/usr/local/bin/merge-profiles"
+//! ```
+
+use crate::schedule::split_args;
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Ordering,
+    fmt,
+    io::{self, Write},
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+/// How a value is picked out of the different ones peers returned
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    LastWriterWins,
+    HighestSequence,
+    Script,
+}
+
+impl Strategy {
+    /// the json field the strategy compares unless told otherwise
+    pub fn default_field(&self) -> Option<&'static str> {
+        match self {
+            Strategy::LastWriterWins => Some("timestamp"),
+            Strategy::HighestSequence => Some("seq"),
+            Strategy::Script => None,
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::LastWriterWins => write!(f, "last-writer-wins"),
+            Strategy::HighestSequence => write!(f, "highest-sequence"),
+            Strategy::Script => write!(f, "script"),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last-writer-wins" | "lww" => Ok(Strategy::LastWriterWins),
+            "highest-sequence" => Ok(Strategy::HighestSequence),
+            "script" => Ok(Strategy::Script),
+            _ => Err(format!(
+                "unknown merge strategy {s}, expected last-writer-wins, highest-sequence or script"
+            )),
+        }
+    }
+}
+
+/// A `[[merge]]` config entry, the strategy for the keys under a prefix
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MergeRule {
+    /// the keys it applies to, the longest matching prefix wins
+    #[serde(default)]
+    pub prefix: String,
+    pub strategy: Strategy,
+    /// the json field compared, instead of the strategy's default
+    pub field: Option<String>,
+    /// the command line of the script strategy
+    pub script: Option<String>,
+}
+
+impl MergeRule {
+    /// check the rule has what its strategy needs
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.strategy, &self.script) {
+            (Strategy::Script, None) => Err(format!("merge rule {} needs a script", self.prefix)),
+            (Strategy::Script, Some(script)) => match split_args(script)?.is_empty() {
+                true => Err(format!("merge rule {} has an empty script", self.prefix)),
+                false => Ok(()),
+            },
+            (_, Some(_)) => Err(format!(
+                "merge rule {} has a script but the {} strategy",
+                self.prefix, self.strategy
+            )),
+            (_, None) => Ok(()),
+        }
+    }
+
+    /// the json field compared
+    pub fn field(&self) -> Option<&str> {
+        self.field.as_deref().or(self.strategy.default_field())
+    }
+}
+
+/// the rule for a key, the one with the longest prefix of it
+pub fn rule_for<'a>(rules: &'a [MergeRule], key: &[u8]) -> Option<&'a MergeRule> {
+    rules
+        .iter()
+        .filter(|rule| key.starts_with(rule.prefix.as_bytes()))
+        .max_by_key(|rule| rule.prefix.len())
+}
+
+/// A value peers returned and which peers returned it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Candidate {
+    pub value: Vec<u8>,
+    pub publisher: Option<PeerId>,
+    /// the peers that returned it, none for our own store
+    pub from: Vec<Option<PeerId>>,
+}
+
+/// true if two values mean the same, the same bytes or the same json
+pub fn equivalent(a: &[u8], b: &[u8]) -> bool {
+    if a == b {
+        return true;
+    }
+    match (json(a), json(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// group the answers into the different values, in the order they first came
+pub fn candidates<I>(answers: I) -> Vec<Candidate>
+where
+    I: IntoIterator<Item = (Option<PeerId>, Vec<u8>, Option<PeerId>)>,
+{
+    let mut candidates: Vec<Candidate> = Vec::new();
+    for (from, value, publisher) in answers {
+        match candidates.iter_mut().find(|c| equivalent(&c.value, &value)) {
+            Some(candidate) => candidate.from.push(from),
+            None => candidates.push(Candidate {
+                value,
+                publisher,
+                from: vec![from],
+            }),
+        }
+    }
+    candidates
+}
+
+/// pick the value to use out of the different ones
+pub fn merge(rule: &MergeRule, candidates: &[Candidate]) -> Result<Vec<u8>, String> {
+    if let [only] = candidates {
+        return Ok(only.value.clone());
+    }
+    if rule.strategy == Strategy::Script {
+        let script = rule
+            .script
+            .as_deref()
+            .ok_or("the script strategy needs a script")?;
+        return run_script(script, candidates);
+    }
+    let field = rule.field().unwrap_or_default();
+    candidates
+        .iter()
+        .max_by(|a, b| rank(a, b, field))
+        .map(|c| c.value.clone())
+        .ok_or_else(|| "there are no values to merge".to_string())
+}
+
+// order two candidates by the field, then how many peers returned them, then their bytes
+fn rank(a: &Candidate, b: &Candidate, field: &str) -> Ordering {
+    let a_field = number(&a.value, field);
+    let b_field = number(&b.value, field);
+    let by_field = match (a_field, b_field) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    };
+    by_field
+        .then(a.from.len().cmp(&b.from.len()))
+        .then(a.value.cmp(&b.value))
+}
+
+// a numeric field of a json object value
+fn number(value: &[u8], field: &str) -> Option<f64> {
+    json(value)?.get(field)?.as_f64()
+}
+
+fn json(value: &[u8]) -> Option<serde_json::Value> {
+    serde_json::from_slice(value).ok()
+}
+
+// give the script the values and read back the one to use
+fn run_script(script: &str, candidates: &[Candidate]) -> Result<Vec<u8>, String> {
+    let args = split_args(script)?;
+    let (program, args) = args.split_first().ok_or("the merge script is empty")?;
+    let failed = |e: io::Error| format!("merge script {program}: {e}");
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(failed)?;
+    if let Some(mut stdin) = child.stdin.take() {
+        for candidate in candidates {
+            writeln!(stdin, "{}", hex::encode(&candidate.value)).map_err(failed)?;
+        }
+    }
+    let output = child.wait_with_output().map_err(failed)?;
+    if !output.status.success() {
+        return Err(format!(
+            "merge script {program} failed with {}",
+            output.status
+        ));
+    }
+    let answer = String::from_utf8_lossy(&output.stdout);
+    hex::decode(answer.trim()).map_err(|e| format!("merge script {program} printed bad hex: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn merges() {
+        let a = PeerId::random();
+        let b = PeerId::random();
+        let c = PeerId::random();
+        let old = br#"{"timestamp": 10, "seq": 7, "v": "old"}"#.to_vec();
+        let new = br#"{"timestamp": 20, "seq": 3, "v": "new"}"#.to_vec();
+        // the same json as old, with its fields in another order
+        let same = br#"{"v":"old","seq":7,"timestamp":10}"#.to_vec();
+        let found = candidates(vec![
+            (Some(a), old.clone(), None),
+            (Some(b), new.clone(), None),
+            (Some(c), same, None),
+        ]);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].from, vec![Some(a), Some(c)]);
+
+        let config: Config = toml::from_str(
+            "[[merge]]\nprefix = \"/app/\"\nstrategy = \"last-writer-wins\"\n\
+             [[merge]]\nprefix = \"/app/seq/\"\nstrategy = \"highest-sequence\"\n",
+        )
+        .unwrap();
+        let rules = config.merge.unwrap();
+        let lww = rule_for(&rules, b"/app/status").unwrap();
+        assert_eq!(merge(lww, &found).unwrap(), new);
+        let seq = rule_for(&rules, b"/app/seq/1").unwrap();
+        assert_eq!(merge(seq, &found).unwrap(), old);
+        assert!(rule_for(&rules, b"/other").is_none());
+
+        // without the field more peers win
+        let plain = candidates(vec![
+            (Some(a), b"x".to_vec(), None),
+            (Some(b), b"y".to_vec(), None),
+            (Some(c), b"y".to_vec(), None),
+        ]);
+        assert_eq!(merge(lww, &plain).unwrap(), b"y");
+
+        let script = MergeRule {
+            prefix: String::new(),
+            strategy: Strategy::Script,
+            field: None,
+            script: None,
+        };
+        assert!(script.validate().is_err());
+    }
+}
@@ -0,0 +1,89 @@
+//! Gossipsub control traffic told from a node's meshes, for observing pubsub networks.
+//!
+//! libp2p 0.52 doesn't hand gossipsub's control messages to the program running it, so
+//! GRAFTs and PRUNEs are counted from the peers joining and leaving our mesh of each topic
+//! between samples, whichever side sent them. That's counted per topic and per peer. What
+//! gossipsub counts itself, served with `fleyg sub --metrics-addr`, has the IWANTs asked
+//! for per topic and why peers joined or left meshes, but nothing per peer.
+//!
+//! IHAVEs aren't counted by libp2p 0.52 at all, and IDONTWANT isn't supported by it,
+//! it came with gossipsub v1.2 in later libp2p versions.
+
+use libp2p::PeerId;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+/// The GRAFTs and PRUNEs counted for a topic or a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Churn {
+    /// times a peer joined the mesh
+    pub grafts: u64,
+    /// times a peer left the mesh
+    pub prunes: u64,
+}
+
+/// The last sample of each topic's mesh, and the churn counted since the first
+#[derive(Debug, Default)]
+pub struct MeshStats {
+    meshes: HashMap<String, HashSet<PeerId>>,
+    topics: BTreeMap<String, Churn>,
+    peers: HashMap<PeerId, Churn>,
+}
+
+impl MeshStats {
+    /// compare a topic's mesh with its last sample, the peers that joined were grafted and
+    /// the ones that left pruned
+    pub fn sample<I: IntoIterator<Item = PeerId>>(&mut self, topic: &str, mesh: I) {
+        let mesh: HashSet<PeerId> = mesh.into_iter().collect();
+        let last = self.meshes.entry(topic.to_string()).or_default();
+        let churn = self.topics.entry(topic.to_string()).or_default();
+        for peer in mesh.difference(last) {
+            churn.grafts += 1;
+            self.peers.entry(*peer).or_default().grafts += 1;
+        }
+        for peer in last.difference(&mesh) {
+            churn.prunes += 1;
+            self.peers.entry(*peer).or_default().prunes += 1;
+        }
+        *last = mesh;
+    }
+
+    /// the churn of each topic, by name
+    pub fn topics(&self) -> impl Iterator<Item = (&str, Churn)> {
+        self.topics
+            .iter()
+            .map(|(topic, churn)| (topic.as_str(), *churn))
+    }
+
+    /// the churn of each peer that was ever in a mesh, the busiest first
+    pub fn peers(&self) -> Vec<(PeerId, Churn)> {
+        let mut peers: Vec<_> = self.peers.iter().map(|(p, c)| (*p, *c)).collect();
+        peers.sort_by_key(|(_, churn)| std::cmp::Reverse(churn.grafts + churn.prunes));
+        peers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn churn(grafts: u64, prunes: u64) -> Churn {
+        Churn { grafts, prunes }
+    }
+
+    #[test]
+    fn counts_mesh_changes() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let mut stats = MeshStats::default();
+        stats.sample("blocks", [a, b]);
+        stats.sample("blocks", [b, c]);
+        stats.sample("txs", [a]);
+        stats.sample("txs", [a]);
+
+        let topics: Vec<_> = stats.topics().collect();
+        assert_eq!(topics, [("blocks", churn(3, 1)), ("txs", churn(1, 0))]);
+        let peers = stats.peers();
+        assert_eq!(peers.len(), 3);
+        assert_eq!(peers[0], (a, churn(2, 1)));
+        assert!(peers.contains(&(c, churn(1, 0))));
+    }
+}
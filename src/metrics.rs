@@ -0,0 +1,339 @@
+//! Prometheus metrics for monitoring a long running node.
+//!
+//! With `--metrics-addr` serve counts what its behaviours do with `libp2p-metrics`,
+//! connections, Kademlia queries and their latencies, ping round trips, identify answers
+//! and the circuits a relay server carries. Along with those it counts the identify
+//! answers that diverged from what we observed, how late serve's event loop woke and how
+//! often it stalled, what the `[routing]` policy decided for peers kademlia left out of
+//! its routing table, evictions included, the streams not opened to peers known not to
+//! speak their protocol and the connections the daemon's control calls had to open for
+//! their queries. It answers `GET /metrics` with them in the OpenMetrics text format for
+//! Prometheus to scrape:
+//!
+//! ```text
+//! fleyg --metrics-addr 127.0.0.1:9091
+//! curl http://127.0.0.1:9091/metrics
+//! ```
+//!
+//! `fleyg sub` serves its gossipsub's own metrics the same way: per topic the messages,
+//! the mesh peers and why they joined or left, and the times it decided to send an IWANT.
+//! libp2p 0.52 counts nothing per peer there, and no IHAVEs.
+//!
+//! The endpoint has no authentication, so it only listens on loopback addresses and unix
+//! sockets. Serving metrics needs the `metrics` feature.
+
+use crate::{apilisten::ApiListen, node::FleygBehaviorEvent};
+use libp2p::swarm::SwarmEvent;
+use std::{fmt, io, time::Duration};
+
+#[cfg(feature = "metrics")]
+use crate::runtime;
+#[cfg(feature = "metrics")]
+use futures::prelude::*;
+#[cfg(feature = "metrics")]
+use libp2p::metrics::{Metrics, Recorder};
+#[cfg(feature = "metrics")]
+use log::*;
+#[cfg(feature = "metrics")]
+use prometheus_client::{
+    encoding::text::encode,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Registry, Unit},
+};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+
+/// the path metrics are served on
+#[cfg(feature = "metrics")]
+const PATH: &str = "/metrics";
+
+/// The metrics of a node
+#[cfg(feature = "metrics")]
+pub struct NodeMetrics {
+    metrics: Metrics,
+    /// the routing policy's decisions for routable peers
+    routable: Family<Vec<(String, String)>, Counter>,
+    /// the offers skipped for peers known not to speak the protocol
+    skipped: Family<Vec<(String, String)>, Counter>,
+    /// the identify answers that diverged from what we observed, by what diverged
+    divergences: Family<Vec<(String, String)>, Counter>,
+    /// the outbound connections each control call's query opened
+    query_connections: Histogram,
+    /// the times the event loop stalled, and how late it woke each time
+    stalls: Counter,
+    lag: Histogram,
+    registry: Arc<Registry>,
+}
+
+#[cfg(feature = "metrics")]
+impl NodeMetrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::with_prefix("fleyg");
+        let metrics = Metrics::new(&mut registry);
+        let routable = Family::default();
+        registry.register(
+            "routable_peers",
+            "What the routing policy did with peers kademlia had addresses for but didn't add",
+            routable.clone(),
+        );
+        let skipped = Family::default();
+        registry.register(
+            "skipped_offers",
+            "Protocols not offered to peers whose identify said they don't speak them",
+            skipped.clone(),
+        );
+        let divergences = Family::default();
+        registry.register(
+            "identify_divergence",
+            "Identify answers that didn't match the address we dialed or the protocols we knew",
+            divergences.clone(),
+        );
+        let query_connections = Histogram::new([0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0].into_iter());
+        registry.register(
+            "query_connections_opened",
+            "Outbound connections the queries of control calls opened",
+            query_connections.clone(),
+        );
+        let stalls = Counter::default();
+        registry.register(
+            "event_loop_stalls",
+            "Times serve's event loop woke late or took too long handling a wake",
+            stalls.clone(),
+        );
+        let lag = Histogram::new(exponential_buckets(0.001, 4.0, 7));
+        registry.register_with_unit(
+            "event_loop_lag",
+            "How late serve's event loop woke for its timer",
+            Unit::Seconds,
+            lag.clone(),
+        );
+        Self {
+            metrics,
+            routable,
+            skipped,
+            divergences,
+            query_connections,
+            stalls,
+            lag,
+            registry: Arc::new(registry),
+        }
+    }
+
+    /// the registry gossipsub counts its meshes, messages and IWANTs per topic into, it
+    /// has to be set up before the metrics are served
+    pub fn gossipsub_registry(&mut self) -> &mut Registry {
+        Arc::get_mut(&mut self.registry)
+            .expect("gossipsub is set up before the metrics are served")
+            .sub_registry_with_prefix("gossipsub")
+    }
+
+    /// count a routing policy decision for a routable peer
+    pub fn routable(&self, decision: &str) {
+        let labels = vec![("decision".to_string(), decision.to_string())];
+        self.routable.get_or_create(&labels).inc();
+    }
+
+    /// count a protocol not offered to a peer known not to speak it
+    pub fn skipped_offer(&self, protocol: &str) {
+        let labels = vec![("protocol".to_string(), protocol.to_string())];
+        self.skipped.get_or_create(&labels).inc();
+    }
+
+    /// count an identify answer diverging from what we observed
+    pub fn identify_divergence(&self, kind: &str) {
+        let labels = vec![("kind".to_string(), kind.to_string())];
+        self.divergences.get_or_create(&labels).inc();
+    }
+
+    /// record the connections a control call's query opened
+    pub fn query_connections(&self, opened: u32) {
+        self.query_connections.observe(opened.into());
+    }
+
+    /// record how late the event loop woke
+    pub fn woke(&self, lag: Duration) {
+        self.lag.observe(lag.as_secs_f64());
+    }
+
+    /// count an event loop stall
+    pub fn stalled(&self) {
+        self.stalls.inc();
+    }
+
+    /// count a swarm event
+    pub fn record<E: fmt::Debug>(&self, event: &SwarmEvent<FleygBehaviorEvent, E>) {
+        match event {
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Kademlia(e)) => self.metrics.record(e),
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Identify(e)) => self.metrics.record(e),
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Ping(e)) => self.metrics.record(e),
+            #[cfg(feature = "pubsub")]
+            SwarmEvent::Behaviour(FleygBehaviorEvent::Gossipsub(e)) => self.metrics.record(e),
+            SwarmEvent::Behaviour(FleygBehaviorEvent::RelayServer(e)) => self.metrics.record(e),
+            _ => {}
+        }
+        self.metrics.record(event);
+    }
+
+    /// answer scrapes on the given address until the process exits
+    pub fn serve(&self, listen: &ApiListen) -> io::Result<()> {
+        listen
+            .check("metrics")
+            .map_err(|e| io::Error::new(io::ErrorKind::PermissionDenied, e))?;
+        let registry = self.registry.clone();
+        match listen {
+            ApiListen::Tcp(addr) => {
+                let incoming = runtime::incoming(std::net::TcpListener::bind(addr)?)?;
+                runtime::spawn(accept(incoming, registry));
+            }
+            #[cfg(unix)]
+            ApiListen::Unix(path) => {
+                let incoming = runtime::incoming_unix(crate::apilisten::unix::bind(path)?)?;
+                runtime::spawn(accept(incoming, registry));
+            }
+            #[cfg(not(unix))]
+            ApiListen::Unix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unix sockets aren't supported here",
+                ))
+            }
+            ApiListen::Pipe(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "metrics can't be scraped over a named pipe, listen on a loopback address",
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Default for NodeMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl fmt::Debug for NodeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeMetrics").finish_non_exhaustive()
+    }
+}
+
+// answer each connection on its own task
+#[cfg(feature = "metrics")]
+async fn accept<I, S>(mut incoming: I, registry: Arc<Registry>)
+where
+    I: Stream<Item = io::Result<S>> + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    while let Some(stream) = incoming.next().await {
+        match stream {
+            Ok(stream) => {
+                let registry = registry.clone();
+                runtime::spawn(async move {
+                    if let Err(e) = respond(stream, &registry).await {
+                        debug!("Metrics scrape failed: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("Can't accept a metrics scrape: {e}"),
+        }
+    }
+}
+
+// read a request's head and answer it
+#[cfg(feature = "metrics")]
+async fn respond<S>(mut stream: S, registry: &Registry) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 8 * 1024 {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let response = if wants_metrics(&String::from_utf8_lossy(&head)) {
+        let mut body = String::new();
+        encode(&mut body, registry).map_err(io::Error::other)?;
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/openmetrics-text; version=1.0.0; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.close().await
+}
+
+/// Without the metrics feature nothing is counted
+#[cfg(not(feature = "metrics"))]
+#[derive(Debug, Default)]
+pub struct NodeMetrics;
+
+#[cfg(not(feature = "metrics"))]
+impl NodeMetrics {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn record<E: fmt::Debug>(&self, _event: &SwarmEvent<FleygBehaviorEvent, E>) {}
+
+    pub fn routable(&self, _decision: &str) {}
+
+    pub fn skipped_offer(&self, _protocol: &str) {}
+
+    pub fn identify_divergence(&self, _kind: &str) {}
+
+    pub fn query_connections(&self, _opened: u32) {}
+
+    pub fn woke(&self, _lag: Duration) {}
+
+    pub fn stalled(&self) {}
+
+    pub fn serve(&self, _listen: &ApiListen) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "fleyg was built without the metrics feature",
+        ))
+    }
+}
+
+// true if an http request head asks for the metrics
+#[cfg(feature = "metrics")]
+fn wants_metrics(head: &str) -> bool {
+    let mut request = head.lines().next().unwrap_or_default().split_whitespace();
+    let (Some(method), Some(target)) = (request.next(), request.next()) else {
+        return false;
+    };
+    let path = target.split('?').next().unwrap_or_default();
+    method == "GET" && (path == PATH || path == "/")
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scrape_requests() {
+        assert!(wants_metrics(
+            "GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n"
+        ));
+        assert!(wants_metrics("GET /metrics?name[]=up HTTP/1.1\r\n\r\n"));
+        assert!(wants_metrics("GET / HTTP/1.0\r\n\r\n"));
+        assert!(!wants_metrics("POST /metrics HTTP/1.1\r\n\r\n"));
+        assert!(!wants_metrics("GET /favicon.ico HTTP/1.1\r\n\r\n"));
+        assert!(!wants_metrics(""));
+    }
+}
@@ -0,0 +1,149 @@
+//! Mirroring records from one DHT network onto another.
+//!
+//! `fleyg mirror` joins two networks, the configured one as the source and the one
+//! named in the `[mirror]` section as the target. It looks up the watched keys on the
+//! source every interval and stores each value it finds on the target, under a key
+//! rewritten by the first rule whose prefix matches:
+//!
+//! ```toml
+//! [mirror]
+//! to = "ipfs"
+//! keys = ["/acme/status", "/acme/peers"]
+//! interval = "10m"
+//!
+//! [[mirror.rewrite]]
+//! from = "/acme/"
+//! to = "/acme-public/"
+//! ```
+//!
+//! A value is only stored again when it changes or the last copy is getting old, records
+//! expire from the DHT if nobody republishes them. The target copy is published by the
+//! mirroring node, records whose validity depends on their key or publisher, like IPNS,
+//! don't survive being rewritten.
+
+use crate::timespec::parse_duration;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// The `[mirror]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MirrorSettings {
+    /// the network preset to mirror onto
+    pub to: String,
+    /// bootstrap peers to use instead of the target network's
+    pub bootnodes: Option<Vec<Multiaddr>>,
+    /// the record keys to watch on the source network
+    pub keys: Vec<String>,
+    /// how keys are renamed on the target network, the first matching rule applies
+    pub rewrite: Vec<Rewrite>,
+    /// how often the keys are looked up, e.g. "10m"
+    pub interval: String,
+    /// store an unchanged value again after this long, e.g. "12h"
+    pub republish: String,
+}
+
+impl Default for MirrorSettings {
+    fn default() -> Self {
+        Self {
+            to: "ipfs".into(),
+            bootnodes: None,
+            keys: Vec::new(),
+            rewrite: Vec::new(),
+            interval: "10m".into(),
+            republish: "12h".into(),
+        }
+    }
+}
+
+/// A key namespace rewrite, replacing the `from` prefix with `to`
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rewrite {
+    pub from: String,
+    pub to: String,
+}
+
+impl MirrorSettings {
+    /// the key a source key is stored under on the target network
+    pub fn target_key(&self, key: &[u8]) -> Vec<u8> {
+        for rule in &self.rewrite {
+            if let Some(rest) = key.strip_prefix(rule.from.as_bytes()) {
+                return [rule.to.as_bytes(), rest].concat();
+            }
+        }
+        key.to_vec()
+    }
+
+    /// the lookup interval
+    pub fn interval(&self) -> Result<Duration, String> {
+        parse_duration(&self.interval).map_err(|e| format!("mirror.interval: {e}"))
+    }
+
+    /// how long an unchanged copy lasts before it's stored again
+    pub fn republish(&self) -> Result<Duration, String> {
+        parse_duration(&self.republish).map_err(|e| format!("mirror.republish: {e}"))
+    }
+}
+
+/// Tracks what was last stored on the target network for each key
+#[derive(Debug)]
+pub struct Mirror {
+    republish: Duration,
+    stored: HashMap<Vec<u8>, ([u8; 32], Instant)>,
+}
+
+impl Mirror {
+    /// storing unchanged values again after `republish`
+    pub fn new(republish: Duration) -> Self {
+        Self {
+            republish,
+            stored: HashMap::new(),
+        }
+    }
+
+    /// true if the value found for the key has to be stored on the target
+    pub fn needs_store(&self, key: &[u8], value: &[u8], now: Instant) -> bool {
+        match self.stored.get(key) {
+            Some((hash, at)) => *hash != digest(value) || now.duration_since(*at) >= self.republish,
+            None => true,
+        }
+    }
+
+    /// the value was stored on the target
+    pub fn stored(&mut self, key: &[u8], value: &[u8], now: Instant) {
+        self.stored.insert(key.to_vec(), (digest(value), now));
+    }
+}
+
+fn digest(value: &[u8]) -> [u8; 32] {
+    Sha256::digest(value).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_and_tracks() {
+        let settings: MirrorSettings = toml::from_str(
+            "keys = [\"/acme/a\"]\n[[rewrite]]\nfrom = \"/acme/\"\nto = \"/pub/\"\n",
+        )
+        .unwrap();
+        assert_eq!(settings.to, "ipfs");
+        assert_eq!(settings.target_key(b"/acme/a"), b"/pub/a");
+        assert_eq!(settings.target_key(b"/other/a"), b"/other/a");
+
+        let now = Instant::now();
+        let mut mirror = Mirror::new(Duration::from_secs(60));
+        assert!(mirror.needs_store(b"k", b"v1", now));
+        mirror.stored(b"k", b"v1", now);
+        assert!(!mirror.needs_store(b"k", b"v1", now + Duration::from_secs(30)));
+        assert!(mirror.needs_store(b"k", b"v2", now + Duration::from_secs(30)));
+        assert!(mirror.needs_store(b"k", b"v1", now + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,179 @@
+//! The keys of an application namespace and how available their providers are.
+//!
+//! `fleyg providers scan` looks up the providers of every key an application keeps under
+//! a prefix. The names under the prefix come from a file, one per line, or from an
+//! expression expanding numeric ranges and alternatives in braces:
+//!
+//! ```text
+//! fleyg providers scan --prefix /myapp/ --generate 'shard-{000..127}'
+//! fleyg providers scan --prefix /myapp/ --generate '{users,posts}/{0..9}'
+//! ```
+//!
+//! Each key is the prefix followed by the name, as bytes.
+
+use libp2p::PeerId;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::Path,
+};
+
+/// the most names an expression may expand to
+pub const MAX_NAMES: usize = 100_000;
+
+/// the names an expression expands to, in order
+pub fn generate(expr: &str) -> Result<Vec<String>, String> {
+    let mut names = vec![String::new()];
+    let mut rest = expr;
+    while !rest.is_empty() {
+        let Some(open) = rest.find(['{', '}']) else {
+            names.iter_mut().for_each(|n| n.push_str(rest));
+            break;
+        };
+        if rest[open..].starts_with('}') {
+            return Err(format!("unmatched }} in {expr}"));
+        }
+        let close = rest[open..]
+            .find('}')
+            .map(|c| open + c)
+            .ok_or_else(|| format!("unmatched {{ in {expr}"))?;
+        let literal = &rest[..open];
+        let choices = alternatives(&rest[open + 1..close])?;
+        if names.len().saturating_mul(choices.len()) > MAX_NAMES {
+            return Err(format!("{expr} expands to more than {MAX_NAMES} names"));
+        }
+        names = names
+            .iter()
+            .flat_map(|n| choices.iter().map(move |c| format!("{n}{literal}{c}")))
+            .collect();
+        rest = &rest[close + 1..];
+    }
+    Ok(names)
+}
+
+// what a brace group stands for: a range like 0..9 or 000..127, or a comma separated list
+fn alternatives(group: &str) -> Result<Vec<String>, String> {
+    if group.contains('{') {
+        return Err(format!("nested braces in {{{group}}}"));
+    }
+    let Some((start, end)) = group.split_once("..") else {
+        return Ok(group.split(',').map(str::to_string).collect());
+    };
+    let (from, to) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(from), Ok(to)) if from <= to => (from, to),
+        _ => return Err(format!("bad range {{{group}}}")),
+    };
+    if to - from >= MAX_NAMES as u64 {
+        return Err(format!(
+            "{{{group}}} expands to more than {MAX_NAMES} names"
+        ));
+    }
+    // a leading zero pads every number to the start's width
+    let width = match start.len() > 1 && start.starts_with('0') {
+        true => start.len(),
+        false => 0,
+    };
+    Ok((from..=to).map(|n| format!("{n:0width$}")).collect())
+}
+
+/// the names in a file, one per line, skipping blank lines and # comments
+pub fn read_names<P: AsRef<Path>>(path: P) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// the key of a name in the namespace
+pub fn key(prefix: &str, name: &str) -> Vec<u8> {
+    format!("{prefix}{name}").into_bytes()
+}
+
+/// The providers found for each key of a namespace
+#[derive(Clone, Debug, Default)]
+pub struct ScanReport {
+    keys: BTreeMap<String, BTreeSet<PeerId>>,
+}
+
+impl ScanReport {
+    /// a report of the names, none of them with providers yet
+    pub fn new<I: IntoIterator<Item = String>>(names: I) -> Self {
+        Self {
+            keys: names.into_iter().map(|n| (n, BTreeSet::new())).collect(),
+        }
+    }
+
+    /// a provider was found for the name's key
+    pub fn found(&mut self, name: &str, peer: PeerId) {
+        if let Some(providers) = self.keys.get_mut(name) {
+            providers.insert(peer);
+        }
+    }
+
+    /// each name with the providers found for it
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &BTreeSet<PeerId>)> {
+        self.keys.iter().map(|(n, p)| (n.as_str(), p))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// the number of keys with at least one provider
+    pub fn available(&self) -> usize {
+        self.keys.values().filter(|p| !p.is_empty()).count()
+    }
+
+    /// each provider with the number of keys it provides, most first
+    pub fn providers(&self) -> Vec<(PeerId, usize)> {
+        let mut counts = BTreeMap::new();
+        for peer in self.keys.values().flatten() {
+            *counts.entry(*peer).or_insert(0) += 1;
+        }
+        let mut providers: Vec<_> = counts.into_iter().collect();
+        providers.sort_by(|a, b| b.1.cmp(&a.1));
+        providers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expressions() {
+        assert_eq!(generate("plain").unwrap(), vec!["plain"]);
+        assert_eq!(
+            generate("shard-{08..10}").unwrap(),
+            vec!["shard-08", "shard-09", "shard-10"]
+        );
+        assert_eq!(
+            generate("{a,b}/{1..2}.json").unwrap(),
+            vec!["a/1.json", "a/2.json", "b/1.json", "b/2.json"]
+        );
+        assert!(generate("{1..").is_err());
+        assert!(generate("x}").is_err());
+        assert!(generate("{9..1}").is_err());
+        assert!(generate("{0..999}{0..999}").is_err());
+        assert_eq!(key("/app/", "a/1"), b"/app/a/1".to_vec());
+    }
+
+    #[test]
+    fn report() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let mut report = ScanReport::new(generate("k{1..3}").unwrap());
+        report.found("k1", a);
+        report.found("k2", a);
+        report.found("k2", b);
+        report.found("unknown", b);
+        assert_eq!(report.len(), 3);
+        assert_eq!(report.available(), 2);
+        assert_eq!(report.providers(), vec![(a, 2), (b, 1)]);
+    }
+}
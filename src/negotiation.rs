@@ -0,0 +1,127 @@
+//! The protocols each peer supports, so streams aren't opened for ones it doesn't.
+//!
+//! Every protocol offered on a stream the peer rejects costs a multistream-select round
+//! trip for nothing, and during crawls and bulk queries that's a round trip to every peer
+//! that doesn't speak our kademlia. The cache keeps the protocols each peer last
+//! identified with, seeded from the peer store so they're known before the first identify
+//! of a run. Every identify replaces them, including the pushes a peer sends when its
+//! protocols change, so a peer that starts speaking a protocol is offered it again. A peer
+//! whose protocols aren't known is offered everything.
+
+use crate::peerstore::PeerStore;
+use libp2p::{identify, PeerId};
+use std::collections::{HashMap, HashSet};
+
+/// The protocols of the peers we know them for and the offers skipped
+#[derive(Clone, Debug, Default)]
+pub struct NegotiationCache {
+    peers: HashMap<PeerId, HashSet<String>>,
+    skipped: u64,
+}
+
+impl NegotiationCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// start with the protocols the peer store last saw each peer with
+    pub fn seed(peers: &PeerStore) -> Self {
+        let peers = peers
+            .iter()
+            .filter(|(_, record)| !record.protocols.is_empty())
+            .map(|(peer, record)| (*peer, record.protocols.iter().cloned().collect()))
+            .collect();
+        Self { peers, skipped: 0 }
+    }
+
+    /// a peer identified, or pushed an update, replacing what we knew, true if its
+    /// protocols changed
+    pub fn identified(&mut self, peer: PeerId, info: &identify::Info) -> bool {
+        let protocols: HashSet<String> = info.protocols.iter().map(|p| p.to_string()).collect();
+        self.peers.insert(peer, protocols.clone()) != Some(protocols)
+    }
+
+    /// a protocol the peer rejected, though its identify listed it
+    pub fn rejected(&mut self, peer: &PeerId, protocol: &str) {
+        if let Some(protocols) = self.peers.get_mut(peer) {
+            protocols.remove(protocol);
+        }
+    }
+
+    /// forget a peer, it's offered everything until it identifies again
+    pub fn forget(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// true or false if we know whether the peer speaks the protocol
+    pub fn supports(&self, peer: &PeerId, protocol: &str) -> Option<bool> {
+        self.peers.get(peer).map(|p| p.contains(protocol))
+    }
+
+    /// true unless the peer is known to speak none of the protocols, counting the offers
+    /// skipped
+    pub fn worth_offering<P: AsRef<str>>(&mut self, peer: &PeerId, protocols: &[P]) -> bool {
+        let worth = match self.peers.get(peer) {
+            Some(known) => protocols.iter().any(|p| known.contains(p.as_ref())),
+            None => true,
+        };
+        if !worth {
+            self.skipped += 1;
+        }
+        worth
+    }
+
+    /// how many offers were skipped
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// the peers whose protocols are known
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{identity::Keypair, StreamProtocol};
+
+    fn info(protocols: &[&'static str]) -> identify::Info {
+        identify::Info {
+            public_key: Keypair::generate_ed25519().public(),
+            protocol_version: "ipfs/0.1.0".into(),
+            agent_version: "test".into(),
+            listen_addrs: Vec::new(),
+            protocols: protocols.iter().map(|p| StreamProtocol::new(p)).collect(),
+            observed_addr: "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn skips_unsupported() {
+        let kad = ["/ipfs/kad/1.0.0"];
+        let peer = PeerId::random();
+        let mut cache = NegotiationCache::new();
+        assert_eq!(cache.supports(&peer, kad[0]), None);
+        assert!(cache.worth_offering(&peer, &kad));
+
+        assert!(cache.identified(peer, &info(&["/ipfs/ping/1.0.0"])));
+        assert!(!cache.worth_offering(&peer, &kad));
+        assert_eq!(cache.skipped(), 1);
+
+        // a push adding kademlia makes it worth offering again
+        assert!(!cache.identified(peer, &info(&["/ipfs/ping/1.0.0"])));
+        assert!(cache.identified(peer, &info(&["/ipfs/ping/1.0.0", kad[0]])));
+        assert!(cache.worth_offering(&peer, &kad));
+
+        cache.rejected(&peer, kad[0]);
+        assert_eq!(cache.supports(&peer, kad[0]), Some(false));
+        cache.forget(&peer);
+        assert!(cache.worth_offering(&peer, &kad));
+    }
+}
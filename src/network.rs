@@ -0,0 +1,248 @@
+//! Network presets: the bootstrap peers and protocol names of known DHT networks.
+
+use crate::{
+    chainspec::ChainSpec,
+    transport::{NoiseSettings, TransportSettings},
+};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+
+/// the names of the built in presets
+pub const PRESETS: &[&str] = &["ipfs", "kusama", "lan", "none", "polkadot"];
+
+/// the identify protocol version substrate nodes send
+const SUBSTRATE_IDENTIFY: &str = "/substrate/1.0";
+
+/// the name of the LAN DHT fleyg lan forms unless given another
+pub const DEFAULT_LAN: &str = "default";
+
+/// the genesis hashes of the built in substrate chains
+const POLKADOT_GENESIS: &str = "91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3";
+const KUSAMA_GENESIS: &str = "b0a8d493285c2df73290dfb7e61f870f17b41801197a149ca93654499ea3dafe";
+
+/// the public IPFS bootstrap peers
+const IPFS_BOOTNODES: [&str; 4] = [
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTbq5TbXJJ16u19uLTa",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9KiKNN6tpvbUcqanj75Nb",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1ZjYZcYW3dwt",
+];
+
+/// A DHT network fleyg can join
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Network {
+    /// the name of the network
+    pub name: String,
+    /// the bootstrap peers, each address ends with /p2p/<peer id>
+    pub bootnodes: Vec<Multiaddr>,
+    /// the kademlia protocol names in order of preference, empty for the libp2p default
+    pub kad_protocols: Vec<String>,
+    /// the protocol version sent in identify
+    pub identify_protocol: String,
+    /// the noise handshake settings
+    pub noise: NoiseSettings,
+    /// the transports we connect with
+    pub transports: TransportSettings,
+}
+
+impl Network {
+    /// look up a built in preset by name
+    pub fn preset(name: &str) -> Result<Self, String> {
+        match name {
+            "ipfs" => Ok(Self::ipfs()),
+            "kusama" => Self::substrate("kusama", KUSAMA_GENESIS, Some("ksmcc3"), None),
+            "lan" => Self::lan(DEFAULT_LAN),
+            "none" => Ok(Self::none()),
+            "polkadot" => Self::substrate("polkadot", POLKADOT_GENESIS, Some("dot"), None),
+            _ => Err(format!(
+                "unknown network preset: {name} (known: {})",
+                PRESETS.join(", ")
+            )),
+        }
+    }
+
+    /// the public IPFS DHT
+    pub fn ipfs() -> Self {
+        Self {
+            name: "ipfs".into(),
+            bootnodes: IPFS_BOOTNODES
+                .iter()
+                .map(|a| a.parse().expect("valid bootnode address"))
+                .collect(),
+            kad_protocols: Vec::new(),
+            identify_protocol: "ipfs/0.1.0".into(),
+            noise: NoiseSettings::default(),
+            transports: TransportSettings::default(),
+        }
+    }
+
+    /// no bootstrap peers, for building private networks
+    pub fn none() -> Self {
+        Self {
+            name: "none".into(),
+            bootnodes: Vec::new(),
+            kad_protocols: Vec::new(),
+            identify_protocol: "ipfs/0.1.0".into(),
+            noise: NoiseSettings::default(),
+            transports: TransportSettings::default(),
+        }
+    }
+
+    /// a DHT of the machines on a local network, found with mDNS. Its kademlia protocol is
+    /// named after the LAN so it never mixes with a public DHT or another LAN's
+    pub fn lan(name: &str) -> Result<Self, String> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            return Err(format!(
+                "bad LAN name {name}, use letters, digits, - _ and ."
+            ));
+        }
+        Ok(Self {
+            name: format!("lan-{name}"),
+            bootnodes: Vec::new(),
+            kad_protocols: vec![format!("/fleyg/lan/{name}/kad/1.0.0")],
+            identify_protocol: "fleyg-lan/0.1.0".into(),
+            noise: NoiseSettings::default(),
+            transports: TransportSettings::default(),
+        })
+    }
+
+    /// a substrate chain's discovery DHT, its kademlia protocol is named after the
+    /// genesis hash with the legacy protocol id name as a fallback
+    pub fn substrate(
+        name: &str,
+        genesis_hash: &str,
+        protocol_id: Option<&str>,
+        fork_id: Option<&str>,
+    ) -> Result<Self, String> {
+        let genesis = genesis_hash.trim_start_matches("0x").to_lowercase();
+        if hex::decode(&genesis).map(|h| h.len()) != Ok(32) {
+            return Err(format!("invalid genesis hash: {genesis_hash}"));
+        }
+        let mut kad_protocols = vec![match fork_id {
+            Some(fork) => format!("/{genesis}/{fork}/kad"),
+            None => format!("/{genesis}/kad"),
+        }];
+        if let Some(id) = protocol_id {
+            kad_protocols.push(format!("/{id}/kad"));
+        }
+        Ok(Self {
+            name: name.into(),
+            bootnodes: Vec::new(),
+            kad_protocols,
+            identify_protocol: SUBSTRATE_IDENTIFY.into(),
+            noise: NoiseSettings::default(),
+            transports: TransportSettings::default(),
+        })
+    }
+
+    /// a substrate chain from its chain spec and genesis hash
+    pub fn from_chainspec(spec: &ChainSpec, genesis_hash: &str) -> Result<Self, String> {
+        let mut network = Self::substrate(
+            &spec.id,
+            genesis_hash,
+            spec.protocol_id.as_deref(),
+            spec.fork_id.as_deref(),
+        )?;
+        network.bootnodes = spec.bootnodes()?;
+        Ok(network)
+    }
+
+    /// join the chain in a chain spec, built in chains know their genesis hash and only
+    /// take the spec's bootnodes, any other chain needs the genesis hash given
+    pub fn with_chainspec(
+        self,
+        spec: &ChainSpec,
+        genesis_hash: Option<&str>,
+    ) -> Result<Self, String> {
+        match genesis_hash {
+            Some(hash) => Self::from_chainspec(spec, hash),
+            None if self.identify_protocol == SUBSTRATE_IDENTIFY => Ok(Self {
+                bootnodes: spec.bootnodes()?,
+                ..self
+            }),
+            None => Err(format!("a genesis hash is needed to join {}", spec.id)),
+        }
+    }
+
+    /// the bootstrap peers split into peer id and address
+    pub fn bootnode_peers(&self) -> Vec<(PeerId, Multiaddr)> {
+        self.bootnodes.iter().filter_map(split_p2p).collect()
+    }
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self::ipfs()
+    }
+}
+
+/// split a /p2p/<peer id> terminated address into the peer id and the address without it
+pub fn split_p2p(addr: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut addr = addr.clone();
+    match addr.pop()? {
+        Protocol::P2p(peer) => Some((peer, addr)),
+        _ => None,
+    }
+}
+
+/// parse a bootstrap peer's address, it has to end with the peer's /p2p/<peer id>
+pub fn parse_bootnode(s: &str) -> Result<Multiaddr, String> {
+    let addr: Multiaddr = s.parse().map_err(|e| format!("bad address {s}: {e}"))?;
+    match split_p2p(&addr) {
+        Some(_) => Ok(addr),
+        None => Err(format!("{s} doesn't end with /p2p/<peer id>")),
+    }
+}
+
+/// parse a peer to dial, given by its peer id or an address that may end with the peer's
+/// /p2p/<peer id>
+pub fn parse_target(s: &str) -> Result<(Option<PeerId>, Option<Multiaddr>), String> {
+    if let Ok(peer) = s.parse::<PeerId>() {
+        return Ok((Some(peer), None));
+    }
+    let addr: Multiaddr = s
+        .parse()
+        .map_err(|_| format!("{s} isn't a peer id or an address"))?;
+    Ok((split_p2p(&addr).map(|(peer, _)| peer), Some(addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipfs_bootnodes() {
+        let peers = Network::ipfs().bootnode_peers();
+        assert_eq!(peers.len(), 4);
+        assert_eq!(
+            peers[0].1,
+            "/dnsaddr/bootstrap.libp2p.io".parse::<Multiaddr>().unwrap()
+        );
+        assert!(Network::preset("bogus").is_err());
+
+        let polkadot = Network::preset("polkadot").unwrap();
+        assert_eq!(
+            polkadot.kad_protocols,
+            vec![format!("/{POLKADOT_GENESIS}/kad"), "/dot/kad".to_string()]
+        );
+        assert!(Network::substrate("bad", "0x1234", None, None).is_err());
+        let lan = Network::preset("lan").unwrap();
+        assert!(lan.bootnodes.is_empty());
+        assert_eq!(lan.kad_protocols, vec!["/fleyg/lan/default/kad/1.0.0"]);
+        assert!(Network::lan("a/b").is_err());
+
+        assert!(parse_bootnode(IPFS_BOOTNODES[0]).is_ok());
+        assert!(parse_bootnode("/ip4/127.0.0.1/tcp/4920").is_err());
+
+        let (peer, _) = split_p2p(&IPFS_BOOTNODES[0].parse().unwrap()).unwrap();
+        assert_eq!(parse_target(&peer.to_string()), Ok((Some(peer), None)));
+        let (found, addr) = parse_target(IPFS_BOOTNODES[0]).unwrap();
+        assert_eq!((found, addr.is_some()), (Some(peer), true));
+        let (found, addr) = parse_target("/ip4/127.0.0.1/tcp/4920").unwrap();
+        assert_eq!((found, addr.is_some()), (None, true));
+        assert!(parse_target("nonsense").is_err());
+    }
+}
@@ -0,0 +1,407 @@
+//! Building a fleyg node: its transport, behaviours and swarm.
+//!
+//! [`FleygNode`] puts together what every fleyg peer runs, identify, kademlia, ping, a
+//! relay client and, if enabled, AutoNAT, a relay server, peer exchange, the block
+//! exchange, gossipsub and mDNS, over the transport stack in [`crate::transport`].
+//! Gossipsub is only there when fleyg is built with the `pubsub` feature.
+//! Everything but the identity has a default, a node for the IPFS DHT with fleyg's agent
+//! string:
+//!
+//! ```text
+//! let swarm = FleygNode::new(key).network(network).agent("mytool/0.1").build().await?;
+//! ```
+
+use crate::{
+    blocks,
+    capture::Capture,
+    dialrace::DialSettings,
+    identifyfilter::{FilteredIdentify, IdentifySettings, Unlisted},
+    infra::RelayServerSettings,
+    metrics::NodeMetrics,
+    network::Network,
+    pex::{self, PexSettings},
+    querybudget::QuerySettings,
+    routingpolicy::{Inserts, RoutingPolicy},
+    runtime,
+    store::{FleygStore, StoreSettings},
+    transport,
+};
+#[cfg(feature = "pubsub")]
+use libp2p::gossipsub::{self, MessageAuthenticity};
+use libp2p::{
+    autonat, identify,
+    identity::Keypair,
+    kad::{Kademlia, KademliaBucketInserts, KademliaConfig, KademliaStoreInserts},
+    mdns, ping, relay,
+    swarm::{behaviour::toggle::Toggle, NetworkBehaviour},
+    PeerId, StreamProtocol, Swarm,
+};
+use log::*;
+use std::{io, num::NonZeroUsize, sync::Arc, time::Duration};
+
+/// the agent version fleyg identifies with
+pub const AGENT: &str = "fleyg/0.0.1";
+
+/// the gossipsub behaviour, a dummy that's never enabled without the pubsub feature
+#[cfg(feature = "pubsub")]
+pub type Gossipsub = gossipsub::Behaviour;
+#[cfg(not(feature = "pubsub"))]
+pub type Gossipsub = libp2p::swarm::dummy::Behaviour;
+
+/// The behaviours of a fleyg node, each but mDNS refusing streams for the protocols the
+/// `[identify]` section hides
+#[derive(NetworkBehaviour)]
+pub struct FleygBehavior {
+    pub identify: Unlisted<FilteredIdentify>,
+    pub kademlia: Unlisted<Kademlia<FleygStore>>,
+    pub ping: Unlisted<ping::Behaviour>,
+    pub relay_client: Unlisted<relay::client::Behaviour>,
+    pub pex: Unlisted<Toggle<pex::Behaviour>>,
+    pub blocks: Unlisted<Toggle<blocks::Behaviour>>,
+    pub gossipsub: Unlisted<Toggle<Gossipsub>>,
+    pub mdns: Toggle<runtime::Mdns>,
+    pub autonat: Unlisted<Toggle<autonat::Behaviour>>,
+    pub relay_server: Unlisted<Toggle<relay::Behaviour>>,
+}
+
+/// A builder for a fleyg node's swarm
+pub struct FleygNode {
+    key: Keypair,
+    network: Network,
+    agent: String,
+    capture: Option<Arc<Capture>>,
+    queries: QuerySettings,
+    identify: IdentifySettings,
+    dial: DialSettings,
+    store: StoreSettings,
+    pex: PexSettings,
+    routing: RoutingPolicy,
+    ping_interval: Option<Duration>,
+    blocks: bool,
+    gossipsub: bool,
+    mdns: bool,
+    autonat: bool,
+    relay_server: RelayServerSettings,
+}
+
+impl FleygNode {
+    /// a node with the given identity
+    pub fn new(key: Keypair) -> Self {
+        Self {
+            key,
+            network: Network::default(),
+            agent: AGENT.to_string(),
+            capture: None,
+            queries: QuerySettings::default(),
+            identify: IdentifySettings::default(),
+            dial: DialSettings::default(),
+            store: StoreSettings::default(),
+            pex: PexSettings::default(),
+            routing: RoutingPolicy::default(),
+            ping_interval: None,
+            blocks: false,
+            gossipsub: false,
+            mdns: false,
+            autonat: false,
+            relay_server: RelayServerSettings::default(),
+        }
+    }
+
+    /// the network to join, the IPFS DHT if not set
+    pub fn network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// the agent version to identify with
+    pub fn agent<S: Into<String>>(mut self, agent: S) -> Self {
+        self.agent = agent.into();
+        self
+    }
+
+    /// record every stream into the capture
+    pub fn capture(mut self, capture: Option<Arc<Capture>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// the query parallelism settings
+    pub fn queries(mut self, queries: QuerySettings) -> Self {
+        self.queries = queries;
+        self
+    }
+
+    /// what our identify answers leave out or mask
+    pub fn identify_settings(mut self, settings: IdentifySettings) -> Self {
+        self.identify = settings;
+        self
+    }
+
+    /// how many of a peer's addresses are dialed at once
+    pub fn dial_settings(mut self, settings: DialSettings) -> Self {
+        self.dial = settings;
+        self
+    }
+
+    /// where the kademlia records are kept, in memory if not set
+    pub fn store_settings(mut self, settings: StoreSettings) -> Self {
+        self.store = settings;
+        self
+    }
+
+    /// whether to answer and send peer exchange requests, off if not set
+    pub fn pex_settings(mut self, settings: PexSettings) -> Self {
+        self.pex = settings;
+        self
+    }
+
+    /// which peers kademlia adds to the routing table by itself
+    pub fn routing_policy(mut self, policy: RoutingPolicy) -> Self {
+        self.routing = policy;
+        self
+    }
+
+    /// how often to ping each connection, libp2p's 15 seconds if not set
+    pub fn ping_interval(mut self, interval: Option<Duration>) -> Self {
+        self.ping_interval = interval;
+        self
+    }
+
+    /// whether to request and answer requests for blocks, off if not set
+    pub fn blocks(mut self, enabled: bool) -> Self {
+        self.blocks = enabled;
+        self
+    }
+
+    /// whether to run gossipsub, off if not set
+    pub fn gossipsub(mut self, enabled: bool) -> Self {
+        self.gossipsub = enabled;
+        self
+    }
+
+    /// whether to find peers on the local network with mDNS, off if not set
+    pub fn mdns(mut self, enabled: bool) -> Self {
+        self.mdns = enabled;
+        self
+    }
+
+    /// whether to probe if our listen addresses are reachable with AutoNAT, off if not set
+    pub fn autonat(mut self, enabled: bool) -> Self {
+        self.autonat = enabled;
+        self
+    }
+
+    /// whether to relay circuits for other peers and with what limits, off if not set
+    pub fn relay_server(mut self, settings: RelayServerSettings) -> Self {
+        self.relay_server = settings;
+        self
+    }
+
+    /// build the swarm, it isn't listening or dialing anything yet
+    pub async fn build(self) -> io::Result<Swarm<FleygBehavior>> {
+        self.build_with_metrics(None).await
+    }
+
+    /// build the swarm with gossipsub counting what it does into the metrics, before
+    /// they're served
+    pub async fn build_with_metrics(
+        self,
+        metrics: Option<&mut NodeMetrics>,
+    ) -> io::Result<Swarm<FleygBehavior>> {
+        let local_peer_id = PeerId::from(self.key.public());
+        info!("Local peer id: {}", local_peer_id);
+
+        // set up the transport, able to dial through relays
+        let (relay_transport, relay_client) = relay::client::new(local_peer_id);
+        let transport = transport::build(
+            &self.key,
+            &self.network.noise,
+            &self.network.transports,
+            self.capture,
+            Some(relay_transport),
+        )
+        .await?;
+
+        let mut network = self.network;
+        if let Some(version) = &self.identify.protocol_version {
+            network.identify_protocol = version.clone();
+        }
+        let agent = self.identify.agent.as_deref().unwrap_or(&self.agent);
+        let store = FleygStore::open(local_peer_id, &self.store)?;
+        let hidden = self.identify.hidden_protocols();
+        let identify = FilteredIdentify::new(identify(&self.key, &network, agent), &self.identify);
+        let kademlia = kademlia(local_peer_id, &network, &self.queries, &self.routing, store)?;
+        let ping = ping::Behaviour::new(match self.ping_interval {
+            Some(interval) => ping::Config::new().with_interval(interval),
+            None => ping::Config::new(),
+        });
+        let relay_server = self
+            .relay_server
+            .enabled
+            .then(|| relay::Behaviour::new(local_peer_id, self.relay_server.config()))
+            .into();
+        let behavior = FleygBehavior {
+            identify: Unlisted::new(identify, &hidden),
+            kademlia: Unlisted::new(kademlia, &hidden),
+            ping: Unlisted::new(ping, &hidden),
+            relay_client: Unlisted::new(relay_client, &hidden),
+            pex: Unlisted::new(pex::behaviour(&self.pex), &hidden),
+            blocks: Unlisted::new(blocks::behaviour(self.blocks), &hidden),
+            gossipsub: Unlisted::new(gossipsub(&self.key, self.gossipsub, metrics)?, &hidden),
+            mdns: mdns(local_peer_id, self.mdns)?,
+            autonat: Unlisted::new(autonat(local_peer_id, self.autonat), &hidden),
+            relay_server: Unlisted::new(relay_server, &hidden),
+        };
+        let swarm = runtime::swarm(transport, behavior, local_peer_id)
+            .dial_concurrency_factor(self.dial.concurrency())
+            .build();
+        Ok(swarm)
+    }
+}
+
+/// the identify behavior for the network
+pub fn identify(key: &Keypair, network: &Network, agent: &str) -> identify::Behaviour {
+    let cfg = identify::Config::new(network.identify_protocol.clone(), key.public())
+        .with_agent_version(agent.to_string());
+    identify::Behaviour::new(cfg)
+}
+
+/// the gossipsub behavior signing what we publish, counting what it does into the metrics
+/// if given, switched off unless enabled
+#[cfg(feature = "pubsub")]
+pub fn gossipsub(
+    key: &Keypair,
+    enabled: bool,
+    metrics: Option<&mut NodeMetrics>,
+) -> io::Result<Toggle<Gossipsub>> {
+    if !enabled {
+        return Ok(None.into());
+    }
+    let privacy = MessageAuthenticity::Signed(key.clone());
+    let config = gossipsub::Config::default();
+    #[cfg(feature = "metrics")]
+    let behavior = match metrics {
+        Some(metrics) => gossipsub::Behaviour::new_with_metrics(
+            privacy,
+            config,
+            metrics.gossipsub_registry(),
+            gossipsub::MetricsConfig::default(),
+        ),
+        None => gossipsub::Behaviour::new(privacy, config),
+    };
+    #[cfg(not(feature = "metrics"))]
+    let behavior = {
+        let _ = metrics;
+        gossipsub::Behaviour::new(privacy, config)
+    };
+    let behavior = behavior.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok(Some(behavior).into())
+}
+
+#[cfg(not(feature = "pubsub"))]
+pub fn gossipsub(
+    _key: &Keypair,
+    enabled: bool,
+    _metrics: Option<&mut NodeMetrics>,
+) -> io::Result<Toggle<Gossipsub>> {
+    match enabled {
+        true => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "fleyg was built without the pubsub feature",
+        )),
+        false => Ok(None.into()),
+    }
+}
+
+/// the mDNS behavior announcing us on the local network, switched off unless enabled
+pub fn mdns(local_peer_id: PeerId, enabled: bool) -> io::Result<Toggle<runtime::Mdns>> {
+    if !enabled {
+        return Ok(None.into());
+    }
+    let behavior = runtime::Mdns::new(mdns::Config::default(), local_peer_id)?;
+    Ok(Some(behavior).into())
+}
+
+/// the AutoNAT behavior asking the peers we're connected to to dial us back, switched off
+/// unless enabled
+pub fn autonat(local_peer_id: PeerId, enabled: bool) -> Toggle<autonat::Behaviour> {
+    enabled
+        .then(|| autonat::Behaviour::new(local_peer_id, autonat::Config::default()))
+        .into()
+}
+
+/// the kademlia behavior for the network, knowing its bootstrap peers
+pub fn kademlia(
+    local_peer_id: PeerId,
+    network: &Network,
+    queries: &QuerySettings,
+    routing: &RoutingPolicy,
+    store: FleygStore,
+) -> io::Result<Kademlia<FleygStore>> {
+    let mut cfg = KademliaConfig::default();
+    cfg.set_query_timeout(Duration::from_secs(5 * 60));
+    if let Some(parallelism) = queries.parallelism.and_then(NonZeroUsize::new) {
+        cfg.set_parallelism(parallelism);
+    }
+    cfg.set_record_filtering(KademliaStoreInserts::FilterBoth);
+    if routing.inserts == Inserts::Manual {
+        cfg.set_kbucket_inserts(KademliaBucketInserts::Manual);
+    }
+    if !network.kad_protocols.is_empty() {
+        let names = network
+            .kad_protocols
+            .iter()
+            .map(|p| StreamProtocol::try_from_owned(p.clone()))
+            .collect::<Result<_, _>>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        cfg.set_protocol_names(names);
+    }
+    let mut behavior = Kademlia::with_config(local_peer_id, store, cfg);
+    for (peer, addr) in network.bootnode_peers() {
+        behavior.add_address(&peer, addr);
+    }
+    for protocol in behavior.protocol_names() {
+        info!("Kademlia protocol: {protocol}");
+    }
+    Ok(behavior)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_default_and_toggled_behaviours() {
+        runtime::block_on(async {
+            let swarm = FleygNode::new(Keypair::generate_ed25519())
+                .build()
+                .await
+                .unwrap();
+            let behaviour = swarm.behaviour();
+            assert!(!behaviour.blocks.is_enabled());
+            assert!(!behaviour.gossipsub.is_enabled());
+            assert!(!behaviour.mdns.is_enabled());
+            assert!(!behaviour.autonat.is_enabled());
+            assert!(!behaviour.relay_server.is_enabled());
+
+            let swarm = FleygNode::new(Keypair::generate_ed25519())
+                .agent("test/0.1")
+                .blocks(true)
+                .gossipsub(cfg!(feature = "pubsub"))
+                .autonat(true)
+                .relay_server(RelayServerSettings {
+                    enabled: true,
+                    ..Default::default()
+                })
+                .build()
+                .await
+                .unwrap();
+            let behaviour = swarm.behaviour();
+            assert!(behaviour.blocks.is_enabled());
+            assert_eq!(behaviour.gossipsub.is_enabled(), cfg!(feature = "pubsub"));
+            assert!(!behaviour.mdns.is_enabled());
+            assert!(behaviour.autonat.is_enabled());
+            assert!(behaviour.relay_server.is_enabled());
+        });
+    }
+}
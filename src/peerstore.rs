@@ -0,0 +1,289 @@
+//! A simple persistent store of what we have learned about remote peers.
+
+use crate::relays::RelayHistory;
+use libp2p::{identify, multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// how often a dirty store is flushed to disk
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// the most addresses of a peer whose dials are remembered
+const MAX_DIAL_HISTORY: usize = 32;
+
+/// the confidence in an address never dialed
+pub const UNKNOWN_CONFIDENCE: f64 = 0.5;
+
+/// Everything we remember about a single peer
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct PeerRecord {
+    /// the listen addresses the peer last reported
+    pub addrs: Vec<Multiaddr>,
+    /// the agent version the peer last reported
+    pub agent: Option<String>,
+    /// the protocols the peer last reported
+    pub protocols: Vec<String>,
+    /// unix time in seconds we last heard from the peer
+    pub last_seen: u64,
+    /// addresses for this peer that authenticated as some other peer
+    #[serde(default)]
+    pub wrong_ids: Vec<WrongPeerId>,
+    /// the last measured ping round trip time
+    #[serde(default)]
+    pub rtt: Option<Duration>,
+    /// how circuits through the peer went, if we used it as a relay
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relayed: Option<RelayHistory>,
+    /// how dials to each of the peer's addresses went
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dials: Vec<AddrHistory>,
+}
+
+/// How dials to one of a peer's addresses have gone
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddrHistory {
+    /// the address, without the peer's id
+    pub addr: Multiaddr,
+    pub successes: u32,
+    pub failures: u32,
+    /// unix time in seconds of the last dial
+    pub last_dialed: u64,
+    /// whether the last dial connected
+    pub last_ok: bool,
+}
+
+impl AddrHistory {
+    /// how likely a dial to the address is to connect, from 0 to 1: the share of dials
+    /// that connected, with one of each assumed, halved if the last one didn't
+    pub fn confidence(&self) -> f64 {
+        let rate = f64::from(self.successes + 1) / f64::from(self.successes + self.failures + 2);
+        if self.last_ok {
+            rate
+        } else {
+            rate / 2.0
+        }
+    }
+}
+
+/// An address published for a peer that turned out to belong to a different peer
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WrongPeerId {
+    /// the address that was dialed
+    pub addr: Multiaddr,
+    /// the peer id the address actually authenticated as
+    pub obtained: PeerId,
+    /// unix time in seconds the mismatch was seen
+    pub seen: u64,
+}
+
+impl PeerRecord {
+    /// update the record from an identify exchange
+    pub fn update(&mut self, info: &identify::Info) {
+        self.addrs = info.listen_addrs.clone();
+        self.agent = Some(info.agent_version.clone());
+        self.protocols = info.protocols.iter().map(|p| p.to_string()).collect();
+        self.last_seen = now_secs();
+    }
+
+    /// count a dial to one of the peer's addresses, forgetting the address dialed least
+    /// recently if too many are remembered
+    pub fn record_dial(&mut self, addr: &Multiaddr, success: bool) {
+        let addr = without_peer(addr);
+        let now = now_secs();
+        let i = match self.dials.iter().position(|h| h.addr == addr) {
+            Some(i) => i,
+            None => {
+                if self.dials.len() >= MAX_DIAL_HISTORY {
+                    let oldest = (0..self.dials.len()).min_by_key(|&i| self.dials[i].last_dialed);
+                    if let Some(oldest) = oldest {
+                        self.dials.swap_remove(oldest);
+                    }
+                }
+                self.dials.push(AddrHistory {
+                    addr,
+                    successes: 0,
+                    failures: 0,
+                    last_dialed: now,
+                    last_ok: false,
+                });
+                self.dials.len() - 1
+            }
+        };
+        let history = &mut self.dials[i];
+        if success {
+            history.successes += 1;
+        } else {
+            history.failures += 1;
+        }
+        history.last_dialed = now;
+        history.last_ok = success;
+    }
+
+    /// how likely dialing the address is to connect, see [`AddrHistory::confidence`]
+    pub fn confidence(&self, addr: &Multiaddr) -> f64 {
+        let addr = without_peer(addr);
+        self.dials
+            .iter()
+            .find(|h| h.addr == addr)
+            .map_or(UNKNOWN_CONFIDENCE, AddrHistory::confidence)
+    }
+}
+
+// the address without a trailing /p2p/<peer id>, the swarm adds it to what it dials
+fn without_peer(addr: &Multiaddr) -> Multiaddr {
+    let mut addr = addr.clone();
+    if let Some(Protocol::P2p(_)) = addr.iter().last() {
+        addr.pop();
+    }
+    addr
+}
+
+/// A map of peer records optionally backed by a json file
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    path: Option<PathBuf>,
+    peers: HashMap<PeerId, PeerRecord>,
+    dirty: bool,
+    last_save: Option<Instant>,
+}
+
+impl PeerStore {
+    /// a store that is never written to disk
+    pub fn memory() -> Self {
+        Self::default()
+    }
+
+    /// open the store at the given path, starting empty if the file doesn't exist
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let peers = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            path: Some(path),
+            peers,
+            ..Default::default()
+        })
+    }
+
+    /// get the record for a peer
+    pub fn get(&self, peer: &PeerId) -> Option<&PeerRecord> {
+        self.peers.get(peer)
+    }
+
+    /// get the record for a peer, creating an empty one if needed
+    pub fn entry(&mut self, peer: PeerId) -> &mut PeerRecord {
+        self.dirty = true;
+        self.peers.entry(peer).or_default()
+    }
+
+    /// remember that dialing `expected` at `addr` reached `obtained` instead
+    pub fn record_wrong_peer_id(&mut self, expected: PeerId, addr: Multiaddr, obtained: PeerId) {
+        let record = self.entry(expected);
+        record
+            .wrong_ids
+            .retain(|w| !(w.addr == addr && w.obtained == obtained));
+        record.wrong_ids.push(WrongPeerId {
+            addr,
+            obtained,
+            seen: now_secs(),
+        });
+    }
+
+    /// count a dial to one of a peer's addresses
+    pub fn record_dial(&mut self, peer: PeerId, addr: &Multiaddr, success: bool) {
+        self.entry(peer).record_dial(addr, success);
+    }
+
+    /// how likely dialing a peer's address is to connect
+    pub fn confidence(&self, peer: &PeerId, addr: &Multiaddr) -> f64 {
+        self.get(peer)
+            .map_or(UNKNOWN_CONFIDENCE, |record| record.confidence(addr))
+    }
+
+    /// the number of peers in the store
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// true if the store is empty
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// iterate over all of the peers in the store
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &PeerRecord)> {
+        self.peers.iter()
+    }
+
+    /// write the store to disk if it has a path
+    pub fn save(&mut self) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("tmp");
+            fs::write(&tmp, serde_json::to_vec_pretty(&self.peers)?)?;
+            fs::rename(&tmp, path)?;
+        }
+        self.dirty = false;
+        self.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    /// write the store to disk if it has changed and hasn't been saved recently
+    pub fn maybe_save(&mut self) -> io::Result<()> {
+        let due = self
+            .last_save
+            .map(|t| t.elapsed() >= SAVE_INTERVAL)
+            .unwrap_or(true);
+        if self.dirty && due {
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// the current unix time in seconds
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dial_confidence() {
+        let peer = PeerId::random();
+        let good: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let stale: Multiaddr = "/ip4/5.6.7.8/tcp/4001".parse().unwrap();
+        let mut peers = PeerStore::memory();
+        assert_eq!(peers.confidence(&peer, &good), UNKNOWN_CONFIDENCE);
+
+        // the swarm reports dialed addresses with the peer id on the end
+        let dialed = good.clone().with(Protocol::P2p(peer));
+        peers.record_dial(peer, &dialed, true);
+        peers.record_dial(peer, &good, true);
+        peers.record_dial(peer, &stale, true);
+        peers.record_dial(peer, &stale, false);
+        let record = peers.get(&peer).unwrap();
+        assert_eq!(record.dials.len(), 2);
+        assert_eq!(peers.confidence(&peer, &good), 0.75);
+        assert_eq!(peers.confidence(&peer, &stale), 0.25);
+
+        let mut record = PeerRecord::default();
+        for port in 0..=MAX_DIAL_HISTORY as u16 {
+            let addr: Multiaddr = format!("/ip4/1.2.3.4/tcp/{port}").parse().unwrap();
+            record.record_dial(&addr, false);
+        }
+        assert_eq!(record.dials.len(), MAX_DIAL_HISTORY);
+    }
+}
@@ -0,0 +1,150 @@
+//! The peers a lightweight crawl has found and which of them it has visited.
+//!
+//! `fleyg-crawl` finds peers with random walks of the DHT and dials every one it hasn't
+//! seen, so the peers it connects to fill the routing table the next walks start from.
+//! A [`PeerWalk`] keeps each peer once however often the walks turn it up, gathering its
+//! addresses until it's dialed.
+
+use libp2p::{Multiaddr, PeerId};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// Where a peer is in the crawl
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Visit {
+    Queued,
+    /// dialed at the given time and not identified yet
+    Dialing(Instant),
+    Done,
+}
+
+/// What the crawl knows about one peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Found {
+    /// the addresses the walks turned up, without duplicates
+    pub addrs: Vec<Multiaddr>,
+    pub visit: Visit,
+}
+
+/// Every peer a crawl has found, in the order they're to be dialed
+#[derive(Clone, Debug, Default)]
+pub struct PeerWalk {
+    peers: HashMap<PeerId, Found>,
+    queue: VecDeque<PeerId>,
+    dialing: usize,
+}
+
+impl PeerWalk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// every peer found so far
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// peers waiting to be dialed
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// peers dialed and not finished
+    pub fn dialing(&self) -> usize {
+        self.dialing
+    }
+
+    /// a peer and some of its addresses, returns true if the peer is new
+    pub fn discovered(&mut self, peer: PeerId, addrs: Vec<Multiaddr>) -> bool {
+        let new = !self.peers.contains_key(&peer);
+        let found = self.peers.entry(peer).or_insert(Found {
+            addrs: Vec::new(),
+            visit: Visit::Queued,
+        });
+        for addr in addrs {
+            if !found.addrs.contains(&addr) {
+                found.addrs.push(addr);
+            }
+        }
+        if new {
+            self.queue.push_back(peer);
+        }
+        new
+    }
+
+    /// the next peer to dial with the addresses found for it, marking it dialing
+    pub fn next(&mut self, now: Instant) -> Option<(PeerId, Vec<Multiaddr>)> {
+        let peer = self.queue.pop_front()?;
+        let found = self.peers.get_mut(&peer)?;
+        found.visit = Visit::Dialing(now);
+        self.dialing += 1;
+        Some((peer, found.addrs.clone()))
+    }
+
+    /// finish a peer that was being dialed, returns the addresses it was dialed on, or
+    /// None if it wasn't being dialed
+    pub fn finish(&mut self, peer: &PeerId) -> Option<Vec<Multiaddr>> {
+        let found = self.peers.get_mut(peer)?;
+        if !matches!(found.visit, Visit::Dialing(_)) {
+            return None;
+        }
+        found.visit = Visit::Done;
+        self.dialing -= 1;
+        Some(found.addrs.clone())
+    }
+
+    /// the peers dialed longer than the timeout ago
+    pub fn late(&self, now: Instant, timeout: Duration) -> Vec<PeerId> {
+        self.peers
+            .iter()
+            .filter(|(_, found)| match found.visit {
+                Visit::Dialing(at) => now.saturating_duration_since(at) > timeout,
+                _ => false,
+            })
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// the peers never finished, still queued or being dialed
+    pub fn unfinished(&self) -> impl Iterator<Item = (&PeerId, &Found)> {
+        self.peers.iter().filter(|(_, f)| f.visit != Visit::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_peer_once() {
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let other: Multiaddr = "/ip4/1.2.3.4/udp/4001/quic-v1".parse().unwrap();
+        let mut walk = PeerWalk::new();
+        assert!(walk.discovered(a, vec![addr.clone()]));
+        assert!(walk.discovered(b, Vec::new()));
+        assert!(!walk.discovered(a, vec![addr.clone(), other.clone()]));
+        assert_eq!((walk.len(), walk.queued()), (2, 2));
+
+        let now = Instant::now();
+        assert_eq!(walk.next(now), Some((a, vec![addr.clone(), other])));
+        assert_eq!(walk.dialing(), 1);
+        // found again while it's being dialed, it isn't queued a second time
+        assert!(!walk.discovered(a, Vec::new()));
+        assert_eq!(walk.queued(), 1);
+        assert_eq!(
+            walk.late(now + Duration::from_secs(20), Duration::from_secs(10)),
+            vec![a]
+        );
+        assert!(walk.finish(&a).is_some());
+        assert!(walk.finish(&a).is_none());
+        assert_eq!(walk.dialing(), 0);
+        assert_eq!(walk.unfinished().count(), 1);
+    }
+}
@@ -0,0 +1,213 @@
+//! Peer exchange between fleyg nodes.
+//!
+//! A private deployment without a rendezvous server bootstraps slowly, a new node only
+//! knows its bootstrap peers and has to find the rest through the DHT. Nodes that turn
+//! on peer exchange answer requests on `/fleyg/pex/1.0.0` with a sample of their peer
+//! stores, the peers they've seen most recently. A serving node with a small peer store
+//! asks every peer whose identify lists the protocol, so only nodes that opted in are
+//! asked, and queues dials to the peers they share.
+//!
+//! ```toml
+//! [pex]
+//! enabled = true
+//! share = 32
+//! want = 64
+//! ```
+
+use crate::peerstore::PeerStore;
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    request_response::{self, ProtocolSupport},
+    swarm::behaviour::toggle::Toggle,
+    Multiaddr, PeerId, StreamProtocol,
+};
+use serde::{Deserialize, Serialize};
+use std::{io, iter};
+
+/// the peer exchange protocol
+pub const PROTOCOL: StreamProtocol = StreamProtocol::new("/fleyg/pex/1.0.0");
+
+/// messages larger than this are refused
+const MAX_MESSAGE: u64 = 1024 * 1024;
+
+/// The `[pex]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PexSettings {
+    /// answer and send peer exchange requests
+    pub enabled: bool,
+    /// the most peers to share in an answer
+    pub share: usize,
+    /// keep asking peers while the peer store holds fewer than this
+    pub want: usize,
+}
+
+impl Default for PexSettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            share: 32,
+            want: 64,
+        }
+    }
+}
+
+/// A request for peers
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexRequest {
+    /// the most peers the asker wants
+    pub max: usize,
+}
+
+/// A peer and where to reach it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexPeer {
+    pub peer: PeerId,
+    pub addrs: Vec<Multiaddr>,
+}
+
+/// The answer to a request
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PexResponse {
+    pub peers: Vec<PexPeer>,
+}
+
+/// The peer exchange behaviour
+pub type Behaviour = request_response::Behaviour<PexCodec>;
+
+/// the behaviour, switched off unless peer exchange is enabled
+pub fn behaviour(settings: &PexSettings) -> Toggle<Behaviour> {
+    let behaviour = settings.enabled.then(|| {
+        Behaviour::new(
+            PexCodec,
+            iter::once((PROTOCOL, ProtocolSupport::Full)),
+            request_response::Config::default(),
+        )
+    });
+    behaviour.into()
+}
+
+/// up to `max` of the most recently seen peers with addresses, leaving out the asker
+pub fn sample(peers: &PeerStore, max: usize, asker: &PeerId) -> PexResponse {
+    let mut known: Vec<_> = peers
+        .iter()
+        .filter(|(peer, record)| *peer != asker && !record.addrs.is_empty())
+        .collect();
+    known.sort_by_key(|(_, record)| std::cmp::Reverse(record.last_seen));
+    PexResponse {
+        peers: known
+            .into_iter()
+            .take(max)
+            .map(|(peer, record)| PexPeer {
+                peer: *peer,
+                addrs: record.addrs.clone(),
+            })
+            .collect(),
+    }
+}
+
+/// Peer exchange messages as json, one per stream
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PexCodec;
+
+#[async_trait]
+impl request_response::Codec for PexCodec {
+    type Protocol = StreamProtocol;
+    type Request = PexRequest;
+    type Response = PexResponse;
+
+    async fn read_request<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PexRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &StreamProtocol, io: &mut T) -> io::Result<PexResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_json(io).await
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        request: PexRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &request).await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &StreamProtocol,
+        io: &mut T,
+        response: PexResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_json(io, &response).await
+    }
+}
+
+// read a message up to the end of the stream
+async fn read_json<T, M>(io: &mut T) -> io::Result<M>
+where
+    T: AsyncRead + Unpin + Send,
+    M: for<'de> Deserialize<'de>,
+{
+    let mut bytes = Vec::new();
+    io.take(MAX_MESSAGE).read_to_end(&mut bytes).await?;
+    Ok(serde_json::from_slice(&bytes)?)
+}
+
+// write a message and close our side of the stream
+async fn write_json<T, M>(io: &mut T, message: &M) -> io::Result<()>
+where
+    T: AsyncWrite + Unpin + Send,
+    M: Serialize,
+{
+    io.write_all(&serde_json::to_vec(message)?).await?;
+    io.close().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor::block_on, io::Cursor};
+
+    #[test]
+    fn shares_recent_peers() {
+        let mut peers = PeerStore::memory();
+        let (asker, old, new, unreachable) = (
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+            PeerId::random(),
+        );
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/4920".parse().unwrap();
+        for (peer, seen) in [(asker, 30), (old, 10), (new, 20)] {
+            let record = peers.entry(peer);
+            record.addrs = vec![addr.clone()];
+            record.last_seen = seen;
+        }
+        peers.entry(unreachable).last_seen = 40;
+
+        let response = sample(&peers, 10, &asker);
+        let shared: Vec<_> = response.peers.iter().map(|p| p.peer).collect();
+        assert_eq!(shared, vec![new, old]);
+        assert_eq!(sample(&peers, 1, &asker).peers.len(), 1);
+
+        let mut stream = Cursor::new(Vec::new());
+        block_on(write_json(&mut stream, &response)).unwrap();
+        stream.set_position(0);
+        let read: PexResponse = block_on(read_json(&mut stream)).unwrap();
+        assert_eq!(read, response);
+    }
+}
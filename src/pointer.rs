@@ -0,0 +1,288 @@
+//! Values too large for a DHT record, kept as blocks behind a small signed pointer.
+//!
+//! Kademlia peers refuse messages over 16 KiB, so a record's value has to fit in a little
+//! less. `fleyg put --large <file>` splits a larger value into 256 KiB chunks and lists
+//! their ids in a manifest, a two level content DAG of [`crate::blocks`] whose root is the
+//! manifest. Only a pointer goes in the DHT, a json value naming the root with the value's
+//! size and sha256, signed by the publisher along with the record key so it can't be moved
+//! to another key:
+//!
+//! ```json
+//! {"fleyg_pointer": 1, "root": "4f1c...", "size": 10485760, "sha256": "9a0e...",
+//!  "publisher": "12D3KooW...", "public_key": "0801...", "signature": "be4d..."}
+//! ```
+//!
+//! The publisher keeps the blocks and provides the root in the DHT while it serves, and
+//! `fleyg get --follow` fetches them from the providers, checking every block against its
+//! id and the value against the pointer.
+
+use crate::blocks::{self, BlockId, MAX_BLOCK};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// the largest value a record holds itself, what fits in a kademlia message with the key
+pub const MAX_INLINE: usize = 14 * 1024;
+
+/// the size values are split into
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// the pointer format version
+const VERSION: u32 = 1;
+
+/// the prefix signed along with the pointer so the signature can't be reused
+const DOMAIN: &[u8] = b"fleyg-pointer:";
+
+/// The root block, the value's chunks in order
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub size: u64,
+    #[serde(with = "hex_ids")]
+    pub chunks: Vec<BlockId>,
+}
+
+impl Manifest {
+    /// split a value into its manifest block and chunks
+    pub fn split(value: &[u8]) -> Result<(Vec<u8>, Vec<Vec<u8>>), String> {
+        let chunks: Vec<Vec<u8>> = value.chunks(CHUNK_SIZE).map(<[u8]>::to_vec).collect();
+        let manifest = Manifest {
+            size: value.len() as u64,
+            chunks: chunks.iter().map(|c| blocks::id(c)).collect(),
+        };
+        let block = serde_json::to_vec(&manifest).expect("manifests always serialize");
+        if block.len() > MAX_BLOCK {
+            return Err(format!(
+                "a {} byte value has too many chunks for one manifest",
+                value.len()
+            ));
+        }
+        Ok((block, chunks))
+    }
+
+    /// read a manifest block
+    pub fn parse(block: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(block).map_err(|e| format!("bad manifest: {e}"))
+    }
+
+    /// put the chunks back together into the value
+    pub fn assemble(&self, chunks: Vec<Vec<u8>>) -> Result<Vec<u8>, String> {
+        if chunks.len() != self.chunks.len() {
+            return Err(format!(
+                "{} chunks for a manifest of {}",
+                chunks.len(),
+                self.chunks.len()
+            ));
+        }
+        let value: Vec<u8> = chunks.concat();
+        match value.len() as u64 == self.size {
+            true => Ok(value),
+            false => Err(format!(
+                "the chunks make {} bytes, not {}",
+                value.len(),
+                self.size
+            )),
+        }
+    }
+}
+
+// the signed part of a pointer
+#[derive(Serialize)]
+struct Signed<'a> {
+    #[serde(with = "hex::serde")]
+    key: &'a [u8],
+    #[serde(with = "hex::serde")]
+    root: &'a BlockId,
+    size: u64,
+    #[serde(with = "hex::serde")]
+    sha256: &'a [u8],
+    publisher: &'a PeerId,
+}
+
+/// A record value pointing at a value kept as blocks
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pointer {
+    fleyg_pointer: u32,
+    /// the manifest's block id
+    #[serde(with = "hex::serde")]
+    pub root: BlockId,
+    pub size: u64,
+    #[serde(with = "hex::serde")]
+    pub sha256: Vec<u8>,
+    pub publisher: PeerId,
+    /// the publisher's public key in protobuf format
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub signature: Vec<u8>,
+}
+
+impl Pointer {
+    /// sign a pointer to the value under the record key with our key
+    pub fn sign(key: &Keypair, record_key: &[u8], root: BlockId, value: &[u8]) -> Self {
+        let mut pointer = Self {
+            fleyg_pointer: VERSION,
+            root,
+            size: value.len() as u64,
+            sha256: Sha256::digest(value).to_vec(),
+            publisher: key.public().to_peer_id(),
+            public_key: key.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        pointer.signature = key
+            .sign(&pointer.message(record_key))
+            .expect("signing with our own key works");
+        pointer
+    }
+
+    /// the pointer in a record value, none if the value isn't one
+    pub fn from_value(value: &[u8]) -> Option<Self> {
+        serde_json::from_slice::<Self>(value)
+            .ok()
+            .filter(|p| p.fleyg_pointer == VERSION)
+    }
+
+    /// the record value holding the pointer
+    pub fn to_value(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("pointers always serialize")
+    }
+
+    /// check the publisher signed the pointer for this record key
+    pub fn verify(&self, record_key: &[u8]) -> Result<(), String> {
+        let public = PublicKey::try_decode_protobuf(&self.public_key).map_err(|e| e.to_string())?;
+        if public.to_peer_id() != self.publisher {
+            return Err(format!("pointer key doesn't belong to {}", self.publisher));
+        }
+        if !public.verify(&self.message(record_key), &self.signature) {
+            return Err("pointer signature is invalid".to_string());
+        }
+        Ok(())
+    }
+
+    /// check a fetched value is the one pointed at
+    pub fn check(&self, value: &[u8]) -> Result<(), String> {
+        if value.len() as u64 != self.size || Sha256::digest(value).as_slice() != self.sha256 {
+            return Err("the value doesn't match the pointer".to_string());
+        }
+        Ok(())
+    }
+
+    fn message(&self, record_key: &[u8]) -> Vec<u8> {
+        let signed = Signed {
+            key: record_key,
+            root: &self.root,
+            size: self.size,
+            sha256: &self.sha256,
+            publisher: &self.publisher,
+        };
+        let json = serde_json::to_vec(&signed).expect("pointers always serialize");
+        [DOMAIN, &json].concat()
+    }
+}
+
+// block ids as a list of hex strings
+mod hex_ids {
+    use crate::blocks::{parse_id, BlockId};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(ids: &[BlockId], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(ids.iter().map(hex::encode))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<BlockId>, D::Error> {
+        Vec::<String>::deserialize(d)?
+            .iter()
+            .map(|s| parse_id(s).map_err(D::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockCodec, BlockStore, PROTOCOL};
+    use futures::{executor::block_on, io::Cursor};
+    use libp2p::request_response::Codec;
+    use std::fs;
+
+    // a block sent over the block exchange and checked against its id, as get --follow does
+    fn fetch(id: &BlockId, sent: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+        let mut wire = Cursor::new(Vec::new());
+        block_on(BlockCodec.write_response(&PROTOCOL, &mut wire, sent)).unwrap();
+        wire.set_position(0);
+        let block = block_on(BlockCodec.read_response(&PROTOCOL, &mut wire)).unwrap();
+        match block {
+            Some(block) if blocks::id(&block) == *id => Ok(block),
+            Some(_) => Err("a bad block".into()),
+            None => Err("no block".into()),
+        }
+    }
+
+    #[test]
+    fn points_at_chunked_values() {
+        let value: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let (manifest_block, chunks) = Manifest::split(&value).unwrap();
+        assert_eq!(chunks.len(), 3);
+        let manifest = Manifest::parse(&manifest_block).unwrap();
+        assert_eq!(manifest.chunks[2], blocks::id(&chunks[2]));
+        assert_eq!(manifest.assemble(chunks.clone()).unwrap(), value);
+        assert!(manifest.assemble(chunks[..2].to_vec()).is_err());
+
+        let key = Keypair::generate_ed25519();
+        let pointer = Pointer::sign(&key, b"/big", blocks::id(&manifest_block), &value);
+        let stored = pointer.to_value();
+        assert!(stored.len() < MAX_INLINE);
+        let read = Pointer::from_value(&stored).unwrap();
+        assert_eq!(read, pointer);
+        assert!(read.verify(b"/big").is_ok());
+        assert!(read.verify(b"/elsewhere").is_err());
+        assert!(read.check(&value).is_ok());
+        assert!(read.check(b"other").is_err());
+        assert!(Pointer::from_value(br#"{"plain": "json"}"#).is_none());
+    }
+
+    #[test]
+    fn fetches_and_reassembles_blocks() {
+        let dir = std::env::temp_dir().join(format!("fleyg-pointer-{}", std::process::id()));
+        let store = BlockStore::open(&dir).unwrap();
+        let value: Vec<u8> = (0..CHUNK_SIZE + 100).map(|i| (i * 7) as u8).collect();
+        let (manifest_block, chunks) = Manifest::split(&value).unwrap();
+        let root = store.put(&manifest_block).unwrap();
+        for chunk in &chunks {
+            store.put(chunk).unwrap();
+        }
+        let key = Keypair::generate_ed25519();
+        let pointer = Pointer::sign(&key, b"/big", root, &value);
+
+        let served = |id: &BlockId| fetch(id, store.get(id).unwrap());
+        let manifest = Manifest::parse(&served(&pointer.root).unwrap()).unwrap();
+        let fetched: Vec<_> = manifest
+            .chunks
+            .iter()
+            .map(|id| served(id).unwrap())
+            .collect();
+        let assembled = manifest.assemble(fetched).unwrap();
+        assert!(pointer.check(&assembled).is_ok());
+        assert_eq!(assembled, value);
+
+        // a block changed by the sender doesn't match its id
+        let mut tampered = chunks[1].clone();
+        tampered[0] ^= 1;
+        let id = manifest.chunks[1];
+        assert_eq!(
+            fetch(&id, Some(tampered.clone())),
+            Err("a bad block".into())
+        );
+        // nor is one changed on disk served
+        fs::write(dir.join(hex::encode(id)), &tampered).unwrap();
+        assert_eq!(served(&id), Err("no block".into()));
+        // and chunks that were never checked don't make the value the pointer signed
+        let forged = manifest
+            .assemble(vec![chunks[0].clone(), tampered])
+            .unwrap();
+        assert!(pointer.check(&forged).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
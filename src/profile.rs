@@ -0,0 +1,63 @@
+//! Named profiles, each with its own identity, config, peer store and data directory.
+//!
+//! A profile's config lives in `~/.config/fleyg/profiles/<name>/config.toml` and its
+//! state in the XDG data directory (see [`crate::state`]).
+
+use crate::state::{project_dirs, StateDir};
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// the profile's config file
+const CONFIG_FILE: &str = "config.toml";
+
+/// The path of the config file used when no profile is selected
+pub fn default_config_path() -> io::Result<PathBuf> {
+    Ok(project_dirs()?.config_dir().join(CONFIG_FILE))
+}
+
+/// A named profile on disk
+#[derive(Clone, Debug)]
+pub struct Profile {
+    name: String,
+    dir: PathBuf,
+}
+
+impl Profile {
+    /// open the named profile, creating its config directory if needed
+    pub fn open(name: &str) -> io::Result<Self> {
+        if name.is_empty() || name.contains(|c: char| c == '/' || c == '\\' || c == '.') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid profile name: {name:?}"),
+            ));
+        }
+        let dir = project_dirs()?.config_dir().join("profiles").join(name);
+        fs::create_dir_all(&dir)?;
+        Ok(Self {
+            name: name.to_string(),
+            dir,
+        })
+    }
+
+    /// the name of the profile
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// the profile's config directory
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// the path of the profile's config file
+    pub fn config_path(&self) -> PathBuf {
+        self.dir.join(CONFIG_FILE)
+    }
+
+    /// the default location of the profile's state directory
+    pub fn state_root(&self) -> io::Result<PathBuf> {
+        StateDir::default_root(Some(&self.name))
+    }
+}
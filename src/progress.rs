@@ -0,0 +1,89 @@
+//! Progress display for long running operations.
+//!
+//! Progress is only drawn when stdout is a terminal, so piped or scripted output stays
+//! clean, and can be switched off for the whole process with [`disable`].
+
+use indicatif::{ProgressBar, ProgressStyle};
+use std::{
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// never draw progress in this process
+pub fn disable() {
+    ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// true if progress should be drawn
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) && io::stdout().is_terminal()
+}
+
+/// A spinner or progress bar, possibly hidden
+#[derive(Clone, Debug)]
+pub struct Progress {
+    bar: ProgressBar,
+}
+
+impl Progress {
+    /// a spinner showing elapsed time and a message
+    pub fn spinner(msg: &str) -> Self {
+        let bar = if enabled() {
+            ProgressBar::new_spinner()
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}")
+                .expect("valid progress template"),
+        );
+        bar.set_message(msg.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+        Self { bar }
+    }
+
+    /// a bar counting towards a total, showing elapsed and estimated remaining time
+    pub fn bar(len: u64, msg: &str) -> Self {
+        let bar = if enabled() {
+            ProgressBar::new(len)
+        } else {
+            ProgressBar::hidden()
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {wide_bar} {pos}/{len} (eta {eta}) {msg}",
+            )
+            .expect("valid progress template"),
+        );
+        bar.set_message(msg.to_string());
+        Self { bar }
+    }
+
+    /// update the message
+    pub fn set_message(&self, msg: String) {
+        self.bar.set_message(msg);
+    }
+
+    /// set the position towards the total
+    pub fn set_position(&self, pos: u64) {
+        self.bar.set_position(pos);
+    }
+
+    /// change the total
+    pub fn set_length(&self, len: u64) {
+        self.bar.set_length(len);
+    }
+
+    /// advance the position by n
+    pub fn inc(&self, n: u64) {
+        self.bar.inc(n);
+    }
+
+    /// remove the progress display
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
@@ -0,0 +1,166 @@
+//! A cap on the outbound DHT queries running at once.
+//!
+//! Every subsystem queues its queries here instead of starting them directly. At most
+//! `max_concurrent` run at a time and the queues are served round robin, so one
+//! subsystem issuing lots of queries can't starve the others or flood the network. How
+//! many requests each query has in flight is kademlia's parallelism, set from the same
+//! config section.
+
+use libp2p::{
+    kad::{record::store::RecordStore, record::Key, Kademlia, QueryId},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+/// The `[queries]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuerySettings {
+    /// queries running at once across every subsystem
+    pub max_concurrent: usize,
+    /// requests each query has in flight, kademlia's alpha
+    pub parallelism: Option<usize>,
+}
+
+impl Default for QuerySettings {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 16,
+            parallelism: None,
+        }
+    }
+}
+
+/// A query waiting for a slot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    ClosestPeers(PeerId),
+    Providers(Key),
+    Record(Key),
+}
+
+impl Query {
+    /// start the query
+    pub fn start<S>(self, kademlia: &mut Kademlia<S>) -> QueryId
+    where
+        S: RecordStore + Send + 'static,
+    {
+        match self {
+            Query::ClosestPeers(peer) => kademlia.get_closest_peers(peer),
+            Query::Providers(key) => kademlia.get_providers(key),
+            Query::Record(key) => kademlia.get_record(key),
+        }
+    }
+}
+
+/// The queued and running queries of every subsystem
+#[derive(Debug)]
+pub struct QueryBudget {
+    max_concurrent: usize,
+    // one queue per subsystem, the front one is served next
+    queues: VecDeque<(&'static str, VecDeque<Query>)>,
+    running: HashMap<QueryId, &'static str>,
+}
+
+impl Default for QueryBudget {
+    fn default() -> Self {
+        Self::new(QuerySettings::default().max_concurrent)
+    }
+}
+
+impl QueryBudget {
+    /// a budget running at most `max_concurrent` queries
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            queues: VecDeque::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// queue a query for the subsystem
+    pub fn push(&mut self, subsystem: &'static str, query: Query) {
+        match self.queues.iter_mut().find(|(s, _)| *s == subsystem) {
+            Some((_, queue)) => queue.push_back(query),
+            None => self.queues.push_back((subsystem, VecDeque::from([query]))),
+        }
+    }
+
+    /// take the next query while there is a free slot, taking turns between subsystems
+    pub fn next_ready(&mut self) -> Option<(&'static str, Query)> {
+        if self.running.len() >= self.max_concurrent {
+            return None;
+        }
+        for _ in 0..self.queues.len() {
+            let (subsystem, mut queue) = self.queues.pop_front()?;
+            let query = queue.pop_front();
+            if !queue.is_empty() {
+                self.queues.push_back((subsystem, queue));
+            }
+            if let Some(query) = query {
+                return Some((subsystem, query));
+            }
+        }
+        None
+    }
+
+    /// a query taken from `next_ready` was started
+    pub fn started(&mut self, id: QueryId, subsystem: &'static str) {
+        self.running.insert(id, subsystem);
+    }
+
+    /// start as many queued queries as there are free slots, returning each one's id
+    pub fn start_ready<S>(&mut self, kademlia: &mut Kademlia<S>) -> Vec<(QueryId, Query)>
+    where
+        S: RecordStore + Send + 'static,
+    {
+        let mut started = Vec::new();
+        while let Some((subsystem, query)) = self.next_ready() {
+            let id = query.clone().start(kademlia);
+            self.started(id, subsystem);
+            started.push((id, query));
+        }
+        started
+    }
+
+    /// a query finished, freeing its slot, returns false if it wasn't one of ours
+    pub fn finished(&mut self, id: &QueryId) -> bool {
+        self.running.remove(id).is_some()
+    }
+
+    /// the number of running queries
+    pub fn running(&self) -> usize {
+        self.running.len()
+    }
+
+    /// the number of running queries of a subsystem
+    pub fn running_for(&self, subsystem: &str) -> usize {
+        self.running.values().filter(|s| **s == subsystem).count()
+    }
+
+    /// the number of queued queries
+    pub fn queued(&self) -> usize {
+        self.queues.iter().map(|(_, q)| q.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_robin() {
+        let mut budget = QueryBudget::new(8);
+        let a = PeerId::random();
+        for _ in 0..3 {
+            budget.push("crawl", Query::ClosestPeers(a));
+        }
+        budget.push("health", Query::Providers(Key::new(b"k")));
+        let order: Vec<_> = std::iter::from_fn(|| budget.next_ready())
+            .map(|(s, _)| s)
+            .collect();
+        assert_eq!(order, ["crawl", "health", "crawl", "crawl"]);
+        assert_eq!(budget.queued(), 0);
+    }
+}
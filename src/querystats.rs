@@ -0,0 +1,66 @@
+//! Counting the connections opened on behalf of DHT queries.
+//!
+//! Queries that can run over connections we already have are cheap, ones that have to dial
+//! fresh peers aren't. Every outbound connection established while queries are running is
+//! charged to each of them, which over counts when queries overlap but is exact for the
+//! common case of one query at a time. The daemon counts the queries of its control calls
+//! into the `query_connections_opened` metric, and the one-shot commands log theirs.
+
+use libp2p::kad::QueryId;
+use std::collections::HashMap;
+
+/// Outbound connections opened per running query
+#[derive(Debug, Default)]
+pub struct QueryConnections {
+    running: HashMap<QueryId, u32>,
+}
+
+impl QueryConnections {
+    /// start counting for a query
+    pub fn started(&mut self, id: QueryId) {
+        self.running.insert(id, 0);
+    }
+
+    /// an outbound connection was established
+    pub fn opened(&mut self) {
+        for count in self.running.values_mut() {
+            *count += 1;
+        }
+    }
+
+    /// the query finished, returning the connections it opened
+    pub fn finished(&mut self, id: &QueryId) -> Option<u32> {
+        self.running.remove(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::{
+        kad::{record::store::MemoryStore, Kademlia},
+        PeerId,
+    };
+
+    #[test]
+    fn charges_running_queries() {
+        // query ids can only be had from a running kademlia
+        let local = PeerId::random();
+        let mut kademlia = Kademlia::new(local, MemoryStore::new(local));
+        let (a, b) = (
+            kademlia.get_closest_peers(PeerId::random()),
+            kademlia.get_closest_peers(PeerId::random()),
+        );
+
+        let mut connections = QueryConnections::default();
+        connections.opened();
+        connections.started(a);
+        connections.opened();
+        connections.started(b);
+        connections.opened();
+        assert_eq!(connections.finished(&a), Some(2));
+        connections.opened();
+        assert_eq!(connections.finished(&b), Some(2));
+        assert_eq!(connections.finished(&b), None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Connection rate limits for measurement runs that shouldn't look like attacks.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+/// A token bucket refilling at a steady rate up to a burst size
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    per_sec: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// a full bucket allowing `per_sec` on average and `burst` at once
+    pub fn new(per_sec: f64, burst: f64, now: Instant) -> Self {
+        let burst = burst.max(1.0);
+        Self {
+            per_sec,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_sec).min(self.burst);
+        self.last = now;
+    }
+
+    /// true if a token is available now
+    pub fn ready(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= 1.0
+    }
+
+    /// take a token, true if there was one
+    pub fn take(&mut self, now: Instant) -> bool {
+        if self.ready(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Connection rate ceilings, all unlimited by default
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// connections per second to everyone
+    pub global: Option<f64>,
+    /// connections per minute to a single ip address
+    pub per_ip: Option<f64>,
+    /// connections per minute into a single autonomous system
+    pub per_asn: Option<f64>,
+}
+
+/// Token buckets for the global, per-ip and per-asn limits
+#[derive(Debug)]
+pub struct RateLimiter {
+    limits: Limits,
+    global: Option<TokenBucket>,
+    per_ip: HashMap<IpAddr, TokenBucket>,
+    per_asn: HashMap<u32, TokenBucket>,
+}
+
+impl RateLimiter {
+    /// a limiter enforcing the given limits
+    pub fn new(limits: Limits) -> Self {
+        Self {
+            limits,
+            global: limits
+                .global
+                .map(|rate| TokenBucket::new(rate, rate, Instant::now())),
+            per_ip: HashMap::new(),
+            per_asn: HashMap::new(),
+        }
+    }
+
+    /// the limits being enforced
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// take a connection slot against every limit if all of them allow it, ips and asns
+    /// are the ones the connection may reach
+    pub fn allow(&mut self, ips: &[IpAddr], asns: &[u32], now: Instant) -> bool {
+        let allowed = self.global.as_mut().is_none_or(|b| b.ready(now))
+            && ips
+                .iter()
+                .all(|ip| ready(&mut self.per_ip, ip, self.limits.per_ip, now))
+            && asns
+                .iter()
+                .all(|asn| ready(&mut self.per_asn, asn, self.limits.per_asn, now));
+        if !allowed {
+            return false;
+        }
+        if let Some(bucket) = self.global.as_mut() {
+            bucket.take(now);
+        }
+        for ip in ips {
+            if let Some(bucket) = self.per_ip.get_mut(ip) {
+                bucket.take(now);
+            }
+        }
+        for asn in asns {
+            if let Some(bucket) = self.per_asn.get_mut(asn) {
+                bucket.take(now);
+            }
+        }
+        true
+    }
+
+    /// forget buckets that have been full for a while so long runs don't grow without bound
+    pub fn prune(&mut self, now: Instant) {
+        let idle = Duration::from_secs(10 * 60);
+        self.per_ip
+            .retain(|_, b| now.saturating_duration_since(b.last) < idle);
+        self.per_asn
+            .retain(|_, b| now.saturating_duration_since(b.last) < idle);
+    }
+}
+
+// check a keyed per-minute bucket, creating it full the first time
+fn ready<K: Hash + Eq + Copy>(
+    buckets: &mut HashMap<K, TokenBucket>,
+    key: &K,
+    per_minute: Option<f64>,
+    now: Instant,
+) -> bool {
+    let Some(per_minute) = per_minute else {
+        return true;
+    };
+    buckets
+        .entry(*key)
+        .or_insert_with(|| TokenBucket::new(per_minute / 60.0, per_minute.min(1.0), now))
+        .ready(now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_ip_and_global() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::new(Limits {
+            global: Some(2.0),
+            per_ip: Some(60.0),
+            per_asn: None,
+        });
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        assert!(limiter.allow(&[a], &[64512], now));
+        // one a second for each ip
+        assert!(!limiter.allow(&[a], &[], now));
+        assert!(limiter.allow(&[b], &[], now));
+        // the global burst is used up
+        assert!(!limiter.allow(&[], &[], now));
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.allow(&[a], &[], later));
+    }
+}
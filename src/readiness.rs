@@ -0,0 +1,150 @@
+//! When a node counts as bootstrapped.
+//!
+//! The kademlia bootstrap query finishing says little about the routing table it left
+//! behind, it finishes just the same when every bootstrap peer is down. A node is
+//! bootstrapped once its routing table holds enough peers spread over enough buckets,
+//! peers all at one distance don't let it route to the rest of the key space. The goal
+//! is set in the `[bootstrap]` config section:
+//!
+//! ```toml
+//! [bootstrap]
+//! min_peers = 20
+//! min_buckets = 4
+//! timeout = "60s"
+//! ```
+//!
+//! `fleyg bootstrap --wait` exits once the goal is reached, or fails at the timeout, and
+//! a serving node logs when it gets there and holds back the work that needs a routing
+//! table until it has.
+
+use crate::{routing::RoutingSnapshot, timespec::parse_duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+/// The `[bootstrap]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BootstrapGoal {
+    /// peers the routing table has to hold
+    pub min_peers: usize,
+    /// non-empty buckets they have to be spread over
+    pub min_buckets: usize,
+    /// how long to wait for the goal, e.g. "60s"
+    pub timeout: String,
+}
+
+impl Default for BootstrapGoal {
+    fn default() -> Self {
+        Self {
+            min_peers: 20,
+            min_buckets: 4,
+            timeout: "60s".into(),
+        }
+    }
+}
+
+impl BootstrapGoal {
+    /// how long to wait for the goal
+    pub fn timeout(&self) -> Result<Duration, String> {
+        parse_duration(&self.timeout).map_err(|e| format!("bootstrap timeout: {e}"))
+    }
+
+    /// how far a routing table is from the goal
+    pub fn fill(&self, rt: &RoutingSnapshot) -> Fill {
+        Fill {
+            peers: rt.len(),
+            buckets: rt.buckets.len(),
+            reached: rt.len() >= self.min_peers && rt.buckets.len() >= self.min_buckets,
+        }
+    }
+}
+
+/// The size of a routing table measured against the goal
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Fill {
+    pub peers: usize,
+    /// non-empty buckets
+    pub buckets: usize,
+    pub reached: bool,
+}
+
+impl fmt::Display for Fill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} peers in {} buckets", self.peers, self.buckets)
+    }
+}
+
+/// Watches a running node's routing table for the goal
+#[derive(Clone, Debug)]
+pub struct Readiness {
+    goal: BootstrapGoal,
+    started: Instant,
+    ready_after: Option<Duration>,
+}
+
+impl Readiness {
+    pub fn new(goal: BootstrapGoal) -> Self {
+        Self {
+            goal,
+            started: Instant::now(),
+            ready_after: None,
+        }
+    }
+
+    /// true once the goal has been reached, a node stays ready if peers drop out later
+    pub fn is_ready(&self) -> bool {
+        self.ready_after.is_some()
+    }
+
+    /// check the routing table, returns how long it took the first time the goal is
+    /// reached
+    pub fn update(&mut self, rt: &RoutingSnapshot, now: Instant) -> Option<Duration> {
+        if self.is_ready() || !self.goal.fill(rt).reached {
+            return None;
+        }
+        let after = now.saturating_duration_since(self.started);
+        self.ready_after = Some(after);
+        Some(after)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    fn table(sizes: &[usize]) -> RoutingSnapshot {
+        let buckets: BTreeMap<u32, BTreeSet<PeerId>> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, n)| (i as u32, (0..*n).map(|_| PeerId::random()).collect()))
+            .collect();
+        RoutingSnapshot {
+            taken_at: 0,
+            buckets,
+        }
+    }
+
+    #[test]
+    fn needs_peers_and_buckets() {
+        let goal = BootstrapGoal {
+            min_peers: 10,
+            min_buckets: 3,
+            ..Default::default()
+        };
+        assert!(!goal.fill(&table(&[20])).reached);
+        assert!(!goal.fill(&table(&[2, 2, 2])).reached);
+        assert!(goal.fill(&table(&[6, 3, 1])).reached);
+
+        let mut readiness = Readiness::new(goal);
+        let now = Instant::now();
+        assert_eq!(readiness.update(&table(&[20]), now), None);
+        assert!(readiness.update(&table(&[6, 3, 1]), now).is_some());
+        assert!(readiness.is_ready());
+        assert_eq!(readiness.update(&table(&[6, 3, 1]), now), None);
+    }
+}
@@ -0,0 +1,171 @@
+//! Signed receipts for records we published.
+//!
+//! A receipt lists the peers that acknowledged storing a record and when, signed with our
+//! key so it can be shown later as evidence of publication. It only holds a hash of the
+//! value, `verify` can check a value against it.
+
+use crate::peerstore::now_secs;
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad::Quorum,
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path};
+
+/// the prefix signed along with the receipt so the signature can't be reused
+const DOMAIN: &[u8] = b"fleyg-receipt:";
+
+/// A peer acknowledging it stored the record
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ack {
+    pub peer: PeerId,
+    /// unix seconds when the acknowledgement arrived
+    pub at: u64,
+}
+
+// the signed part of a receipt
+#[derive(Serialize)]
+struct Signed<'a> {
+    #[serde(with = "hex::serde")]
+    key: &'a [u8],
+    #[serde(with = "hex::serde")]
+    value_sha256: &'a [u8],
+    publisher: &'a PeerId,
+    acks: &'a [Ack],
+    issued: u64,
+}
+
+/// A publication receipt
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    /// the record key
+    #[serde(with = "hex::serde")]
+    pub key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub value_sha256: Vec<u8>,
+    pub publisher: PeerId,
+    pub acks: Vec<Ack>,
+    /// unix seconds when it was signed
+    pub issued: u64,
+    /// the publisher's public key in protobuf format
+    #[serde(with = "hex::serde")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub signature: Vec<u8>,
+}
+
+/// how many of the closest peers have to acknowledge a record to meet the quorum
+pub fn required_acks(quorum: Quorum, closest: usize) -> usize {
+    match quorum {
+        Quorum::One => 1,
+        Quorum::Majority => closest / 2 + 1,
+        Quorum::All => closest,
+        Quorum::N(n) => n.get(),
+    }
+    .max(1)
+}
+
+/// parse a quorum: one, majority, all or a number of peers
+pub fn parse_quorum(s: &str) -> Result<Quorum, String> {
+    match s {
+        "one" => Ok(Quorum::One),
+        "majority" => Ok(Quorum::Majority),
+        "all" => Ok(Quorum::All),
+        n => n
+            .parse()
+            .map(Quorum::N)
+            .map_err(|_| format!("unknown quorum: {s} (one, majority, all or a number)")),
+    }
+}
+
+impl Receipt {
+    /// sign a receipt for the record with our key
+    pub fn sign(key: &Keypair, record_key: &[u8], value: &[u8], acks: Vec<Ack>) -> Self {
+        let mut receipt = Self {
+            key: record_key.to_vec(),
+            value_sha256: Sha256::digest(value).to_vec(),
+            publisher: key.public().to_peer_id(),
+            acks,
+            issued: now_secs(),
+            public_key: key.public().encode_protobuf(),
+            signature: Vec::new(),
+        };
+        receipt.signature = key
+            .sign(&receipt.message())
+            .expect("signing with our own key works");
+        receipt
+    }
+
+    /// check the receipt was signed by its publisher, and matches the value if given
+    pub fn verify(&self, value: Option<&[u8]>) -> Result<(), String> {
+        let public = PublicKey::try_decode_protobuf(&self.public_key).map_err(|e| e.to_string())?;
+        if public.to_peer_id() != self.publisher {
+            return Err(format!("receipt key doesn't belong to {}", self.publisher));
+        }
+        if !public.verify(&self.message(), &self.signature) {
+            return Err("receipt signature is invalid".to_string());
+        }
+        if let Some(value) = value {
+            if Sha256::digest(value).as_slice() != self.value_sha256.as_slice() {
+                return Err("the value doesn't match the receipt".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// read a receipt file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// write the receipt to a file
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    fn message(&self) -> Vec<u8> {
+        let signed = Signed {
+            key: &self.key,
+            value_sha256: &self.value_sha256,
+            publisher: &self.publisher,
+            acks: &self.acks,
+            issued: self.issued,
+        };
+        let json = serde_json::to_vec(&signed).expect("receipts always serialize");
+        [DOMAIN, &json].concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let key = Keypair::generate_ed25519();
+        let acks = vec![Ack {
+            peer: PeerId::random(),
+            at: 1_700_000_000,
+        }];
+        let receipt = Receipt::sign(&key, b"/key", b"value", acks);
+        assert!(receipt.verify(Some(b"value")).is_ok());
+        assert!(receipt.verify(Some(b"other")).is_err());
+
+        let mut forged = receipt.clone();
+        forged.acks.push(Ack {
+            peer: PeerId::random(),
+            at: 1_700_000_001,
+        });
+        assert!(forged.verify(None).is_err());
+    }
+
+    #[test]
+    fn quorums() {
+        assert_eq!(required_acks(parse_quorum("majority").unwrap(), 20), 11);
+        assert_eq!(required_acks(parse_quorum("all").unwrap(), 0), 1);
+        assert_eq!(required_acks(parse_quorum("3").unwrap(), 20), 3);
+        assert!(parse_quorum("0").is_err());
+    }
+}
@@ -0,0 +1,151 @@
+//! Choosing between relays to reach a peer behind a NAT.
+//!
+//! A peer reachable only through relays advertises a circuit address for each relay it
+//! holds a reservation with, `<relay addr>/p2p/<relay>/p2p-circuit/p2p/<peer>`. Each relay
+//! is scored by how often circuits through it have worked, its round trip time and how
+//! many circuits we are already running through it, and the best ones are tried first.
+
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// How circuits through a relay have gone before
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayHistory {
+    pub successes: u32,
+    pub failures: u32,
+}
+
+impl RelayHistory {
+    /// count a circuit through the relay
+    pub fn record(&mut self, success: bool) {
+        if success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+    }
+
+    /// the fraction of circuits that worked, a relay never used counts as a coin toss
+    pub fn success_rate(&self) -> f64 {
+        f64::from(self.successes + 1) / f64::from(self.successes + self.failures + 2)
+    }
+}
+
+/// What we know about a relay when choosing between them
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RelayStats {
+    pub history: RelayHistory,
+    pub rtt: Option<Duration>,
+    /// circuits we have open or opening through it
+    pub load: u32,
+}
+
+impl RelayStats {
+    /// higher is better: the success rate, scaled down by the round trip time to half at
+    /// 100ms, and divided between the circuits already using it
+    pub fn score(&self) -> f64 {
+        // an unmeasured relay is assumed to be middling
+        let rtt = self.rtt.unwrap_or(Duration::from_millis(150));
+        let latency = 1.0 / (1.0 + rtt.as_secs_f64() * 10.0);
+        self.history.success_rate() * latency / f64::from(1 + self.load)
+    }
+}
+
+/// the relay a circuit address goes through, none for a direct address
+pub fn relay_of(addr: &Multiaddr) -> Option<PeerId> {
+    let mut relay = None;
+    for protocol in addr.iter() {
+        match protocol {
+            Protocol::P2p(peer) => relay = Some(peer),
+            Protocol::P2pCircuit => return relay,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// the circuit address reaching `peer` through the relay at `relay_addr`
+pub fn circuit(relay_addr: &Multiaddr, relay: PeerId, peer: PeerId) -> Multiaddr {
+    let mut addr: Multiaddr = relay_addr
+        .iter()
+        .filter(|p| !matches!(p, Protocol::P2p(_)))
+        .collect();
+    addr.push(Protocol::P2p(relay));
+    addr.push(Protocol::P2pCircuit);
+    addr.push(Protocol::P2p(peer));
+    addr
+}
+
+/// put direct addresses first, in their given order, then the circuit addresses best
+/// relay first, with each circuit's relay and score. Each circuit picked adds to its
+/// relay's load, so circuits are spread over the relays rather than piled on the best one.
+pub fn rank(
+    addrs: Vec<Multiaddr>,
+    stats: &HashMap<PeerId, RelayStats>,
+) -> Vec<(Multiaddr, Option<(PeerId, f64)>)> {
+    let mut stats = stats.clone();
+    let mut ranked = Vec::new();
+    let mut relayed = Vec::new();
+    for addr in addrs {
+        match relay_of(&addr) {
+            Some(relay) => relayed.push((addr, relay)),
+            None => ranked.push((addr, None)),
+        }
+    }
+    while !relayed.is_empty() {
+        let score = |relay: &PeerId| stats.get(relay).copied().unwrap_or_default().score();
+        let best = relayed
+            .iter()
+            .enumerate()
+            .max_by(|(_, (_, a)), (_, (_, b))| score(a).total_cmp(&score(b)))
+            .map(|(i, _)| i)
+            .unwrap_or_default();
+        let (addr, relay) = relayed.remove(best);
+        ranked.push((addr, Some((relay, score(&relay)))));
+        stats.entry(relay).or_default().load += 1;
+    }
+    ranked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranks_relays() {
+        let (peer, good, bad) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let relay_addr: Multiaddr = "/ip4/1.2.3.4/tcp/4001".parse().unwrap();
+        let direct: Multiaddr = "/ip4/5.6.7.8/tcp/4001".parse().unwrap();
+        let via_good = circuit(&relay_addr, good, peer);
+        let via_bad = circuit(&relay_addr, bad, peer);
+        assert_eq!(relay_of(&via_good), Some(good));
+        assert_eq!(relay_of(&direct), None);
+        assert_eq!(
+            via_good.to_string(),
+            format!("/ip4/1.2.3.4/tcp/4001/p2p/{good}/p2p-circuit/p2p/{peer}")
+        );
+
+        let mut stats = HashMap::new();
+        stats.insert(
+            bad,
+            RelayStats {
+                history: RelayHistory {
+                    successes: 0,
+                    failures: 5,
+                },
+                ..Default::default()
+            },
+        );
+        stats.insert(
+            good,
+            RelayStats {
+                rtt: Some(Duration::from_millis(20)),
+                ..Default::default()
+            },
+        );
+        let ranked = rank(vec![via_bad, via_good.clone(), direct.clone()], &stats);
+        let order: Vec<_> = ranked.iter().map(|(a, _)| a.clone()).collect();
+        assert_eq!(order[..2], [direct, via_good]);
+    }
+}
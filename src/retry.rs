@@ -0,0 +1,226 @@
+//! Bounded retries with jittered exponential backoff for dials and DHT operations.
+//!
+//! The config file sets a default policy and can override it per command:
+//!
+//! ```toml
+//! [retry]
+//! max_attempts = 3
+//! base_delay = "1s"
+//! retry_on = ["timeout", "unreachable"]
+//!
+//! [retry.commands.dial]
+//! max_attempts = 5
+//! ```
+
+use libp2p::kad::{GetRecordError, PutRecordError};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::RandomState, BTreeMap},
+    fmt,
+    hash::{BuildHasher, Hasher},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::timespec::parse_duration;
+
+/// Why an attempt failed, for deciding whether to try again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Failure {
+    /// no answer in time
+    Timeout,
+    /// the peer or address couldn't be reached
+    Unreachable,
+    /// the query finished without finding anything
+    NotFound,
+    /// too few peers stored or answered
+    Quorum,
+    /// retrying won't help
+    Fatal,
+}
+
+impl FromStr for Failure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timeout" => Ok(Failure::Timeout),
+            "unreachable" => Ok(Failure::Unreachable),
+            "not_found" => Ok(Failure::NotFound),
+            "quorum" => Ok(Failure::Quorum),
+            "fatal" => Ok(Failure::Fatal),
+            _ => Err(format!("unknown failure kind: {s}")),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Timeout => write!(f, "timeout"),
+            Failure::Unreachable => write!(f, "unreachable"),
+            Failure::NotFound => write!(f, "not found"),
+            Failure::Quorum => write!(f, "quorum failed"),
+            Failure::Fatal => write!(f, "fatal"),
+        }
+    }
+}
+
+impl From<&PutRecordError> for Failure {
+    fn from(e: &PutRecordError) -> Self {
+        match e {
+            PutRecordError::QuorumFailed { .. } => Failure::Quorum,
+            PutRecordError::Timeout { .. } => Failure::Timeout,
+        }
+    }
+}
+
+impl From<&GetRecordError> for Failure {
+    fn from(e: &GetRecordError) -> Self {
+        match e {
+            GetRecordError::NotFound { .. } => Failure::NotFound,
+            GetRecordError::QuorumFailed { .. } => Failure::Quorum,
+            GetRecordError::Timeout { .. } => Failure::Timeout,
+        }
+    }
+}
+
+/// How many times to try and how long to wait in between
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// attempts including the first, at least 1
+    pub max_attempts: u32,
+    /// the wait after the first failure, doubling after each one
+    pub base_delay: Duration,
+    /// the longest wait
+    pub max_delay: Duration,
+    /// how much of each wait is random, from 0 to 1
+    pub jitter: f64,
+    /// the failures worth retrying
+    pub retry_on: Vec<Failure>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            retry_on: vec![Failure::Timeout, Failure::Unreachable, Failure::Quorum],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// how long to wait before trying again after `attempts` tries ended in `failure`,
+    /// none to give up
+    pub fn retry(&self, attempts: u32, failure: Failure) -> Option<Duration> {
+        if attempts >= self.max_attempts || !self.retry_on.contains(&failure) {
+            return None;
+        }
+        let exp = attempts.saturating_sub(1).min(16);
+        let delay = self.base_delay.saturating_mul(1 << exp).min(self.max_delay);
+        let jitter = self.jitter.clamp(0.0, 1.0) * random();
+        Some(delay.mul_f64(1.0 - jitter))
+    }
+}
+
+/// Retry settings as written in the config file, unset fields use the defaults
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetrySettings {
+    pub max_attempts: Option<u32>,
+    /// e.g. "1s"
+    pub base_delay: Option<String>,
+    pub max_delay: Option<String>,
+    pub jitter: Option<f64>,
+    pub retry_on: Option<Vec<Failure>>,
+}
+
+impl RetrySettings {
+    // override the policy with whatever is set here
+    fn apply(&self, policy: &mut RetryPolicy) -> Result<(), String> {
+        if let Some(n) = self.max_attempts {
+            policy.max_attempts = n.max(1);
+        }
+        if let Some(d) = &self.base_delay {
+            policy.base_delay = parse_duration(d)?;
+        }
+        if let Some(d) = &self.max_delay {
+            policy.max_delay = parse_duration(d)?;
+        }
+        if let Some(j) = self.jitter {
+            policy.jitter = j;
+        }
+        if let Some(on) = &self.retry_on {
+            policy.retry_on = on.clone();
+        }
+        Ok(())
+    }
+}
+
+/// The `[retry]` config section, a default and per command overrides
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RetryConfig {
+    #[serde(flatten)]
+    pub default: RetrySettings,
+    #[serde(default)]
+    pub commands: BTreeMap<String, RetrySettings>,
+}
+
+impl RetryConfig {
+    /// the policy for the named command
+    pub fn policy(&self, command: &str) -> Result<RetryPolicy, String> {
+        let mut policy = RetryPolicy::default();
+        self.default.apply(&mut policy)?;
+        if let Some(settings) = self.commands.get(command) {
+            settings
+                .apply(&mut policy)
+                .map_err(|e| format!("retry.commands.{command}: {e}"))?;
+        }
+        Ok(policy)
+    }
+}
+
+// a random number from 0 to 1
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let policy = RetryPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.retry(1, Failure::Timeout),
+            Some(Duration::from_secs(1))
+        );
+        assert_eq!(
+            policy.retry(2, Failure::Timeout),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(policy.retry(3, Failure::Timeout), None);
+        assert_eq!(policy.retry(1, Failure::Fatal), None);
+
+        let config: RetryConfig = toml::from_str(
+            "max_attempts = 2\n[commands.dial]\nmax_attempts = 5\nbase_delay = \"2s\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.policy("get").unwrap().max_attempts, 2);
+        let dial = config.policy("dial").unwrap();
+        assert_eq!(
+            (dial.max_attempts, dial.base_delay),
+            (5, Duration::from_secs(2))
+        );
+    }
+}
@@ -0,0 +1,191 @@
+//! Routing table snapshots and diffs between them.
+
+use libp2p::{
+    kad::{record::store::RecordStore, Kademlia},
+    PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The peers in each k-bucket at a point in time
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoutingSnapshot {
+    /// unix time in seconds the snapshot was taken
+    pub taken_at: u64,
+    /// the peers in each non-empty bucket, keyed by bucket index (log2 distance)
+    pub buckets: BTreeMap<u32, BTreeSet<PeerId>>,
+}
+
+impl RoutingSnapshot {
+    /// capture the current state of a kademlia routing table
+    pub fn capture<S>(kademlia: &mut Kademlia<S>) -> Self
+    where
+        S: RecordStore + Send + 'static,
+    {
+        let mut buckets = BTreeMap::new();
+        for bucket in kademlia.kbuckets() {
+            let index = bucket.range().0.ilog2().unwrap_or_default();
+            let peers: BTreeSet<PeerId> = bucket.iter().map(|e| *e.node.key.preimage()).collect();
+            if !peers.is_empty() {
+                buckets.insert(index, peers);
+            }
+        }
+        Self {
+            taken_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            buckets,
+        }
+    }
+
+    /// the total number of peers in the snapshot
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(BTreeSet::len).sum()
+    }
+
+    /// true if the routing table was empty
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+
+    /// compare this (earlier) snapshot to a later one
+    pub fn diff(&self, later: &RoutingSnapshot) -> RoutingDiff {
+        let empty = BTreeSet::new();
+        let indexes: BTreeSet<u32> = self
+            .buckets
+            .keys()
+            .chain(later.buckets.keys())
+            .copied()
+            .collect();
+        let mut buckets = BTreeMap::new();
+        for index in indexes {
+            let before = self.buckets.get(&index).unwrap_or(&empty);
+            let after = later.buckets.get(&index).unwrap_or(&empty);
+            let change = BucketChange {
+                added: after.difference(before).copied().collect(),
+                removed: before.difference(after).copied().collect(),
+            };
+            if !change.added.is_empty() || !change.removed.is_empty() {
+                buckets.insert(index, change);
+            }
+        }
+
+        let all_before: BTreeSet<&PeerId> = self.buckets.values().flatten().collect();
+        let all_after: BTreeSet<&PeerId> = later.buckets.values().flatten().collect();
+        let changed = all_before.symmetric_difference(&all_after).count();
+        let union = all_before.union(&all_after).count();
+        let churn = if union == 0 {
+            0.0
+        } else {
+            changed as f64 / union as f64
+        };
+
+        RoutingDiff {
+            from: self.taken_at,
+            to: later.taken_at,
+            buckets,
+            churn,
+        }
+    }
+}
+
+/// The peers added to and removed from a bucket
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BucketChange {
+    pub added: Vec<PeerId>,
+    pub removed: Vec<PeerId>,
+}
+
+/// The changes between two routing table snapshots
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingDiff {
+    pub from: u64,
+    pub to: u64,
+    /// the buckets that changed, keyed by bucket index
+    pub buckets: BTreeMap<u32, BucketChange>,
+    /// the fraction of all peers seen in either snapshot that are only in one of them
+    pub churn: f64,
+}
+
+/// A directory of routing table snapshots
+#[derive(Clone, Debug)]
+pub struct SnapshotDir {
+    dir: PathBuf,
+}
+
+impl SnapshotDir {
+    /// use the given directory, creating it if needed
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// write a snapshot into the directory
+    pub fn save(&self, snapshot: &RoutingSnapshot) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("rt-{}.json", snapshot.taken_at));
+        fs::write(&path, serde_json::to_vec(snapshot)?)?;
+        Ok(path)
+    }
+
+    /// the times of all of the snapshots in the directory, oldest first
+    pub fn list(&self) -> io::Result<Vec<u64>> {
+        let mut times = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            let Some(name) = name.to_str() else { continue };
+            if let Some(t) = name
+                .strip_prefix("rt-")
+                .and_then(|n| n.strip_suffix(".json"))
+                .and_then(|n| n.parse().ok())
+            {
+                times.push(t);
+            }
+        }
+        times.sort();
+        Ok(times)
+    }
+
+    /// load the latest snapshot taken at or before the given time, or the earliest one if
+    /// they are all later
+    pub fn load_at(&self, time: u64) -> io::Result<RoutingSnapshot> {
+        let times = self.list()?;
+        let t = times
+            .iter()
+            .rev()
+            .find(|t| **t <= time)
+            .or_else(|| times.first())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no routing table snapshots"))?;
+        let bytes = fs::read(self.dir.join(format!("rt-{t}.json")))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_snapshots() {
+        let (a, b, c) = (PeerId::random(), PeerId::random(), PeerId::random());
+        let before = RoutingSnapshot {
+            taken_at: 1,
+            buckets: BTreeMap::from([(255, BTreeSet::from([a, b]))]),
+        };
+        let after = RoutingSnapshot {
+            taken_at: 2,
+            buckets: BTreeMap::from([(255, BTreeSet::from([b])), (254, BTreeSet::from([c]))]),
+        };
+        let diff = before.diff(&after);
+        assert_eq!(diff.buckets[&255].removed, vec![a]);
+        assert_eq!(diff.buckets[&254].added, vec![c]);
+        assert!((diff.churn - 2.0 / 3.0).abs() < f64::EPSILON);
+    }
+}
@@ -0,0 +1,375 @@
+//! What serve does with the peers kademlia leaves out of its routing table.
+//!
+//! Kademlia reports a peer that connected without an address it can be dialed back on
+//! as unroutable, and a peer it has addresses for but didn't add, because its bucket was
+//! full or inserts are manual, as routable. Both are dropped unless the `[routing]`
+//! section says otherwise, which on a sparse network leaves the table emptier than it has
+//! to be:
+//!
+//! ```toml
+//! [routing]
+//! # add the addresses an unroutable peer identifies with
+//! unroutable = "discover"
+//! # add a routable peer if its bucket has room
+//! routable = "add"
+//! # only add peers the policies let in, instead of every peer that connects
+//! inserts = "manual"
+//! # let a peer with no room in its bucket take the place of an unhealthy one
+//! evict = "unhealthy"
+//! max_ping_failures = 3
+//! max_rtt_ms = 2000
+//! ```
+//!
+//! Kademlia keeps the peers it has known longest, they're the likeliest to stay, so a peer
+//! is only evicted for a newcomer once its pings fail `max_ping_failures` times in a row or
+//! its round trips take longer than `max_rtt_ms`, never for being old. The peers failing
+//! the most pings go first, then the slowest.
+//!
+//! Serve counts each decision and logs the counts when it stops, and logs each eviction.
+
+use libp2p::{kad::K_VALUE, multiaddr::Protocol, Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    time::Duration,
+};
+
+/// The `[routing]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RoutingPolicy {
+    pub unroutable: Unroutable,
+    pub routable: Routable,
+    pub inserts: Inserts,
+    pub evict: Evict,
+    /// the pings in a row a peer has to fail to be unhealthy
+    pub max_ping_failures: u32,
+    /// the round trip time, in milliseconds, that makes a peer unhealthy
+    pub max_rtt_ms: u64,
+}
+
+impl Default for RoutingPolicy {
+    fn default() -> Self {
+        Self {
+            unroutable: Unroutable::default(),
+            routable: Routable::default(),
+            inserts: Inserts::default(),
+            evict: Evict::default(),
+            max_ping_failures: 3,
+            max_rtt_ms: 2000,
+        }
+    }
+}
+
+/// What to do with a peer kademlia has no address for
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unroutable {
+    #[default]
+    Ignore,
+    /// add the listen addresses it identifies with
+    Discover,
+}
+
+/// What to do with a peer kademlia has addresses for but didn't add
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Routable {
+    #[default]
+    Ignore,
+    /// add it if its bucket has room
+    Add,
+}
+
+/// Which peers kademlia puts in the routing table by itself
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Inserts {
+    /// every peer that connects with an address, filling full buckets as peers go away
+    #[default]
+    OnConnected,
+    /// none, the [`Routable`] policy decides
+    Manual,
+}
+
+/// Whether a peer with no room in its bucket may take another's place
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Evict {
+    #[default]
+    Never,
+    /// the place of the bucket's unhealthiest peer, if it has an unhealthy one
+    Unhealthy,
+}
+
+/// Why a peer was evicted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unhealthy {
+    /// its last pings failed
+    PingFailures(u32),
+    /// its last round trip took this long
+    SlowRtt(Duration),
+}
+
+impl fmt::Display for Unhealthy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Unhealthy::PingFailures(n) => write!(f, "{n} failed pings in a row"),
+            Unhealthy::SlowRtt(rtt) => write!(f, "{}ms round trips", rtt.as_millis()),
+        }
+    }
+}
+
+/// What was done with an unroutable or routable peer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// the policy says to leave it out
+    Ignored,
+    /// waiting for it to identify with its listen addresses
+    Discovering,
+    /// its listen addresses were added
+    Discovered,
+    /// it identified without any address worth adding
+    Undiscovered,
+    /// it was added to the routing table
+    Added,
+    /// its bucket had no room
+    BucketFull,
+    /// it was added in the place of an unhealthy peer
+    Evicted { peer: PeerId, reason: Unhealthy },
+}
+
+impl Decision {
+    /// a short name for the decision
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Decision::Ignored => "ignored",
+            Decision::Discovering => "discovering",
+            Decision::Discovered => "discovered",
+            Decision::Undiscovered => "undiscovered",
+            Decision::Added => "added",
+            Decision::BucketFull => "bucket_full",
+            Decision::Evicted { .. } => "evicted",
+        }
+    }
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.kind())
+    }
+}
+
+/// Applies the policy and counts the decisions made
+#[derive(Debug, Default)]
+pub struct RoutingDecisions {
+    policy: RoutingPolicy,
+    /// unroutable peers waiting to identify
+    discovering: HashSet<PeerId>,
+    /// the failed pings in a row and last round trip time of the peers pinged
+    health: HashMap<PeerId, (u32, Option<Duration>)>,
+    counts: BTreeMap<(&'static str, &'static str), u64>,
+}
+
+impl RoutingDecisions {
+    pub fn new(policy: RoutingPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// kademlia has no address for a peer that connected
+    pub fn unroutable(&mut self, peer: PeerId) -> Decision {
+        let decision = match self.policy.unroutable {
+            Unroutable::Ignore => Decision::Ignored,
+            Unroutable::Discover => {
+                self.discovering.insert(peer);
+                Decision::Discovering
+            }
+        };
+        self.count("unroutable", decision)
+    }
+
+    /// a peer identified, returns the addresses to add for it if it was unroutable
+    pub fn identified(&mut self, peer: &PeerId, listen_addrs: &[Multiaddr]) -> Vec<Multiaddr> {
+        if !self.discovering.remove(peer) {
+            return Vec::new();
+        }
+        let addrs: Vec<Multiaddr> = listen_addrs
+            .iter()
+            .filter(|a| is_dialable(a))
+            .cloned()
+            .collect();
+        let decision = match addrs.is_empty() {
+            true => Decision::Undiscovered,
+            false => Decision::Discovered,
+        };
+        self.count("unroutable", decision);
+        addrs
+    }
+
+    /// kademlia has an address for a peer it didn't add, given the peers in its bucket
+    pub fn routable(&mut self, bucket: &[PeerId]) -> Decision {
+        let full = bucket.len() >= K_VALUE.get();
+        let decision = match (self.policy.routable, full) {
+            (_, true) if self.policy.evict == Evict::Unhealthy => match self.unhealthiest(bucket) {
+                Some((peer, reason)) => {
+                    self.health.remove(&peer);
+                    Decision::Evicted { peer, reason }
+                }
+                None if self.policy.routable == Routable::Add => Decision::BucketFull,
+                None => Decision::Ignored,
+            },
+            (Routable::Ignore, _) => Decision::Ignored,
+            (Routable::Add, false) => Decision::Added,
+            (Routable::Add, true) => Decision::BucketFull,
+        };
+        self.count("routable", decision)
+    }
+
+    /// a ping of a peer came back after the round trip time, or failed
+    pub fn pinged(&mut self, peer: PeerId, rtt: Option<Duration>) {
+        let health = self.health.entry(peer).or_default();
+        match rtt {
+            Some(rtt) => *health = (0, Some(rtt)),
+            None => health.0 += 1,
+        }
+    }
+
+    /// a peer that disconnected before it identified isn't waited on any more, and one
+    /// that answered its pings is healthy until it connects again
+    pub fn disconnected(&mut self, peer: &PeerId) {
+        self.discovering.remove(peer);
+        if self
+            .health
+            .get(peer)
+            .is_some_and(|(failures, _)| *failures == 0)
+        {
+            self.health.remove(peer);
+        }
+    }
+
+    // the bucket's peer failing the most pings in a row, or else the slowest, if any is
+    // unhealthy
+    fn unhealthiest(&self, bucket: &[PeerId]) -> Option<(PeerId, Unhealthy)> {
+        let max_rtt = Duration::from_millis(self.policy.max_rtt_ms);
+        let health = || {
+            bucket
+                .iter()
+                .filter_map(|p| Some((*p, *self.health.get(p)?)))
+        };
+        let failing = health()
+            .filter(|(_, (failures, _))| *failures >= self.policy.max_ping_failures.max(1))
+            .max_by_key(|(_, (failures, _))| *failures)
+            .map(|(peer, (failures, _))| (peer, Unhealthy::PingFailures(failures)));
+        failing.or_else(|| {
+            health()
+                .filter_map(|(peer, (_, rtt))| Some((peer, rtt?)))
+                .filter(|(_, rtt)| *rtt > max_rtt)
+                .max_by_key(|(_, rtt)| *rtt)
+                .map(|(peer, rtt)| (peer, Unhealthy::SlowRtt(rtt)))
+        })
+    }
+
+    /// the number of each decision for unroutable and routable peers
+    pub fn counts(&self) -> impl Iterator<Item = (&'static str, &'static str, u64)> + '_ {
+        self.counts
+            .iter()
+            .map(|((event, decision), n)| (*event, *decision, *n))
+    }
+
+    fn count(&mut self, event: &'static str, decision: Decision) -> Decision {
+        *self.counts.entry((event, decision.kind())).or_default() += 1;
+        decision
+    }
+}
+
+// an address another peer could dial: not loopback or unspecified, and not a circuit
+fn is_dialable(addr: &Multiaddr) -> bool {
+    addr.iter().all(|p| match p {
+        Protocol::Ip4(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        Protocol::Ip6(ip) => !ip.is_loopback() && !ip.is_unspecified(),
+        Protocol::P2pCircuit => false,
+        _ => true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decisions() {
+        let policy: RoutingPolicy =
+            toml::from_str("unroutable = \"discover\"\nroutable = \"add\"\n").unwrap();
+        assert_eq!(policy.inserts, Inserts::OnConnected);
+        let mut decisions = RoutingDecisions::new(policy);
+        let (peer, stranger) = (PeerId::random(), PeerId::random());
+        let addrs: Vec<Multiaddr> = ["/ip4/127.0.0.1/tcp/4001", "/ip4/10.0.0.1/tcp/4001"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+
+        assert_eq!(decisions.unroutable(peer), Decision::Discovering);
+        assert!(decisions.identified(&stranger, &addrs).is_empty());
+        assert_eq!(decisions.identified(&peer, &addrs), addrs[1..]);
+        // only the first identify after it was unroutable counts
+        assert!(decisions.identified(&peer, &addrs).is_empty());
+        let full: Vec<PeerId> = (0..K_VALUE.get()).map(|_| PeerId::random()).collect();
+        assert_eq!(decisions.routable(&full[1..]), Decision::Added);
+        assert_eq!(decisions.routable(&full), Decision::BucketFull);
+
+        let counts: Vec<_> = decisions.counts().collect();
+        assert_eq!(
+            counts,
+            vec![
+                ("routable", "added", 1),
+                ("routable", "bucket_full", 1),
+                ("unroutable", "discovered", 1),
+                ("unroutable", "discovering", 1),
+            ]
+        );
+        let mut ignoring = RoutingDecisions::default();
+        assert_eq!(ignoring.unroutable(peer), Decision::Ignored);
+        assert_eq!(ignoring.routable(&full), Decision::Ignored);
+    }
+
+    #[test]
+    fn evictions() {
+        let policy: RoutingPolicy = toml::from_str("evict = \"unhealthy\"\n").unwrap();
+        assert_eq!(policy.max_ping_failures, 3);
+        let mut decisions = RoutingDecisions::new(policy);
+        let full: Vec<PeerId> = (0..K_VALUE.get()).map(|_| PeerId::random()).collect();
+        let (slow, failing) = (full[0], full[1]);
+
+        // a healthy bucket keeps its peers
+        decisions.pinged(slow, Some(Duration::from_millis(1500)));
+        assert_eq!(decisions.routable(&full), Decision::Ignored);
+
+        decisions.pinged(slow, Some(Duration::from_secs(5)));
+        assert_eq!(
+            decisions.routable(&full),
+            Decision::Evicted {
+                peer: slow,
+                reason: Unhealthy::SlowRtt(Duration::from_secs(5))
+            }
+        );
+        // failing pings go before slow ones, and answering one makes a peer healthy again
+        decisions.pinged(slow, Some(Duration::from_secs(5)));
+        for _ in 0..3 {
+            decisions.pinged(failing, None);
+        }
+        assert_eq!(
+            decisions.routable(&full),
+            Decision::Evicted {
+                peer: failing,
+                reason: Unhealthy::PingFailures(3)
+            }
+        );
+        decisions.pinged(slow, Some(Duration::from_millis(20)));
+        assert_eq!(decisions.routable(&full), Decision::Ignored);
+        assert_eq!(decisions.routable(&full[1..]), Decision::Ignored);
+    }
+}
@@ -0,0 +1,345 @@
+//! The async runtime fleyg runs on, async-std unless built with the `tokio` feature.
+//!
+//! Everything that's tied to an executor goes through here: starting the binaries,
+//! spawning tasks, the swarm's connection tasks, the tcp and dns transports and mDNS, and
+//! outside the swarm timeouts, the tcp connections to webhooks and delegated routing, the
+//! metrics endpoint's listeners and the DNS cache's resolver. Connections are read and
+//! written with the `futures` io traits on either runtime. The rest of fleyg only uses
+//! runtime independent futures, so with `--features tokio` a fleyg node can run inside a
+//! tokio based program:
+//!
+//! ```text
+//! let swarm = FleygNode::new(key).build().await?;
+//! tokio::spawn(async move { serve(swarm).await });
+//! ```
+
+pub use imp::*;
+
+use libp2p::{
+    core::{muxing::StreamMuxerBox, transport::Boxed},
+    PeerId,
+};
+
+/// the transport the swarm builders take
+type BoxedTransport = Boxed<(PeerId, StreamMuxerBox)>;
+
+#[cfg(not(feature = "tokio"))]
+mod imp {
+    use super::BoxedTransport;
+    use futures::stream::{self, BoxStream, StreamExt};
+    use libp2p::{
+        dns::DnsConfig,
+        mdns,
+        swarm::{NetworkBehaviour, SwarmBuilder},
+        tcp, PeerId,
+    };
+    use std::{
+        future::Future,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    /// the tcp transport of the runtime
+    pub type Tcp = tcp::async_io::Transport;
+
+    /// the mDNS behaviour of the runtime
+    pub type Mdns = mdns::async_io::Behaviour;
+
+    /// the dns transport of the runtime
+    pub type Dns<T> = DnsConfig<T>;
+
+    /// wrap a transport to resolve the DNS names it dials with the system's resolvers
+    pub async fn dns<T>(inner: T) -> io::Result<Dns<T>> {
+        DnsConfig::system(inner).await
+    }
+
+    /// the DNS resolver of the runtime
+    pub type Resolver = async_std_resolver::AsyncStdResolver;
+
+    /// a resolver using the system's DNS configuration
+    pub async fn resolver() -> io::Result<Resolver> {
+        async_std_resolver::resolver_from_system_conf()
+            .await
+            .map_err(io::Error::other)
+    }
+
+    /// a tcp connection of the runtime
+    pub type TcpStream = async_std::net::TcpStream;
+
+    /// connect to a tcp address, host:port
+    pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+        TcpStream::connect(addr).await
+    }
+
+    /// accept the connections of a bound listener on the runtime
+    pub fn incoming(
+        listener: std::net::TcpListener,
+    ) -> io::Result<BoxStream<'static, io::Result<TcpStream>>> {
+        let listener = async_std::net::TcpListener::from(listener);
+        Ok(stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        })
+        .boxed())
+    }
+
+    /// a unix socket connection of the runtime
+    #[cfg(unix)]
+    pub type UnixStream = async_std::os::unix::net::UnixStream;
+
+    /// accept the connections of a bound unix socket on the runtime
+    #[cfg(unix)]
+    pub fn incoming_unix(
+        listener: std::os::unix::net::UnixListener,
+    ) -> io::Result<BoxStream<'static, io::Result<UnixStream>>> {
+        let listener = async_std::os::unix::net::UnixListener::from(listener);
+        Ok(stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| stream);
+            Some((accepted, listener))
+        })
+        .boxed())
+    }
+
+    /// run a future for at most the limit, failing with TimedOut after it
+    pub async fn timeout<F: Future>(limit: Duration, future: F) -> io::Result<F::Output> {
+        async_std::future::timeout(limit, future)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))
+    }
+
+    /// a swarm builder that runs the connection tasks on the runtime
+    pub fn swarm<B: NetworkBehaviour>(
+        transport: BoxedTransport,
+        behaviour: B,
+        local_peer_id: PeerId,
+    ) -> SwarmBuilder<B> {
+        SwarmBuilder::with_async_std_executor(transport, behaviour, local_peer_id)
+    }
+
+    /// run a future to completion, what the binaries' main functions do
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        async_std::task::block_on(future)
+    }
+
+    /// run a future on its own task
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(async_std::task::spawn(future))
+    }
+
+    /// A spawned task, resolving to its output. Dropping it leaves the task running.
+    #[derive(Debug)]
+    pub struct JoinHandle<T>(async_std::task::JoinHandle<T>);
+
+    impl<T> Future for JoinHandle<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            Pin::new(&mut self.0).poll(cx)
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+mod imp {
+    use super::BoxedTransport;
+    use futures::stream::{self, BoxStream, StreamExt};
+    use libp2p::{
+        dns::TokioDnsConfig,
+        mdns,
+        swarm::{NetworkBehaviour, SwarmBuilder},
+        tcp, PeerId,
+    };
+    use std::{
+        future::Future,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// the tcp transport of the runtime
+    pub type Tcp = tcp::tokio::Transport;
+
+    /// the mDNS behaviour of the runtime
+    pub type Mdns = mdns::tokio::Behaviour;
+
+    /// the dns transport of the runtime
+    pub type Dns<T> = TokioDnsConfig<T>;
+
+    /// wrap a transport to resolve the DNS names it dials with the system's resolvers
+    pub async fn dns<T>(inner: T) -> io::Result<Dns<T>> {
+        TokioDnsConfig::system(inner)
+    }
+
+    /// the DNS resolver of the runtime
+    pub type Resolver = trust_dns_resolver::TokioAsyncResolver;
+
+    /// a resolver using the system's DNS configuration
+    pub async fn resolver() -> io::Result<Resolver> {
+        Resolver::tokio_from_system_conf().map_err(io::Error::other)
+    }
+
+    /// a tcp connection of the runtime
+    pub type TcpStream = Compat<tokio::net::TcpStream>;
+
+    /// connect to a tcp address, host:port
+    pub async fn connect(addr: &str) -> io::Result<TcpStream> {
+        tokio::net::TcpStream::connect(addr).await.map(Compat)
+    }
+
+    /// accept the connections of a bound listener on the runtime
+    pub fn incoming(
+        listener: std::net::TcpListener,
+    ) -> io::Result<BoxStream<'static, io::Result<TcpStream>>> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        Ok(stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| Compat(stream));
+            Some((accepted, listener))
+        })
+        .boxed())
+    }
+
+    /// a unix socket connection of the runtime
+    #[cfg(unix)]
+    pub type UnixStream = Compat<tokio::net::UnixStream>;
+
+    /// accept the connections of a bound unix socket on the runtime
+    #[cfg(unix)]
+    pub fn incoming_unix(
+        listener: std::os::unix::net::UnixListener,
+    ) -> io::Result<BoxStream<'static, io::Result<UnixStream>>> {
+        listener.set_nonblocking(true)?;
+        let listener = tokio::net::UnixListener::from_std(listener)?;
+        Ok(stream::unfold(listener, |listener| async move {
+            let accepted = listener.accept().await.map(|(stream, _)| Compat(stream));
+            Some((accepted, listener))
+        })
+        .boxed())
+    }
+
+    /// run a future for at most the limit, failing with TimedOut after it
+    pub async fn timeout<F: Future>(limit: Duration, future: F) -> io::Result<F::Output> {
+        tokio::time::timeout(limit, future)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::TimedOut, e))
+    }
+
+    /// A tokio connection read and written with the `futures` io traits
+    #[derive(Debug)]
+    pub struct Compat<T>(T);
+
+    impl<T: AsyncRead + Unpin> futures::io::AsyncRead for Compat<T> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let mut buf = ReadBuf::new(buf);
+            Pin::new(&mut self.0)
+                .poll_read(cx, &mut buf)
+                .map_ok(|()| buf.filled().len())
+        }
+    }
+
+    impl<T: AsyncWrite + Unpin> futures::io::AsyncWrite for Compat<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+
+    /// a swarm builder that runs the connection tasks on the runtime
+    pub fn swarm<B: NetworkBehaviour>(
+        transport: BoxedTransport,
+        behaviour: B,
+        local_peer_id: PeerId,
+    ) -> SwarmBuilder<B> {
+        SwarmBuilder::with_tokio_executor(transport, behaviour, local_peer_id)
+    }
+
+    /// run a future to completion on a new multi threaded runtime, what the binaries'
+    /// main functions do
+    pub fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()
+            .expect("a tokio runtime")
+            .block_on(future)
+    }
+
+    /// run a future on its own task
+    pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        JoinHandle(tokio::task::spawn(future))
+    }
+
+    /// A spawned task, resolving to its output. Dropping it leaves the task running.
+    #[derive(Debug)]
+    pub struct JoinHandle<T>(tokio::task::JoinHandle<T>);
+
+    impl<T> Future for JoinHandle<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<T> {
+            // a task that panicked panics whoever waits on it, as with async-std
+            Pin::new(&mut self.0).poll(cx).map(|joined| match joined {
+                Ok(output) => output,
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::prelude::*;
+    use std::time::Duration;
+
+    #[test]
+    fn spawns() {
+        assert_eq!(block_on(async { spawn(async { 2 + 2 }).await }), 4);
+    }
+
+    #[test]
+    fn times_out_and_connects() {
+        block_on(async {
+            let late = timeout(Duration::from_millis(10), future::pending::<()>()).await;
+            assert_eq!(late.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let mut incoming = incoming(listener).unwrap();
+            let mut client = connect(&addr).await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            client.close().await.unwrap();
+            let mut server = incoming.next().await.unwrap().unwrap();
+            let mut read = Vec::new();
+            server.read_to_end(&mut read).await.unwrap();
+            assert_eq!(read, b"ping");
+        });
+    }
+}
@@ -0,0 +1,265 @@
+//! Commands serve runs on a schedule.
+//!
+//! Periodic publishing and measurement are `[[task]]` entries in the config instead of cron
+//! jobs that each start a swarm from cold:
+//!
+//! ```toml
+//! [[task]]
+//! name = "status"
+//! every = "15m"
+//! run = "put /myapp/status 'online since monday'"
+//!
+//! [[task]]
+//! every = "6h"
+//! run = "crawl --incremental"
+//! ```
+//!
+//! A task runs the fleyg command line in `run` as a child of serve, with the node's config
+//! and state directory, as soon as serve starts and then `every` after each run started. A
+//! task still running when it's due again skips that run. Each task's output goes to its
+//! log in `logs/tasks/` and the last run of each is kept in `tasks.json`, which `fleyg tasks`
+//! shows.
+
+use crate::{peerstore::now_secs, timespec::parse_duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+/// A `[[task]]` config entry
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Task {
+    /// the name its log and status go by, its command if not given
+    pub name: Option<String>,
+    /// how often it runs, like 15m
+    pub every: String,
+    /// the fleyg command line to run, without the binary
+    pub run: String,
+}
+
+impl Task {
+    /// the name its log and status go by
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .run
+                .split_whitespace()
+                .next()
+                .unwrap_or("task")
+                .to_string(),
+        }
+    }
+}
+
+/// The last run of a task
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskStatus {
+    pub name: String,
+    pub every: String,
+    pub run: String,
+    /// unix seconds the last run started
+    pub last_run: Option<u64>,
+    /// the exit code of the last finished run, none if it was killed by a signal
+    pub last_exit: Option<i32>,
+    /// how long the last finished run took
+    pub last_duration_ms: Option<u64>,
+    pub runs: u64,
+    pub failures: u64,
+    /// runs skipped because the one before was still going
+    pub skipped: u64,
+    pub running: bool,
+}
+
+// a task and when it's next due
+#[derive(Debug)]
+struct Scheduled {
+    every: Duration,
+    args: Vec<String>,
+    next: Instant,
+    status: TaskStatus,
+}
+
+/// Which tasks are due and how their runs went
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    tasks: Vec<Scheduled>,
+}
+
+impl Scheduler {
+    /// schedule the tasks, all of them due now
+    pub fn new(tasks: &[Task], now: Instant) -> Result<Self, String> {
+        let mut names = HashSet::new();
+        let mut scheduled = Vec::new();
+        for task in tasks {
+            let name = task.name();
+            if !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            {
+                return Err(format!("task name {name} isn't a plain file name"));
+            }
+            if !names.insert(name.clone()) {
+                return Err(format!("more than one task is named {name}"));
+            }
+            let every = parse_duration(&task.every).map_err(|e| format!("task {name}: {e}"))?;
+            if every.is_zero() {
+                return Err(format!("task {name} has to run less often than every 0s"));
+            }
+            let args = split_args(&task.run).map_err(|e| format!("task {name}: {e}"))?;
+            if args.is_empty() {
+                return Err(format!("task {name} has nothing to run"));
+            }
+            scheduled.push(Scheduled {
+                every,
+                args,
+                next: now,
+                status: TaskStatus {
+                    name,
+                    every: task.every.clone(),
+                    run: task.run.clone(),
+                    ..Default::default()
+                },
+            });
+        }
+        Ok(Self { tasks: scheduled })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// the tasks to start now, each counted as running until it finishes
+    pub fn due(&mut self, now: Instant) -> Vec<usize> {
+        let mut due = Vec::new();
+        for (i, task) in self.tasks.iter_mut().enumerate() {
+            if task.next > now {
+                continue;
+            }
+            task.next = now + task.every;
+            if task.status.running {
+                task.status.skipped += 1;
+                continue;
+            }
+            task.status.running = true;
+            task.status.runs += 1;
+            task.status.last_run = Some(now_secs());
+            due.push(i);
+        }
+        due
+    }
+
+    /// a task's run finished, with its exit code if it exited
+    pub fn finished(&mut self, i: usize, exit: Option<i32>, took: Duration) {
+        let Some(task) = self.tasks.get_mut(i) else {
+            return;
+        };
+        task.status.running = false;
+        task.status.last_exit = exit;
+        task.status.last_duration_ms = Some(took.as_millis() as u64);
+        if exit != Some(0) {
+            task.status.failures += 1;
+        }
+    }
+
+    /// when the next task is due
+    pub fn next_due(&self) -> Option<Instant> {
+        self.tasks.iter().map(|t| t.next).min()
+    }
+
+    /// the name of a task
+    pub fn name(&self, i: usize) -> &str {
+        &self.tasks[i].status.name
+    }
+
+    /// the command line of a task
+    pub fn args(&self, i: usize) -> &[String] {
+        &self.tasks[i].args
+    }
+
+    /// the last run of each task, in config order
+    pub fn statuses(&self) -> Vec<TaskStatus> {
+        self.tasks.iter().map(|t| t.status.clone()).collect()
+    }
+}
+
+/// write the task statuses as json
+pub fn save_statuses<P: AsRef<Path>>(path: P, statuses: &[TaskStatus]) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec_pretty(statuses)?)
+}
+
+/// read the task statuses serve last wrote, none if it never ran any
+pub fn load_statuses<P: AsRef<Path>>(path: P) -> io::Result<Vec<TaskStatus>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// split a command line into its arguments, on whitespace outside single or double quotes
+pub fn split_args(line: &str) -> Result<Vec<String>, String> {
+    let mut args = Vec::new();
+    let mut arg = None::<String>;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in {line}"));
+    }
+    args.extend(arg);
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn schedules() {
+        assert_eq!(
+            split_args(r#"put /app/k 'two words' "" x"#).unwrap(),
+            vec!["put", "/app/k", "two words", "", "x"]
+        );
+        assert!(split_args("put 'open").is_err());
+
+        let tasks: Vec<Task> = ["every = \"15m\"\nrun = \"crawl --incremental\"\n"]
+            .iter()
+            .map(|t| toml::from_str(t).unwrap())
+            .collect();
+        let start = Instant::now();
+        let mut scheduler = Scheduler::new(&tasks, start).unwrap();
+        assert_eq!(scheduler.name(0), "crawl");
+        assert_eq!(scheduler.due(start), vec![0]);
+        assert!(scheduler.due(start).is_empty());
+        assert_eq!(scheduler.next_due(), Some(start + Duration::from_secs(900)));
+        // still running when it's due again
+        assert!(scheduler.due(start + Duration::from_secs(900)).is_empty());
+        scheduler.finished(0, Some(1), Duration::from_secs(1000));
+        let status = &scheduler.statuses()[0];
+        assert_eq!((status.runs, status.failures, status.skipped), (1, 1, 1));
+        assert!(!status.running);
+        assert_eq!(scheduler.due(start + Duration::from_secs(1800)), vec![0]);
+
+        let twice = [tasks[0].clone(), tasks[0].clone()];
+        assert!(Scheduler::new(&twice, start).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+//! Recording of peer sessions into parquet datasets for network studies.
+//!
+//! A session starts when the first connection to a peer is established and ends when the
+//! last one closes. Finished sessions are buffered and written to parquet files
+//! partitioned by the day the session ended (`<dir>/date=YYYY-MM-DD/*.parquet`).
+//! libp2p doesn't expose per-connection byte counters so sessions don't carry traffic
+//! volumes. Writing the files needs the `sessions` feature.
+
+#[cfg(feature = "sessions")]
+use crate::latency::RttStats;
+#[cfg(feature = "sessions")]
+use arrow::{
+    array::{ArrayRef, Float64Builder, Int64Builder, ListBuilder, StringBuilder, UInt64Builder},
+    datatypes::{Field, Schema},
+    error::ArrowError,
+    record_batch::RecordBatch,
+};
+use chrono::{TimeZone, Utc};
+use libp2p::{identify, Multiaddr, PeerId};
+#[cfg(feature = "sessions")]
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+#[cfg(feature = "sessions")]
+use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// the number of finished sessions buffered before they are written out
+const FLUSH_SESSIONS: usize = 1000;
+
+/// A single peer session
+#[derive(Clone, Debug)]
+pub struct Session {
+    pub peer: PeerId,
+    pub addrs: Vec<Multiaddr>,
+    pub agent: Option<String>,
+    pub protocols: Vec<String>,
+    /// unix time in milliseconds the first connection was established
+    pub connected_at: u64,
+    /// unix time in milliseconds the last connection closed
+    pub disconnected_at: u64,
+    pub rtts: Vec<Duration>,
+}
+
+impl Session {
+    fn new(peer: PeerId, addr: Multiaddr) -> Self {
+        Self {
+            peer,
+            addrs: vec![addr],
+            agent: None,
+            protocols: Vec::new(),
+            connected_at: now_millis(),
+            disconnected_at: 0,
+            rtts: Vec::new(),
+        }
+    }
+}
+
+/// Tracks open sessions from swarm events
+#[derive(Debug, Default)]
+pub struct SessionTracker {
+    open: HashMap<PeerId, Session>,
+}
+
+impl SessionTracker {
+    /// a connection to the peer was established
+    pub fn connected(&mut self, peer: PeerId, addr: &Multiaddr) {
+        let session = self
+            .open
+            .entry(peer)
+            .or_insert_with(|| Session::new(peer, addr.clone()));
+        if !session.addrs.contains(addr) {
+            session.addrs.push(addr.clone());
+        }
+    }
+
+    /// the peer answered an identify request
+    pub fn identified(&mut self, peer: &PeerId, info: &identify::Info) {
+        if let Some(session) = self.open.get_mut(peer) {
+            session.agent = Some(info.agent_version.clone());
+            session.protocols = info.protocols.iter().map(|p| p.to_string()).collect();
+        }
+    }
+
+    /// the peer answered a ping
+    pub fn pinged(&mut self, peer: &PeerId, rtt: Duration) {
+        if let Some(session) = self.open.get_mut(peer) {
+            session.rtts.push(rtt);
+        }
+    }
+
+    /// a connection closed, returns the finished session if it was the last one
+    pub fn closed(&mut self, peer: &PeerId, remaining: u32) -> Option<Session> {
+        if remaining > 0 {
+            return None;
+        }
+        let mut session = self.open.remove(peer)?;
+        session.disconnected_at = now_millis();
+        Some(session)
+    }
+
+    /// end all open sessions, used at shutdown
+    pub fn close_all(&mut self) -> Vec<Session> {
+        let now = now_millis();
+        self.open
+            .drain()
+            .map(|(_, mut s)| {
+                s.disconnected_at = now;
+                s
+            })
+            .collect()
+    }
+}
+
+/// Errors from writing session datasets
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    #[cfg(feature = "sessions")]
+    Arrow(ArrowError),
+    #[cfg(feature = "sessions")]
+    Parquet(ParquetError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "session i/o error: {e}"),
+            #[cfg(feature = "sessions")]
+            Error::Arrow(e) => write!(f, "session arrow error: {e}"),
+            #[cfg(feature = "sessions")]
+            Error::Parquet(e) => write!(f, "session parquet error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl From<ArrowError> for Error {
+    fn from(e: ArrowError) -> Self {
+        Error::Arrow(e)
+    }
+}
+
+#[cfg(feature = "sessions")]
+impl From<ParquetError> for Error {
+    fn from(e: ParquetError) -> Self {
+        Error::Parquet(e)
+    }
+}
+
+/// Buffers finished sessions and writes them as day-partitioned parquet files
+#[derive(Debug)]
+pub struct SessionRecorder {
+    dir: PathBuf,
+    buffer: Vec<Session>,
+}
+
+impl SessionRecorder {
+    /// record sessions under the given directory
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: dir.into(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// add a finished session, flushing if the buffer is full
+    pub fn record(&mut self, session: Session) -> Result<(), Error> {
+        self.buffer.push(session);
+        if self.buffer.len() >= FLUSH_SESSIONS {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// write all buffered sessions to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        let mut days: BTreeMap<String, Vec<Session>> = BTreeMap::new();
+        for session in self.buffer.drain(..) {
+            days.entry(day_of(session.disconnected_at))
+                .or_default()
+                .push(session);
+        }
+        for (day, sessions) in days {
+            let dir = self.dir.join(format!("date={day}"));
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("sessions-{}.parquet", now_millis()));
+            write_parquet(&path, &sessions)?;
+        }
+        Ok(())
+    }
+}
+
+/// write the sessions to a single parquet file
+#[cfg(feature = "sessions")]
+fn write_parquet(path: &Path, sessions: &[Session]) -> Result<(), Error> {
+    let mut peer = StringBuilder::new();
+    let mut addrs = ListBuilder::new(StringBuilder::new());
+    let mut agent = StringBuilder::new();
+    let mut protocols = ListBuilder::new(StringBuilder::new());
+    let mut connected_at = UInt64Builder::new();
+    let mut disconnected_at = UInt64Builder::new();
+    let mut pings = Int64Builder::new();
+    let mut rtt_min = Float64Builder::new();
+    let mut rtt_avg = Float64Builder::new();
+    let mut rtt_max = Float64Builder::new();
+    let mut rtt_stddev = Float64Builder::new();
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    for s in sessions {
+        peer.append_value(s.peer.to_string());
+        for a in &s.addrs {
+            addrs.values().append_value(a.to_string());
+        }
+        addrs.append(true);
+        agent.append_option(s.agent.as_deref());
+        for p in &s.protocols {
+            protocols.values().append_value(p);
+        }
+        protocols.append(true);
+        connected_at.append_value(s.connected_at);
+        disconnected_at.append_value(s.disconnected_at);
+        pings.append_value(s.rtts.len() as i64);
+        let stats = RttStats::from_samples(&s.rtts);
+        rtt_min.append_option(stats.as_ref().map(|st| ms(st.min)));
+        rtt_avg.append_option(stats.as_ref().map(|st| ms(st.avg)));
+        rtt_max.append_option(stats.as_ref().map(|st| ms(st.max)));
+        rtt_stddev.append_option(stats.as_ref().map(|st| ms(st.stddev)));
+    }
+
+    let columns: Vec<(&str, ArrayRef, bool)> = vec![
+        ("peer_id", Arc::new(peer.finish()), false),
+        ("addrs", Arc::new(addrs.finish()), false),
+        ("agent", Arc::new(agent.finish()), true),
+        ("protocols", Arc::new(protocols.finish()), false),
+        ("connected_at_ms", Arc::new(connected_at.finish()), false),
+        (
+            "disconnected_at_ms",
+            Arc::new(disconnected_at.finish()),
+            false,
+        ),
+        ("pings", Arc::new(pings.finish()), false),
+        ("rtt_min_ms", Arc::new(rtt_min.finish()), true),
+        ("rtt_avg_ms", Arc::new(rtt_avg.finish()), true),
+        ("rtt_max_ms", Arc::new(rtt_max.finish()), true),
+        ("rtt_stddev_ms", Arc::new(rtt_stddev.finish()), true),
+    ];
+    let schema = Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|(name, array, nullable)| Field::new(*name, array.data_type().clone(), *nullable))
+            .collect::<Vec<Field>>(),
+    ));
+    let batch = RecordBatch::try_new(
+        schema.clone(),
+        columns.into_iter().map(|(_, array, _)| array).collect(),
+    )?;
+
+    let file = fs::File::create(path)?;
+    let mut writer = ArrowWriter::try_new(file, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+#[cfg(not(feature = "sessions"))]
+fn write_parquet(_path: &Path, _sessions: &[Session]) -> Result<(), Error> {
+    Err(Error::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "fleyg was built without the sessions feature",
+    )))
+}
+
+/// the utc day, as YYYY-MM-DD, of a unix time in milliseconds
+fn day_of(millis: u64) -> String {
+    Utc.timestamp_millis_opt(millis as i64)
+        .single()
+        .map(|t| t.format("%Y-%m-%d").to_string())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+/// the current unix time in milliseconds
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn day_partitions() {
+        assert_eq!(day_of(0), "1970-01-01");
+        assert_eq!(day_of(1_700_000_000_000), "2023-11-14");
+    }
+}
@@ -0,0 +1,115 @@
+//! Stopping a serving node cleanly on SIGINT and SIGTERM, or Ctrl-C on windows.
+//!
+//! The first signal asks the event loop of a long running command, serve, sub, soak,
+//! infra or a crawl, to stop, which closes the listeners and connections and saves and
+//! reports what it has before exiting. A second signal exits right away, for when that
+//! takes too long.
+
+use log::*;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// the exit code when a second signal cuts the shutdown short, as for SIGINT
+pub const EXIT_FORCED: i32 = 130;
+
+/// Whether a shutdown was asked for, shared with the signal handler
+#[derive(Clone, Debug, Default)]
+pub struct Shutdown {
+    requests: Arc<AtomicUsize>,
+}
+
+impl Shutdown {
+    /// catch the shutdown signals, there can only be one handler per process
+    pub fn on_signals() -> Result<Self, ctrlc::Error> {
+        let shutdown = Self::default();
+        let handler = shutdown.clone();
+        ctrlc::set_handler(move || {
+            if handler.request() {
+                info!("Shutting down, signal again to exit now");
+            } else {
+                warn!("Exiting without finishing the shutdown");
+                std::process::exit(EXIT_FORCED);
+            }
+        })?;
+        Ok(shutdown)
+    }
+
+    /// catch the shutdown signals, or go on without them when they can't be caught
+    pub fn catch() -> Self {
+        Self::on_signals().unwrap_or_else(|e| {
+            warn!("Can't catch shutdown signals: {e}");
+            Self::default()
+        })
+    }
+
+    /// ask for a shutdown, false if one was already asked for
+    pub fn request(&self) -> bool {
+        self.requests.fetch_add(1, Ordering::SeqCst) == 0
+    }
+
+    /// true once a shutdown was asked for
+    pub fn requested(&self) -> bool {
+        self.requests.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// What a node did while it served, logged as it stops
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub uptime: Duration,
+    /// connections established, counting reconnects
+    pub connections: usize,
+    /// records in the store
+    pub records: usize,
+    /// peers in the peer store
+    pub peers: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.uptime.as_secs();
+        let (d, h, m, s) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+        match (d, h) {
+            (0, 0) => write!(f, "up {m}m{s}s")?,
+            (0, _) => write!(f, "up {h}h{m}m{s}s")?,
+            _ => write!(f, "up {d}d{h}h{m}m")?,
+        }
+        write!(
+            f,
+            ", {} connections, {} records stored, {} known peers",
+            self.connections, self.records, self.peers
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_request_is_forced() {
+        let shutdown = Shutdown::default();
+        let handler = shutdown.clone();
+        assert!(!shutdown.requested());
+        assert!(handler.request());
+        assert!(shutdown.requested());
+        assert!(!handler.request());
+
+        let summary = Summary {
+            uptime: Duration::from_secs(3725),
+            connections: 12,
+            records: 3,
+            peers: 40,
+        };
+        assert_eq!(
+            summary.to_string(),
+            "up 1h2m5s, 12 connections, 3 records stored, 40 known peers"
+        );
+    }
+}
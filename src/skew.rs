@@ -0,0 +1,126 @@
+//! Estimating how far a peer's clock is from ours.
+//!
+//! Most of what peers send carries no time at all, so skew is estimated from the few
+//! timestamps they do produce, like the issue time in a signed attestation. A timestamp
+//! made while we timed a request, between sending it and hearing back, bounds the skew on
+//! both sides to within the round trip. A timestamp on a record that could have been made
+//! any time before we got it only bounds it from below: a record from the future shows a
+//! clock running ahead, one from the past shows nothing. Each peer's samples are
+//! intersected, so the estimate tightens as more come in.
+
+use libp2p::PeerId;
+use std::collections::HashMap;
+
+/// skew beyond this is worth flagging, records with short TTLs start to misbehave
+pub const LARGE_SKEW_MS: i64 = 30_000;
+
+/// The range a peer's clock is ahead of ours by, in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Skew {
+    pub lower: i64,
+    /// none if the clock could be arbitrarily far ahead
+    pub upper: Option<i64>,
+}
+
+impl Skew {
+    /// a timestamp the peer made between our `sent` and `received`, all in unix ms
+    pub fn exchange(sent: u64, received: u64, remote: u64) -> Self {
+        let remote = remote as i64;
+        Self {
+            lower: remote - received as i64,
+            upper: Some(remote - sent as i64),
+        }
+    }
+
+    /// a timestamp the peer made some time before we `received` it, both in unix ms
+    pub fn record(received: u64, remote: u64) -> Self {
+        Self {
+            lower: remote as i64 - received as i64,
+            upper: None,
+        }
+    }
+
+    /// narrow the range with another sample, samples that disagree are taken as the
+    /// clock having moved and the newer one wins
+    pub fn merge(self, other: Skew) -> Skew {
+        let lower = self.lower.max(other.lower);
+        let upper = match (self.upper, other.upper) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        match upper {
+            Some(upper) if upper < lower => other,
+            _ => Skew { lower, upper },
+        }
+    }
+
+    /// the best guess at the skew, the middle of the range, or the lower bound if it shows
+    /// the clock ahead and there is no upper bound
+    pub fn estimate(&self) -> Option<i64> {
+        match self.upper {
+            Some(upper) => Some(self.lower + (upper - self.lower) / 2),
+            None if self.lower > 0 => Some(self.lower),
+            None => None,
+        }
+    }
+
+    /// true if the clock is known to be more than `threshold` ms off either way
+    pub fn is_large(&self, threshold: i64) -> bool {
+        self.lower > threshold || self.upper.is_some_and(|u| u < -threshold)
+    }
+}
+
+/// The skew of every peer we have samples for
+#[derive(Clone, Debug, Default)]
+pub struct SkewTracker {
+    peers: HashMap<PeerId, Skew>,
+}
+
+impl SkewTracker {
+    /// add a sample for the peer, returning its updated skew
+    pub fn observe(&mut self, peer: PeerId, sample: Skew) -> Skew {
+        let skew = match self.peers.get(&peer) {
+            Some(skew) => skew.merge(sample),
+            None => sample,
+        };
+        self.peers.insert(peer, skew);
+        skew
+    }
+
+    /// the peer's skew, none without samples
+    pub fn get(&self, peer: &PeerId) -> Option<Skew> {
+        self.peers.get(peer).copied()
+    }
+}
+
+/// a skew in milliseconds for showing, e.g. "+1.2s"
+pub fn format_ms(ms: i64) -> String {
+    format!("{:+.1}s", ms as f64 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrows_range() {
+        // the peer is about 10s ahead, seen over a 200ms round trip
+        let exchange = Skew::exchange(1_000_000, 1_000_200, 1_010_100);
+        assert_eq!(exchange.lower, 9_900);
+        assert_eq!(exchange.upper, Some(10_100));
+        assert_eq!(exchange.estimate(), Some(10_000));
+
+        // an old record says nothing on its own, a future one shows the clock ahead
+        assert_eq!(Skew::record(1_000_000, 900_000).estimate(), None);
+        assert!(Skew::record(1_000_000, 1_060_000).is_large(LARGE_SKEW_MS));
+
+        let mut tracker = SkewTracker::default();
+        let peer = PeerId::random();
+        tracker.observe(peer, Skew::record(1_000_000, 1_009_950));
+        let skew = tracker.observe(peer, exchange);
+        assert_eq!(skew.lower, 9_950);
+        assert_eq!(skew.upper, Some(10_100));
+        assert!(!skew.is_large(LARGE_SKEW_MS));
+        assert_eq!(format_ms(-1_500), "-1.5s");
+    }
+}
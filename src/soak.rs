@@ -0,0 +1,153 @@
+//! Self-audits for long running soak tests.
+//!
+//! A soak test keeps a node serving for hours and samples its own resource use as it
+//! goes, so leaks show up before a release reaches the fleet. Each audit records the
+//! process's resident memory and open file descriptors, read from `/proc` so only
+//! available on Linux, the bytes allocated if built with the `jemalloc` feature, and the
+//! size of the routing table. The audits of each hour are summed up into a report along
+//! with how late the event loop woke up for its timers, a busy or blocked loop wakes late.
+
+use crate::latency::RttStats;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+
+/// What the process is using
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessStats {
+    pub rss_bytes: Option<u64>,
+    /// bytes allocated through jemalloc
+    pub allocated_bytes: Option<u64>,
+    pub fds: Option<usize>,
+}
+
+impl ProcessStats {
+    /// sample our own process
+    pub fn sample() -> Self {
+        Self {
+            rss_bytes: rss_bytes(),
+            allocated_bytes: allocated_bytes(),
+            fds: fs::read_dir("/proc/self/fd").ok().map(|d| d.count()),
+        }
+    }
+}
+
+/// the resident set size
+pub fn rss_bytes() -> Option<u64> {
+    status_bytes("VmRSS:")
+}
+
+/// the most the resident set size has been since the process started
+pub fn peak_rss_bytes() -> Option<u64> {
+    status_bytes("VmHWM:")
+}
+
+// a size from /proc/self/status, which counts them in kB
+fn status_bytes(field: &str) -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with(field))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(feature = "jemalloc")]
+fn allocated_bytes() -> Option<u64> {
+    // the stats are cached until the epoch moves on
+    tikv_jemalloc_ctl::epoch::advance().ok()?;
+    tikv_jemalloc_ctl::stats::allocated::read()
+        .ok()
+        .map(|b| b as u64)
+}
+
+#[cfg(not(feature = "jemalloc"))]
+fn allocated_bytes() -> Option<u64> {
+    None
+}
+
+/// One self-audit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Audit {
+    /// unix seconds
+    pub at: u64,
+    pub process: ProcessStats,
+    pub routing_table: usize,
+    pub connections: usize,
+}
+
+/// The summary of an hour of a soak test
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HourReport {
+    /// hours since the soak started, from 1
+    pub hour: u64,
+    /// the last audit of the hour
+    pub last: Audit,
+    /// the most memory and descriptors seen during the hour
+    pub max_rss_bytes: Option<u64>,
+    pub max_fds: Option<usize>,
+    /// how much resident memory grew since the first audit of the soak
+    pub rss_growth_bytes: Option<i64>,
+    pub lag_median_ms: Option<f64>,
+    pub lag_max_ms: Option<f64>,
+    /// swarm events handled during the hour
+    pub events: u64,
+}
+
+impl HourReport {
+    /// sum up an hour's audits and event loop lag, `first` being the soak's first audit
+    pub fn new(hour: u64, first: &Audit, audits: &[Audit], lag: &[Duration], events: u64) -> Self {
+        let last = audits.last().copied().unwrap_or(*first);
+        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+        let lag = RttStats::from_samples(lag);
+        Self {
+            hour,
+            last,
+            max_rss_bytes: audits.iter().filter_map(|a| a.process.rss_bytes).max(),
+            max_fds: audits.iter().filter_map(|a| a.process.fds).max(),
+            rss_growth_bytes: match (first.process.rss_bytes, last.process.rss_bytes) {
+                (Some(first), Some(last)) => Some(last as i64 - first as i64),
+                _ => None,
+            },
+            lag_median_ms: lag.as_ref().map(|l| ms(l.median)),
+            lag_max_ms: lag.as_ref().map(|l| ms(l.max)),
+            events,
+        }
+    }
+
+    /// add the report to a json lines file
+    pub fn append<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut line = serde_json::to_vec(self)?;
+        line.push(b'\n');
+        file.write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hour_report() {
+        let audit = |rss, fds| Audit {
+            process: ProcessStats {
+                rss_bytes: Some(rss),
+                allocated_bytes: None,
+                fds: Some(fds),
+            },
+            ..Default::default()
+        };
+        let first = audit(100, 10);
+        let audits = [audit(150, 14), audit(120, 12)];
+        let lag = [Duration::from_millis(2), Duration::from_millis(40)];
+        let report = HourReport::new(1, &first, &audits, &lag, 7);
+        assert_eq!(report.max_rss_bytes, Some(150));
+        assert_eq!(report.max_fds, Some(14));
+        assert_eq!(report.rss_growth_bytes, Some(20));
+        assert_eq!(report.lag_max_ms, Some(40.0));
+        assert_eq!(report.last, audits[1]);
+    }
+}
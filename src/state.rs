@@ -0,0 +1,364 @@
+//! The on-disk state directory.
+//!
+//! State lives in the XDG data directory (`$XDG_DATA_HOME/fleyg`, usually
+//! `~/.local/share/fleyg`, on windows `%APPDATA%\fleyg\data`), named profiles keep theirs
+//! in `profiles/<name>` below that. A windows service runs as another account with its own
+//! `%APPDATA%`, so `fleyg service install` gives it the state directory and config by their
+//! full paths. The layout is:
+//!
+//! ```text
+//! key         the node's identity keypair
+//! fleyg.sock  a running daemon's control socket, a named pipe on windows instead
+//! peers.json  the peer store
+//! dns.json    resolved bootstrap addresses
+//! tasks.json  the last run of each scheduled task
+//! records/    the record store
+//! blocks/     the blocks of the large values we published
+//! crawl/      crawl checkpoints
+//! logs/       log files, scheduled tasks' in logs/tasks/
+//! sessions/   recorded peer sessions
+//! rt/         routing table snapshots
+//! bundles/    diagnostic bundles of misbehaving peers
+//! events/     the event archive
+//! ```
+
+use crate::{
+    apilisten::ApiListen,
+    identity::{load_keypair, KeyType},
+};
+use directories::ProjectDirs;
+use libp2p::identity::Keypair;
+use log::*;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+const KEY_FILE: &str = "key";
+const CONTROL_SOCKET: &str = "fleyg.sock";
+const PEER_STORE_FILE: &str = "peers.json";
+const DNS_CACHE_FILE: &str = "dns.json";
+const TASKS_FILE: &str = "tasks.json";
+const RECORDS_DIR: &str = "records";
+const BLOCKS_DIR: &str = "blocks";
+const CRAWL_DIR: &str = "crawl";
+const LOGS_DIR: &str = "logs";
+const SESSIONS_DIR: &str = "sessions";
+const RT_DIR: &str = "rt";
+const BUNDLES_DIR: &str = "bundles";
+const EVENTS_DIR: &str = "events";
+
+/// the subdirectories that hold accumulated data and can be pruned
+pub const PRUNABLE: &[&str] = &[
+    CRAWL_DIR,
+    LOGS_DIR,
+    SESSIONS_DIR,
+    RT_DIR,
+    BUNDLES_DIR,
+    EVENTS_DIR,
+];
+
+/// the fleyg project directories, an error if there is no home directory
+pub fn project_dirs() -> io::Result<ProjectDirs> {
+    ProjectDirs::from("", "", "fleyg")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no home directory found"))
+}
+
+/// Disk usage of one entry in the state directory
+#[derive(Clone, Debug)]
+pub struct Usage {
+    pub name: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// A node's state directory
+#[derive(Clone, Debug)]
+pub struct StateDir {
+    root: PathBuf,
+}
+
+impl StateDir {
+    /// the default state directory for the given profile
+    pub fn default_root(profile: Option<&str>) -> io::Result<PathBuf> {
+        let data = project_dirs()?.data_dir().to_path_buf();
+        Ok(match profile {
+            Some(name) => data.join("profiles").join(name),
+            None => data,
+        })
+    }
+
+    /// open the state directory at the given root, creating the layout if needed
+    ///
+    /// Only the directories fleyg creates are made private, a root that already existed,
+    /// like one given with `--data-dir`, keeps its permissions and is only warned about if
+    /// other users can read it.
+    pub fn open<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        if root.is_dir() {
+            if world_readable(&root)? {
+                warn!(
+                    "The state directory {} can be read by other users",
+                    root.display()
+                );
+            }
+        } else {
+            create_private(&root)?;
+        }
+        for dir in [RECORDS_DIR, CRAWL_DIR, LOGS_DIR, SESSIONS_DIR, RT_DIR] {
+            let path = root.join(dir);
+            if !path.is_dir() {
+                create_private(&path)?;
+            }
+        }
+        Ok(Self { root })
+    }
+
+    /// the root of the state directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// the path of the identity keypair
+    pub fn key_path(&self) -> PathBuf {
+        self.root.join(KEY_FILE)
+    }
+
+    /// the path of a running daemon's control socket
+    pub fn control_socket(&self) -> PathBuf {
+        self.root.join(CONTROL_SOCKET)
+    }
+
+    /// where a running daemon's control API listens, the control socket or on windows the
+    /// state directory's named pipe
+    pub fn control_api(&self) -> ApiListen {
+        match cfg!(windows) {
+            true => ApiListen::pipe_for(&self.root),
+            false => ApiListen::Unix(self.control_socket()),
+        }
+    }
+
+    /// true if a daemon is answering on the control API
+    pub fn daemon_running(&self) -> bool {
+        self.control_api().answers()
+    }
+
+    /// the path of the peer store
+    pub fn peer_store_path(&self) -> PathBuf {
+        self.root.join(PEER_STORE_FILE)
+    }
+
+    /// the path of the DNS cache
+    pub fn dns_cache_path(&self) -> PathBuf {
+        self.root.join(DNS_CACHE_FILE)
+    }
+
+    /// the path of the scheduled tasks' last runs
+    pub fn tasks_path(&self) -> PathBuf {
+        self.root.join(TASKS_FILE)
+    }
+
+    /// the directory of the scheduled tasks' logs
+    pub fn task_logs_dir(&self) -> PathBuf {
+        self.root.join(LOGS_DIR).join("tasks")
+    }
+
+    /// the record store directory
+    pub fn records_dir(&self) -> PathBuf {
+        self.root.join(RECORDS_DIR)
+    }
+
+    /// the block store directory
+    pub fn blocks_dir(&self) -> PathBuf {
+        self.root.join(BLOCKS_DIR)
+    }
+
+    /// the crawl checkpoint directory
+    pub fn crawl_dir(&self) -> PathBuf {
+        self.root.join(CRAWL_DIR)
+    }
+
+    /// the log directory
+    pub fn logs_dir(&self) -> PathBuf {
+        self.root.join(LOGS_DIR)
+    }
+
+    /// the peer session recording directory
+    pub fn sessions_dir(&self) -> PathBuf {
+        self.root.join(SESSIONS_DIR)
+    }
+
+    /// the routing table snapshot directory
+    pub fn rt_dir(&self) -> PathBuf {
+        self.root.join(RT_DIR)
+    }
+
+    /// the misbehavior bundle directory
+    pub fn bundles_dir(&self) -> PathBuf {
+        self.root.join(BUNDLES_DIR)
+    }
+
+    /// the event archive directory
+    pub fn events_dir(&self) -> PathBuf {
+        self.root.join(EVENTS_DIR)
+    }
+
+    /// load the identity keypair, generating and saving a key of the given type, ed25519
+    /// if none, on first use
+    pub fn keypair(&self, key_type: Option<KeyType>) -> io::Result<Keypair> {
+        load_keypair(&self.key_path(), key_type)
+    }
+
+    /// the disk usage of each entry in the state directory
+    pub fn usage(&self) -> io::Result<Vec<Usage>> {
+        let mut usage = Vec::new();
+        let mut entries: Vec<_> = fs::read_dir(&self.root)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let (files, bytes) = du(&entry.path())?;
+            usage.push(Usage {
+                name: entry.file_name().to_string_lossy().into_owned(),
+                files,
+                bytes,
+            });
+        }
+        Ok(usage)
+    }
+
+    /// remove files older than the cutoff from the named prunable subdirectories, returns
+    /// the number of files and bytes removed
+    pub fn prune(&self, dirs: &[&str], older_than: Duration) -> io::Result<(u64, u64)> {
+        let cutoff = SystemTime::now()
+            .checked_sub(older_than)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut removed = (0, 0);
+        for dir in dirs {
+            if !PRUNABLE.contains(dir) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("{dir} can't be pruned"),
+                ));
+            }
+            prune_dir(&self.root.join(dir), cutoff, &mut removed)?;
+        }
+        Ok(removed)
+    }
+}
+
+/// create a directory, and any missing parents, only the owner can use
+fn create_private(path: &Path) -> io::Result<()> {
+    fs::create_dir_all(path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    Ok(())
+}
+
+/// true if users other than the owner and group can read a path
+fn world_readable(path: &Path) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        Ok(fs::metadata(path)?.permissions().mode() & 0o004 != 0)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(false)
+    }
+}
+
+/// count the files and bytes under a path
+fn du(path: &Path) -> io::Result<(u64, u64)> {
+    let meta = fs::metadata(path)?;
+    if !meta.is_dir() {
+        return Ok((1, meta.len()));
+    }
+    let mut total = (0, 0);
+    for entry in fs::read_dir(path)? {
+        let (files, bytes) = du(&entry?.path())?;
+        total.0 += files;
+        total.1 += bytes;
+    }
+    Ok(total)
+}
+
+/// remove files modified before the cutoff, and any directories left empty
+fn prune_dir(dir: &Path, cutoff: SystemTime, removed: &mut (u64, u64)) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let meta = fs::metadata(&path)?;
+        if meta.is_dir() {
+            prune_dir(&path, cutoff, removed)?;
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        } else if meta.modified()? < cutoff {
+            fs::remove_file(&path)?;
+            removed.0 += 1;
+            removed.1 += meta.len();
+        }
+    }
+    Ok(())
+}
+
+/// save a keypair in protobuf format to a new file only we can read
+pub fn write_keypair(path: &Path, key: &Keypair) -> io::Result<()> {
+    let bytes = key
+        .to_protobuf_encoding()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    write_private(path, &bytes)
+}
+
+/// write a file only the owner can read
+pub(crate) fn write_private(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_layout_without_touching_an_existing_root() {
+        let dir = std::env::temp_dir().join(format!("fleyg-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        // a root fleyg creates is private
+        let created = dir.join("created");
+        let state = StateDir::open(&created).unwrap();
+        for sub in [RECORDS_DIR, CRAWL_DIR, LOGS_DIR, SESSIONS_DIR, RT_DIR] {
+            assert!(created.join(sub).is_dir(), "{sub}");
+        }
+        assert_eq!(state.records_dir(), created.join(RECORDS_DIR));
+        let names: Vec<String> = state.usage().unwrap().into_iter().map(|u| u.name).collect();
+        assert_eq!(names, ["crawl", "logs", "records", "rt", "sessions"]);
+
+        // an existing one, like a --data-dir, keeps its permissions
+        let existing = dir.join("existing");
+        fs::create_dir_all(&existing).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = |p: &Path| fs::metadata(p).unwrap().permissions().mode() & 0o777;
+            assert_eq!(mode(&created), 0o700);
+            fs::set_permissions(&existing, fs::Permissions::from_mode(0o755)).unwrap();
+            StateDir::open(&existing).unwrap();
+            assert_eq!(mode(&existing), 0o755);
+            assert_eq!(mode(&existing.join(RECORDS_DIR)), 0o700);
+            assert!(world_readable(&existing).unwrap());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,438 @@
+//! The kademlia record store, in memory or on disk.
+//!
+//! A memory store loses its records on restart, which is fine for commands that exit but
+//! not for a server node others put records on. With `--store disk` the records are kept
+//! in memory for lookups and every change is committed to the [write-ahead
+//! log](crate::wal) in the store directory, which is replayed on start up. The limits
+//! and the store are set in the `[store]` config section:
+//!
+//! ```toml
+//! [store]
+//! kind = "disk"
+//! path = "/var/lib/fleyg/records"
+//! max_records = 65536
+//! max_value_bytes = 65536
+//! ```
+//!
+//! Provider records stay in memory either way, providers republish them every few hours.
+
+use crate::{
+    peerstore::now_secs,
+    wal::{self, Op, StoredRecord, Wal},
+};
+use libp2p::{
+    kad::{
+        record::{
+            store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+            Key, ProviderRecord, Record,
+        },
+        K_VALUE,
+    },
+    PeerId,
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt, fs, io,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// Where the records are kept
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreKind {
+    #[default]
+    Memory,
+    /// in memory, with every change committed to a write-ahead log
+    Disk,
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "memory" | "mem" => Ok(StoreKind::Memory),
+            "disk" => Ok(StoreKind::Disk),
+            _ => Err(format!("unknown record store: {s} (known: memory, disk)")),
+        }
+    }
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreKind::Memory => write!(f, "memory"),
+            StoreKind::Disk => write!(f, "disk"),
+        }
+    }
+}
+
+/// The `[store]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StoreSettings {
+    pub kind: StoreKind,
+    /// the directory of a disk store, the state directory's records directory if not set
+    pub path: Option<PathBuf>,
+    /// records the store holds before refusing more
+    pub max_records: usize,
+    /// the largest value the store accepts
+    pub max_value_bytes: usize,
+    /// keys the store holds provider records for
+    pub max_provided_keys: usize,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        let memory = MemoryStoreConfig::default();
+        Self {
+            kind: StoreKind::Memory,
+            path: None,
+            max_records: memory.max_records,
+            max_value_bytes: memory.max_value_bytes,
+            max_provided_keys: memory.max_provided_keys,
+        }
+    }
+}
+
+/// What checking a running store's log against its records found
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fsck {
+    /// the log, none for a memory store
+    pub path: Option<PathBuf>,
+    pub commits: usize,
+    pub ops: usize,
+    /// the records in memory
+    pub records: usize,
+    /// the bytes after the last complete commit
+    pub torn_bytes: u64,
+    /// the hex keys of the records a restart would load differently from what's in memory
+    pub mismatched: Vec<String>,
+}
+
+impl Fsck {
+    /// true if a restart would load the records in memory
+    pub fn is_consistent(&self) -> bool {
+        self.torn_bytes == 0 && self.mismatched.is_empty()
+    }
+}
+
+/// A record store, optionally backed by a write-ahead log
+pub struct FleygStore {
+    records: MemoryStore,
+    wal: Option<Wal>,
+    /// when each logged record was received, kept through compactions
+    stored: HashMap<Key, u64>,
+    /// operations committed since the log was last compacted
+    ops: usize,
+}
+
+impl FleygStore {
+    /// a store that forgets everything on exit
+    pub fn memory(local: PeerId, settings: &StoreSettings) -> Self {
+        Self {
+            records: MemoryStore::with_config(local, memory_config(settings)),
+            wal: None,
+            stored: HashMap::new(),
+            ops: 0,
+        }
+    }
+
+    /// the store the settings ask for, a disk store is loaded from its log
+    pub fn open(local: PeerId, settings: &StoreSettings) -> io::Result<Self> {
+        let mut store = Self::memory(local, settings);
+        if settings.kind == StoreKind::Memory {
+            return Ok(store);
+        }
+        let Some(dir) = &settings.path else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a disk record store needs a path",
+            ));
+        };
+        fs::create_dir_all(dir)?;
+        let (mut wal, scan) = Wal::open(dir.join(wal::FILE_NAME))?;
+        let now = SystemTime::now();
+        let (mut loaded, mut expired) = (Vec::new(), 0);
+        for stored in scan.records().into_values() {
+            if stored.is_expired(now) {
+                expired += 1;
+                continue;
+            }
+            match store.records.put(from_stored(&stored)) {
+                Ok(()) => {
+                    if let Some(at) = stored.stored {
+                        store.stored.insert(Key::from(stored.key.clone()), at);
+                    }
+                    loaded.push(stored);
+                }
+                Err(e) => warn!("Dropping a stored record: {e:?}"),
+            }
+        }
+        info!(
+            "Loaded {} records from {} ({expired} expired)",
+            loaded.len(),
+            wal.path().display()
+        );
+        // start from a log of just the live records
+        if scan.ops() > loaded.len() {
+            wal.compact(&loaded)?;
+        }
+        store.wal = Some(wal);
+        Ok(store)
+    }
+
+    /// true if the records survive a restart
+    pub fn is_durable(&self) -> bool {
+        self.wal.is_some()
+    }
+
+    /// compact the log to the live records, so the next start has less to replay, the
+    /// operations themselves are synced as they're committed
+    pub fn flush(&mut self) -> io::Result<()> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.compact(&live(&self.records, &self.stored))?;
+        self.ops = 0;
+        Ok(())
+    }
+
+    /// check the log against the records in memory, without repairing anything
+    pub fn fsck(&self) -> io::Result<Fsck> {
+        let mut report = Fsck {
+            records: self.records.records().count(),
+            ..Default::default()
+        };
+        let Some(wal) = &self.wal else {
+            return Ok(report);
+        };
+        let scan = wal::fsck(wal.path())?;
+        report.path = Some(wal.path().to_path_buf());
+        report.commits = scan.batches.len();
+        report.ops = scan.ops();
+        report.torn_bytes = scan.file_len - scan.valid_len;
+        let mut logged = scan.records();
+        for record in self.records.records() {
+            match logged.remove(&record.key.to_vec()) {
+                Some(stored)
+                    if stored.value == record.value && stored.publisher == record.publisher => {}
+                _ => report.mismatched.push(hex::encode(&record.key)),
+            }
+        }
+        // the log may still hold records that expired, a restart drops those too
+        let now = SystemTime::now();
+        let missing = logged.values().filter(|r| !r.is_expired(now));
+        report
+            .mismatched
+            .extend(missing.map(|r| hex::encode(&r.key)));
+        report.mismatched.sort();
+        Ok(report)
+    }
+
+    // commit an operation, compacting the log once it holds mostly replaced records
+    fn commit(&mut self, op: Op) {
+        let Some(wal) = &mut self.wal else {
+            return;
+        };
+        if let Err(e) = wal.commit(&[op]) {
+            warn!("Can't commit to {}: {e}", wal.path().display());
+            return;
+        }
+        self.ops += 1;
+        if self.ops <= 2 * self.records.records().len() + K_VALUE.get() {
+            return;
+        }
+        match wal.compact(&live(&self.records, &self.stored)) {
+            Ok(()) => self.ops = 0,
+            Err(e) => warn!("Can't compact {}: {e}", wal.path().display()),
+        }
+    }
+}
+
+impl RecordStore for FleygStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &Key) -> Option<Cow<'_, Record>> {
+        self.records.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        if self.wal.is_none() {
+            return self.records.put(r);
+        }
+        // a republished record keeps the time we first received it
+        let unchanged = self
+            .records
+            .get(&r.key)
+            .is_some_and(|old| old.value == r.value && old.publisher == r.publisher);
+        let at = match unchanged {
+            true => self.stored.get(&r.key).copied(),
+            false => Some(now_secs()),
+        };
+        let stored = to_stored(&r, at);
+        self.records.put(r)?;
+        if let Some(at) = at {
+            self.stored.insert(Key::from(stored.key.clone()), at);
+        }
+        self.commit(Op::Put(stored));
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &Key) {
+        if self.records.get(k).is_none() {
+            return;
+        }
+        self.records.remove(k);
+        self.stored.remove(k);
+        self.commit(Op::Remove(k.to_vec()));
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.records.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        self.records.add_provider(record)
+    }
+
+    fn providers(&self, key: &Key) -> Vec<ProviderRecord> {
+        self.records.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.records.provided()
+    }
+
+    fn remove_provider(&mut self, k: &Key, p: &PeerId) {
+        self.records.remove_provider(k, p)
+    }
+}
+
+// the memory store's limits from the settings
+fn memory_config(settings: &StoreSettings) -> MemoryStoreConfig {
+    MemoryStoreConfig {
+        max_records: settings.max_records,
+        max_value_bytes: settings.max_value_bytes,
+        max_provided_keys: settings.max_provided_keys,
+        ..Default::default()
+    }
+}
+
+// the records in memory as the log keeps them
+fn live(records: &MemoryStore, stored: &HashMap<Key, u64>) -> Vec<StoredRecord> {
+    records
+        .records()
+        .map(|r| to_stored(&r, stored.get(&r.key).copied()))
+        .collect()
+}
+
+// a record as the log keeps it, with its expiry and when we received it in unix seconds
+fn to_stored(record: &Record, stored: Option<u64>) -> StoredRecord {
+    let now = now_secs();
+    StoredRecord {
+        key: record.key.to_vec(),
+        value: record.value.clone(),
+        publisher: record.publisher,
+        expires: record
+            .expires
+            .map(|at| now + at.saturating_duration_since(Instant::now()).as_secs()),
+        stored,
+    }
+}
+
+// a record from the log, with its expiry as an instant
+fn from_stored(stored: &StoredRecord) -> Record {
+    let now = now_secs();
+    Record {
+        key: Key::from(stored.key.clone()),
+        value: stored.value.clone(),
+        publisher: stored.publisher,
+        expires: stored
+            .expires
+            .map(|at| Instant::now() + Duration::from_secs(at.saturating_sub(now))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn survives_reopen() {
+        let dir = std::env::temp_dir().join(format!("fleyg-store-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let settings = StoreSettings {
+            kind: StoreKind::Disk,
+            path: Some(dir.clone()),
+            max_value_bytes: 8,
+            ..Default::default()
+        };
+        let local = PeerId::random();
+        let record = |key: &[u8], value: &[u8]| Record::new(key.to_vec(), value.to_vec());
+
+        let mut store = FleygStore::open(local, &settings).unwrap();
+        assert!(store.is_durable());
+        store.put(record(b"a", b"1")).unwrap();
+        store.put(record(b"b", b"2")).unwrap();
+        store.put(record(b"a", b"3")).unwrap();
+        store.remove(&Key::from(b"b".to_vec()));
+        assert!(store.put(record(b"c", b"too large")).is_err());
+        drop(store);
+
+        let store = FleygStore::open(local, &settings).unwrap();
+        assert_eq!(store.records().count(), 1);
+        assert_eq!(store.get(&Key::from(b"a".to_vec())).unwrap().value, b"3");
+        // the reopened log was compacted down to the live record
+        assert_eq!(wal::scan(dir.join(wal::FILE_NAME)).unwrap().ops(), 1);
+        let fsck = store.fsck().unwrap();
+        assert!(fsck.is_consistent(), "{fsck:?}");
+        assert_eq!((fsck.commits, fsck.records), (1, 1));
+
+        // a record the log lost shows up as mismatched
+        fs::write(dir.join(wal::FILE_NAME), b"").unwrap();
+        let fsck = store.fsck().unwrap();
+        assert_eq!(fsck.mismatched, [hex::encode(b"a")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_keeps_stored_times() {
+        let dir = std::env::temp_dir().join(format!("fleyg-stored-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(wal::FILE_NAME);
+        let settings = StoreSettings {
+            kind: StoreKind::Disk,
+            path: Some(dir.clone()),
+            ..Default::default()
+        };
+        let old = StoredRecord {
+            key: b"a".to_vec(),
+            value: b"1".to_vec(),
+            publisher: None,
+            expires: None,
+            stored: Some(1000),
+        };
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.commit(&[Op::Put(old)]).unwrap();
+        drop(wal);
+
+        // republishing the same value isn't new, a new record is
+        let record = |key: &[u8], value: &[u8]| Record::new(key.to_vec(), value.to_vec());
+        let mut store = FleygStore::open(PeerId::random(), &settings).unwrap();
+        store.put(record(b"a", b"1")).unwrap();
+        store.put(record(b"b", b"2")).unwrap();
+        store.flush().unwrap();
+        let logged = wal::scan(&path).unwrap().records();
+        assert_eq!(logged[&b"a"[..]].stored, Some(1000));
+        assert!(logged[&b"b"[..]].stored > Some(1000));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,333 @@
+//! Columnar, optionally colored, output for result listings.
+//!
+//! Colors follow the NO_COLOR convention (https://no-color.org) and are never used when
+//! stdout isn't a terminal. With `--output json` each row is written as a json object
+//! keyed by column name instead, one per line.
+
+use crate::filter::Filter;
+use serde_json::{Map, Value};
+use std::{
+    env, fmt,
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+/// How results are written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Format {
+    /// aligned columns and log lines
+    #[default]
+    Text,
+    /// a json object per row or event
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown output format: {s} (known: text, json)")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::Text => write!(f, "text"),
+            Format::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// How a cell is highlighted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Status {
+    #[default]
+    Normal,
+    Good,
+    Warn,
+    Bad,
+}
+
+impl Status {
+    fn ansi(&self) -> Option<&'static str> {
+        match self {
+            Status::Normal => None,
+            Status::Good => Some("32"),
+            Status::Warn => Some("33"),
+            Status::Bad => Some("31"),
+        }
+    }
+}
+
+/// A single value in a table
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cell {
+    pub text: String,
+    pub status: Status,
+}
+
+impl Cell {
+    /// a cell with the given highlight
+    pub fn new<S: Into<String>>(text: S, status: Status) -> Self {
+        Self {
+            text: text.into(),
+            status,
+        }
+    }
+
+    /// a cell for a good result
+    pub fn good<S: Into<String>>(text: S) -> Self {
+        Self::new(text, Status::Good)
+    }
+
+    /// a cell for a questionable result
+    pub fn warn<S: Into<String>>(text: S) -> Self {
+        Self::new(text, Status::Warn)
+    }
+
+    /// a cell for a bad result
+    pub fn bad<S: Into<String>>(text: S) -> Self {
+        Self::new(text, Status::Bad)
+    }
+}
+
+impl From<String> for Cell {
+    fn from(text: String) -> Self {
+        Self::new(text, Status::Normal)
+    }
+}
+
+impl From<&str> for Cell {
+    fn from(text: &str) -> Self {
+        Self::new(text, Status::Normal)
+    }
+}
+
+/// Rows of cells under named columns
+#[derive(Clone, Debug, Default)]
+pub struct Table {
+    columns: Vec<String>,
+    rows: Vec<Vec<Cell>>,
+}
+
+impl Table {
+    /// an empty table with the given column names
+    pub fn new(columns: &[&str]) -> Self {
+        Self {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    /// the column names
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// add a row, missing cells are left empty and extra cells are dropped
+    pub fn push(&mut self, mut row: Vec<Cell>) {
+        row.resize(self.columns.len(), Cell::default());
+        self.rows.push(row);
+    }
+
+    /// the number of rows
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// true if there are no rows
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// keep only the rows matching the filter, comparing against the cell text under
+    /// each column name
+    pub fn filter(&mut self, filter: &Filter) -> Result<(), String> {
+        for field in filter.fields() {
+            if !self.columns.iter().any(|c| c == field) {
+                return Err(format!(
+                    "unknown filter field {field}, expected one of: {}",
+                    self.columns.join(", ")
+                ));
+            }
+        }
+        let columns = &self.columns;
+        self.rows.retain(|row| {
+            filter.matches(&|field| {
+                let i = columns.iter().position(|c| c == field)?;
+                Some(row[i].text.as_str())
+            })
+        });
+        Ok(())
+    }
+
+    /// skip the first `offset` rows and keep at most `limit` of the rest
+    pub fn page(&mut self, offset: usize, limit: Option<usize>) {
+        self.rows.drain(..offset.min(self.rows.len()));
+        if let Some(limit) = limit {
+            self.rows.truncate(limit);
+        }
+    }
+
+    /// keep only the named columns, in the order given
+    pub fn select(&mut self, names: &[String]) -> Result<(), String> {
+        let mut indices = Vec::new();
+        for name in names {
+            match self.columns.iter().position(|c| c == name) {
+                Some(i) => indices.push(i),
+                None => {
+                    return Err(format!(
+                        "unknown column {name}, expected one of: {}",
+                        self.columns.join(", ")
+                    ))
+                }
+            }
+        }
+        self.columns = indices.iter().map(|&i| self.columns[i].clone()).collect();
+        for row in &mut self.rows {
+            *row = indices.iter().map(|&i| row[i].clone()).collect();
+        }
+        Ok(())
+    }
+
+    /// render the table with aligned columns and an optionally colored header and cells
+    pub fn render(&self, color: bool) -> String {
+        let mut widths: Vec<usize> = self.columns.iter().map(|c| c.chars().count()).collect();
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.text.chars().count());
+            }
+        }
+
+        let mut out = String::new();
+        let header: Vec<Cell> = self
+            .columns
+            .iter()
+            .map(|c| Cell::from(c.to_uppercase()))
+            .collect();
+        let header = line(&header, &widths, false);
+        if color {
+            out.push_str(&format!("\x1b[1m{header}\x1b[0m\n"));
+        } else {
+            out.push_str(&header);
+            out.push('\n');
+        }
+        for row in &self.rows {
+            out.push_str(&line(row, &widths, color));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// render each row as a json object keyed by column name, one per line
+    pub fn render_json(&self) -> String {
+        let mut out = String::new();
+        for row in &self.rows {
+            let object: Map<String, Value> = self
+                .columns
+                .iter()
+                .zip(row)
+                .map(|(column, cell)| (column.clone(), Value::String(cell.text.clone())))
+                .collect();
+            out.push_str(&Value::Object(object).to_string());
+            out.push('\n');
+        }
+        out
+    }
+}
+
+/// true if output should be colored
+pub fn use_color(no_color: bool) -> bool {
+    let disabled = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    !no_color && !disabled && io::stdout().is_terminal()
+}
+
+/// How tables are printed
+#[derive(Clone, Debug, Default)]
+pub struct Output {
+    /// the columns to show, all of them if empty
+    pub columns: Vec<String>,
+    /// color the output
+    pub color: bool,
+    /// only show rows matching this expression
+    pub filter: Option<Filter>,
+    /// the number of matching rows to skip
+    pub offset: usize,
+    /// the most rows to show
+    pub limit: Option<usize>,
+    /// text tables or json lines
+    pub format: Format,
+}
+
+impl Output {
+    /// filter, page and select the configured columns, then render the table
+    pub fn render(&self, mut table: Table) -> Result<String, String> {
+        if let Some(filter) = &self.filter {
+            table.filter(filter)?;
+        }
+        table.page(self.offset, self.limit);
+        if !self.columns.is_empty() {
+            table.select(&self.columns)?;
+        }
+        Ok(match self.format {
+            Format::Text => table.render(self.color),
+            Format::Json => table.render_json(),
+        })
+    }
+
+    /// render the table to stdout
+    pub fn print(&self, table: Table) -> Result<(), String> {
+        print!("{}", self.render(table)?);
+        Ok(())
+    }
+}
+
+// pad each cell to its column width, leaving no trailing whitespace
+fn line(cells: &[Cell], widths: &[usize], color: bool) -> String {
+    let mut out = String::new();
+    let last = cells.iter().rposition(|c| !c.text.is_empty()).unwrap_or(0);
+    for (i, (cell, width)) in cells.iter().zip(widths).enumerate().take(last + 1) {
+        if i > 0 {
+            out.push_str("  ");
+        }
+        match cell.status.ansi().filter(|_| color) {
+            Some(code) => out.push_str(&format!("\x1b[{code}m{}\x1b[0m", cell.text)),
+            None => out.push_str(&cell.text),
+        }
+        if i < last {
+            out.push_str(&" ".repeat(width - cell.text.chars().count()));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_and_render() {
+        let mut table = Table::new(&["id", "agent", "rtt"]);
+        table.push(vec!["a".into(), "kubo/0.21".into(), Cell::good("12ms")]);
+        table.push(vec!["bb".into()]);
+        assert!(table.select(&["nope".to_string()]).is_err());
+        assert!(table.filter(&"nope=1".parse().unwrap()).is_err());
+        table
+            .select(&["rtt".to_string(), "id".to_string()])
+            .unwrap();
+        assert_eq!(table.render(false), "RTT   ID\n12ms  a\n      bb\n");
+        assert_eq!(
+            table.render(true),
+            "\x1b[1mRTT   ID\x1b[0m\n\x1b[32m12ms\x1b[0m  a\n      bb\n"
+        );
+        assert_eq!(
+            table.render_json(),
+            "{\"id\":\"a\",\"rtt\":\"12ms\"}\n{\"id\":\"bb\",\"rtt\":\"\"}\n"
+        );
+    }
+}
@@ -0,0 +1,63 @@
+//! Parsing of human friendly times and durations used on the command line.
+
+use chrono::DateTime;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// parse a duration like "90s", "15m", "2h" or "1d", a bare number is seconds
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("invalid duration: {s}"))?;
+    let secs = match unit {
+        "" | "s" => n,
+        "m" => n * 60,
+        "h" => n * 60 * 60,
+        "d" => n * 60 * 60 * 24,
+        "ms" => return Ok(Duration::from_millis(n)),
+        _ => return Err(format!("invalid duration unit: {unit}")),
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+/// parse a point in time as unix seconds: "now", a unix timestamp, an RFC 3339 date or a
+/// duration meaning that long ago (e.g. "2h")
+pub fn parse_time(s: &str) -> Result<u64, String> {
+    let now = now_secs();
+    let s = s.trim();
+    if s == "now" {
+        return Ok(now);
+    }
+    if let Ok(secs) = s.parse::<u64>() {
+        return Ok(secs);
+    }
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return u64::try_from(t.timestamp()).map_err(|_| format!("time before epoch: {s}"));
+    }
+    let ago = parse_duration(s).map_err(|_| format!("invalid time: {s}"))?;
+    Ok(now.saturating_sub(ago.as_secs()))
+}
+
+/// the current unix time in seconds
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_and_times() {
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(900)));
+        assert_eq!(parse_duration("30"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert!(parse_duration("2w").is_err());
+        assert_eq!(parse_time("1700000000"), Ok(1_700_000_000));
+        assert_eq!(parse_time("2023-11-14T22:13:20Z"), Ok(1_700_000_000));
+        assert!(parse_time("2h").unwrap() < now_secs());
+    }
+}
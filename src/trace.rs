@@ -0,0 +1,143 @@
+//! Trace ids for correlating log lines with the user operation that caused them.
+//!
+//! An operation sets the current trace id when it starts and every log line written while
+//! it is set is tagged with `trace=<id>`.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// zero means no trace is set
+static CURRENT: AtomicU64 = AtomicU64::new(0);
+
+/// An identifier for a single user initiated operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TraceId(u64);
+
+impl TraceId {
+    /// a new random trace id
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos())
+            .unwrap_or_default();
+        loop {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(nanos);
+            let id = hasher.finish();
+            if id != 0 {
+                return Self(id);
+            }
+        }
+    }
+
+    /// the trace id of the operation in progress, if any
+    pub fn current() -> Option<Self> {
+        match CURRENT.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(Self(id)),
+        }
+    }
+
+    /// make this the trace id of the operation in progress
+    pub fn enter(self) {
+        CURRENT.store(self.0, Ordering::Relaxed);
+    }
+
+    /// clear the trace id of the operation in progress
+    pub fn exit() {
+        CURRENT.store(0, Ordering::Relaxed);
+    }
+
+    /// make this the trace id until the guard is dropped, for work done on behalf of
+    /// another operation, like a daemon answering a call
+    pub fn scope(self) -> Scope {
+        Scope(CURRENT.swap(self.0, Ordering::Relaxed))
+    }
+}
+
+/// Puts back the trace id a scope replaced when it's dropped
+#[derive(Debug)]
+pub struct Scope(u64);
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.store(self.0, Ordering::Relaxed);
+    }
+}
+
+impl Default for TraceId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl FromStr for TraceId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match u64::from_str_radix(s, 16) {
+            Ok(0) | Err(_) => Err(format!("invalid trace id: {s}")),
+            Ok(id) => Ok(Self(id)),
+        }
+    }
+}
+
+/// an env_logger format that tags each line with the current trace id
+pub fn format(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let ts = buf.timestamp();
+    match TraceId::current() {
+        Some(trace) => writeln!(
+            buf,
+            "[{ts} {:<5} {} trace={trace}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+        None => writeln!(
+            buf,
+            "[{ts} {:<5} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let id = TraceId::new();
+        assert_eq!(id.to_string().len(), 16);
+        assert_eq!(id.to_string().parse::<TraceId>(), Ok(id));
+        assert!("0".parse::<TraceId>().is_err());
+        assert!("xyz".parse::<TraceId>().is_err());
+
+        assert_eq!(TraceId::current(), None);
+        id.enter();
+        assert_eq!(TraceId::current(), Some(id));
+        let other = TraceId::new();
+        {
+            let _scope = other.scope();
+            assert_eq!(TraceId::current(), Some(other));
+        }
+        assert_eq!(TraceId::current(), Some(id));
+        TraceId::exit();
+        assert_eq!(TraceId::current(), None);
+    }
+}
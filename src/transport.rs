@@ -0,0 +1,260 @@
+//! The transport stack: tcp and websockets with dns, secured with noise and multiplexed
+//! with yamux.
+//!
+//! Nodes run plain tcp unless the `[transport]` section or `--transport` adds
+//! websockets, for peers only reachable through browsers or websocket gateways. Secure
+//! websockets can always be dialed, listening on a `/wss` address needs a certificate:
+//!
+//! ```toml
+//! [transport]
+//! kinds = ["tcp", "ws"]
+//! tls_cert = "/etc/fleyg/fullchain.pem"
+//! tls_key = "/etc/fleyg/privkey.pem"
+//! ```
+//!
+//! Private deployments can set a noise prologue, both sides of a handshake have to use
+//! the same one, so nodes only connect to peers configured for the same network. The
+//! noise implementation only has the XX handshake, IK can be selected but is refused.
+//!
+//! A capture can be given to record every stream's decrypted bytes, and a relay client
+//! transport to dial peers through circuit relays.
+
+use crate::{
+    capture::{Capture, CaptureMuxer},
+    runtime,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use libp2p::{
+    core::{
+        muxing::StreamMuxerBox,
+        transport::{Boxed, OptionalTransport},
+        upgrade,
+    },
+    identity::Keypair,
+    noise, relay, tcp, websocket, yamux, PeerId, Transport,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// A noise handshake pattern
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Pattern {
+    #[default]
+    Xx,
+    Ik,
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "xx" => Ok(Pattern::Xx),
+            "ik" => Ok(Pattern::Ik),
+            _ => Err(format!(
+                "unknown noise handshake pattern: {s} (known: xx, ik)"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Xx => write!(f, "XX"),
+            Pattern::Ik => write!(f, "IK"),
+        }
+    }
+}
+
+/// The noise handshake settings
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NoiseSettings {
+    pub pattern: Pattern,
+    /// bound into the handshake, peers with a different prologue fail to connect
+    pub prologue: Option<String>,
+}
+
+/// A transport a node can run
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Tcp,
+    /// websockets over tcp, with TLS for `/wss` addresses
+    Ws,
+}
+
+impl FromStr for Kind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tcp" => Ok(Kind::Tcp),
+            "ws" | "websocket" => Ok(Kind::Ws),
+            _ => Err(format!("unknown transport: {s} (known: tcp, ws)")),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Kind::Tcp => write!(f, "tcp"),
+            Kind::Ws => write!(f, "ws"),
+        }
+    }
+}
+
+/// The `[transport]` config section
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+    /// the transports to dial and listen with
+    pub kinds: Vec<Kind>,
+    /// the PEM certificate chain `/wss` listeners present
+    pub tls_cert: Option<PathBuf>,
+    /// the PEM or DER private key of the certificate
+    pub tls_key: Option<PathBuf>,
+}
+
+impl Default for TransportSettings {
+    fn default() -> Self {
+        Self {
+            kinds: vec![Kind::Tcp],
+            tls_cert: None,
+            tls_key: None,
+        }
+    }
+}
+
+impl TransportSettings {
+    /// true if the transport is to run
+    pub fn has(&self, kind: Kind) -> bool {
+        self.kinds.contains(&kind)
+    }
+
+    // the certificate for wss listeners, if one is configured
+    fn tls(&self) -> io::Result<Option<websocket::tls::Config>> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "a wss certificate needs both tls_cert and tls_key",
+                ))
+            }
+        };
+        let certs = pem_blocks(cert)?
+            .into_iter()
+            .map(websocket::tls::Certificate::new);
+        let key = pem_blocks(key)?.into_iter().next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "the tls_key file is empty")
+        })?;
+        websocket::tls::Config::new(websocket::tls::PrivateKey::new(key), certs)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// the DER blocks of a PEM file, or the file itself if it's DER already
+fn pem_blocks(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let bytes = fs::read(path)?;
+    let Ok(pem) = std::str::from_utf8(&bytes) else {
+        return Ok(vec![bytes]);
+    };
+    let mut blocks = Vec::new();
+    let mut body = None;
+    for line in pem.lines() {
+        if line.starts_with("-----BEGIN") {
+            body = Some(String::new());
+        } else if line.starts_with("-----END") {
+            if let Some(b64) = body.take() {
+                let der = STANDARD.decode(b64).map_err(|e| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("bad PEM in {}: {e}", path.display()),
+                    )
+                })?;
+                blocks.push(der);
+            }
+        } else if let Some(b64) = &mut body {
+            b64.push_str(line.trim());
+        }
+    }
+    Ok(blocks)
+}
+
+/// build the transport for the given identity
+pub async fn build(
+    key: &Keypair,
+    settings: &NoiseSettings,
+    transports: &TransportSettings,
+    capture: Option<Arc<Capture>>,
+    relay: Option<relay::client::Transport>,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    if transports.kinds.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no transports to run",
+        ));
+    }
+    if settings.pattern != Pattern::Xx {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("the {} noise handshake isn't supported", settings.pattern),
+        ));
+    }
+    let mut noise =
+        noise::Config::new(key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    if let Some(prologue) = &settings.prologue {
+        noise = noise.with_prologue(prologue.as_bytes().to_vec());
+    }
+
+    let tcp = || runtime::Tcp::new(tcp::Config::new().nodelay(true));
+    let dns_tcp = match transports.has(Kind::Tcp) {
+        true => OptionalTransport::some(runtime::dns(tcp()).await?),
+        false => OptionalTransport::none(),
+    };
+    let ws_dns_tcp = match transports.has(Kind::Ws) {
+        true => {
+            let mut ws = websocket::WsConfig::new(runtime::dns(tcp()).await?);
+            if let Some(tls) = transports.tls()? {
+                ws.set_tls_config(tls);
+            }
+            OptionalTransport::some(ws)
+        }
+        false => OptionalTransport::none(),
+    };
+    let relay = match relay {
+        Some(relay) => OptionalTransport::some(relay),
+        None => OptionalTransport::none(),
+    };
+    Ok(dns_tcp
+        .or_transport(ws_dns_tcp)
+        .or_transport(relay)
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise)
+        .multiplex(yamux::Config::default())
+        .timeout(Duration::from_secs(20))
+        .map(move |(peer, muxer), endpoint| {
+            let muxer = StreamMuxerBox::new(muxer);
+            match &capture {
+                Some(capture) => {
+                    let dialer = endpoint.is_dialer();
+                    let muxer = CaptureMuxer::new(muxer, capture.clone(), peer, dialer);
+                    (peer, StreamMuxerBox::new(muxer))
+                }
+                None => (peer, muxer),
+            }
+        })
+        .boxed())
+}
@@ -0,0 +1,270 @@
+//! A write-ahead log for the on-disk record store.
+//!
+//! Every change to the store is committed as one frame holding a batch of operations:
+//!
+//! ```text
+//! len    u32 little endian length of the payload
+//! crc    u32 little endian crc32 of the payload
+//! payload  the batch as json
+//! ```
+//!
+//! A batch is applied completely or not at all. A crash part way through a write leaves a
+//! short or mismatched last frame, opening the log drops everything from the first bad
+//! frame on so the store comes back as of the last complete commit.
+
+use libp2p::PeerId;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// the name of the log in the records directory
+pub const FILE_NAME: &str = "store.wal";
+
+/// frames larger than this are treated as corrupt
+const MAX_FRAME: u32 = 64 * 1024 * 1024;
+
+/// A record as the store keeps it
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredRecord {
+    #[serde(with = "hex::serde")]
+    pub key: Vec<u8>,
+    #[serde(with = "hex::serde")]
+    pub value: Vec<u8>,
+    pub publisher: Option<PeerId>,
+    /// unix seconds after which the record is dropped
+    pub expires: Option<u64>,
+    /// unix seconds when the store received the record
+    #[serde(default)]
+    pub stored: Option<u64>,
+}
+
+impl StoredRecord {
+    /// true if the record has expired at the given time
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        self.expires.is_some_and(|at| at <= now)
+    }
+
+    /// true if the store received the record at or after the given time
+    pub fn stored_since(&self, since: SystemTime) -> bool {
+        let since = since
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.stored.is_some_and(|at| at >= since)
+    }
+}
+
+/// One change to the store
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Op {
+    Put(StoredRecord),
+    Remove(#[serde(with = "hex::serde")] Vec<u8>),
+}
+
+/// What reading a log found
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Scan {
+    /// the complete batches in commit order
+    pub batches: Vec<Vec<Op>>,
+    /// the length of the log up to the end of the last complete batch
+    pub valid_len: u64,
+    /// the length of the log file
+    pub file_len: u64,
+}
+
+impl Scan {
+    /// true if there is nothing after the last complete batch
+    pub fn is_clean(&self) -> bool {
+        self.valid_len == self.file_len
+    }
+
+    /// the number of operations in the complete batches
+    pub fn ops(&self) -> usize {
+        self.batches.iter().map(Vec::len).sum()
+    }
+
+    /// the records left after applying every batch in order
+    pub fn records(&self) -> BTreeMap<Vec<u8>, StoredRecord> {
+        let mut records = BTreeMap::new();
+        for op in self.batches.iter().flatten() {
+            match op {
+                Op::Put(record) => {
+                    records.insert(record.key.clone(), record.clone());
+                }
+                Op::Remove(key) => {
+                    records.remove(key);
+                }
+            }
+        }
+        records
+    }
+}
+
+/// read every complete batch in the log at the given path, a missing log is empty
+pub fn scan<P: AsRef<Path>>(path: P) -> io::Result<Scan> {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    let mut scan = Scan {
+        file_len: bytes.len() as u64,
+        ..Default::default()
+    };
+    let mut rest = &bytes[..];
+    while let Some((batch, used)) = frame(rest) {
+        scan.batches.push(batch);
+        scan.valid_len += used as u64;
+        rest = &rest[used..];
+    }
+    Ok(scan)
+}
+
+// decode the frame at the start of the bytes and the number of bytes it used
+fn frame(bytes: &[u8]) -> Option<(Vec<Op>, usize)> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+    let crc = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+    if len > MAX_FRAME {
+        return None;
+    }
+    let payload = bytes.get(8..8 + len as usize)?;
+    if crc32(payload) != crc {
+        return None;
+    }
+    let batch = serde_json::from_slice(payload).ok()?;
+    Some((batch, 8 + len as usize))
+}
+
+/// An open write-ahead log
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// open the log at the given path, dropping anything after the last complete batch,
+    /// and return it with what it holds
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Scan)> {
+        let path = path.as_ref().to_path_buf();
+        let scan = scan(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if !scan.is_clean() {
+            warn!(
+                "Recovered {}: dropped {} bytes of an incomplete commit",
+                path.display(),
+                scan.file_len - scan.valid_len
+            );
+            file.set_len(scan.valid_len)?;
+            file.sync_data()?;
+        }
+        Ok((Self { path, file }, scan))
+    }
+
+    /// the path of the log
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// write a batch and wait for it to reach the disk
+    pub fn commit(&mut self, batch: &[Op]) -> io::Result<()> {
+        self.file.write_all(&encode(batch)?)?;
+        self.file.sync_data()
+    }
+
+    /// replace the log with a single batch putting the given records, so replaying it
+    /// doesn't grow without bound
+    pub fn compact<'a, I>(&mut self, records: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = &'a StoredRecord>,
+    {
+        let batch: Vec<Op> = records.into_iter().cloned().map(Op::Put).collect();
+        let tmp = self.path.with_extension("compact");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&encode(&batch)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// check the log at the given path without repairing anything, an error if it is missing
+pub fn fsck<P: AsRef<Path>>(path: P) -> io::Result<Scan> {
+    fs::metadata(path.as_ref())?;
+    scan(path)
+}
+
+// frame a batch for writing
+fn encode(batch: &[Op]) -> io::Result<Vec<u8>> {
+    let payload = serde_json::to_vec(batch)?;
+    let mut buf = Vec::with_capacity(payload.len() + 8);
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32(&payload).to_le_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// the standard crc32 (ieee) of the bytes
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recovers_torn_commit() {
+        let path = std::env::temp_dir().join(format!("fleyg-wal-{}.wal", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let put = |key: &[u8]| {
+            Op::Put(StoredRecord {
+                key: key.to_vec(),
+                value: b"v".to_vec(),
+                publisher: None,
+                expires: None,
+                stored: None,
+            })
+        };
+
+        let (mut wal, scan) = Wal::open(&path).unwrap();
+        assert!(scan.batches.is_empty());
+        wal.commit(&[put(b"a"), put(b"b")]).unwrap();
+        wal.commit(&[Op::Remove(b"a".to_vec())]).unwrap();
+        drop(wal);
+
+        // a crash part way through the next commit
+        let torn = encode(&[put(b"c")]).unwrap();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&torn[..torn.len() - 3]).unwrap();
+        drop(file);
+        assert!(!fsck(&path).unwrap().is_clean());
+
+        let (_, scan) = Wal::open(&path).unwrap();
+        assert_eq!(scan.ops(), 3);
+        let records = scan.records();
+        assert_eq!(records.keys().collect::<Vec<_>>(), vec![&b"b".to_vec()]);
+        assert!(fsck(&path).unwrap().is_clean());
+        fs::remove_file(&path).unwrap();
+    }
+}